@@ -0,0 +1,49 @@
+//! Times route matching as the route table grows. Run it with
+//! `cargo run --release --example route_bench`.
+use std::time::Instant;
+
+use rust_http_server::{HttpMethod, HttpRequest, HttpResponse, Router};
+
+const DISPATCHES: u32 = 100_000;
+
+/// A router with `count` resources, each with a collection, an item and a
+/// nested collection route, as a large API would have.
+fn router(count: usize) -> Router {
+    (0..count / 3).fold(Router::new(), |router, resource| {
+        router
+            .route(HttpMethod::GET, &format!("/api/r{}", resource), |_| async {
+                HttpResponse::ok()
+            })
+            .route(HttpMethod::GET, &format!("/api/r{}/:id", resource), |_| async {
+                HttpResponse::ok()
+            })
+            .route(
+                HttpMethod::GET,
+                &format!("/api/r{}/:id/children", resource),
+                |_| async { HttpResponse::ok() },
+            )
+    })
+}
+
+#[tokio::main]
+async fn main() {
+    for &count in &[30, 3_000, 30_000] {
+        let router = router(count);
+        // The last resource's routes were added last, the worst case for a
+        // linear scan.
+        let target = format!("/api/r{}/42/children", count / 3 - 1);
+        let started = Instant::now();
+        for _ in 0..DISPATCHES {
+            let response = router
+                .dispatch(HttpRequest::new(HttpMethod::GET, &target))
+                .await;
+            assert_eq!(200, response.status);
+        }
+        let elapsed = started.elapsed();
+        println!(
+            "{:>6} routes: {:>8.0} ns per dispatch",
+            count,
+            elapsed.as_nanos() as f64 / f64::from(DISPATCHES)
+        );
+    }
+}
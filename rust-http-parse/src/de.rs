@@ -0,0 +1,257 @@
+use std::fmt;
+
+use serde::de::value::{BorrowedStrDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+
+/// Why `key=value` pairs couldn't be deserialized into the type asked for,
+/// such as a missing field or a value that isn't a number. Meant to be shown
+/// to the client, so it names the field but not the type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeserializeError(String);
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeserializeError(msg.to_string())
+    }
+}
+
+/// Deserializes decoded `key=value` pairs as a map or struct. Values are
+/// parsed as whatever their field's type is, a repeated key fills a `Vec`
+/// field, and a key given with an empty value leaves an `Option` field
+/// `None`. Otherwise the first value of a repeated key is used.
+pub(crate) struct PairsDeserializer<'de> {
+    fields: Vec<(&'de str, Vec<&'de str>)>,
+}
+impl<'de> PairsDeserializer<'de> {
+    pub(crate) fn new(pairs: impl Iterator<Item = (&'de str, &'de str)>) -> Self {
+        let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+        for (key, value) in pairs {
+            match fields.iter_mut().find(|(field, _)| *field == key) {
+                Some((_, values)) => values.push(value),
+                None => fields.push((key, vec![value])),
+            }
+        }
+        PairsDeserializer { fields }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for PairsDeserializer<'de> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(Fields {
+            fields: self.fields.into_iter(),
+            current: None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct Fields<'de> {
+    fields: std::vec::IntoIter<(&'de str, Vec<&'de str>)>,
+    current: Option<(&'de str, Vec<&'de str>)>,
+}
+
+impl<'de> MapAccess<'de> for Fields<'de> {
+    type Error = DeserializeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        self.current = self.fields.next();
+        match &self.current {
+            Some((key, _)) => seed
+                .deserialize(BorrowedStrDeserializer::new(key))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, values) = self
+            .current
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before its key"))?;
+        seed.deserialize(Values(values))
+            .map_err(|error| DeserializeError(format!("{}: {}", key, error)))
+    }
+}
+
+/// The values given for one key.
+struct Values<'de>(Vec<&'de str>);
+
+/// Parses the first value with `FromStr`, reporting what was expected if it
+/// doesn't parse.
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                let value = self.first();
+                match value.parse() {
+                    Ok(parsed) => visitor.$visit(parsed),
+                    Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(value), &visitor)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Values<'de> {
+    fn first(&self) -> &'de str {
+        self.0.first().copied().unwrap_or_default()
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Values<'de> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.first())
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.0.iter().all(|value| value.is_empty()) {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let values = self.0.into_iter().map(|value| Values(vec![value]));
+        visitor.visit_seq(SeqDeserializer::new(values))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.first().into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeserializeError> for Values<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Query;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Order {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search<'a> {
+        q: &'a str,
+        page: Option<u32>,
+        #[serde(default)]
+        tag: Vec<String>,
+        order: Option<Order>,
+        exact: Option<bool>,
+    }
+
+    #[test]
+    fn deserializes_typed_fields() {
+        let query = Query::parse("q=rust+http&page=2&tag=a&tag=b&order=desc&exact=");
+
+        assert_eq!(
+            Ok(Search {
+                q: "rust http",
+                page: Some(2),
+                tag: vec!["a".to_owned(), "b".to_owned()],
+                order: Some(Order::Desc),
+                exact: None,
+            }),
+            query.deserialize()
+        );
+        assert_eq!(
+            Ok(Search {
+                q: "",
+                page: None,
+                tag: vec![],
+                order: None,
+                exact: Some(true),
+            }),
+            Query::parse("q&exact=true").deserialize()
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_malformed_fields() {
+        let missing = Query::parse("page=2").deserialize::<Search>().unwrap_err();
+        let malformed = Query::parse("q=x&page=two")
+            .deserialize::<Search>()
+            .unwrap_err();
+        let unknown = Query::parse("q=x&order=up")
+            .deserialize::<Search>()
+            .unwrap_err();
+
+        assert_eq!("missing field `q`", missing.to_string());
+        assert_eq!(
+            "page: invalid value: string \"two\", expected u32",
+            malformed.to_string()
+        );
+        assert_eq!(
+            "order: unknown variant `up`, expected `asc` or `desc`",
+            unknown.to_string()
+        );
+    }
+}
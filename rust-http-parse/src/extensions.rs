@@ -0,0 +1,94 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A type-keyed map for values attached to a request as it moves through
+/// the server, such as an authenticated identity stashed by middleware for
+/// handlers further down. Holds at most one value per type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Stores `value`, returning the value of the same type it replaced.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct UserId(u32);
+
+    #[test]
+    fn stores_one_value_per_type() {
+        let mut extensions = Extensions::new();
+
+        assert_eq!(None, extensions.insert(UserId(1)));
+        assert_eq!(None, extensions.insert("trace-id".to_string()));
+        assert_eq!(Some(UserId(1)), extensions.insert(UserId(2)));
+
+        assert_eq!(Some(&UserId(2)), extensions.get::<UserId>());
+        assert_eq!(Some(&"trace-id".to_string()), extensions.get::<String>());
+        assert_eq!(2, extensions.len());
+    }
+
+    #[test]
+    fn get_mut_and_remove_by_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(UserId(1));
+
+        extensions.get_mut::<UserId>().unwrap().0 = 7;
+
+        assert_eq!(Some(UserId(7)), extensions.remove::<UserId>());
+        assert_eq!(None, extensions.get::<UserId>());
+        assert!(extensions.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use serde::de::Error;
+
+use crate::de::DeserializeError;
+use crate::Query;
+
+/// The fields of a form body, by its `Content-Type`: either
+/// `application/x-www-form-urlencoded`, which is also assumed when there's no
+/// `Content-Type`, or `multipart/form-data` holding only text fields.
+pub(crate) fn form_fields(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Vec<(String, String)>, DeserializeError> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| DeserializeError::custom("form is not valid UTF-8"))?;
+    let mut params = content_type.unwrap_or_default().split(';');
+    let media_type = params
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match media_type.as_str() {
+        "" | "application/x-www-form-urlencoded" => Ok(Query::parse(body)
+            .iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()),
+        "multipart/form-data" => {
+            let boundary = params
+                .find_map(|param| param.trim().strip_prefix("boundary="))
+                .map(|boundary| boundary.trim_matches('"'))
+                .filter(|boundary| !boundary.is_empty())
+                .ok_or_else(|| DeserializeError::custom("multipart form has no boundary"))?;
+            multipart_fields(body, boundary)
+        }
+        _ => Err(DeserializeError::custom(format!(
+            "expected a form, not {}",
+            media_type
+        ))),
+    }
+}
+
+/// Splits a `multipart/form-data` body into its fields. Parts that carry a
+/// file are refused, since a file can't be a struct field.
+fn multipart_fields(body: &str, boundary: &str) -> Result<Vec<(String, String)>, DeserializeError> {
+    let malformed = || DeserializeError::custom("malformed multipart form");
+    let delimiter = format!("--{}", boundary);
+    let mut parts = body.split(delimiter.as_str()).skip(1);
+    let mut fields = Vec::new();
+    loop {
+        let part = parts.next().ok_or_else(malformed)?;
+        if part.starts_with("--") {
+            return Ok(fields);
+        }
+        let part = part.strip_prefix("\r\n").ok_or_else(malformed)?;
+        let part = part.strip_suffix("\r\n").ok_or_else(malformed)?;
+        let (headers, value) = part.split_once("\r\n\r\n").ok_or_else(malformed)?;
+        let disposition = headers
+            .split("\r\n")
+            .find_map(|header| {
+                let (name, value) = header.split_once(':')?;
+                Some(value).filter(|_| name.trim().eq_ignore_ascii_case("Content-Disposition"))
+            })
+            .ok_or_else(malformed)?;
+        let mut name = None;
+        for param in disposition.split(';').skip(1) {
+            match param.trim().split_once('=') {
+                Some(("name", value)) => name = Some(value.trim_matches('"')),
+                Some(("filename", _)) => {
+                    return Err(DeserializeError::custom(
+                        "file uploads aren't supported in forms",
+                    ))
+                }
+                _ => {}
+            }
+        }
+        fields.push((name.ok_or_else(malformed)?.to_owned(), value.to_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{HttpMethod, HttpRequestBuilder};
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Signup {
+        name: String,
+        age: u8,
+        #[serde(default)]
+        interest: Vec<String>,
+    }
+
+    fn post(content_type: &str, body: &str) -> crate::HttpRequest {
+        HttpRequestBuilder::new()
+            .with_method(HttpMethod::POST)
+            .with_path("/signup")
+            .with_header("Content-Type", content_type)
+            .with_body(body)
+            .build()
+            .unwrap()
+    }
+
+    fn signup() -> Signup {
+        Signup {
+            name: "Ada L".to_owned(),
+            age: 36,
+            interest: vec!["math".to_owned(), "engines".to_owned()],
+        }
+    }
+
+    #[test]
+    fn deserializes_urlencoded_forms() {
+        let request = post(
+            "application/x-www-form-urlencoded",
+            "name=Ada+L&age=36&interest=math&interest=engines",
+        );
+
+        assert_eq!(Ok(signup()), request.form_as());
+        let lowercase = HttpRequestBuilder::new()
+            .with_method(HttpMethod::POST)
+            .with_path("/signup")
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("name=Ada+L&age=36&interest=math&interest=engines")
+            .build()
+            .unwrap();
+        assert_eq!(Ok(signup()), lowercase.form_as());
+    }
+
+    #[test]
+    fn deserializes_multipart_forms() {
+        let body = "--xyz\r\n\
+            Content-Disposition: form-data; name=\"name\"\r\n\r\nAda L\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"age\"\r\n\r\n36\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"interest\"\r\n\r\nmath\r\n\
+            --xyz\r\n\
+            content-disposition: form-data; name=interest\r\n\
+            Content-Type: text/plain\r\n\r\nengines\r\n\
+            --xyz--\r\n";
+        let request = post("multipart/form-data; boundary=\"xyz\"", body);
+
+        assert_eq!(Ok(signup()), request.form_as());
+    }
+
+    #[test]
+    fn rejects_files_and_other_bodies() {
+        let file = "--xyz\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"a.png\"\r\n\r\n...\r\n\
+            --xyz--\r\n";
+        let error =
+            |request: crate::HttpRequest| request.form_as::<Signup>().unwrap_err().to_string();
+
+        assert_eq!(
+            "file uploads aren't supported in forms",
+            error(post("multipart/form-data; boundary=xyz", file))
+        );
+        assert_eq!(
+            "malformed multipart form",
+            error(post("multipart/form-data; boundary=xyz", "--xyz\r\nname"))
+        );
+        assert_eq!(
+            "expected a form, not application/json",
+            error(post("application/json", "{}"))
+        );
+        assert_eq!("missing field `age`", error(post("", "name=Ada")));
+    }
+}
@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Header names common enough in requests that they're worth sharing
+/// rather than allocating, in their usual capitalization.
+const COMMON_NAMES: [&str; 22] = [
+    "Host",
+    "User-Agent",
+    "Accept",
+    "Accept-Encoding",
+    "Accept-Language",
+    "Connection",
+    "Content-Length",
+    "Content-Type",
+    "Cookie",
+    "Authorization",
+    "Referer",
+    "Origin",
+    "Cache-Control",
+    "Pragma",
+    "If-None-Match",
+    "If-Modified-Since",
+    "Range",
+    "Transfer-Encoding",
+    "Upgrade-Insecure-Requests",
+    "X-Forwarded-For",
+    "X-Requested-With",
+    "DNT",
+];
+
+/// A header name, borrowed from a static table for common headers so
+/// parsing them doesn't allocate.
+pub(crate) type HeaderName = Cow<'static, str>;
+
+/// Makes a header name from raw bytes, sharing the static copy when the
+/// name is a common one in any case. Header names are ASCII tokens by the
+/// time they get here, so the lossy conversion never actually loses anything.
+pub(crate) fn header_name(bytes: &[u8]) -> HeaderName {
+    match common_name(bytes) {
+        Some(name) => Cow::Borrowed(name),
+        None => Cow::Owned(String::from_utf8_lossy(bytes).into_owned()),
+    }
+}
+
+/// The static copy of a common header name, matched without regard to case.
+fn common_name(bytes: &[u8]) -> Option<&'static str> {
+    COMMON_NAMES
+        .iter()
+        .copied()
+        .find(|name| name.as_bytes().eq_ignore_ascii_case(bytes))
+}
+
+/// Looks up a header by name, ignoring case. Common names are stored in the
+/// table's capitalization and other names usually as they're asked for, so
+/// an exact match is tried first.
+pub(crate) fn get<'a>(headers: &'a HashMap<HeaderName, String>, name: &str) -> Option<&'a String> {
+    let name = common_name(name.as_bytes()).unwrap_or(name);
+    headers.get(name).or_else(|| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    })
+}
+
+/// Sets a header, replacing any of the same name in another case.
+pub(crate) fn insert(headers: &mut HashMap<HeaderName, String>, name: HeaderName, value: String) {
+    if !headers.contains_key(&name) {
+        headers.retain(|key, _| !key.eq_ignore_ascii_case(&name));
+    }
+    headers.insert(name, value);
+}
+
+/// Removes a header in any case, returning its value.
+pub(crate) fn remove(headers: &mut HashMap<HeaderName, String>, name: &str) -> Option<String> {
+    let key = headers
+        .keys()
+        .find(|key| key.eq_ignore_ascii_case(name))?
+        .clone();
+    headers.remove(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_common_names_without_allocating() {
+        assert!(matches!(
+            header_name(b"Content-Length"),
+            Cow::Borrowed("Content-Length")
+        ));
+        assert!(matches!(
+            header_name(b"user-agent"),
+            Cow::Borrowed("User-Agent")
+        ));
+        assert!(matches!(header_name(b"X-Custom"), Cow::Owned(ref name) if name == "X-Custom"));
+    }
+
+    #[test]
+    fn names_match_in_any_case() {
+        let mut headers = HashMap::new();
+        insert(&mut headers, header_name(b"host"), "a.example".to_owned());
+        insert(&mut headers, header_name(b"X-Token"), "1".to_owned());
+        insert(&mut headers, header_name(b"x-token"), "2".to_owned());
+
+        assert_eq!(Some(&"a.example".to_owned()), get(&headers, "Host"));
+        assert_eq!(Some(&"2".to_owned()), get(&headers, "X-TOKEN"));
+        assert_eq!(None, get(&headers, "Cookie"));
+        assert_eq!(2, headers.len());
+    }
+}
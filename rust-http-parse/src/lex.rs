@@ -1,33 +1,37 @@
 use lazy_static::lazy_static;
 use log::trace;
+use memchr::{memchr, memchr2, memchr3};
 use std::str::FromStr;
 use tokio::io::AsyncReadExt;
 
+use super::header::{header_name, HeaderName};
 use super::HttpMethod;
 
 type LexResult = (Token, Option<LexState>);
 
-const TOKEN_REGEX_STR: &str = r"^[!\#\$%\&'\*+-\.\^_`\|~a-zA-Z0-9]+";
-const CRLF_REGEX_STR: &str = r"^\r\n";
 const MAX_HEADER_SIZE: usize = 1024 * 8;
-use regex::Regex;
+use regex::bytes::Regex;
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Method(HttpMethod),
+    /// A well-formed method the server doesn't know, such as `PROPFIND`.
+    UnknownMethod(String),
     Path(String),
     Protocol,
-    HeaderName(String),
+    HeaderName(HeaderName),
     HeaderValue(String),
     Body(Vec<u8>),
     Crlf,
     Error,
     MaxHeaderSizeExceeded,
+    EarlyEof,
+    IoError(String),
+    InvalidEncoding,
 }
 
 #[derive(Debug, Clone, Copy)]
 enum LexState {
-    Initial,
     RequestLine,
     HeaderName,
     HeaderValue,
@@ -39,47 +43,67 @@ pub struct Lexer<'a, T>
 where
     T: AsyncReadExt + Unpin,
 {
-    buffer: String,
+    buffer: Vec<u8>,
     state: LexState,
     pos: usize,
     stream: &'a mut T,
     is_eof: bool,
     expecting_content_length: bool,
     content_length: Option<usize>,
+    lenient_line_endings: bool,
+    request_line_started: bool,
 }
 
 impl<'a, T> Lexer<'a, T>
 where
     T: AsyncReadExt + Unpin,
 {
+    #[cfg(test)]
     pub fn new(reader: &'a mut T) -> Self {
+        Lexer::with_buffer(reader, Vec::new())
+    }
+
+    /// Creates a lexer that reads into an existing buffer, reusing its
+    /// allocation. Any previous contents are discarded.
+    pub fn with_buffer(reader: &'a mut T, mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
         Lexer {
-            buffer: String::new(),
-            state: LexState::Initial,
+            buffer,
+            state: LexState::RequestLine,
             pos: 0,
             stream: reader,
             is_eof: false,
             expecting_content_length: false,
             content_length: None,
+            lenient_line_endings: false,
+            request_line_started: false,
         }
     }
 
+    /// Accepts a bare LF wherever a CRLF ends a line, and skips any empty
+    /// lines sent before the request line.
+    pub fn lenient_line_endings(mut self) -> Self {
+        self.lenient_line_endings = true;
+        self
+    }
+
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+
     pub async fn next(&mut self) -> Option<Token> {
         if self.is_eof && self.pos >= self.buffer.len() {
             return None;
         }
 
         let (token, new_state) = match self.state {
-            LexState::Initial => {
-                self.refill_buffer().await;
-                self.state = LexState::RequestLine;
-                self.lex_request_line()
-            }
             LexState::RequestLine => {
                 if self.header_size_exceeded() {
                     return Some(Token::MaxHeaderSizeExceeded);
                 }
-                self.lex_request_line()
+                let lexed = self.lex_request_line().await;
+                self.request_line_started = true;
+                lexed
             }
             LexState::HeaderName => {
                 if self.header_size_exceeded() {
@@ -94,7 +118,9 @@ where
                 self.lex_header_value().await
             }
             LexState::Body => {
-                self.fill_buffer_until_content_length_or_eof().await;
+                if let Err(e) = self.fill_buffer_until_content_length_or_eof().await {
+                    return Some(Token::IoError(e.to_string()));
+                }
                 self.lex_body()
             }
             LexState::End => return None,
@@ -111,226 +137,287 @@ where
         self.pos > MAX_HEADER_SIZE
     }
 
-    async fn refill_buffer(&mut self) {
+    async fn refill_buffer(&mut self) -> std::io::Result<()> {
         let mut buffer = [0; 1024];
-        let bytes_read = (self.stream.read(&mut buffer).await).unwrap();
-        let buffer_str = &String::from_utf8_lossy(&buffer[..bytes_read]);
+        let bytes_read = self.stream.read(&mut buffer).await?;
         self.is_eof = bytes_read == 0;
-        self.buffer.push_str(buffer_str);
+        self.buffer.extend_from_slice(&buffer[..bytes_read]);
+        Ok(())
+    }
+
+    /// Reads more input for a token that ran off the end of the buffer,
+    /// returning the token to emit instead if no more input is available.
+    async fn refill_mid_token(&mut self) -> Option<Token> {
+        if let Err(e) = self.refill_buffer().await {
+            return Some(Token::IoError(e.to_string()));
+        }
+        if self.is_eof {
+            return Some(Token::EarlyEof);
+        }
+        None
     }
 
-    async fn fill_buffer_until_max_header_size(&mut self) {
-        let mut buf = String::new();
+    /// Reads until at least `len` bytes are buffered past the current
+    /// position, returning the token to emit instead if the input ends first.
+    async fn fill_at_least(&mut self, len: usize) -> Option<Token> {
+        while self.buffer.len() < self.pos + len {
+            if let Some(token) = self.refill_mid_token().await {
+                return Some(token);
+            }
+        }
+        None
+    }
 
-        self.stream
-            .take(MAX_HEADER_SIZE as u64)
-            .read_to_string(&mut buf)
-            .await
-            .expect("Could not read");
-        self.buffer.push_str(&buf);
+    /// Reads until the request line token at the current position is
+    /// terminated by whitespace, so it is never matched against a partial read.
+    async fn fill_request_line_token(&mut self) -> Option<Token> {
+        while !self.buffer[self.pos..]
+            .iter()
+            .any(|c| c.is_ascii_whitespace())
+        {
+            if self.buffer.len() > MAX_HEADER_SIZE {
+                return Some(Token::MaxHeaderSizeExceeded);
+            }
+            if let Some(token) = self.refill_mid_token().await {
+                return Some(token);
+            }
+        }
+        None
     }
 
-    async fn fill_buffer_until_content_length_or_eof(&mut self) {
+    async fn fill_buffer_until_content_length_or_eof(&mut self) -> std::io::Result<()> {
         if self.is_eof || self.content_length.is_none() {
-            return;
+            return Ok(());
         }
 
-        let mut eof = false;
-
         if let Some(content_length) = self.content_length {
-            let mut buf = String::new();
+            let buffered = self.buffer.len() - self.pos;
+            let remaining = content_length.saturating_sub(buffered);
 
             self.stream
-                .take(content_length as u64)
-                .read_to_string(&mut buf)
-                .await
-                .expect("Could not read");
-            self.buffer.push_str(&buf);
+                .take(remaining as u64)
+                .read_to_end(&mut self.buffer)
+                .await?;
         } else {
-            while !eof {
-                let mut buffer = [0; 1024];
-                let bytes_read = (self.stream.read(&mut buffer).await).unwrap();
-                let buffer_str = &String::from_utf8_lossy(&buffer[..bytes_read]);
-                eof = bytes_read == 0;
-                self.buffer.push_str(buffer_str);
-            }
+            self.stream.read_to_end(&mut self.buffer).await?;
         }
 
         self.is_eof = true;
+        Ok(())
     }
 
     fn lex_body(&mut self) -> LexResult {
         trace!("Lexing body");
-        let body_vec = match self.content_length {
-            Some(content_length) => self.buffer[self.pos..self.pos + content_length]
-                .as_bytes()
-                .to_vec(),
-            _ => self.buffer[self.pos..].as_bytes().to_vec(),
+        let end = match self.content_length {
+            Some(content_length) if self.pos + content_length > self.buffer.len() => {
+                return (Token::EarlyEof, None);
+            }
+            Some(content_length) => self.pos + content_length,
+            _ => self.buffer.len(),
         };
-        let body_len = body_vec.len();
-        let body = (Token::Body(body_vec), Some(LexState::End));
-        self.pos += body_len;
-        body
+        let body_vec = self.buffer[self.pos..end].to_vec();
+        self.pos = end;
+        (Token::Body(body_vec), Some(LexState::End))
     }
 
     async fn lex_header_name(&mut self) -> LexResult {
         trace!("Lexing header name");
-        if self.buffer.chars().nth(self.pos) == Some('\r') {
-            return self.lex_end_headers();
-        }
         let start_pos = self.pos;
         loop {
-            match self.buffer.chars().nth(self.pos) {
-                Some(c) => {
-                    if c == ':' {
-                        let name = &self.buffer[start_pos..self.pos].to_owned();
-                        self.pos += 1;
-                        self.expecting_content_length = name.to_lowercase() == "content-length";
-                        return (
-                            Token::HeaderName(name.to_string()),
-                            Some(LexState::HeaderValue),
-                        );
-                    }
-
-                    if self.header_size_exceeded() {
-                        return (Token::MaxHeaderSizeExceeded, None);
-                    }
-
-                    if self.is_valid_header_name_char(c) {
-                        self.pos += 1;
-                        continue;
-                    }
+            let unscanned = &self.buffer[self.pos..];
+            let line_end = if self.lenient_line_endings {
+                b'\n'
+            } else {
+                b'\r'
+            };
+            let (scanned, end) = match memchr3(b':', b'\r', line_end, unscanned) {
+                Some(offset) => (offset, Some(unscanned[offset])),
+                None => (unscanned.len(), None),
+            };
+            if !unscanned[..scanned]
+                .iter()
+                .all(|&c| self.is_valid_header_name_char(c))
+            {
+                return (Token::Error, None);
+            }
+            self.pos += scanned;
+            if self.header_size_exceeded() {
+                return (Token::MaxHeaderSizeExceeded, None);
+            }
 
-                    return (Token::Error, None);
+            match end {
+                Some(b'\r') | Some(b'\n') if self.pos == start_pos => {
+                    return self.lex_end_headers().await
+                }
+                Some(b':') if self.pos > start_pos => {
+                    let name = header_name(&self.buffer[start_pos..self.pos]);
+                    self.pos += 1;
+                    self.expecting_content_length = name.eq_ignore_ascii_case("content-length");
+                    return (Token::HeaderName(name), Some(LexState::HeaderValue));
                 }
+                Some(_) => return (Token::Error, None),
                 None => {
-                    self.refill_buffer().await;
-                    continue;
+                    if let Some(token) = self.refill_mid_token().await {
+                        return (token, None);
+                    }
                 }
             }
         }
     }
 
-    fn is_valid_header_name_char(&self, c: char) -> bool {
-        c.is_alphanumeric() || c == '-'
+    /// Header names are RFC 9110 tokens, so whitespace before the colon,
+    /// a line with no colon, or a folded continuation line is rejected.
+    fn is_valid_header_name_char(&self, c: u8) -> bool {
+        c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
     }
 
+    /// Finds the end of the value with `memchr`, which scans many bytes at
+    /// a time with SSE2/AVX2 where available, then checks the value's bytes
+    /// in a single tight pass.
     async fn lex_header_value(&mut self) -> LexResult {
         trace!("Lexing header value");
         let start_pos = self.pos;
         loop {
-            match self.buffer.chars().nth(self.pos) {
-                Some(c) => {
-                    if c == '\r' {
-                        let value = &self.buffer[start_pos..self.pos].to_owned();
-                        return self.lex_end_header_value(value);
-                    }
-
-                    if self.header_size_exceeded() {
-                        return (Token::MaxHeaderSizeExceeded, None);
-                    }
-
-                    if self.is_valid_header_value_char(c) {
-                        self.pos += 1;
-                        continue;
-                    }
+            let unscanned = &self.buffer[self.pos..];
+            let found = if self.lenient_line_endings {
+                memchr2(b'\r', b'\n', unscanned)
+            } else {
+                memchr(b'\r', unscanned)
+            };
+            let (scanned, found_end) = match found {
+                Some(offset) => (offset, true),
+                None => (unscanned.len(), false),
+            };
+            if !unscanned[..scanned]
+                .iter()
+                .all(|&c| is_valid_header_value_char(c))
+            {
+                return (Token::Error, None);
+            }
+            self.pos += scanned;
+            if self.header_size_exceeded() {
+                return (Token::MaxHeaderSizeExceeded, None);
+            }
 
-                    return (Token::Error, None);
-                }
-                None => {
-                    self.refill_buffer().await;
-                    continue;
-                }
+            if found_end {
+                let value = trim_whitespace(&self.buffer[start_pos..self.pos]);
+                return match String::from_utf8(value.to_vec()) {
+                    Ok(value) => self.lex_end_header_value(value).await,
+                    Err(_) => (Token::InvalidEncoding, None),
+                };
+            }
+            if let Some(token) = self.refill_mid_token().await {
+                return (token, None);
             }
         }
     }
 
-    fn lex_end_header_value(&mut self, value: &str) -> LexResult {
-        lazy_static! {
-            static ref CRLF_RE: Regex = Regex::new(CRLF_REGEX_STR).unwrap();
-        }
-        if let Some(mat) = (CRLF_RE).find(&self.buffer[self.pos..]) {
-            self.pos += mat.end();
-            if self.expecting_content_length {
-                self.expecting_content_length = false;
-                if let Ok(content_length) = value.trim_start().parse::<usize>() {
-                    self.content_length = Some(content_length);
+    async fn lex_end_header_value(&mut self, value: String) -> LexResult {
+        match self.lex_line_end().await {
+            Ok(true) => {
+                if self.expecting_content_length {
+                    self.expecting_content_length = false;
+                    if let Ok(content_length) = value.parse::<usize>() {
+                        self.content_length = Some(content_length);
+                    }
                 }
+                (Token::HeaderValue(value), Some(LexState::HeaderName))
             }
-            return (
-                Token::HeaderValue(value.trim_start().to_owned()),
-                Some(LexState::HeaderName),
-            );
+            Ok(false) => (Token::Error, None),
+            Err(token) => (token, None),
         }
-
-        (Token::Error, None)
-    }
-
-    fn is_valid_header_value_char(&self, c: char) -> bool {
-        c != '\r' && c != '\n'
     }
 
-    fn lex_end_headers(&mut self) -> LexResult {
+    async fn lex_end_headers(&mut self) -> LexResult {
         trace!("Lexing end of headers");
-        lazy_static! {
-            static ref CRLF_RE: Regex = Regex::new(CRLF_REGEX_STR).unwrap();
+        match self.lex_line_end().await {
+            Ok(true) => (Token::Crlf, Some(LexState::Body)),
+            Ok(false) => (Token::Error, None),
+            Err(token) => (token, None),
         }
-        if let Some(mat) = (CRLF_RE).find(&self.buffer[self.pos..]) {
-            self.pos += mat.end();
-            return (Token::Crlf, Some(LexState::Body));
-        }
-
-        (Token::Error, None)
     }
 
-    fn lex_request_line(&mut self) -> LexResult {
+    async fn lex_request_line(&mut self) -> LexResult {
         trace!("Lexing request line");
-        match self.buffer.chars().nth(self.pos) {
-            Some(c) => {
-                if c == '\r' {
-                    return self.lex_end_request_line();
-                }
+        loop {
+            if self.header_size_exceeded() {
+                return (Token::MaxHeaderSizeExceeded, None);
+            }
 
-                if c.is_whitespace() {
-                    self.pos += 1;
-                    return self.lex_request_line();
-                }
+            match self.buffer.get(self.pos).copied() {
+                Some(c) => {
+                    if self.lenient_line_endings && !self.request_line_started {
+                        if c == b'\r' || c == b'\n' {
+                            self.pos += 1;
+                            continue;
+                        }
+                    } else if c == b'\r' || (c == b'\n' && self.lenient_line_endings) {
+                        return self.lex_end_request_line().await;
+                    }
 
-                if c.is_alphabetic() {
-                    return self.lex_method_or_protocol();
-                }
+                    if c.is_ascii_whitespace() {
+                        self.pos += 1;
+                        continue;
+                    }
 
-                if c == '/' {
-                    return self.lex_path();
-                }
+                    if let Some(token) = self.fill_request_line_token().await {
+                        return (token, None);
+                    }
+
+                    if c.is_ascii_alphabetic() {
+                        return self.lex_method_or_protocol();
+                    }
+
+                    if c == b'/' {
+                        return self.lex_path();
+                    }
 
-                (Token::Error, None)
+                    return (Token::Error, None);
+                }
+                None => {
+                    if let Some(token) = self.refill_mid_token().await {
+                        return (token, None);
+                    }
+                }
             }
-            None => (Token::Error, None),
         }
     }
 
-    fn lex_end_request_line(&mut self) -> LexResult {
+    async fn lex_end_request_line(&mut self) -> LexResult {
         trace!("Lexing end of request line");
-        lazy_static! {
-            static ref CRLF_RE: Regex = Regex::new(CRLF_REGEX_STR).unwrap();
-        }
-        if let Some(mat) = (CRLF_RE).find(&self.buffer[self.pos..]) {
-            self.pos += mat.end();
-            return (Token::Crlf, Some(LexState::HeaderName));
+        match self.lex_line_end().await {
+            Ok(true) => (Token::Crlf, Some(LexState::HeaderName)),
+            Ok(false) => (Token::Error, None),
+            Err(token) => (token, None),
         }
+    }
 
-        (Token::Error, None)
+    /// Consumes the CRLF, or in lenient mode the bare LF, at the current
+    /// position, returning whether there was one.
+    async fn lex_line_end(&mut self) -> Result<bool, Token> {
+        if self.lenient_line_endings && self.buffer.get(self.pos) == Some(&b'\n') {
+            self.pos += 1;
+            return Ok(true);
+        }
+        if let Some(token) = self.fill_at_least(2).await {
+            return Err(token);
+        }
+        if self.buffer[self.pos..].starts_with(b"\r\n") {
+            self.pos += 2;
+            return Ok(true);
+        }
+        Ok(false)
     }
 
     fn lex_path(&mut self) -> LexResult {
         trace!("Lexing request path");
         lazy_static! {
-            static ref PATH_RE: Regex = Regex::new(r"^[a-z0-9\-._~%!$&'()*+,;=:@/]+").unwrap();
+            static ref PATH_RE: Regex = Regex::new(r"^[a-zA-Z0-9\-._~%!$&'()*+,;=:@/?]+").unwrap();
         }
         if let Some(mat) = (PATH_RE).find(&self.buffer[self.pos..]) {
+            let path = &self.buffer[self.pos + mat.start()..self.pos + mat.end()];
             let ret = (
-                Token::Path(self.buffer[self.pos + mat.start()..self.pos + mat.end()].to_string()),
+                Token::Path(String::from_utf8_lossy(path).into_owned()),
                 None,
             );
             self.pos += mat.end();
@@ -343,23 +430,23 @@ where
     fn lex_method_or_protocol(&mut self) -> LexResult {
         lazy_static! {
             static ref METHOD_RE: Regex =
-                Regex::new(r"^GET|POST|PUT|PATCH|HEAD|OPTIONS|TRACE").unwrap();
+                Regex::new(r"^(GET|POST|PUT|PATCH|DELETE|HEAD|OPTIONS|TRACE) ").unwrap();
             static ref PROTOCOL_RE: Regex = Regex::new(r"^HTTP/1\.1").unwrap();
+            static ref UNKNOWN_METHOD_RE: Regex = Regex::new(r"^[A-Z][A-Z_-]* ").unwrap();
         }
         if let Some(mat) = (METHOD_RE).find(&self.buffer[self.pos..]) {
             trace!("Lexing request method");
 
-            let ret = (
-                Token::Method(
-                    HttpMethod::from_str(
-                        &self.buffer[self.pos + mat.start()..self.pos + mat.end()],
-                    )
-                    .unwrap(),
-                ),
-                None,
-            );
-            self.pos += mat.end();
-            return ret;
+            // The space is left to separate the method from the path.
+            let end = self.pos + mat.end() - 1;
+            let method = std::str::from_utf8(&self.buffer[self.pos..end])
+                .ok()
+                .and_then(|method| HttpMethod::from_str(method).ok());
+            self.pos = end;
+            return match method {
+                Some(method) => (Token::Method(method), None),
+                None => (Token::Error, None),
+            };
         }
 
         if let Some(mat) = (PROTOCOL_RE).find(&self.buffer[self.pos..]) {
@@ -370,13 +457,61 @@ where
             return ret;
         }
 
+        if let Some(mat) = (UNKNOWN_METHOD_RE).find(&self.buffer[self.pos..]) {
+            let end = self.pos + mat.end() - 1;
+            let method = String::from_utf8_lossy(&self.buffer[self.pos..end]).into_owned();
+            self.pos = end;
+            return (Token::UnknownMethod(method), None);
+        }
+
         (Token::Error, None)
     }
 }
 
+/// Allows visible characters, spaces, tabs and obs-text, rejecting bare
+/// line feeds and every other control character.
+fn is_valid_header_value_char(c: u8) -> bool {
+    c == b'\t' || (c >= b' ' && c != 0x7f)
+}
+
+/// Strips the optional whitespace allowed around a header value.
+fn trim_whitespace(value: &[u8]) -> &[u8] {
+    let is_whitespace = |c: &u8| *c == b' ' || *c == b'\t';
+    let start = value
+        .iter()
+        .position(|c| !is_whitespace(c))
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|c| !is_whitespace(c))
+        .map_or(start, |last| last + 1);
+    &value[start..end]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    /// Hands out its input one byte per read, like a very slow client.
+    struct TrickleReader<'a> {
+        input: &'a [u8],
+    }
+    impl AsyncRead for TrickleReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if let Some((first, rest)) = self.input.split_first() {
+                buf.put_slice(&[*first]);
+                self.input = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
 
     #[tokio::test]
     async fn lexes_valid_get_request_line() {
@@ -393,7 +528,7 @@ mod tests {
         assert_eq!(Some(Token::Crlf), lexer.next().await);
 
         assert_eq!(
-            Some(Token::HeaderName("Header-1".to_string())),
+            Some(Token::HeaderName("Header-1".into())),
             lexer.next().await
         );
         assert_eq!(
@@ -402,7 +537,7 @@ mod tests {
         );
 
         assert_eq!(
-            Some(Token::HeaderName("Another-Header".to_string())),
+            Some(Token::HeaderName("Another-Header".into())),
             lexer.next().await
         );
         assert_eq!(
@@ -433,7 +568,7 @@ mod tests {
         assert_eq!(Some(Token::Crlf), lexer.next().await);
 
         assert_eq!(
-            Some(Token::HeaderName("Header-1".to_string())),
+            Some(Token::HeaderName("Header-1".into())),
             lexer.next().await
         );
         assert_eq!(
@@ -442,7 +577,7 @@ mod tests {
         );
 
         assert_eq!(
-            Some(Token::HeaderName("Another-Header".to_string())),
+            Some(Token::HeaderName("Another-Header".into())),
             lexer.next().await
         );
         assert_eq!(
@@ -455,4 +590,97 @@ mod tests {
 
         assert_eq!(None, lexer.next().await);
     }
+
+    #[tokio::test]
+    async fn lexes_request_delivered_one_byte_per_read() {
+        let input = "POST /upload HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        let mut reader = TrickleReader {
+            input: input.as_bytes(),
+        };
+        let mut lexer = Lexer::new(&mut reader);
+
+        assert_eq!(Some(Token::Method(HttpMethod::POST)), lexer.next().await);
+        assert_eq!(Some(Token::Path("/upload".to_string())), lexer.next().await);
+        assert_eq!(Some(Token::Protocol), lexer.next().await);
+        assert_eq!(Some(Token::Crlf), lexer.next().await);
+        assert_eq!(
+            Some(Token::HeaderName("Content-Length".into())),
+            lexer.next().await
+        );
+        assert_eq!(
+            Some(Token::HeaderValue("4".to_string())),
+            lexer.next().await
+        );
+        assert_eq!(Some(Token::Crlf), lexer.next().await);
+        assert_eq!(Some(Token::Body(b"body".to_vec())), lexer.next().await);
+        assert_eq!(None, lexer.next().await);
+    }
+
+    #[tokio::test]
+    async fn lexes_header_values_spanning_several_reads() {
+        let value = "v".repeat(3000);
+        let input = format!("GET / HTTP/1.1\r\nHeader-1:  {} \r\n\r\n", value);
+        let mut bytes = input.as_bytes();
+        let mut lexer = Lexer::new(&mut bytes);
+        for _ in 0..5 {
+            lexer.next().await;
+        }
+
+        assert_eq!(Some(Token::HeaderValue(value)), lexer.next().await);
+        assert_eq!(Some(Token::Crlf), lexer.next().await);
+    }
+
+    async fn lex_first_header(input: &str) -> Option<Token> {
+        let mut bytes = input.as_bytes();
+        let mut lexer = Lexer::new(&mut bytes);
+        for _ in 0..4 {
+            lexer.next().await;
+        }
+        lexer.next().await
+    }
+
+    #[tokio::test]
+    async fn lexes_header_names_with_any_token_character() {
+        let token = lex_first_header("GET / HTTP/1.1\r\nX_Custom.Header~1: v\r\n\r\n").await;
+
+        assert_eq!(Some(Token::HeaderName("X_Custom.Header~1".into())), token);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_header_lines() {
+        let malformed = [
+            "GET / HTTP/1.1\r\nHeader-1 : value\r\n\r\n",
+            "GET / HTTP/1.1\r\nNo colon here\r\n\r\n",
+            "GET / HTTP/1.1\r\n: value\r\n\r\n",
+            "GET / HTTP/1.1\r\n folded: value\r\n\r\n",
+        ];
+
+        for input in malformed.iter() {
+            assert_eq!(
+                Some(Token::Error),
+                lex_first_header(input).await,
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_control_characters_in_header_values() {
+        let mut bytes: &[u8] = b"GET / HTTP/1.1\r\nHeader-1: a\x00b\r\n\r\n";
+        let mut lexer = Lexer::new(&mut bytes);
+        for _ in 0..5 {
+            lexer.next().await;
+        }
+
+        assert_eq!(Some(Token::Error), lexer.next().await);
+    }
+
+    #[tokio::test]
+    async fn empty_input_is_early_eof() {
+        let mut bytes: &[u8] = b"";
+        let mut lexer = Lexer::new(&mut bytes);
+
+        assert_eq!(Some(Token::EarlyEof), lexer.next().await);
+    }
 }
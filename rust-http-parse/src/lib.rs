@@ -1,8 +1,26 @@
+#[cfg(feature = "serde")]
+mod de;
+mod extensions;
+#[cfg(feature = "serde")]
+mod form;
+mod header;
 mod lex;
 mod parse;
+mod query;
+mod redact;
 
-pub use self::parse::{parse_from_reader, ParseError};
+#[cfg(feature = "serde")]
+pub use self::de::DeserializeError;
+pub use self::extensions::Extensions;
+pub use self::parse::{
+    parse_from_reader, parse_from_reader_with_buffer, parse_from_reader_with_limit,
+    parse_from_reader_with_options, ParseError, ParseOptions, ParseTiming, SimpleRequest,
+};
+pub use self::query::Query;
+pub use self::redact::{Mask, Redacted, Redaction};
 
+use self::header::{header_name, HeaderName};
+use custom_error::custom_error;
 use std::collections::HashMap;
 use std::str::FromStr;
 
@@ -13,6 +31,7 @@ pub enum HttpMethod {
     POST,
     PUT,
     PATCH,
+    DELETE,
     OPTIONS,
     TRACE,
 }
@@ -26,6 +45,7 @@ impl FromStr for HttpMethod {
             "POST" => Ok(HttpMethod::POST),
             "PUT" => Ok(HttpMethod::PUT),
             "PATCH" => Ok(HttpMethod::PATCH),
+            "DELETE" => Ok(HttpMethod::DELETE),
             "OPTIONS" => Ok(HttpMethod::OPTIONS),
             "TRACE" => Ok(HttpMethod::TRACE),
             _ => Err(()),
@@ -33,7 +53,7 @@ impl FromStr for HttpMethod {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct HttpBody {
     content: Vec<u8>,
 }
@@ -44,89 +64,323 @@ impl HttpBody {
         }
     }
 
-    pub fn from_content(content: &Vec<u8>) -> Self {
-        HttpBody {
-            content: content.clone(),
-        }
+    pub fn from_content(content: Vec<u8>) -> Self {
+        HttpBody { content }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.content
     }
 
-    pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.content).unwrap()
+    pub fn as_str(&self) -> Result<&str, ParseError> {
+        std::str::from_utf8(&self.content).map_err(|_| ParseError::InvalidEncoding)
+    }
+
+    pub fn into_content(self) -> Vec<u8> {
+        self.content
     }
 }
 
 #[derive(Debug)]
 pub struct HttpRequest {
     pub method: HttpMethod,
+    /// The path the request was routed by: the target up to any `?`, with
+    /// empty, `.` and `..` segments resolved by `normalize_path`.
     pub path: String,
-    headers: HashMap<String, String>,
+    raw_path: String,
+    raw_query: String,
+    query: Query,
+    headers: HashMap<HeaderName, String>,
+    params: HashMap<String, String>,
+    extensions: Extensions,
     body: HttpBody,
 }
 impl HttpRequest {
-    pub fn new(method: HttpMethod, path: &str) -> Self {
+    pub fn new(method: HttpMethod, target: &str) -> Self {
+        let (raw_path, raw_query) = split_target(target);
         HttpRequest {
             method,
-            path: path.to_owned(),
+            path: normalize_path(&raw_path),
+            query: Query::parse(&raw_query),
+            raw_path,
+            raw_query,
             headers: HashMap::new(),
+            params: HashMap::new(),
+            extensions: Extensions::new(),
             body: HttpBody::new(),
         }
     }
 
     pub fn set_header(&mut self, name: &str, value: &str) {
-        self.headers.insert(name.to_owned(), value.to_owned());
+        header::insert(
+            &mut self.headers,
+            header_name(name.as_bytes()),
+            value.to_owned(),
+        );
     }
 
+    /// The value of the header called `name`, in any case.
     pub fn header(&self, name: &str) -> Option<&String> {
-        self.headers.get(name)
+        header::get(&self.headers, name)
+    }
+
+    /// Removes the header called `name`, in any case, returning its value.
+    pub fn remove_header(&mut self, name: &str) -> Option<String> {
+        header::remove(&mut self.headers, name)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_ref(), value.as_str()))
+    }
+
+    pub fn set_param(&mut self, name: &str, value: &str) {
+        self.params.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub fn param(&self, name: &str) -> Option<&String> {
+        self.params.get(name)
+    }
+
+    /// The path exactly as the client sent it, before normalization.
+    pub fn raw_path(&self) -> &str {
+        &self.raw_path
+    }
+
+    /// The query string exactly as the client sent it, without the `?`.
+    /// Empty if there was none.
+    pub fn raw_query(&self) -> &str {
+        &self.raw_query
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// Deserializes the query string into `T`, parsing each value as its
+    /// field's type. A repeated key fills a `Vec` field and a key with an
+    /// empty value leaves an `Option` field `None`. The error says which
+    /// field was missing or malformed, for a 400 response.
+    #[cfg(feature = "serde")]
+    pub fn query_as<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, DeserializeError> {
+        self.query.deserialize()
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
     }
 
-    pub fn body_as_string(&self) -> &str {
+    pub fn body(&self) -> &[u8] {
+        self.body.as_bytes()
+    }
+
+    /// Takes the body out of the request, so it can be kept without copying.
+    pub fn into_body(self) -> Vec<u8> {
+        self.body.into_content()
+    }
+
+    /// Sets the body once the parser has read it.
+    pub(crate) fn set_body(&mut self, content: Vec<u8>) {
+        self.body = HttpBody::from_content(content);
+    }
+
+    pub fn body_as_string(&self) -> Result<&str, ParseError> {
         self.body.as_str()
     }
+
+    /// Deserializes a form body into `T` the way `query_as` does a query
+    /// string. The body may be `application/x-www-form-urlencoded` or a
+    /// `multipart/form-data` form without file uploads.
+    #[cfg(feature = "serde")]
+    pub fn form_as<T: serde::de::DeserializeOwned>(&self) -> Result<T, DeserializeError> {
+        let fields =
+            form::form_fields(self.header("Content-Type").map(String::as_str), self.body())?;
+        T::deserialize(de::PairsDeserializer::new(
+            fields
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        ))
+    }
 }
 
+custom_error! {#[derive(PartialEq)] pub BuildError
+    MissingMethod = "Request has no method",
+    MissingPath = "Request has no path"
+}
+
+/// Builds an `HttpRequest` field by field, for tests and for handlers that
+/// make requests of their own:
+///
+/// ```
+/// # use rust_http_parse::{HttpMethod, HttpRequestBuilder};
+/// let request = HttpRequestBuilder::new()
+///     .with_method(HttpMethod::POST)
+///     .with_path("/items?draft=true")
+///     .with_header("Content-Type", "text/plain")
+///     .with_body("hello")
+///     .build()
+///     .unwrap();
+/// assert_eq!("/items", request.path);
+/// ```
+#[derive(Default)]
 pub struct HttpRequestBuilder {
-    method: HttpMethod,
-    path: String,
-    headers: HashMap<String, String>,
+    method: Option<HttpMethod>,
+    path: Option<String>,
+    headers: HashMap<HeaderName, String>,
     body: HttpBody,
 }
 impl HttpRequestBuilder {
     pub fn new() -> Self {
-        HttpRequestBuilder {
-            method: HttpMethod::GET,
-            path: String::new(),
-            headers: HashMap::new(),
-            body: HttpBody::new(),
-        }
+        HttpRequestBuilder::default()
     }
 
-    pub fn with_method(&mut self, method: HttpMethod) -> &mut HttpRequestBuilder {
-        self.method = method;
+    pub fn with_method(mut self, method: HttpMethod) -> Self {
+        self.method = Some(method);
         self
     }
 
-    pub fn with_path(&mut self, path: &str) -> &mut HttpRequestBuilder {
-        self.path = path.to_string();
+    /// Sets the request target, which may include a query string.
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
         self
     }
 
-    pub fn with_header(&mut self, name: &str, value: &str) -> &mut HttpRequestBuilder {
-        self.headers.insert(name.to_owned(), value.to_owned());
+    pub fn with_header(mut self, name: impl AsRef<str>, value: impl Into<String>) -> Self {
+        header::insert(
+            &mut self.headers,
+            header_name(name.as_ref().as_bytes()),
+            value.into(),
+        );
         self
     }
 
-    pub fn with_body(&mut self, content: &Vec<u8>) -> &mut HttpRequestBuilder {
-        self.body = HttpBody::from_content(content);
+    /// Adds a header the parser has already allocated, without copying it.
+    pub(crate) fn insert_header(&mut self, name: HeaderName, value: String) {
+        header::insert(&mut self.headers, name, value);
+    }
+
+    /// Takes ownership of the body, so large uploads aren't copied.
+    pub fn with_body(mut self, content: impl Into<Vec<u8>>) -> Self {
+        self.body = HttpBody::from_content(content.into());
         self
     }
 
-    pub fn build(self) -> HttpRequest {
-        HttpRequest {
-            method: self.method,
-            path: self.path.to_string(),
+    /// Builds the request, failing if no method or an empty path was given.
+    pub fn build(self) -> Result<HttpRequest, BuildError> {
+        let method = self.method.ok_or(BuildError::MissingMethod)?;
+        let target = self
+            .path
+            .filter(|path| !path.is_empty())
+            .ok_or(BuildError::MissingPath)?;
+        let (raw_path, raw_query) = split_target(&target);
+        Ok(HttpRequest {
+            method,
+            path: normalize_path(&raw_path),
+            query: Query::parse(&raw_query),
+            raw_path,
+            raw_query,
             headers: self.headers,
+            params: HashMap::new(),
+            extensions: Extensions::new(),
             body: self.body,
+        })
+    }
+}
+
+/// Splits a request target into its path and query string.
+fn split_target(target: &str) -> (String, String) {
+    match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), query.to_owned()),
+        None => (target.to_owned(), String::new()),
+    }
+}
+
+/// Removes dot segments from an absolute path as in RFC 3986 §5.2.4, also
+/// collapsing repeated slashes and treating percent-encoded dots as dots, so
+/// `/a//b/./%2e%2e/c` becomes `/a/c`. `..` never climbs above the root.
+pub fn normalize_path(path: &str) -> String {
+    if !path.starts_with('/') {
+        return path.to_owned();
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path[1..].split('/') {
+        trailing_slash = true;
+        match decode_dots(segment).as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
         }
     }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+fn decode_dots(segment: &str) -> String {
+    segment.replace("%2e", ".").replace("%2E", ".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_dot_segments_and_repeated_slashes() {
+        let cases = [
+            ("/", "/"),
+            ("/a/b", "/a/b"),
+            ("/a/b/", "/a/b/"),
+            ("//a///b", "/a/b"),
+            ("/a/./b/.", "/a/b/"),
+            ("/a/b/../c", "/a/c"),
+            ("/a/b/..", "/a/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/static/%2e%2E/secret", "/secret"),
+            ("/a/..b/c", "/a/..b/c"),
+        ];
+
+        for (path, expected) in cases.iter() {
+            assert_eq!(*expected, normalize_path(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn keeps_the_raw_path_alongside_the_normalized_one() {
+        let request = HttpRequest::new(HttpMethod::GET, "/a/./b/../c?x=1");
+
+        assert_eq!("/a/c", request.path);
+        assert_eq!("/a/./b/../c", request.raw_path());
+        assert_eq!("x=1", request.raw_query());
+    }
+
+    #[test]
+    fn builder_requires_a_method_and_path() {
+        let missing_method = HttpRequestBuilder::new().with_path("/").build();
+        let missing_path = HttpRequestBuilder::new()
+            .with_method(HttpMethod::GET)
+            .with_path("")
+            .build();
+
+        assert_eq!(Some(BuildError::MissingMethod), missing_method.err());
+        assert_eq!(Some(BuildError::MissingPath), missing_path.err());
+    }
 }
@@ -1,64 +1,221 @@
 use super::lex::{Lexer, Token};
-use super::{HttpRequest, HttpRequestBuilder};
+use super::{HttpMethod, HttpRequest, HttpRequestBuilder};
 use custom_error::custom_error;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncReadExt;
 
 custom_error! {#[derive(PartialEq)] pub ParseError
     Unexpected{msg: String} = "Unexpected token error: {msg}",
     EarlyEof = "Unexpected EOF",
-    MaxHeaderSizeExceeded = "Max header size exceeded"
+    MaxHeaderSizeExceeded = "Max header size exceeded",
+    Io{msg: String} = "I/O error while reading request: {msg}",
+    InvalidEncoding = "Request is not valid UTF-8",
+    InvalidFraming{msg: String} = "Ambiguous or invalid message framing: {msg}",
+    UnsupportedTransferCoding{coding: String} = "Unsupported transfer coding: {coding}",
+    UnsupportedMethod{method: String} = "Unsupported method: {method}",
+    BodyTooLarge{length: usize, max: usize} = "Body of {length} bytes is over the {max} byte limit"
+}
+
+/// How long parsing a request took, attached to every parsed request as an
+/// extension. `headers` runs from the start of parsing, so it includes any
+/// wait for the client to start sending.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParseTiming {
+    pub headers: Duration,
+    pub body: Duration,
+}
+
+/// Attached as an extension to a request that came as an HTTP/0.9 simple
+/// request, such as `GET /index.html`, which must be answered with the bare
+/// response body: no status line and no headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimpleRequest;
+
+/// How forgiving the parser is of requests that bend the grammar.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParseOptions {
+    lenient_line_endings: bool,
+    http09: bool,
+    max_body: Option<usize>,
+}
+impl ParseOptions {
+    pub fn new() -> Self {
+        ParseOptions::default()
+    }
+
+    /// Accepts lines ended by a bare LF instead of CRLF, and skips empty
+    /// lines sent before the request line, as some old or hand-written
+    /// clients do. By default both are rejected.
+    pub fn lenient_line_endings(mut self) -> Self {
+        self.lenient_line_endings = true;
+        self
+    }
+
+    /// Accepts HTTP/0.9 simple requests, a `GET` request line with no
+    /// version and no headers, marking them with `SimpleRequest`. Meant for
+    /// compatibility testing and very old clients; by default they are
+    /// rejected as missing their version.
+    pub fn http09(mut self) -> Self {
+        self.http09 = true;
+        self
+    }
+
+    /// Rejects requests whose Content-Length is over `bytes` with
+    /// `BodyTooLarge`, before any of the body is read. By default bodies of
+    /// any length are read.
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = Some(bytes);
+        self
+    }
 }
 
 pub async fn parse_from_reader<T>(reader: &mut T) -> Result<HttpRequest, ParseError>
 where
     T: AsyncReadExt + Unpin,
 {
-    let mut lexer = Lexer::new(reader);
-    let mut request_builder = parse_request_line(&mut lexer).await?;
+    parse_from_reader_with_buffer(reader, &mut Vec::new()).await
+}
+
+/// Parses a request like `parse_from_reader`, reading into `buffer` so its
+/// allocation can be reused for later requests. The buffer is handed back
+/// whether or not parsing succeeds.
+pub async fn parse_from_reader_with_buffer<T>(
+    reader: &mut T,
+    buffer: &mut Vec<u8>,
+) -> Result<HttpRequest, ParseError>
+where
+    T: AsyncReadExt + Unpin,
+{
+    parse_from_reader_with_options(reader, buffer, ParseOptions::default()).await
+}
+
+/// Parses a request like `parse_from_reader_with_buffer`, as forgivingly as
+/// `options` allow.
+pub async fn parse_from_reader_with_options<T>(
+    reader: &mut T,
+    buffer: &mut Vec<u8>,
+    options: ParseOptions,
+) -> Result<HttpRequest, ParseError>
+where
+    T: AsyncReadExt + Unpin,
+{
+    parse_from_reader_with_limit(reader, buffer, options, |_| None).await
+}
+
+/// Parses a request like `parse_from_reader_with_options`, also rejecting
+/// it with `BodyTooLarge` if its Content-Length is over what `body_limit`
+/// returns for it. `body_limit` is given the request before its body is
+/// read, so a body that's too large is never buffered.
+pub async fn parse_from_reader_with_limit<T, F>(
+    reader: &mut T,
+    buffer: &mut Vec<u8>,
+    options: ParseOptions,
+    body_limit: F,
+) -> Result<HttpRequest, ParseError>
+where
+    T: AsyncReadExt + Unpin,
+    F: FnOnce(&HttpRequest) -> Option<usize>,
+{
+    let mut lexer = Lexer::with_buffer(reader, std::mem::take(buffer));
+    if options.lenient_line_endings {
+        lexer = lexer.lenient_line_endings();
+    }
+    let result = parse_request(&mut lexer, options, body_limit).await;
+    *buffer = lexer.into_buffer();
+    result
+}
+
+async fn parse_request<'a, T, F>(
+    lexer: &mut Lexer<'a, T>,
+    options: ParseOptions,
+    body_limit: F,
+) -> Result<HttpRequest, ParseError>
+where
+    T: AsyncReadExt + Unpin,
+    F: FnOnce(&HttpRequest) -> Option<usize>,
+{
+    let started = Instant::now();
+    let (mut request_builder, simple) = parse_request_line(lexer, options).await?;
+    if simple {
+        let mut request = request_builder
+            .build()
+            .map_err(|e| ParseError::Unexpected { msg: e.to_string() })?;
+        request.extensions_mut().insert(SimpleRequest);
+        request.extensions_mut().insert(ParseTiming {
+            headers: started.elapsed(),
+            body: Duration::default(),
+        });
+        return Ok(request);
+    }
+    let mut framing = Framing::default();
     let mut parsing_headers = true;
 
     while parsing_headers {
-        parsing_headers = parse_header_lines(&mut lexer, &mut request_builder).await?;
+        parsing_headers = parse_header_lines(lexer, &mut request_builder, &mut framing).await?;
+    }
+    framing.check()?;
+    let headers_parsed = Instant::now();
+    let mut request = request_builder
+        .build()
+        .map_err(|e| ParseError::Unexpected { msg: e.to_string() })?;
+    if let Some(length) = framing.content_length() {
+        let limits = [options.max_body, body_limit(&request)];
+        if let Some(max) = limits.iter().flatten().copied().min() {
+            if length > max {
+                return Err(ParseError::BodyTooLarge { length, max });
+            }
+        }
+    }
+    if let Some(content) = parse_body(lexer).await? {
+        request.set_body(content);
     }
-    parse_body(&mut lexer, &mut request_builder).await?;
 
-    Ok(request_builder.build())
+    request.extensions_mut().insert(ParseTiming {
+        headers: headers_parsed - started,
+        body: headers_parsed.elapsed(),
+    });
+    Ok(request)
 }
 
+/// Parses the request line, also returning whether it was an HTTP/0.9
+/// simple request, which ends after the path.
 async fn parse_request_line<'a, T>(
     token_iter: &mut Lexer<'a, T>,
-) -> Result<HttpRequestBuilder, ParseError>
+    options: ParseOptions,
+) -> Result<(HttpRequestBuilder, bool), ParseError>
 where
     T: AsyncReadExt + Unpin,
 {
     match token_iter.next().await {
         Some(Token::Method(method)) => {
             if let Some(Token::Path(path)) = token_iter.next().await {
-                parse_protocol(token_iter).await?;
+                let simple_allowed = options.http09 && matches!(method, HttpMethod::GET);
+                let builder = HttpRequestBuilder::new()
+                    .with_method(method)
+                    .with_path(path);
+                match token_iter.next().await {
+                    Some(Token::Protocol) => {}
+                    Some(Token::Crlf) if simple_allowed => return Ok((builder, true)),
+                    other => return Err(unexpected(other, "Expected protocol version")),
+                }
                 parse_crlf(token_iter).await?;
 
-                let mut builder = HttpRequestBuilder::new();
-                builder.with_method(method);
-                builder.with_path(&path);
-
-                return Ok(builder);
+                return Ok((builder, false));
             }
 
             Err(ParseError::Unexpected {
                 msg: "Expected path".to_string(),
             })
         }
-        Some(Token::MaxHeaderSizeExceeded) => Err(ParseError::MaxHeaderSizeExceeded),
-        Some(_) => Err(ParseError::Unexpected {
-            msg: "Expected HTTP Method".to_string(),
-        }),
-        _ => Err(ParseError::EarlyEof),
+        Some(Token::UnknownMethod(method)) => Err(ParseError::UnsupportedMethod { method }),
+        other => Err(unexpected(other, "Expected HTTP Method")),
     }
 }
 
 async fn parse_header_lines<'a, T>(
     token_iter: &mut Lexer<'a, T>,
     request_builder: &mut HttpRequestBuilder,
+    framing: &mut Framing,
 ) -> Result<bool, ParseError>
 where
     T: AsyncReadExt + Unpin,
@@ -68,62 +225,87 @@ where
             Some(Token::Crlf) => {
                 return Ok(false);
             }
-            Some(Token::MaxHeaderSizeExceeded) => {
-                return Err(ParseError::MaxHeaderSizeExceeded);
-            }
             Some(Token::HeaderName(header_name)) => match token_iter.next().await {
                 Some(Token::HeaderValue(header_val)) => {
-                    request_builder.with_header(header_name.as_str(), header_val.as_str());
+                    framing.record(&header_name, &header_val);
+                    request_builder.insert_header(header_name, header_val);
                     return Ok(true);
                 }
-                Some(Token::MaxHeaderSizeExceeded) => {
-                    return Err(ParseError::MaxHeaderSizeExceeded);
-                }
-                _ => {
-                    return Err(ParseError::Unexpected {
-                        msg: "Expected header value".to_string(),
-                    });
+                other => {
+                    return Err(unexpected(other, "Malformed header value"));
                 }
             },
-            _ => {
-                return Err(ParseError::Unexpected {
-                    msg: "Expected header".to_string(),
-                });
+            other => {
+                return Err(unexpected(other, "Malformed header field"));
             }
         }
     }
 }
 
-async fn parse_body<'a, T>(
-    token_iter: &mut Lexer<'a, T>,
-    request_builder: &mut HttpRequestBuilder,
-) -> Result<(), ParseError>
-where
-    T: AsyncReadExt + Unpin,
-{
-    match token_iter.next().await {
-        Some(Token::Body(ref content)) => {
-            request_builder.with_body(content);
-            Ok(())
+/// The headers that decide where the body ends, collected so requests that
+/// frame it ambiguously can be turned away before any of it is read
+/// (RFC 9112 §6.1 and §6.3).
+#[derive(Default)]
+struct Framing {
+    content_lengths: Vec<String>,
+    transfer_encodings: Vec<String>,
+}
+impl Framing {
+    fn record(&mut self, name: &str, value: &str) {
+        if name.eq_ignore_ascii_case("content-length") {
+            self.content_lengths.push(value.trim().to_owned());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            self.transfer_encodings.push(value.trim().to_owned());
         }
-        Some(other) => Err(ParseError::Unexpected {
-            msg: format!("Expected body, got {:?}", other),
-        }),
-        None => Ok(()),
+    }
+
+    /// The body's length, once `check` has passed.
+    fn content_length(&self) -> Option<usize> {
+        self.content_lengths.first()?.parse().ok()
+    }
+
+    fn check(&self) -> Result<(), ParseError> {
+        if !self.transfer_encodings.is_empty() && !self.content_lengths.is_empty() {
+            return Err(ParseError::InvalidFraming {
+                msg: "both Transfer-Encoding and Content-Length present".to_string(),
+            });
+        }
+        // No transfer coding can be decoded yet, so the body length of any
+        // request that uses one is unknown.
+        if let Some(coding) = self.transfer_encodings.first() {
+            return Err(ParseError::UnsupportedTransferCoding {
+                coding: coding.clone(),
+            });
+        }
+
+        let mut content_lengths = self.content_lengths.iter();
+        if let Some(first) = content_lengths.next() {
+            // Too large a length would be ignored by the lexer, leaving the
+            // body to whatever happened to be buffered.
+            let digits = !first.is_empty() && first.bytes().all(|c| c.is_ascii_digit());
+            if !digits || first.parse::<usize>().is_err() {
+                return Err(ParseError::InvalidFraming {
+                    msg: format!("invalid Content-Length {:?}", first),
+                });
+            }
+            if content_lengths.any(|other| other != first) {
+                return Err(ParseError::InvalidFraming {
+                    msg: "conflicting Content-Length headers".to_string(),
+                });
+            }
+        }
+        Ok(())
     }
 }
 
-async fn parse_protocol<'a, T>(token_iter: &mut Lexer<'a, T>) -> Result<(), ParseError>
+async fn parse_body<'a, T>(token_iter: &mut Lexer<'a, T>) -> Result<Option<Vec<u8>>, ParseError>
 where
     T: AsyncReadExt + Unpin,
 {
     match token_iter.next().await {
-        Some(Token::Protocol) => Ok(()),
-        Some(Token::MaxHeaderSizeExceeded) => Err(ParseError::MaxHeaderSizeExceeded),
-        Some(_) => Err(ParseError::Unexpected {
-            msg: "Expected protocol version".to_string(),
-        }),
-        _ => Err(ParseError::EarlyEof),
+        Some(Token::Body(content)) => Ok(Some(content)),
+        Some(other) => Err(unexpected(Some(other), "Expected body")),
+        None => Ok(None),
     }
 }
 
@@ -133,11 +315,22 @@ where
 {
     match token_iter.next().await {
         Some(Token::Crlf) => Ok(()),
-        Some(Token::MaxHeaderSizeExceeded) => Err(ParseError::MaxHeaderSizeExceeded),
-        Some(_) => Err(ParseError::Unexpected {
-            msg: "Expected CRLF".to_string(),
-        }),
-        _ => Err(ParseError::EarlyEof),
+        other => Err(unexpected(other, "Expected CRLF")),
+    }
+}
+
+/// Maps a token the parser was not expecting to the error it represents,
+/// surfacing lexer failures as their own variants.
+fn unexpected(token: Option<Token>, msg: &str) -> ParseError {
+    match token {
+        Some(Token::MaxHeaderSizeExceeded) => ParseError::MaxHeaderSizeExceeded,
+        Some(Token::EarlyEof) => ParseError::EarlyEof,
+        Some(Token::IoError(msg)) => ParseError::Io { msg },
+        Some(Token::InvalidEncoding) => ParseError::InvalidEncoding,
+        Some(_) => ParseError::Unexpected {
+            msg: msg.to_string(),
+        },
+        None => ParseError::EarlyEof,
     }
 }
 
@@ -165,6 +358,24 @@ mod tests {
         assert_eq!(Some(&"value3".to_string()), request.header("Header-3"));
     }
 
+    #[tokio::test]
+    async fn looks_up_headers_in_any_case() {
+        let input = "GET / HTTP/1.1\r\n\
+        host: a.example\r\n\
+        content-type: application/json\r\n\
+        X-Request-ID: 7\r\n\
+        \r\n";
+
+        let request = (parse_from_reader(&mut input.as_bytes()).await).unwrap();
+
+        assert_eq!(Some(&"a.example".to_string()), request.header("Host"));
+        assert_eq!(
+            Some(&"application/json".to_string()),
+            request.header("Content-Type")
+        );
+        assert_eq!(Some(&"7".to_string()), request.header("x-request-id"));
+    }
+
     #[tokio::test]
     async fn parses_simple_valid_post_request_with_body() {
         let input = "POST / HTTP/1.1\r\n\
@@ -182,7 +393,7 @@ mod tests {
         assert_eq!(Some(&"value2".to_string()), request.header("Header-2"));
         assert_eq!(Some(&"value3".to_string()), request.header("Header-3"));
 
-        assert_eq!("This is the body", request.body_as_string());
+        assert_eq!("This is the body", request.body_as_string().unwrap());
     }
 
     #[tokio::test]
@@ -196,7 +407,7 @@ mod tests {
         assert_eq!(HttpMethod::from_str("POST").unwrap(), request.method);
         assert_eq!("/", request.path);
 
-        assert_eq!("This", request.body_as_string());
+        assert_eq!("This", request.body_as_string().unwrap());
     }
 
     #[tokio::test]
@@ -225,7 +436,7 @@ mod tests {
         assert_eq!(Some(&"value2".to_string()), request.header("Header-2"));
         assert_eq!(Some(&"value3".to_string()), request.header("Header-3"));
 
-        assert_eq!(50000, request.body_as_string().len());
+        assert_eq!(50000, request.body_as_string().unwrap().len());
     }
 
     #[tokio::test]
@@ -250,4 +461,270 @@ mod tests {
             panic!("Expected error, got OK");
         }
     }
+
+    #[tokio::test]
+    async fn non_utf8_header_value_returns_invalid_encoding_error() {
+        let mut input = b"GET / HTTP/1.1\r\nHeader-1: ".to_vec();
+        input.extend_from_slice(&[0xff, 0xfe]);
+        input.extend_from_slice(b"\r\n\r\n");
+
+        let request = parse_from_reader(&mut input.as_slice()).await;
+
+        assert_eq!(ParseError::InvalidEncoding, request.unwrap_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_header_field_returns_unexpected_error() {
+        let input = "GET / HTTP/1.1\r\nHeader-1 : value\r\n\r\n";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await;
+
+        assert_eq!(
+            ParseError::Unexpected {
+                msg: "Malformed header field".to_string()
+            },
+            request.unwrap_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_return_error_instead_of_waiting_forever() {
+        let input = "GET / HTTP/1.1\r\nHeader-1: val";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await;
+
+        assert_eq!(ParseError::EarlyEof, request.unwrap_err());
+    }
+
+    #[tokio::test]
+    async fn body_shorter_than_content_length_returns_early_eof() {
+        let input = "POST / HTTP/1.1\r\n\
+        Content-Length: 100\r\n\
+        \r\nshort";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await;
+
+        assert_eq!(ParseError::EarlyEof, request.unwrap_err());
+    }
+
+    #[tokio::test]
+    async fn binary_body_is_kept_as_bytes() {
+        let mut input = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\n".to_vec();
+        input.extend_from_slice(&[0x00, 0xff, 0x80]);
+
+        let request = (parse_from_reader(&mut input.as_slice()).await).unwrap();
+
+        assert_eq!(&[0x00, 0xff, 0x80], request.body());
+        assert_eq!(
+            ParseError::InvalidEncoding,
+            request.body_as_string().unwrap_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn reuses_and_returns_caller_buffer() {
+        let mut buffer = Vec::with_capacity(4096);
+        buffer.extend_from_slice(b"stale data from a previous request");
+
+        let request = parse_from_reader_with_buffer(
+            &mut "GET /reused HTTP/1.1\r\n\r\n".as_bytes(),
+            &mut buffer,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!("/reused", request.path);
+        assert!(buffer.capacity() >= 4096);
+        assert!(buffer.starts_with(b"GET /reused"));
+    }
+
+    #[tokio::test]
+    async fn accepts_bare_line_feeds_only_when_lenient() {
+        let input = "\r\n\nPOST /form HTTP/1.1\nHost: example.com\r\nContent-Length: 4\n\nbody";
+        let lenient = ParseOptions::new().lenient_line_endings();
+
+        let request =
+            parse_from_reader_with_options(&mut input.as_bytes(), &mut Vec::new(), lenient)
+                .await
+                .unwrap();
+        let strict = parse_from_reader(&mut input.as_bytes()).await;
+        let bare_lf = parse_from_reader(&mut &input.as_bytes()[3..]).await;
+
+        assert_eq!("/form", request.path);
+        assert_eq!(Some(&"example.com".to_string()), request.header("Host"));
+        assert_eq!(b"body", request.body());
+        assert!(strict.is_err());
+        assert!(bare_lf.is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_simple_requests_only_when_enabled() {
+        let input = "GET /index.html\r\n";
+        let http09 = ParseOptions::new().http09();
+
+        let request =
+            parse_from_reader_with_options(&mut input.as_bytes(), &mut Vec::new(), http09)
+                .await
+                .unwrap();
+        let disabled = parse_from_reader(&mut input.as_bytes()).await;
+        let post = parse_from_reader_with_options(
+            &mut "POST /form\r\n".as_bytes(),
+            &mut Vec::new(),
+            http09,
+        )
+        .await;
+        let full = parse_from_reader_with_options(
+            &mut "GET / HTTP/1.1\r\nHost: a\r\n\r\n".as_bytes(),
+            &mut Vec::new(),
+            http09,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!("/index.html", request.path);
+        assert_eq!(Some(&SimpleRequest), request.extensions().get());
+        assert!(disabled.is_err());
+        assert!(post.is_err());
+        assert_eq!(None, full.extensions().get::<SimpleRequest>());
+        assert_eq!(Some(&"a".to_string()), full.header("Host"));
+    }
+
+    #[tokio::test]
+    async fn rejects_transfer_encoding_with_content_length() {
+        let input = "POST / HTTP/1.1\r\n\
+        Content-Length: 4\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n0\r\n\r\n";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await;
+
+        assert!(matches!(
+            request.unwrap_err(),
+            ParseError::InvalidFraming { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_transfer_codings_it_cannot_decode() {
+        let input = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await;
+
+        assert_eq!(
+            ParseError::UnsupportedTransferCoding {
+                coding: "gzip".to_string()
+            },
+            request.unwrap_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_methods_it_does_not_know() {
+        let input = "PROPFIND /files HTTP/1.1\r\n\r\n";
+        let prefixed = "GETX /files HTTP/1.1\r\n\r\n";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await;
+        let prefixed = parse_from_reader(&mut prefixed.as_bytes()).await;
+
+        assert_eq!(
+            ParseError::UnsupportedMethod {
+                method: "PROPFIND".to_string()
+            },
+            request.unwrap_err()
+        );
+        assert_eq!(
+            ParseError::UnsupportedMethod {
+                method: "GETX".to_string()
+            },
+            prefixed.unwrap_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_conflicting_or_malformed_content_length() {
+        let conflicting = "POST / HTTP/1.1\r\n\
+        Content-Length: 4\r\n\
+        content-length: 10\r\n\
+        \r\nbody";
+        let malformed = "POST / HTTP/1.1\r\nContent-Length: +4\r\n\r\nbody";
+        let overflowing = "POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\nbody";
+        let repeated = "POST / HTTP/1.1\r\n\
+        Content-Length: 4\r\n\
+        Content-Length: 4\r\n\
+        \r\nbody";
+
+        let conflicting = parse_from_reader(&mut conflicting.as_bytes()).await;
+        let malformed = parse_from_reader(&mut malformed.as_bytes()).await;
+        let overflowing = parse_from_reader(&mut overflowing.as_bytes()).await;
+        let repeated = parse_from_reader(&mut repeated.as_bytes()).await;
+
+        assert!(matches!(
+            conflicting,
+            Err(ParseError::InvalidFraming { .. })
+        ));
+        assert!(matches!(malformed, Err(ParseError::InvalidFraming { .. })));
+        assert!(matches!(
+            overflowing,
+            Err(ParseError::InvalidFraming { .. })
+        ));
+        assert_eq!("body", repeated.unwrap().body_as_string().unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit_before_reading_them() {
+        let input = "POST /upload HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n";
+        let options = ParseOptions::new().max_body(1024);
+
+        let global =
+            parse_from_reader_with_options(&mut input.as_bytes(), &mut Vec::new(), options).await;
+        let routed = parse_from_reader_with_limit(
+            &mut input.as_bytes(),
+            &mut Vec::new(),
+            ParseOptions::new(),
+            |head| Some(10).filter(|_| head.path == "/upload"),
+        )
+        .await;
+        let small = parse_from_reader_with_options(
+            &mut "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody".as_bytes(),
+            &mut Vec::new(),
+            options,
+        )
+        .await;
+
+        assert_eq!(
+            Err(ParseError::BodyTooLarge {
+                length: 1_000_000,
+                max: 1024
+            }),
+            global.map(|_| ())
+        );
+        assert_eq!(
+            Err(ParseError::BodyTooLarge {
+                length: 1_000_000,
+                max: 10
+            }),
+            routed.map(|_| ())
+        );
+        assert_eq!(b"body", small.unwrap().body());
+    }
+
+    #[tokio::test]
+    async fn splits_query_string_from_path() {
+        let input = "GET /Search?tag=a&tag=b&q=rust+http HTTP/1.1\r\n\r\n";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await.unwrap();
+
+        assert_eq!("/Search", request.path);
+        assert_eq!(vec!["a", "b"], request.query().get_all("tag"));
+        assert_eq!(Some("rust http"), request.query().get("q"));
+    }
+
+    #[tokio::test]
+    async fn attaches_parse_timing_to_request() {
+        let input = "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+
+        let request = parse_from_reader(&mut input.as_bytes()).await.unwrap();
+
+        assert!(request.extensions().get::<ParseTiming>().is_some());
+    }
 }
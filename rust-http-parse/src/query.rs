@@ -0,0 +1,111 @@
+#[cfg(feature = "serde")]
+use crate::de::{DeserializeError, PairsDeserializer};
+
+/// The decoded `key=value` pairs of a request's query string, in the order
+/// they were sent. Repeated keys such as `?tag=a&tag=b` keep every value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+impl Query {
+    /// Parses a query string without its leading `?`. Keys and values are
+    /// percent-decoded, with `+` read as a space; a key with no `=` has an
+    /// empty value.
+    pub fn parse(query: &str) -> Self {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                let key = parts.next().unwrap_or("");
+                let value = parts.next().unwrap_or("");
+                (decode(key), decode(value))
+            })
+            .collect();
+
+        Query { pairs }
+    }
+
+    /// The first value given for `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// Every value given for `key`, in request order.
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Deserializes the pairs into `T`, as described on `HttpRequest::query_as`.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, DeserializeError> {
+        T::deserialize(PairsDeserializer::new(self.iter()))
+    }
+}
+
+/// Percent-decodes a query component, leaving malformed escapes as they are.
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (c, _) => decoded.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_repeated_keys_in_order() {
+        let query = Query::parse("tag=a&page=2&tag=b");
+
+        assert_eq!(Some("a"), query.get("tag"));
+        assert_eq!(vec!["a", "b"], query.get_all("tag"));
+        assert_eq!(
+            vec![("tag", "a"), ("page", "2"), ("tag", "b")],
+            query.iter().collect::<Vec<_>>()
+        );
+        assert!(query.get_all("missing").is_empty());
+    }
+
+    #[test]
+    fn decodes_keys_and_values() {
+        let query = Query::parse("q=hello+world%21&flag&bad=%zz&sign=%+1&&na%6De=x");
+
+        assert_eq!(Some("hello world!"), query.get("q"));
+        assert_eq!(Some(""), query.get("flag"));
+        assert_eq!(Some("%zz"), query.get("bad"));
+        assert_eq!(Some("% 1"), query.get("sign"));
+        assert_eq!(Some("x"), query.get("name"));
+    }
+}
@@ -0,0 +1,448 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::HttpRequest;
+
+const DEFAULT_SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+const DEFAULT_MAX_BODY: usize = 64;
+/// Bytes of a binary body shown, in hex.
+const BINARY_PREVIEW: usize = 16;
+/// Bytes looked at to decide whether a body is binary.
+const BINARY_SNIFF: usize = 512;
+
+/// How much of a logged field is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mask {
+    Keep,
+    /// Keeps enough to group entries: the network part of a client address,
+    /// or the first segment of a path.
+    Partial,
+    Hide,
+}
+impl FromStr for Mask {
+    type Err = String;
+
+    fn from_str(mask: &str) -> Result<Self, Self::Err> {
+        match mask {
+            "keep" => Ok(Mask::Keep),
+            "partial" => Ok(Mask::Partial),
+            "hide" => Ok(Mask::Hide),
+            _ => Err(format!("expected keep, partial or hide, got '{}'", mask)),
+        }
+    }
+}
+
+/// Says which headers and body fields are masked, how much of a body is
+/// shown, and how the client address and path are masked when a message or
+/// access log entry is written. By default the credentials headers
+/// (Authorization, Proxy-Authorization, Cookie and Set-Cookie) are masked,
+/// bodies are cut to 64 bytes, and addresses and paths are kept. Binary
+/// bodies are shown as the hex of their first few bytes.
+#[derive(Debug, Clone)]
+pub struct Redaction {
+    headers: Vec<String>,
+    body_fields: Vec<String>,
+    max_body: usize,
+    client: Mask,
+    path: Mask,
+}
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction {
+            headers: DEFAULT_SENSITIVE_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            body_fields: Vec::new(),
+            max_body: DEFAULT_MAX_BODY,
+            client: Mask::Keep,
+            path: Mask::Keep,
+        }
+    }
+}
+impl Redaction {
+    pub fn new() -> Self {
+        Redaction::default()
+    }
+
+    /// Masks another header, matched case-insensitively.
+    pub fn header(mut self, name: &str) -> Self {
+        if !self.is_sensitive(name) {
+            self.headers.push(name.to_ascii_lowercase());
+        }
+        self
+    }
+
+    /// Masks the value of a field in JSON and form bodies, e.g. `password`.
+    pub fn body_field(mut self, name: &str) -> Self {
+        self.body_fields.push(name.to_owned());
+        self
+    }
+
+    /// Shows at most `max_body` bytes of each body; zero hides bodies.
+    pub fn max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    pub fn mask_client(mut self, mask: Mask) -> Self {
+        self.client = mask;
+        self
+    }
+
+    pub fn mask_path(mut self, mask: Mask) -> Self {
+        self.path = mask;
+        self
+    }
+
+    /// The client address to log. A partial IPv4 address keeps its /24
+    /// network and a partial IPv6 address its /48.
+    pub fn client(&self, address: &str) -> String {
+        match (self.client, address.parse::<IpAddr>()) {
+            (Mask::Keep, _) => address.to_owned(),
+            (Mask::Partial, Ok(IpAddr::V4(ip))) => {
+                let [a, b, c, _] = ip.octets();
+                format!("{}.{}.{}.0", a, b, c)
+            }
+            (Mask::Partial, Ok(IpAddr::V6(ip))) => {
+                let [a, b, c, ..] = ip.segments();
+                format!("{:x}:{:x}:{:x}::", a, b, c)
+            }
+            _ => "-".to_owned(),
+        }
+    }
+
+    /// The path to log. A partial path keeps its first segment and replaces
+    /// the rest, which tend to hold IDs, with `*`.
+    pub fn path(&self, path: &str) -> String {
+        match self.path {
+            Mask::Keep => path.to_owned(),
+            Mask::Partial => {
+                let mut segments = path.split('/').skip(1);
+                let first = segments.next().unwrap_or_default();
+                let mut masked = format!("/{}", first);
+                for segment in segments {
+                    masked.push_str(if segment.is_empty() { "/" } else { "/*" });
+                }
+                masked
+            }
+            Mask::Hide => "-".to_owned(),
+        }
+    }
+
+    pub fn is_sensitive(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|sensitive| sensitive.eq_ignore_ascii_case(name))
+    }
+
+    /// The value to log for a header: the value itself, or `<redacted>`.
+    pub fn header_value<'a>(&self, name: &str, value: &'a str) -> &'a str {
+        if self.is_sensitive(name) {
+            "<redacted>"
+        } else {
+            value
+        }
+    }
+
+    /// Formats a request with its path masked. The query string is left out,
+    /// since it often carries tokens of its own.
+    pub fn request<'a>(&'a self, request: &'a HttpRequest) -> Redacted<'a> {
+        self.message(
+            format!("{:?} {}", request.method, self.path(request.raw_path())),
+            request.headers(),
+            request.body(),
+        )
+    }
+
+    /// Formats any message from its first line, headers and body, for types
+    /// outside this crate such as responses.
+    pub fn message<'a>(
+        &'a self,
+        start_line: String,
+        headers: impl Iterator<Item = (&'a str, &'a str)>,
+        body: &'a [u8],
+    ) -> Redacted<'a> {
+        let mut headers: Vec<_> = headers.collect();
+        headers.sort_unstable();
+        Redacted {
+            redaction: self,
+            start_line,
+            headers,
+            body,
+        }
+    }
+}
+
+/// A message that formats without its sensitive headers and with its body
+/// truncated, through both `Display` and `Debug`.
+pub struct Redacted<'a> {
+    redaction: &'a Redaction,
+    start_line: String,
+    headers: Vec<(&'a str, &'a str)>,
+    body: &'a [u8],
+}
+impl Redacted<'_> {
+    /// The start of the body as text with its masked fields hidden, and
+    /// whether anything was cut off, or `None` for a binary body.
+    fn shown_body(&self) -> Option<(String, bool)> {
+        if is_binary(self.body) {
+            return None;
+        }
+        let max_body = self.redaction.max_body;
+        if self.redaction.body_fields.is_empty() {
+            let shown = &self.body[..self.body.len().min(max_body)];
+            let cut = self.body.len() > max_body;
+            return Some((String::from_utf8_lossy(shown).into_owned(), cut));
+        }
+
+        // The whole body is masked before it's cut, so a secret straddling
+        // the cut can't show.
+        let mut text = String::from_utf8_lossy(self.body).into_owned();
+        for field in &self.redaction.body_fields {
+            text = mask_field(&text, field);
+        }
+        let cut = text.len() > max_body;
+        let mut end = text.len().min(max_body);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        Some((text, cut))
+    }
+
+    fn binary_preview(&self) -> String {
+        let shown = &self.body[..self.body.len().min(BINARY_PREVIEW)];
+        let hex: Vec<_> = shown.iter().map(|byte| format!("{:02x}", byte)).collect();
+        hex.join(" ")
+    }
+}
+
+/// Whether a body looks like binary data rather than text: it has a NUL
+/// byte, isn't UTF-8, or is more than a tenth control characters.
+fn is_binary(body: &[u8]) -> bool {
+    let sniffed = &body[..body.len().min(BINARY_SNIFF)];
+    if sniffed.contains(&0) {
+        return true;
+    }
+    if let Err(error) = std::str::from_utf8(sniffed) {
+        // A character cut in half by the sniffing limit is fine.
+        if error.error_len().is_some() || sniffed.len() == body.len() {
+            return true;
+        }
+    }
+    let controls = sniffed
+        .iter()
+        .filter(|&&byte| byte < 0x20 && !b"\t\n\r\x0c".contains(&byte))
+        .count();
+    controls * 10 > sniffed.len()
+}
+
+/// Replaces the values of `field` in a JSON (`"field": value`) or form
+/// (`field=value`) body with `<redacted>`.
+fn mask_field(text: &str, field: &str) -> String {
+    let json_key = format!("\"{}\"", field);
+    let mut masked = String::with_capacity(text.len());
+    let mut rest = text;
+    loop {
+        let json = rest.find(&json_key);
+        let form = find_form_field(rest, field);
+        let (value_start, value_end) = match (json, form) {
+            (Some(json), Some((form, value))) if form < json => {
+                (value, form_value_end(rest, value))
+            }
+            (Some(at), _) => {
+                let key_end = at + json_key.len();
+                match json_value(rest, key_end) {
+                    Some(value) => value,
+                    None => {
+                        masked.push_str(&rest[..key_end]);
+                        rest = &rest[key_end..];
+                        continue;
+                    }
+                }
+            }
+            (None, Some((_, value))) => (value, form_value_end(rest, value)),
+            (None, None) => break,
+        };
+        masked.push_str(&rest[..value_start]);
+        masked.push_str("<redacted>");
+        rest = &rest[value_end..];
+    }
+    masked.push_str(rest);
+    masked
+}
+
+/// Where `field=` starts a form field in `text`, and where its value starts.
+fn find_form_field(text: &str, field: &str) -> Option<(usize, usize)> {
+    let key = format!("{}=", field);
+    let mut from = 0;
+    while let Some(found) = text[from..].find(&key) {
+        let at = from + found;
+        if at == 0 || text.as_bytes()[at - 1] == b'&' {
+            return Some((at, at + key.len()));
+        }
+        from = at + key.len();
+    }
+    None
+}
+
+fn form_value_end(text: &str, start: usize) -> usize {
+    text[start..]
+        .find('&')
+        .map_or(text.len(), |end| start + end)
+}
+
+/// The span of the JSON value following a key that ends at `key_end`, with
+/// a string's quotes left out, or `None` if the key isn't followed by `:`.
+fn json_value(text: &str, key_end: usize) -> Option<(usize, usize)> {
+    let after_key = &text[key_end..];
+    let colon = after_key.len() - after_key.trim_start().len();
+    if !after_key[colon..].starts_with(':') {
+        return None;
+    }
+    let value = &after_key[colon + 1..];
+    let start = key_end + colon + 1 + (value.len() - value.trim_start().len());
+    let value = &text[start..];
+    if let Some(string) = value.strip_prefix('"') {
+        let mut escaped = false;
+        for (i, c) in string.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => return Some((start + 1, start + 1 + i)),
+                _ => {}
+            }
+        }
+        return Some((start + 1, text.len()));
+    }
+    let end = value
+        .find(|c: char| c == ',' || c == '}' || c == ']' || c.is_whitespace())
+        .unwrap_or(value.len());
+    Some((start, start + end))
+}
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [", self.start_line)?;
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            let value = self.redaction.header_value(name, value);
+            write!(f, "{}{}: {}", separator, name, value)?;
+        }
+        write!(f, "] {} byte body", self.body.len())?;
+        if self.redaction.max_body > 0 && !self.body.is_empty() {
+            match self.shown_body() {
+                Some((shown, cut)) => {
+                    write!(f, " {:?}", shown)?;
+                    if cut {
+                        write!(f, "...")?;
+                    }
+                }
+                None => write!(f, " binary {}", self.binary_preview())?,
+            }
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: Vec<_> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (*name, self.redaction.header_value(name, value)))
+            .collect();
+        f.debug_struct("Redacted")
+            .field("start_line", &self.start_line)
+            .field("headers", &headers)
+            .field("body_len", &self.body.len())
+            .field(
+                "body",
+                &self
+                    .shown_body()
+                    .map_or_else(|| self.binary_preview(), |(shown, _)| shown),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpMethod, HttpRequestBuilder};
+
+    #[test]
+    fn masks_sensitive_headers_and_truncates_bodies() {
+        let request = HttpRequestBuilder::new()
+            .with_method(HttpMethod::POST)
+            .with_path("/login?next=%2F")
+            .with_header("Authorization", "Bearer secret")
+            .with_header("X-Api-Key", "key")
+            .with_header("Host", "example.com")
+            .with_body("0123456789")
+            .build()
+            .unwrap();
+        let redaction = Redaction::new().header("x-api-key").max_body(4);
+
+        let formatted = redaction.request(&request).to_string();
+
+        assert_eq!(
+            "POST /login [Authorization: <redacted>, Host: example.com, \
+             X-Api-Key: <redacted>] 10 byte body \"0123\"...",
+            formatted
+        );
+        let debug = format!("{:?}", redaction.request(&request));
+        assert!(!debug.contains("secret"), "{}", debug);
+    }
+
+    #[test]
+    fn masks_body_fields_and_spots_binary_bodies() {
+        let redaction = Redaction::new().body_field("password").body_field("pin");
+        let shown = |body: &[u8]| {
+            redaction
+                .message("200 OK".to_owned(), std::iter::empty(), body)
+                .to_string()
+        };
+
+        assert_eq!(
+            r#"200 OK [] 50 byte body "{\"user\": \"ann\", \"password\" : \"<redacted>\", \"pin\": <redacted>}""#,
+            shown(br#"{"user": "ann", "password" : "se\"cret", "pin": 1}"#)
+        );
+        assert_eq!(
+            "200 OK [] 31 byte body \"user=ann&password=<redacted>&keep=1\"",
+            shown(b"user=ann&password=secret&keep=1")
+        );
+        assert_eq!(
+            "200 OK [] 5 byte body binary 1f 8b 08 00 00",
+            shown(&[0x1f, 0x8b, 0x08, 0x00, 0x00])
+        );
+        assert_eq!("a=1&xpassword=2", mask_field("a=1&xpassword=2", "password"));
+    }
+
+    #[test]
+    fn masks_client_addresses_and_paths() {
+        let partial = Redaction::new()
+            .mask_client(Mask::Partial)
+            .mask_path(Mask::Partial);
+        let hidden = Redaction::new()
+            .mask_client(Mask::Hide)
+            .mask_path(Mask::Hide);
+
+        assert_eq!("203.0.113.0", partial.client("203.0.113.57"));
+        assert_eq!(
+            "2001:db8:85a3::",
+            partial.client("2001:db8:85a3::8a2e:370:7334")
+        );
+        assert_eq!("-", partial.client("-"));
+        assert_eq!("/users/*/*/", partial.path("/users/42/orders/"));
+        assert_eq!("/", partial.path("/"));
+        assert_eq!("-", hidden.client("203.0.113.57"));
+        assert_eq!("-", hidden.path("/users/42"));
+        assert_eq!("/users/42", Redaction::new().path("/users/42"));
+    }
+}
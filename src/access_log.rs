@@ -0,0 +1,449 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use rust_http_parse::{HttpRequest, Redaction};
+
+use super::{Geo, HttpResponse};
+
+/// The fields written when none are given, those IIS logs by default.
+const DEFAULT_FIELDS: [&str; 8] = [
+    "date",
+    "time",
+    "c-ip",
+    "cs-method",
+    "cs-uri-stem",
+    "cs-uri-query",
+    "sc-status",
+    "time-taken",
+];
+
+/// Writes the access log in the W3C Extended Log File Format, for log
+/// pipelines that only take that format. The `#Version`, `#Date` and
+/// `#Fields` directives are logged when the server starts listening, and
+/// start each file when written to a `directory`.
+///
+/// The fields are `date` and `time` in UTC, `c-ip`, `cs-method`,
+/// `cs-uri-stem`, `cs-uri-query`, `cs-uri`, `sc-status`,
+/// `cs-bytes` and `sc-bytes` (body sizes), `time-taken` in seconds, and
+/// `cs(Header)` and `sc(Header)` for any request or response header, plus
+/// `x-country` and `x-asn` for the client's `Geo`. Client
+/// addresses, paths and sensitive headers are masked as set with
+/// `ServerBuilder::redact`; the query string isn't, so leave `cs-uri-query`
+/// and `cs-uri` out if it may carry tokens.
+#[derive(Debug, Clone)]
+pub struct W3cLog {
+    fields: Vec<Field>,
+    files: Option<Arc<Mutex<DailyFiles>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Date,
+    Time,
+    ClientIp,
+    Method,
+    UriStem,
+    UriQuery,
+    Uri,
+    Status,
+    RequestBytes,
+    ResponseBytes,
+    TimeTaken,
+    Country,
+    Asn,
+    RequestHeader(String),
+    ResponseHeader(String),
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        let header = |prefix: &str| {
+            name.strip_prefix(prefix)?
+                .strip_suffix(')')
+                .filter(|header| !header.is_empty())
+                .map(str::to_owned)
+        };
+        Some(match name {
+            "date" => Field::Date,
+            "time" => Field::Time,
+            "c-ip" => Field::ClientIp,
+            "cs-method" => Field::Method,
+            "cs-uri-stem" => Field::UriStem,
+            "cs-uri-query" => Field::UriQuery,
+            "cs-uri" => Field::Uri,
+            "sc-status" => Field::Status,
+            "cs-bytes" => Field::RequestBytes,
+            "sc-bytes" => Field::ResponseBytes,
+            "time-taken" => Field::TimeTaken,
+            "x-country" => Field::Country,
+            "x-asn" => Field::Asn,
+            _ => match (header("cs("), header("sc(")) {
+                (Some(header), _) => Field::RequestHeader(header),
+                (_, Some(header)) => Field::ResponseHeader(header),
+                _ => return None,
+            },
+        })
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Field::Date => "date".to_owned(),
+            Field::Time => "time".to_owned(),
+            Field::ClientIp => "c-ip".to_owned(),
+            Field::Method => "cs-method".to_owned(),
+            Field::UriStem => "cs-uri-stem".to_owned(),
+            Field::UriQuery => "cs-uri-query".to_owned(),
+            Field::Uri => "cs-uri".to_owned(),
+            Field::Status => "sc-status".to_owned(),
+            Field::RequestBytes => "cs-bytes".to_owned(),
+            Field::ResponseBytes => "sc-bytes".to_owned(),
+            Field::TimeTaken => "time-taken".to_owned(),
+            Field::Country => "x-country".to_owned(),
+            Field::Asn => "x-asn".to_owned(),
+            Field::RequestHeader(header) => format!("cs({})", header),
+            Field::ResponseHeader(header) => format!("sc({})", header),
+        }
+    }
+}
+
+impl Default for W3cLog {
+    fn default() -> Self {
+        W3cLog::new(&DEFAULT_FIELDS).unwrap()
+    }
+}
+
+impl W3cLog {
+    /// The log target the lines are logged to, below `access`. Format its
+    /// records as their bare message to get a file the format's readers take.
+    pub const TARGET: &'static str = "access::w3c";
+
+    /// Writes `fields`, named as in the `#Fields` directive, in order.
+    pub fn new<S: AsRef<str>>(fields: &[S]) -> Result<Self, String> {
+        let fields = fields
+            .iter()
+            .map(|name| {
+                let name = name.as_ref();
+                Field::parse(name).ok_or_else(|| format!("unknown W3C log field '{}'", name))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(W3cLog {
+            fields,
+            files: None,
+        })
+    }
+
+    /// Writes the log to a file a day in `directory`, named for the UTC
+    /// date as IIS names them (`u_ex231114.log`), instead of to the log
+    /// target, so it isn't mixed with the application log.
+    pub fn directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.files = Some(Arc::new(Mutex::new(DailyFiles {
+            directory: directory.into(),
+            current: None,
+        })));
+        self
+    }
+
+    /// Starts the log with its directives.
+    pub(crate) fn start(&self, now: SystemTime) {
+        match &self.files {
+            Some(files) => {
+                if let Err(e) = files.lock().unwrap().file(self, now) {
+                    warn!("Could not open the W3C access log: {}", e);
+                }
+            }
+            None => {
+                for directive in self.directives(now) {
+                    info!(target: W3cLog::TARGET, "{}", directive);
+                }
+            }
+        }
+    }
+
+    /// Writes a line made by `line`.
+    pub(crate) fn write(&self, line: &str, now: SystemTime) {
+        let files = match &self.files {
+            Some(files) => files,
+            None => return info!(target: W3cLog::TARGET, "{}", line),
+        };
+        let mut files = files.lock().unwrap();
+        let written = files
+            .file(self, now)
+            .and_then(|file| writeln!(file, "{}", line));
+        if let Err(e) = written {
+            warn!("Could not write to the W3C access log: {}", e);
+        }
+    }
+
+    /// The directive lines that start the log.
+    pub(crate) fn directives(&self, now: SystemTime) -> Vec<String> {
+        let (date, time) = date_time(now);
+        let fields: Vec<_> = self.fields.iter().map(Field::name).collect();
+        vec![
+            "#Version: 1.0".to_owned(),
+            format!("#Date: {} {}", date, time),
+            format!("#Fields: {}", fields.join(" ")),
+        ]
+    }
+
+    /// Takes what the log needs from `request` before it's handled, with
+    /// `None` for the fields that come from the response.
+    pub(crate) fn request_fields(
+        &self,
+        request: &HttpRequest,
+        redaction: &Redaction,
+    ) -> Vec<Option<String>> {
+        let stem = || redaction.path(request.raw_path());
+        let geo = request.extensions().get::<Geo>();
+        self.fields
+            .iter()
+            .map(|field| match field {
+                Field::Method => Some(format!("{:?}", request.method)),
+                Field::UriStem => Some(stem()),
+                Field::UriQuery => Some(request.raw_query().to_owned()),
+                Field::Uri if request.raw_query().is_empty() => Some(stem()),
+                Field::Uri => Some(format!("{}?{}", stem(), request.raw_query())),
+                Field::RequestBytes => Some(request.body().len().to_string()),
+                Field::Country => Some(geo.and_then(|geo| geo.country.clone()).unwrap_or_default()),
+                Field::Asn => Some(
+                    geo.and_then(|geo| geo.asn)
+                        .map_or_else(String::new, |asn| asn.to_string()),
+                ),
+                Field::RequestHeader(name) => Some(
+                    request
+                        .header(name)
+                        .map_or("", |value| redaction.header_value(name, value))
+                        .to_owned(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The log line for a request, given what `request_fields` took from it,
+    /// or nothing if it couldn't be parsed.
+    pub(crate) fn line(
+        &self,
+        request_fields: &[Option<String>],
+        client: &str,
+        status: u16,
+        response: &HttpResponse,
+        taken: Duration,
+        now: SystemTime,
+    ) -> String {
+        let (date, time) = date_time(now);
+        let values: Vec<String> = self
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                let value = match field {
+                    Field::Date => date.clone(),
+                    Field::Time => time.clone(),
+                    Field::ClientIp => client.to_owned(),
+                    Field::Status => status.to_string(),
+                    Field::ResponseBytes => response.body().len().to_string(),
+                    Field::TimeTaken => format!("{:.3}", taken.as_secs_f64()),
+                    Field::ResponseHeader(name) => {
+                        response.header(name).cloned().unwrap_or_default()
+                    }
+                    _ => request_fields
+                        .get(index)
+                        .cloned()
+                        .flatten()
+                        .unwrap_or_default(),
+                };
+                escape(&value)
+            })
+            .collect();
+        values.join(" ")
+    }
+}
+
+/// The log's file for the current UTC day.
+#[derive(Debug)]
+struct DailyFiles {
+    directory: PathBuf,
+    current: Option<(String, File)>,
+}
+impl DailyFiles {
+    /// The file for the day of `now`, opening a new one that starts with
+    /// the directives when the day changes.
+    fn file(&mut self, log: &W3cLog, now: SystemTime) -> io::Result<&mut File> {
+        let (date, _) = date_time(now);
+        let current = match self.current.take() {
+            Some((day, file)) if day == date => (day, file),
+            _ => {
+                fs::create_dir_all(&self.directory)?;
+                let name = format!("u_ex{}{}{}.log", &date[2..4], &date[5..7], &date[8..10]);
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.directory.join(name))?;
+                for directive in log.directives(now) {
+                    writeln!(file, "{}", directive)?;
+                }
+                (date, file)
+            }
+        };
+        Ok(&mut self.current.insert(current).1)
+    }
+}
+
+/// Fields are separated by spaces and empty ones written as `-`, so spaces
+/// become `+`, as IIS writes them.
+fn escape(value: &str) -> String {
+    if value.is_empty() {
+        return "-".to_owned();
+    }
+    value
+        .chars()
+        .map(|c| match c {
+            ' ' => '+',
+            c if c.is_control() => '?',
+            c => c,
+        })
+        .collect()
+}
+
+/// The UTC date as `YYYY-MM-DD` and time as `HH:MM:SS`.
+fn date_time(now: SystemTime) -> (String, String) {
+    let seconds = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    // Days since the epoch to a civil date, after Howard Hinnant's algorithm.
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!(
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::{HttpMethod, HttpRequestBuilder, Mask};
+
+    #[test]
+    fn writes_the_chosen_fields_in_order() {
+        let log = W3cLog::new(&[
+            "date",
+            "time",
+            "c-ip",
+            "cs-method",
+            "cs-uri",
+            "sc-status",
+            "sc-bytes",
+            "time-taken",
+            "cs(User-Agent)",
+            "cs(Authorization)",
+            "sc(Content-Type)",
+        ])
+        .unwrap();
+        let request = HttpRequestBuilder::new()
+            .with_method(HttpMethod::GET)
+            .with_path("/orders/42?page=2")
+            .with_header("User-Agent", "curl 8.0")
+            .with_header("Authorization", "Bearer s3cret")
+            .build()
+            .unwrap();
+        let redaction = Redaction::new().mask_path(Mask::Partial);
+        let response = HttpResponse::ok()
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"done".to_vec());
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let fields = log.request_fields(&request, &redaction);
+        let line = log.line(
+            &fields,
+            "10.0.0.1",
+            200,
+            &response,
+            Duration::from_millis(1500),
+            now,
+        );
+
+        assert_eq!(
+            vec![
+                "#Version: 1.0",
+                "#Date: 2023-11-14 22:13:20",
+                "#Fields: date time c-ip cs-method cs-uri sc-status sc-bytes time-taken \
+                 cs(User-Agent) cs(Authorization) sc(Content-Type)"
+            ],
+            log.directives(now)
+        );
+        assert_eq!(
+            "2023-11-14 22:13:20 10.0.0.1 GET /orders/*?page=2 200 4 1.500 curl+8.0 \
+             <redacted> text/plain",
+            line
+        );
+        assert_eq!(
+            "- - - 400",
+            W3cLog::new(&["cs-method", "cs-uri-stem", "cs(Host)", "sc-status"])
+                .unwrap()
+                .line(
+                    &[],
+                    "-",
+                    400,
+                    &HttpResponse::new(400),
+                    Duration::default(),
+                    now
+                )
+        );
+    }
+
+    #[test]
+    fn starts_a_file_with_the_directives_each_day() {
+        let directory = std::env::temp_dir().join(format!("w3c-log-{}", std::process::id()));
+        let log = W3cLog::new(&["time", "sc-status"])
+            .unwrap()
+            .directory(&directory);
+        let day = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let next_day = day + Duration::from_secs(86400);
+
+        log.start(day);
+        log.write("22:13:20 200", day);
+        log.write("22:13:20 404", next_day);
+
+        assert_eq!(
+            "#Version: 1.0\n#Date: 2023-11-14 22:13:20\n#Fields: time sc-status\n\
+             22:13:20 200\n",
+            std::fs::read_to_string(directory.join("u_ex231114.log")).unwrap()
+        );
+        assert_eq!(
+            "#Version: 1.0\n#Date: 2023-11-15 22:13:20\n#Fields: time sc-status\n\
+             22:13:20 404\n",
+            std::fs::read_to_string(directory.join("u_ex231115.log")).unwrap()
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(W3cLog::new(&["date", "s-sitename"]).is_err());
+        assert!(W3cLog::new(&["cs()"]).is_err());
+        assert_eq!(DEFAULT_FIELDS.len(), W3cLog::default().fields.len());
+    }
+}
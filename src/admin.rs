@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use rust_http_parse::{HttpMethod, HttpRequest};
+
+use super::auth::bearer_token_matches;
+use super::{
+    BoxFuture, BufferPool, Handler, Health, HttpResponse, Metrics, Phase, Router, RuntimeMounts,
+    LATENCY_BUCKETS_MICROS,
+};
+
+/// Builds the router served on the admin listener. `GET /status` reports
+/// the server's live state as JSON.
+pub(crate) fn router(
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    buffer_pool: Arc<BufferPool>,
+) -> Router {
+    Router::new().route(HttpMethod::GET, "/status", move |_: HttpRequest| {
+        let body = status_json(&metrics, &health, &buffer_pool);
+        async move {
+            HttpResponse::ok()
+                .with_header("Content-Type", "application/json")
+                .with_body(body.into_bytes())
+        }
+    })
+}
+
+/// Adds `/mounts` to the admin `router`. GET lists the runtime mounts as
+/// JSON, PUT with `?prefix=/docs&root=/srv/docs` adds one and DELETE with
+/// `?prefix=/docs` removes one; each answers with the mounts left.
+pub(crate) fn mount_routes(router: Router, mounts: RuntimeMounts, token: &str) -> Router {
+    let endpoint = MountsEndpoint {
+        mounts,
+        token: Arc::new(token.to_owned()),
+    };
+    router
+        .route(HttpMethod::GET, "/mounts", endpoint.clone())
+        .route(HttpMethod::PUT, "/mounts", endpoint.clone())
+        .route(HttpMethod::DELETE, "/mounts", endpoint)
+}
+
+#[derive(Clone)]
+struct MountsEndpoint {
+    mounts: RuntimeMounts,
+    token: Arc<String>,
+}
+impl MountsEndpoint {
+    fn respond(&self, request: &HttpRequest) -> HttpResponse {
+        if !bearer_token_matches(request, &self.token) {
+            return HttpResponse::new(401).with_header("WWW-Authenticate", "Bearer");
+        }
+
+        let query = request.query();
+        let changed = match (&request.method, query.get("prefix"), query.get("root")) {
+            (HttpMethod::GET, _, _) => Ok(()),
+            (HttpMethod::PUT, Some(prefix), Some(root)) => self.mounts.add(prefix, root),
+            (HttpMethod::DELETE, Some(prefix), _) => match self.mounts.remove(prefix) {
+                Ok(true) => Ok(()),
+                Ok(false) => return HttpResponse::not_found(),
+                Err(e) => Err(e),
+            },
+            _ => {
+                return HttpResponse::new(400)
+                    .with_body(b"prefix and, to add a mount, root are required".to_vec())
+            }
+        };
+        match changed {
+            Ok(()) => HttpResponse::ok()
+                .with_header("Content-Type", "application/json")
+                .with_body(mounts_json(&self.mounts).into_bytes()),
+            Err(e) => HttpResponse::new(500)
+                .with_body(format!("Mounts changed but not saved: {}", e).into_bytes()),
+        }
+    }
+}
+
+impl Handler for MountsEndpoint {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let response = self.respond(&request);
+        Box::pin(async { response })
+    }
+}
+
+fn mounts_json(mounts: &RuntimeMounts) -> String {
+    let mounts = mounts
+        .mounts()
+        .iter()
+        .map(|(prefix, root)| {
+            format!(
+                "{{\"prefix\":{},\"root\":{}}}",
+                json_string(prefix),
+                json_string(&root.to_string_lossy())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{{\"mounts\":[{}]}}", mounts)
+}
+
+fn status_json(metrics: &Metrics, health: &Health, buffer_pool: &BufferPool) -> String {
+    let pool = buffer_pool.stats();
+    let routes = metrics
+        .routes()
+        .iter()
+        .map(|(route, stats)| {
+            let statuses = stats
+                .statuses
+                .iter()
+                .map(|(status, count)| format!("\"{}\":{}", status, count))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!(
+                "{}:{{\"requests\":{},\"server_errors\":{},\"statuses\":{{{}}},\
+                 \"latency\":{{\"count\":{},\"sum_us\":{},\"buckets\":{}}}}}",
+                json_string(route),
+                stats.requests,
+                stats.server_errors,
+                statuses,
+                stats.latency.count,
+                stats.latency.sum_micros,
+                json_array(&stats.latency.buckets)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let phases = Phase::ALL
+        .iter()
+        .map(|phase| {
+            let histogram = metrics.phase_latency(*phase);
+            format!(
+                "\"{}\":{{\"count\":{},\"sum_us\":{},\"buckets\":{}}}",
+                phase.name(),
+                histogram.count,
+                histogram.sum_micros,
+                json_array(&histogram.buckets)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let connections = metrics.connections();
+    let lifetime = metrics.connection_lifetime();
+
+    format!(
+        "{{\"ready\":{},\"active_connections\":{},\"requests_in_flight\":{},\
+         \"buffer_pool\":{{\"pooled\":{},\"in_use\":{},\"allocated\":{},\"reused\":{}}},\
+         \"connections\":{{\"opened\":{},\"closed\":{},\"bytes_read\":{},\"bytes_written\":{},\
+         \"requests\":{},\"accept_errors\":{},\"client_aborts\":{},\"lifetime\":{{\"count\":{},\"sum_us\":{},\"buckets\":{}}}}},\
+         \"routes\":{{{}}},\"latency_buckets_us\":{},\"phases\":{{{}}}}}",
+        health.is_ready(),
+        metrics.active_connections(),
+        metrics.requests_in_flight(),
+        pool.pooled,
+        pool.in_use,
+        pool.allocated,
+        pool.reused,
+        connections.opened,
+        connections.closed,
+        connections.bytes_read,
+        connections.bytes_written,
+        connections.requests,
+        connections.accept_errors,
+        connections.client_aborts,
+        lifetime.count,
+        lifetime.sum_micros,
+        json_array(&lifetime.buckets),
+        routes,
+        json_array(&LATENCY_BUCKETS_MICROS),
+        phases
+    )
+}
+
+fn json_array(values: &[u64]) -> String {
+    let values: Vec<_> = values.iter().map(|value| value.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn status_reports_server_state_as_json() {
+        let metrics = Arc::new(Metrics::new());
+        let _connection = metrics.connection_opened();
+        let router = router(
+            metrics,
+            Arc::new(Health::new()),
+            Arc::new(BufferPool::default()),
+        );
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/status"))
+            .await;
+
+        assert_eq!(200, response.status);
+        assert_eq!(
+            "{\"ready\":false,\"active_connections\":1,\"requests_in_flight\":0,\
+             \"buffer_pool\":{\"pooled\":0,\"in_use\":0,\"allocated\":0,\"reused\":0},\
+             \"connections\":{\"opened\":1,\"closed\":0,\"bytes_read\":0,\"bytes_written\":0,\
+             \"requests\":0,\"accept_errors\":0,\"client_aborts\":0,\"lifetime\":{\"count\":0,\"sum_us\":0,\"buckets\":[0,0,0,0,0,0,0,0,0,0,0]}},\
+             \"routes\":{},\"latency_buckets_us\":[100,500,1000,5000,10000,50000,100000,500000,1000000,5000000],\
+             \"phases\":{\"headers\":{\"count\":0,\"sum_us\":0,\"buckets\":[0,0,0,0,0,0,0,0,0,0,0]},\
+             \"body\":{\"count\":0,\"sum_us\":0,\"buckets\":[0,0,0,0,0,0,0,0,0,0,0]},\
+             \"handler\":{\"count\":0,\"sum_us\":0,\"buckets\":[0,0,0,0,0,0,0,0,0,0,0]},\
+             \"write\":{\"count\":0,\"sum_us\":0,\"buckets\":[0,0,0,0,0,0,0,0,0,0,0]}}}",
+            String::from_utf8(response.body().to_vec()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn mounts_are_managed_with_the_token() {
+        let mounts = RuntimeMounts::new();
+        let router = mount_routes(Router::new(), mounts.clone(), "s3cret");
+        let request = |method, target: &str, token: &str| {
+            let mut request = HttpRequest::new(method, target);
+            request.set_header("Authorization", &format!("Bearer {}", token));
+            router.dispatch(request)
+        };
+
+        let denied = request(
+            HttpMethod::PUT,
+            "/mounts?prefix=/docs&root=/srv/docs",
+            "guess",
+        )
+        .await;
+        assert_eq!(401, denied.status);
+        assert!(mounts.mounts().is_empty());
+
+        let added = request(
+            HttpMethod::PUT,
+            "/mounts?prefix=/docs&root=/srv/docs",
+            "s3cret",
+        )
+        .await;
+        assert_eq!(200, added.status);
+        assert_eq!(
+            b"{\"mounts\":[{\"prefix\":\"/docs\",\"root\":\"/srv/docs\"}]}",
+            added.body()
+        );
+        let missing = request(HttpMethod::DELETE, "/mounts?prefix=/other", "s3cret").await;
+        assert_eq!(404, missing.status);
+        let removed = request(HttpMethod::DELETE, "/mounts?prefix=/docs", "s3cret").await;
+        assert_eq!(b"{\"mounts\":[]}", removed.body());
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!("\"a\\\"b\\\\c\\u000a\"", json_string("a\"b\\c\n"));
+    }
+}
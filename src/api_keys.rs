@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, HttpResponse, Middleware, Next};
+
+const DEFAULT_HEADER: &str = "X-Api-Key";
+
+/// Only lets through requests carrying a known API key, read from a header
+/// (`X-Api-Key` by default) or, if enabled, a query parameter. A request
+/// with no key gets a 401, one with an unknown key a 403, and one whose key
+/// has used up its rate limit a 429 with `Retry-After`.
+#[derive(Debug)]
+pub struct ApiKeys {
+    header: String,
+    query: Option<String>,
+    keys: HashMap<String, Option<Mutex<Bucket>>>,
+}
+impl Default for ApiKeys {
+    fn default() -> Self {
+        ApiKeys {
+            header: DEFAULT_HEADER.to_owned(),
+            query: None,
+            keys: HashMap::new(),
+        }
+    }
+}
+impl ApiKeys {
+    pub fn new() -> Self {
+        ApiKeys::default()
+    }
+
+    /// Reads the key from `name` instead of `X-Api-Key`.
+    pub fn header(mut self, name: &str) -> Self {
+        self.header = name.to_owned();
+        self
+    }
+
+    /// Also accepts the key as the query parameter `name`, for clients that
+    /// can't set headers. The header wins if both are given.
+    pub fn query(mut self, name: &str) -> Self {
+        self.query = Some(name.to_owned());
+        self
+    }
+
+    /// Accepts `key` with no rate limit.
+    pub fn key(mut self, key: &str) -> Self {
+        self.keys.insert(key.to_owned(), None);
+        self
+    }
+
+    /// Accepts `key` for up to `per_minute` requests a minute, allowing
+    /// bursts of up to a minute's worth.
+    pub fn limited_key(mut self, key: &str, per_minute: u32) -> Self {
+        self.keys
+            .insert(key.to_owned(), Some(Mutex::new(Bucket::new(per_minute))));
+        self
+    }
+
+    /// Adds the keys listed in a file, one per line, each optionally followed
+    /// by its requests-per-minute limit. Blank lines and lines starting with
+    /// `#` are skipped.
+    pub fn keys_from_file(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let key = fields.next().unwrap_or_default();
+            self = match fields.next() {
+                Some(limit) => {
+                    let per_minute = limit.parse().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid rate limit '{}' for an API key", limit),
+                        )
+                    })?;
+                    self.limited_key(key, per_minute)
+                }
+                None => self.key(key),
+            };
+        }
+        Ok(self)
+    }
+
+    /// The status and `Retry-After` delay to refuse the request with, if any.
+    fn refusal(&self, request: &HttpRequest) -> Option<(u16, Option<Duration>)> {
+        let key = request
+            .header(&self.header)
+            .map(String::as_str)
+            .or_else(|| {
+                self.query
+                    .as_ref()
+                    .and_then(|name| request.query().get(name))
+            });
+        let key = match key {
+            Some(key) => key,
+            None => return Some((401, None)),
+        };
+        match self.keys.get(key) {
+            None => Some((403, None)),
+            Some(None) => None,
+            Some(Some(bucket)) => {
+                let wait = bucket.lock().unwrap().take(Instant::now())?;
+                Some((429, Some(wait)))
+            }
+        }
+    }
+}
+
+impl Middleware for ApiKeys {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        match self.refusal(&request) {
+            None => next.run(request),
+            Some((status, retry_after)) => {
+                debug!(
+                    "Refused {:?} {} with {}",
+                    request.method, request.path, status
+                );
+                let mut response = HttpResponse::new(status);
+                if let Some(wait) = retry_after {
+                    response = response.with_retry_after(wait);
+                }
+                Box::pin(async move { response })
+            }
+        }
+    }
+}
+
+/// A token bucket holding up to a minute's worth of requests, refilled
+/// continuously.
+#[derive(Debug)]
+pub(crate) struct Bucket {
+    per_minute: f64,
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    pub(crate) fn new(per_minute: u32) -> Self {
+        Bucket {
+            per_minute: f64::from(per_minute),
+            tokens: f64::from(per_minute),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or says how long until one is available.
+    pub(crate) fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_minute / 60.0).min(self.per_minute);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        if self.per_minute == 0.0 {
+            return Some(Duration::from_secs(60));
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) * 60.0 / self.per_minute,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+
+    fn protected(keys: ApiKeys) -> Router {
+        Router::new()
+            .route(HttpMethod::GET, "/data", |_request: HttpRequest| async {
+                HttpResponse::ok()
+            })
+            .middleware(keys)
+    }
+
+    fn request(target: &str, key: Option<&str>) -> HttpRequest {
+        let mut request = HttpRequest::new(HttpMethod::GET, target);
+        if let Some(key) = key {
+            request.set_header("X-Api-Key", key);
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn checks_keys_from_the_header_or_query() {
+        let router = protected(ApiKeys::new().key("secret").query("api_key"));
+
+        let statuses = [
+            router
+                .dispatch(request("/data", Some("secret")))
+                .await
+                .status,
+            router
+                .dispatch(request("/data?api_key=secret", None))
+                .await
+                .status,
+            router.dispatch(request("/data", None)).await.status,
+            router
+                .dispatch(request("/data", Some("guess")))
+                .await
+                .status,
+        ];
+
+        assert_eq!([200, 200, 401, 403], statuses);
+    }
+
+    #[tokio::test]
+    async fn limits_each_key_separately() {
+        let router = protected(ApiKeys::new().limited_key("slow", 2).key("fast"));
+
+        for _ in 0..2 {
+            assert_eq!(
+                200,
+                router.dispatch(request("/data", Some("slow"))).await.status
+            );
+        }
+        let limited = router.dispatch(request("/data", Some("slow"))).await;
+        let other = router.dispatch(request("/data", Some("fast"))).await;
+
+        assert_eq!(429, limited.status);
+        assert_eq!(Some(&"30".to_owned()), limited.header("Retry-After"));
+        assert_eq!(200, other.status);
+    }
+
+    #[test]
+    fn reads_keys_and_limits_from_a_file() {
+        let path = std::env::temp_dir().join("rust-http-server-api-keys");
+        std::fs::write(&path, "# partners\nalpha 10\n\nbeta\n").unwrap();
+
+        let keys = ApiKeys::new().keys_from_file(&path).unwrap();
+
+        assert!(matches!(keys.keys.get("alpha"), Some(Some(_))));
+        assert!(matches!(keys.keys.get("beta"), Some(None)));
+        std::fs::write(&path, "alpha lots\n").unwrap();
+        assert!(ApiKeys::new().keys_from_file(&path).is_err());
+    }
+}
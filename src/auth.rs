@@ -0,0 +1,53 @@
+use rust_http_parse::HttpRequest;
+
+/// Whether `request` carries `Authorization: Bearer <token>` with exactly
+/// `token`. The scheme is matched without case, as RFC 7235 has it.
+pub(crate) fn bearer_token_matches(request: &HttpRequest, token: &str) -> bool {
+    request
+        .header("Authorization")
+        .and_then(|value| {
+            let (scheme, sent) = value.split_once(' ')?;
+            Some(sent).filter(|_| scheme.eq_ignore_ascii_case("Bearer"))
+        })
+        .is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()))
+}
+
+/// Compares two secrets in time that depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        let mut request = HttpRequest::new(HttpMethod::GET, "/");
+        for (name, value) in headers {
+            request.set_header(name, value);
+        }
+        request
+    }
+
+    #[test]
+    fn matches_only_the_exact_bearer_token() {
+        assert!(bearer_token_matches(
+            &request(&[("Authorization", "Bearer s3cret")]),
+            "s3cret"
+        ));
+        assert!(bearer_token_matches(
+            &request(&[("authorization", "bearer s3cret")]),
+            "s3cret"
+        ));
+        assert!(!bearer_token_matches(
+            &request(&[("Authorization", "Bearer s3cre")]),
+            "s3cret"
+        ));
+        assert!(!bearer_token_matches(
+            &request(&[("Authorization", "Basic s3cret")]),
+            "s3cret"
+        ));
+        assert!(!bearer_token_matches(&request(&[]), "s3cret"));
+    }
+}
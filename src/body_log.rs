@@ -0,0 +1,72 @@
+use log::info;
+use rust_http_parse::{HttpRequest, Redaction};
+
+use super::{BoxFuture, HttpResponse, Middleware, Next};
+
+/// Debugging aid that logs requests and their responses, bodies included,
+/// under the `bodies` log target, for troubleshooting a client without a
+/// packet capture. `redaction` says how much of each body is shown and what
+/// is masked; binary bodies are shown as hex. Only requests whose path
+/// starts with one of the `path_prefix`es are logged, or every request if
+/// none are given.
+#[derive(Debug, Clone)]
+pub struct BodyLog {
+    redaction: Redaction,
+    prefixes: Vec<String>,
+}
+impl BodyLog {
+    pub fn new(redaction: Redaction) -> Self {
+        BodyLog {
+            redaction,
+            prefixes: Vec::new(),
+        }
+    }
+
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.prefixes.push(prefix.to_owned());
+        self
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+impl Middleware for BodyLog {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        if !self.matches(&request.path) {
+            return next.run(request);
+        }
+        let redaction = self.redaction.clone();
+        info!(target: "bodies", "Request {}", redaction.request(&request));
+        let request_line = format!("{:?} {}", request.method, redaction.path(&request.path));
+        Box::pin(async move {
+            let response = next.run(request).await;
+            info!(
+                target: "bodies",
+                "Response to {} {}",
+                request_line,
+                response.redacted(&redaction)
+            );
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_only_matching_paths() {
+        let all = BodyLog::new(Redaction::new());
+        let api = BodyLog::new(Redaction::new())
+            .path_prefix("/api/")
+            .path_prefix("/login");
+
+        assert!(all.matches("/static/app.js"));
+        assert!(api.matches("/api/users"));
+        assert!(api.matches("/login"));
+        assert!(!api.matches("/static/app.js"));
+    }
+}
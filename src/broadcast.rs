@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use log::debug;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{HttpResponse, ResponseWriter};
+
+/// What to do with a subscriber that falls more than the buffer's worth of
+/// messages behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lagged {
+    /// Tells the client how many events it missed in an SSE comment and
+    /// carries on from the oldest message still buffered.
+    Skip,
+    /// Ends the stream, so the client reconnects and can catch up from its
+    /// `Last-Event-ID`.
+    Disconnect,
+}
+
+/// A server-sent event: its data plus an optional event name and ID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    data: String,
+    name: Option<String>,
+    id: Option<String>,
+}
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Event {
+            data: data.to_owned(),
+            name: None,
+            id: None,
+        }
+    }
+
+    /// The `event:` type, which clients listen for with
+    /// `addEventListener(name, ...)` rather than `onmessage`.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// The `id:` a reconnecting client sends back as `Last-Event-ID`.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_owned());
+        self
+    }
+
+    /// The event in `text/event-stream` format, with multi-line data split
+    /// over several `data:` lines.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut formatted = String::new();
+        if let Some(name) = &self.name {
+            formatted.push_str(&format!("event: {}\n", name));
+        }
+        if let Some(id) = &self.id {
+            formatted.push_str(&format!("id: {}\n", id));
+        }
+        for line in self.data.split('\n') {
+            formatted.push_str(&format!("data: {}\n", line));
+        }
+        formatted.push('\n');
+        formatted.into_bytes()
+    }
+}
+
+/// Fans events published by handlers out to every subscribed server-sent
+/// events stream. Each subscriber buffers up to `capacity` events, so a slow
+/// client doesn't hold up the others; what happens when one falls further
+/// behind is set with `on_lag`. Clones publish to the same subscribers.
+#[derive(Debug, Clone)]
+pub struct Broadcaster {
+    sender: broadcast::Sender<Arc<[u8]>>,
+    on_lag: Lagged,
+}
+impl Broadcaster {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Broadcaster {
+            sender,
+            on_lag: Lagged::Skip,
+        }
+    }
+
+    pub fn on_lag(mut self, on_lag: Lagged) -> Self {
+        self.on_lag = on_lag;
+        self
+    }
+
+    /// Sends `event` to every current subscriber, returning how many there
+    /// were.
+    pub fn publish(&self, event: &Event) -> usize {
+        self.sender
+            .send(Arc::from(event.to_bytes()))
+            .unwrap_or_default()
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// A `text/event-stream` response that receives every event published
+    /// from now on, for a handler to return. The subscription ends when the
+    /// client goes away, noticed at the next event.
+    pub fn subscribe(&self) -> HttpResponse {
+        let (response, writer) = HttpResponse::streaming(200);
+        tokio::spawn(forward(self.sender.subscribe(), writer, self.on_lag));
+        response
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+    }
+}
+
+async fn forward(
+    mut events: broadcast::Receiver<Arc<[u8]>>,
+    mut writer: ResponseWriter,
+    on_lag: Lagged,
+) {
+    loop {
+        let written = match events.recv().await {
+            Ok(event) => writer.write_all(&event).await,
+            Err(RecvError::Lagged(missed)) if on_lag == Lagged::Skip => {
+                debug!("Subscriber fell behind, skipping {} events", missed);
+                let comment = format!(": missed {} events\n\n", missed);
+                writer.write_all(comment.as_bytes()).await
+            }
+            Err(RecvError::Lagged(missed)) => {
+                debug!("Subscriber fell {} events behind, disconnecting", missed);
+                return;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if written.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    /// Writes the subscription's response to an in-memory connection and
+    /// returns the client's end of it.
+    fn connect(mut response: HttpResponse) -> DuplexStream {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { response.write_to(&mut server).await });
+        client
+    }
+
+    async fn read_until(client: &mut DuplexStream, end: &str) -> String {
+        let mut received = Vec::new();
+        while !String::from_utf8_lossy(&received).ends_with(end) {
+            let mut chunk = [0; 1024];
+            let read = client.read(&mut chunk).await.unwrap();
+            assert!(read > 0, "{}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&chunk[..read]);
+        }
+        String::from_utf8(received).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_published_events_to_every_subscriber() {
+        let hub = Broadcaster::new(8);
+        let mut first = connect(hub.subscribe());
+        let mut second = connect(hub.subscribe());
+
+        let delivered = hub.publish(&Event::new("hello\nworld").name("greeting").id("1"));
+
+        assert_eq!(2, delivered);
+        for client in [&mut first, &mut second].iter_mut() {
+            let received = read_until(client, "\n\n").await;
+            assert!(received.contains("Content-Type: text/event-stream\r\n"));
+            assert!(
+                received.ends_with("\r\n\r\nevent: greeting\nid: 1\ndata: hello\ndata: world\n\n")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_lagging_subscribers_what_they_missed() {
+        let hub = Broadcaster::new(2);
+        let response = hub.subscribe();
+
+        for n in 0..5 {
+            hub.publish(&Event::new(&n.to_string()));
+        }
+        let mut client = connect(response);
+
+        let received = read_until(&mut client, "data: 4\n\n").await;
+        assert!(received.ends_with(": missed 3 events\n\ndata: 3\n\ndata: 4\n\n"));
+    }
+
+    #[tokio::test]
+    async fn disconnects_lagging_subscribers_if_asked() {
+        let hub = Broadcaster::new(2).on_lag(Lagged::Disconnect);
+        let response = hub.subscribe();
+
+        for n in 0..5 {
+            hub.publish(&Event::new(&n.to_string()));
+        }
+        let mut client = connect(response);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert!(!String::from_utf8(received).unwrap().contains("data:"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_MAX_POOLED: usize = 256;
+const DEFAULT_BUFFER_CAPACITY: usize = 4096;
+const MAX_RETAINED_CAPACITY: usize = 64 * 1024;
+
+/// A snapshot of how a `BufferPool` is being used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferPoolStats {
+    /// Buffers sitting idle in the pool.
+    pub pooled: usize,
+    /// Buffers currently checked out by connections.
+    pub in_use: usize,
+    /// Buffers that had to be freshly allocated.
+    pub allocated: usize,
+    /// Checkouts satisfied by an idle buffer.
+    pub reused: usize,
+}
+
+/// Hands out read buffers to connections and takes them back when the
+/// connection is done, so high connection churn doesn't mean allocating a
+/// new buffer for every request. Buffers that grew past 64 KiB (large
+/// bodies) are dropped rather than pooled.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_pooled: usize,
+    buffer_capacity: usize,
+    in_use: AtomicUsize,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+}
+impl BufferPool {
+    pub fn new(max_pooled: usize, buffer_capacity: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            max_pooled,
+            buffer_capacity,
+            in_use: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>) -> PooledBuffer {
+        let pooled = self.buffers.lock().unwrap().pop();
+        let buffer = match pooled {
+            Some(buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(self.buffer_capacity)
+            }
+        };
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+
+        PooledBuffer {
+            buffer: Some(buffer),
+            pool: self.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            pooled: self.buffers.lock().unwrap().len(),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+        }
+    }
+
+    fn release(&self, mut buffer: Vec<u8>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        if buffer.capacity() > MAX_RETAINED_CAPACITY {
+            return;
+        }
+
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_pooled {
+            buffers.push(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(DEFAULT_MAX_POOLED, DEFAULT_BUFFER_CAPACITY)
+    }
+}
+
+/// A buffer checked out of a `BufferPool`, returned to it on drop.
+pub struct PooledBuffer {
+    buffer: Option<Vec<u8>>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.release(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_released_buffers() {
+        let pool = Arc::new(BufferPool::new(4, 128));
+
+        let mut first = pool.acquire();
+        first.extend_from_slice(b"request bytes");
+        drop(first);
+        let second = pool.acquire();
+
+        assert!(second.is_empty());
+        assert_eq!(
+            BufferPoolStats {
+                pooled: 0,
+                in_use: 1,
+                allocated: 1,
+                reused: 1,
+            },
+            pool.stats()
+        );
+    }
+
+    #[test]
+    fn drops_oversized_buffers_and_buffers_beyond_pool_limit() {
+        let pool = Arc::new(BufferPool::new(1, 128));
+
+        let mut large = pool.acquire();
+        large.reserve(MAX_RETAINED_CAPACITY + 1);
+        let a = pool.acquire();
+        let b = pool.acquire();
+        drop(large);
+        drop(a);
+        drop(b);
+
+        assert_eq!(1, pool.stats().pooled);
+        assert_eq!(0, pool.stats().in_use);
+    }
+}
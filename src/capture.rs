@@ -0,0 +1,225 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use log::{debug, warn};
+use rust_http_parse::HttpRequest;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Saves the raw bytes of incoming requests to numbered files in a
+/// directory, so reports of malformed requests can be replayed against the
+/// parser offline. Requests the parser rejects are saved too. With no
+/// filters every request is captured; otherwise all the filters given must
+/// match.
+pub struct RequestCapture {
+    dir: PathBuf,
+    path_prefix: Option<String>,
+    client_ip: Option<IpAddr>,
+    next: AtomicU64,
+}
+impl RequestCapture {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        RequestCapture {
+            dir: dir.as_ref().to_path_buf(),
+            path_prefix: None,
+            client_ip: None,
+            next: AtomicU64::new(1),
+        }
+    }
+
+    /// Only captures requests whose raw target starts with `prefix`.
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.path_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Only captures requests from `ip`.
+    pub fn client_ip(mut self, ip: IpAddr) -> Self {
+        self.client_ip = Some(ip);
+        self
+    }
+
+    fn matches(&self, peer: Option<IpAddr>, target: Option<&str>) -> bool {
+        let ip_matches = match self.client_ip {
+            Some(ip) => peer == Some(ip),
+            None => true,
+        };
+        let path_matches = match &self.path_prefix {
+            Some(prefix) => target.is_some_and(|target| target.starts_with(prefix.as_str())),
+            None => true,
+        };
+        ip_matches && path_matches
+    }
+
+    /// Writes `raw` to the next free numbered file if the request matches
+    /// the filters. Failures are logged rather than failing the request.
+    pub(crate) async fn save(
+        &self,
+        peer: Option<IpAddr>,
+        request: Option<&HttpRequest>,
+        raw: &[u8],
+    ) {
+        let target = match request {
+            Some(request) => Some(request.raw_path().to_owned()),
+            None => raw_target(raw),
+        };
+        if !self.matches(peer, target.as_deref()) {
+            return;
+        }
+
+        match self.write_next(raw).await {
+            Ok(path) => debug!("Captured request to {}", path.display()),
+            Err(e) => warn!("Could not capture request: {}", e),
+        }
+    }
+
+    async fn write_next(&self, raw: &[u8]) -> io::Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        loop {
+            let number = self.next.fetch_add(1, Ordering::Relaxed);
+            let path = self.dir.join(format!("{:06}.http", number));
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(mut file) => {
+                    file.write_all(raw).await?;
+                    file.flush().await?;
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The request target from the first line of raw request bytes, for
+/// filtering requests that failed to parse.
+fn raw_target(raw: &[u8]) -> Option<String> {
+    let line_end = raw.iter().position(|&c| c == b'\r' || c == b'\n');
+    let line = String::from_utf8_lossy(&raw[..line_end.unwrap_or(raw.len())]).into_owned();
+    line.split(' ').nth(1).map(str::to_owned)
+}
+
+/// A stream that keeps a copy of everything read from it while recording.
+pub(crate) struct Recording<S> {
+    inner: S,
+    recorded: Option<Vec<u8>>,
+}
+impl<S> Recording<S> {
+    pub(crate) fn new(inner: S, enabled: bool) -> Self {
+        Recording {
+            inner,
+            recorded: if enabled { Some(Vec::new()) } else { None },
+        }
+    }
+
+    /// Stops recording, returning what was read so far.
+    pub(crate) fn take_recorded(&mut self) -> Option<Vec<u8>> {
+        self.recorded.take()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recording<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Some(recorded) = &mut self.recorded {
+            recorded.extend_from_slice(&buf.filled()[before..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recording<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::parse_from_reader;
+
+    fn capture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-http-server-capture-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn read_all(input: &[u8]) -> (Result<HttpRequest, rust_http_parse::ParseError>, Vec<u8>) {
+        let mut stream = Recording::new(input, true);
+        let parsed = parse_from_reader(&mut stream).await;
+        (parsed, stream.take_recorded().unwrap())
+    }
+
+    #[tokio::test]
+    async fn saves_matching_requests_to_numbered_files() {
+        let dir = capture_dir("numbered");
+        let capture = RequestCapture::new(&dir).path_prefix("/api");
+        let local = Some("127.0.0.1".parse().unwrap());
+
+        for input in &[
+            &b"GET /api/users HTTP/1.1\r\n\r\n"[..],
+            &b"GET /static/app.js HTTP/1.1\r\n\r\n"[..],
+            &b"GET /api/bad HTTP/1.1\r\nBad Header: x\r\n\r\n"[..],
+        ] {
+            let (parsed, raw) = read_all(input).await;
+            capture.save(local, parsed.as_ref().ok(), &raw).await;
+        }
+
+        assert_eq!(
+            "GET /api/users HTTP/1.1\r\n\r\n",
+            std::fs::read_to_string(dir.join("000001.http")).unwrap()
+        );
+        assert!(std::fs::read_to_string(dir.join("000002.http"))
+            .unwrap()
+            .contains("Bad Header"));
+        assert!(!dir.join("000003.http").exists());
+    }
+
+    #[test]
+    fn filters_on_client_ip() {
+        let capture = RequestCapture::new("unused").client_ip("10.0.0.1".parse().unwrap());
+
+        assert!(capture.matches(Some("10.0.0.1".parse().unwrap()), Some("/")));
+        assert!(!capture.matches(Some("10.0.0.2".parse().unwrap()), Some("/")));
+        assert!(!capture.matches(None, Some("/")));
+    }
+}
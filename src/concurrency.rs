@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::warn;
+use rust_http_parse::HttpRequest;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next};
+
+/// Caps how many requests run at once, for an expensive route or group of
+/// routes. Requests over the cap wait their turn, up to `queue` of them;
+/// beyond that they're answered 503 straight away. This is separate from
+/// the server-wide connection limit. Add it as middleware to share the cap
+/// between routes, or `wrap` a single route's handler. Clones share the cap.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+    max_queued: usize,
+    queued: Arc<AtomicUsize>,
+}
+impl ConcurrencyLimit {
+    /// Lets up to `max_in_flight` requests run at once, queueing none.
+    pub fn new(max_in_flight: usize) -> Self {
+        ConcurrencyLimit {
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+            max_queued: 0,
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Lets up to `max_queued` requests wait for a turn before shedding.
+    pub fn queue(mut self, max_queued: usize) -> Self {
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// Applies the cap to just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        LimitedHandler {
+            limit: self,
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// Waits for a turn, or returns `None` if the queue is already full.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let _waiting = Waiting(&self.queued);
+        if queued >= self.max_queued {
+            return None;
+        }
+        self.permits.clone().acquire_owned().await.ok()
+    }
+
+    pub(crate) async fn run(
+        self,
+        method_and_path: String,
+        request: HttpRequest,
+        handler: impl FnOnce(HttpRequest) -> BoxFuture<HttpResponse>,
+    ) -> HttpResponse {
+        match self.acquire().await {
+            Some(_permit) => handler(request).await,
+            None => {
+                warn!("Shedding {}, too many in flight", method_and_path);
+                HttpResponse::new(503)
+            }
+        }
+    }
+}
+
+/// Counts a request as queued until dropped, including when the client gives
+/// up while waiting.
+struct Waiting<'a>(&'a AtomicUsize);
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn describe(request: &HttpRequest) -> String {
+    format!("{:?} {}", request.method, request.path)
+}
+
+impl Middleware for ConcurrencyLimit {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        let description = describe(&request);
+        Box::pin(
+            self.clone()
+                .run(description, request, move |request| next.run(request)),
+        )
+    }
+}
+
+struct LimitedHandler<H> {
+    limit: ConcurrencyLimit,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for LimitedHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let description = describe(&request);
+        let handler = self.handler.clone();
+        Box::pin(
+            self.limit
+                .clone()
+                .run(description, request, move |request| handler.call(request)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn queues_up_to_a_bound_then_sheds() {
+        let release = Arc::new(Notify::new());
+        let waiting = release.clone();
+        let router = Arc::new(
+            Router::new()
+                .route(
+                    HttpMethod::GET,
+                    "/report",
+                    ConcurrencyLimit::new(1).queue(1).wrap(move |_| {
+                        let waiting = waiting.clone();
+                        async move {
+                            waiting.notified().await;
+                            HttpResponse::ok()
+                        }
+                    }),
+                )
+                .route(HttpMethod::GET, "/cheap", |_| async { HttpResponse::ok() }),
+        );
+        let dispatch = |path: &'static str| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                router
+                    .dispatch(HttpRequest::new(HttpMethod::GET, path))
+                    .await
+                    .status
+            })
+        };
+
+        let running = dispatch("/report");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let queued = dispatch("/report");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(503, dispatch("/report").await.unwrap());
+        assert_eq!(200, dispatch("/cheap").await.unwrap());
+        release.notify_one();
+        assert_eq!(200, running.await.unwrap());
+        release.notify_one();
+        assert_eq!(200, queued.await.unwrap());
+    }
+}
@@ -0,0 +1,757 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use custom_error::custom_error;
+use rust_http_server::{
+    HostQuota, HttpMethod, Mask, Mirror, StaticFiles, StatsdExporter, TlsConfig, TlsError,
+    TlsVersion,
+};
+use serde::{Deserialize, Deserializer};
+
+const DAY_SECONDS: u64 = 24 * 60 * 60;
+
+custom_error! {pub ConfigError
+    Io{source: std::io::Error} = "Could not read config file: {source}",
+    Parse{source: toml::de::Error} = "Invalid config file: {source}"
+}
+
+/// Settings read from a TOML config file. Anything left out falls back to
+/// the command line flag, then to the built-in default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub root: Option<PathBuf>,
+    pub index: Option<String>,
+    pub static_prefix: Option<String>,
+    pub routes: Vec<MountConfig>,
+    pub hosts: BTreeMap<String, PathBuf>,
+    pub host_quotas: BTreeMap<String, HostQuotaConfig>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_port: Option<u32>,
+    pub tls_reload_interval: Option<u64>,
+    pub tls_ocsp: Option<PathBuf>,
+    #[serde(deserialize_with = "parse_str")]
+    pub tls_min_version: Option<TlsVersion>,
+    #[serde(deserialize_with = "parse_str")]
+    pub tls_max_version: Option<TlsVersion>,
+    pub tls_ciphers: Vec<String>,
+    pub tls_hosts: BTreeMap<String, TlsHostConfig>,
+    pub max_connections: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    pub workers: Option<usize>,
+    pub request_timeout: Option<u64>,
+    pub shed_above: Option<usize>,
+    pub fd_headroom: Option<usize>,
+    pub bind_retries: Option<u32>,
+    pub transparent: bool,
+    pub tcp_fast_open: bool,
+    pub lenient_line_endings: bool,
+    pub http09: bool,
+    pub max_body: Option<usize>,
+    #[serde(deserialize_with = "parse_methods")]
+    pub allowed_methods: Vec<HttpMethod>,
+    #[serde(deserialize_with = "parse_path_methods")]
+    pub path_methods: BTreeMap<String, Vec<HttpMethod>>,
+    pub geoip_databases: Vec<PathBuf>,
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+    pub deny_asns: Vec<u32>,
+    pub country_per_minute: BTreeMap<String, u32>,
+    pub preload: BTreeMap<String, Vec<String>>,
+    pub redact_headers: Vec<String>,
+    pub redact_body_fields: Vec<String>,
+    pub log_bodies: Vec<String>,
+    pub log_body_bytes: Option<usize>,
+    #[serde(deserialize_with = "parse_str")]
+    pub mask_client: Option<Mask>,
+    #[serde(deserialize_with = "parse_str")]
+    pub mask_path: Option<Mask>,
+    pub w3c_log: bool,
+    pub w3c_fields: Vec<String>,
+    pub w3c_log_dir: Option<PathBuf>,
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub api_key_file: Option<PathBuf>,
+    pub api_key_header: Option<String>,
+    pub api_key_query: Option<String>,
+    pub statsd: Option<StatsdConfig>,
+    pub mirror: Option<MirrorConfig>,
+    pub admin_port: Option<u32>,
+    pub admin_token: Option<String>,
+    pub runtime_mounts: Vec<RuntimeMountConfig>,
+}
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// Writes `mounts` as the `[[runtime_mounts]]` tables of the config file at
+/// `path`, keeping its other settings. The file is rewritten in full, so its
+/// comments and layout aren't kept.
+pub fn save_runtime_mounts(path: &Path, mounts: &[(String, PathBuf)]) -> io::Result<()> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let contents = std::fs::read_to_string(path)?;
+    let mut config: toml::value::Table =
+        toml::from_str(&contents).map_err(|e: toml::de::Error| invalid(e.to_string()))?;
+    let mounts: Vec<toml::Value> = mounts
+        .iter()
+        .map(|(prefix, root)| {
+            let mut mount = toml::value::Table::new();
+            mount.insert("prefix".to_owned(), prefix.clone().into());
+            mount.insert(
+                "root".to_owned(),
+                root.to_string_lossy().into_owned().into(),
+            );
+            mount.into()
+        })
+        .collect();
+    if mounts.is_empty() {
+        config.remove("runtime_mounts");
+    } else {
+        config.insert("runtime_mounts".to_owned(), mounts.into());
+    }
+    // A bare table is written key by key, which fails once a table sorts
+    // before a plain value; as a value, plain values go first.
+    let contents =
+        toml::to_string(&toml::Value::Table(config)).map_err(|e| invalid(e.to_string()))?;
+
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(temp, path)
+}
+
+/// Reads a setting written as a string the way its flag is, such as a mask
+/// (`"partial"`) or a TLS version (`"1.3"`).
+fn parse_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Reads a list of methods, such as `["GET", "HEAD"]`.
+fn parse_methods<'de, D>(deserializer: D) -> Result<Vec<HttpMethod>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| parse_method(name))
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Reads a `[path_methods]` table of path prefixes and the methods allowed
+/// under each, such as `"/api" = ["GET", "POST"]`.
+fn parse_path_methods<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<String, Vec<HttpMethod>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    BTreeMap::<String, Vec<String>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(prefix, names)| {
+            let methods = names
+                .iter()
+                .map(|name| parse_method(name))
+                .collect::<Result<_, _>>()?;
+            Ok((prefix, methods))
+        })
+        .collect::<Result<_, String>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Parses a method name as written in a request, such as `GET`.
+pub fn parse_method(name: &str) -> Result<HttpMethod, String> {
+    name.parse()
+        .map_err(|_| format!("unknown method '{}'", name))
+}
+
+/// A key accepted by the API key check, given in the config file as an
+/// `[[api_keys]]` table with an optional `per_minute` rate limit.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    pub key: String,
+    #[serde(default)]
+    pub per_minute: Option<u32>,
+}
+
+/// Where and how to push metrics in StatsD format, given in the config file
+/// as a `[statsd]` table. `dogstatsd` sends routes and statuses as tags.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    pub address: String,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Seconds between pushes.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    #[serde(default)]
+    pub dogstatsd: bool,
+}
+impl StatsdConfig {
+    pub fn exporter(&self) -> StatsdExporter {
+        let mut exporter = StatsdExporter::new(&self.address);
+        if let Some(prefix) = &self.prefix {
+            exporter = exporter.prefix(prefix);
+        }
+        if let Some(seconds) = self.interval {
+            exporter = exporter.interval(Duration::from_secs(seconds));
+        }
+        if self.dogstatsd {
+            exporter = exporter.dogstatsd();
+        }
+        exporter
+    }
+}
+
+/// Where to copy requests for traffic shadowing, given in the config file
+/// as a `[mirror]` table. `percent` defaults to 100, and `path_prefixes`
+/// limits mirroring to the requests under them.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub address: String,
+    #[serde(default)]
+    pub percent: Option<u8>,
+    #[serde(default)]
+    pub path_prefixes: Vec<String>,
+}
+impl MirrorConfig {
+    pub fn mirror(&self) -> Mirror {
+        let mut mirror = Mirror::new(&self.address);
+        if let Some(percent) = self.percent {
+            mirror = mirror.percent(percent);
+        }
+        for prefix in &self.path_prefixes {
+            mirror = mirror.path_prefix(prefix);
+        }
+        mirror
+    }
+}
+
+/// A static mount added through the admin listener, saved back to the
+/// config file as a `[[runtime_mounts]]` table.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeMountConfig {
+    pub prefix: String,
+    pub root: PathBuf,
+}
+
+/// A certificate and TLS settings for one host name, picked by SNI. Given on
+/// the command line as `HOST=CERT,KEY[,OPTION...]` or in the config file's
+/// `[tls_hosts]` table. The options are `client_ca=FILE`, which requires
+/// clients to present a certificate from that CA, `client_cert_optional`,
+/// which accepts clients without one too, and `alpn=PROTOCOL`, which can be
+/// repeated.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsHostConfig {
+    #[serde(skip)]
+    pub host: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub client_cert_optional: bool,
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+impl TlsHostConfig {
+    pub fn tls_config(&self) -> Result<TlsConfig, TlsError> {
+        let mut tls = TlsConfig::from_pem_files(&self.cert, &self.key)?;
+        tls = match (&self.client_ca, self.client_cert_optional) {
+            (Some(ca), false) => tls.require_client_cert(ca)?,
+            (Some(ca), true) => tls.request_client_cert(ca)?,
+            (None, _) => tls,
+        };
+        let alpn: Vec<&str> = self.alpn.iter().map(String::as_str).collect();
+        Ok(tls.alpn_protocols(&alpn))
+    }
+}
+
+impl FromStr for TlsHostConfig {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected HOST=CERT,KEY, got '{}'", spec);
+        let mut parts = spec.split(',');
+        let mapping = parts.next().unwrap_or_default();
+        let (host, cert) = match mapping.find('=') {
+            Some(split) if split > 0 => (&mapping[..split], &mapping[split + 1..]),
+            _ => return Err(expected()),
+        };
+        let key = parts.next().unwrap_or_default();
+        if cert.is_empty() || key.is_empty() || key.contains('=') {
+            return Err(expected());
+        }
+
+        let mut tls_host = TlsHostConfig {
+            host: host.to_owned(),
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+            client_ca: None,
+            client_cert_optional: false,
+            alpn: Vec::new(),
+        };
+        for option in parts {
+            match option {
+                "client_cert_optional" => tls_host.client_cert_optional = true,
+                _ if option.starts_with("client_ca=") => {
+                    tls_host.client_ca = Some(PathBuf::from(&option["client_ca=".len()..]))
+                }
+                _ if option.starts_with("alpn=") => {
+                    tls_host.alpn.push(option["alpn=".len()..].to_owned())
+                }
+                _ => return Err(format!("unknown TLS host option '{}'", option)),
+            }
+        }
+
+        Ok(tls_host)
+    }
+}
+
+/// A host name whose requests are served from its own document root, given
+/// on the command line as `HOST=DIR` or in the config file's `[hosts]` table.
+#[derive(Debug, PartialEq)]
+pub struct HostRoot {
+    pub host: String,
+    pub root: PathBuf,
+}
+
+impl FromStr for HostRoot {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.find('=') {
+            Some(split) if split > 0 && split + 1 < spec.len() => Ok(HostRoot {
+                host: spec[..split].to_owned(),
+                root: PathBuf::from(&spec[split + 1..]),
+            }),
+            _ => Err(format!("expected HOST=DIR, got '{}'", spec)),
+        }
+    }
+}
+
+/// Limits for one virtual host, given in the config file's `[host_quotas]`
+/// table under the host's name. `bandwidth` is in bytes per
+/// `bandwidth_window` seconds, a day by default.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HostQuotaConfig {
+    #[serde(default)]
+    pub per_minute: Option<u32>,
+    #[serde(default)]
+    pub max_in_flight: Option<usize>,
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    #[serde(default)]
+    pub bandwidth_window: Option<u64>,
+    #[serde(default)]
+    pub max_body: Option<usize>,
+}
+impl HostQuotaConfig {
+    pub fn quota(&self) -> HostQuota {
+        let mut quota = HostQuota::new();
+        if let Some(per_minute) = self.per_minute {
+            quota = quota.per_minute(per_minute);
+        }
+        if let Some(max) = self.max_in_flight {
+            quota = quota.max_in_flight(max);
+        }
+        if let Some(bytes) = self.bandwidth {
+            let window = Duration::from_secs(self.bandwidth_window.unwrap_or(DAY_SECONDS));
+            quota = quota.bandwidth(bytes, window);
+        }
+        if let Some(bytes) = self.max_body {
+            quota = quota.max_body(bytes);
+        }
+        quota
+    }
+}
+
+/// A URL prefix served from a directory, given on the command line as
+/// `PREFIX=DIR[,OPTION...]` or in the config file as a `[[routes]]` table.
+/// The options are `index=FILE`, `confined`, `write_token=TOKEN`, which
+/// accepts authenticated PUT and DELETE requests under the prefix, and
+/// `download=PATTERN`, which can be repeated to serve files matching `*.EXT`
+/// or a path prefix as downloads, and `method=METHOD`, which can be repeated
+/// to answer every other method under the prefix with 405.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub prefix: String,
+    pub root: PathBuf,
+    #[serde(default)]
+    pub index: Option<String>,
+    #[serde(default)]
+    pub confined: bool,
+    #[serde(default)]
+    pub write_token: Option<String>,
+    #[serde(default)]
+    pub download: Vec<String>,
+    #[serde(default, deserialize_with = "parse_methods")]
+    pub methods: Vec<HttpMethod>,
+}
+impl MountConfig {
+    pub fn static_files(&self, default_index: &str) -> StaticFiles {
+        let mut files =
+            StaticFiles::new(&self.root).index(self.index.as_deref().unwrap_or(default_index));
+        if self.confined {
+            files = files.confined();
+        }
+        if let Some(token) = &self.write_token {
+            files = files.writable(token);
+        }
+        for pattern in &self.download {
+            files = files.download(pattern);
+        }
+        files
+    }
+}
+
+impl FromStr for MountConfig {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut parts = spec.split(',');
+        let mapping = parts.next().unwrap_or_default();
+        let (prefix, root) = match mapping.find('=') {
+            Some(split) => (&mapping[..split], &mapping[split + 1..]),
+            None => return Err(format!("expected PREFIX=DIR, got '{}'", mapping)),
+        };
+        if !prefix.starts_with('/') || root.is_empty() {
+            return Err(format!("expected PREFIX=DIR, got '{}'", mapping));
+        }
+
+        let mut mount = MountConfig {
+            prefix: prefix.to_owned(),
+            root: PathBuf::from(root),
+            index: None,
+            confined: false,
+            write_token: None,
+            download: Vec::new(),
+            methods: Vec::new(),
+        };
+        for option in parts {
+            match option {
+                "confined" => mount.confined = true,
+                _ if option.starts_with("index=") => {
+                    mount.index = Some(option["index=".len()..].to_owned())
+                }
+                _ if option.starts_with("write_token=") => {
+                    mount.write_token = Some(option["write_token=".len()..].to_owned())
+                }
+                _ if option.starts_with("download=") => {
+                    mount.download.push(option["download=".len()..].to_owned())
+                }
+                _ if option.starts_with("method=") => mount
+                    .methods
+                    .push(parse_method(&option["method=".len()..])?),
+                _ => return Err(format!("unknown route option '{}'", option)),
+            }
+        }
+
+        Ok(mount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_keys() {
+        let config: Config =
+            toml::from_str("root = \"/srv/www\"\nstatic_prefix = \"/assets\"").unwrap();
+
+        assert_eq!(Some(PathBuf::from("/srv/www")), config.root);
+        assert_eq!(Some("/assets".to_owned()), config.static_prefix);
+        assert_eq!(None, config.index);
+    }
+
+    #[test]
+    fn saves_runtime_mounts_keeping_other_settings() {
+        let path = std::env::temp_dir().join(format!("runtime-mounts-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "index = \"home.html\"\nworkers = 4\nw3c_log = true\n\
+             [hosts]\n\"blog.example\" = \"/srv/blog\"\n\
+             [statsd]\naddress = \"127.0.0.1:8125\"\n",
+        )
+        .unwrap();
+
+        save_runtime_mounts(&path, &[("/docs".to_owned(), PathBuf::from("/srv/docs"))]).unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(Some("home.html".to_owned()), config.index);
+        assert!(config.statsd.is_some());
+        assert_eq!(Some(4), config.workers);
+        assert!(config.w3c_log);
+        assert_eq!(1, config.hosts.len());
+        assert_eq!(
+            vec![RuntimeMountConfig {
+                prefix: "/docs".to_owned(),
+                root: PathBuf::from("/srv/docs"),
+            }],
+            config.runtime_mounts
+        );
+
+        save_runtime_mounts(&path, &[("/docs".to_owned(), PathBuf::from("/srv/docs"))]).unwrap();
+        save_runtime_mounts(&path, &[]).unwrap();
+        let config = Config::load(&path).unwrap();
+        assert!(config.runtime_mounts.is_empty());
+        assert_eq!(Some(4), config.workers);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_allowed_methods() {
+        let config: Config = toml::from_str("allowed_methods = [\"GET\", \"HEAD\"]").unwrap();
+
+        assert_eq!(
+            vec![HttpMethod::GET, HttpMethod::HEAD],
+            config.allowed_methods
+        );
+        assert!(toml::from_str::<Config>("allowed_methods = [\"get\"]").is_err());
+    }
+
+    #[test]
+    fn parses_path_methods_table() {
+        let config: Config = toml::from_str(
+            r#"
+            [path_methods]
+            "/api" = ["GET", "POST"]
+            "/static" = ["GET", "HEAD"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&vec![HttpMethod::GET, HttpMethod::POST]),
+            config.path_methods.get("/api")
+        );
+        assert_eq!(
+            Some(&vec![HttpMethod::GET, HttpMethod::HEAD]),
+            config.path_methods.get("/static")
+        );
+        assert!(toml::from_str::<Config>("[path_methods]\n\"/api\" = [\"FETCH\"]").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("roots = \"/srv/www\"").is_err());
+    }
+
+    #[test]
+    fn parses_route_tables() {
+        let config: Config = toml::from_str(
+            "[[routes]]\nprefix = \"/docs\"\nroot = \"/srv/docs\"\nconfined = true\n\
+                 methods = [\"GET\", \"HEAD\"]",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![MountConfig {
+                prefix: "/docs".to_owned(),
+                root: PathBuf::from("/srv/docs"),
+                index: None,
+                confined: true,
+                write_token: None,
+                download: Vec::new(),
+                methods: vec![HttpMethod::GET, HttpMethod::HEAD],
+            }],
+            config.routes
+        );
+    }
+
+    #[test]
+    fn parses_host_quota_tables() {
+        let config: Config = toml::from_str(
+            "[host_quotas.\"tenant.example\"]\nper_minute = 600\nbandwidth = 1000000\nmax_body = 65536",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&HostQuotaConfig {
+                per_minute: Some(600),
+                max_in_flight: None,
+                bandwidth: Some(1_000_000),
+                bandwidth_window: None,
+                max_body: Some(65536),
+            }),
+            config.host_quotas.get("tenant.example")
+        );
+    }
+
+    #[test]
+    fn parses_route_flags_with_options() {
+        let mount: MountConfig =
+            "/assets=/srv/assets,index=home.html,confined,write_token=s3cret,download=*.zip,method=GET"
+                .parse()
+                .unwrap();
+
+        assert_eq!("/assets", mount.prefix);
+        assert_eq!(PathBuf::from("/srv/assets"), mount.root);
+        assert_eq!(Some("home.html".to_owned()), mount.index);
+        assert!(mount.confined);
+        assert_eq!(Some("s3cret".to_owned()), mount.write_token);
+        assert_eq!(vec!["*.zip"], mount.download);
+        assert_eq!(vec![HttpMethod::GET], mount.methods);
+        assert_eq!(
+            Err("unknown method 'FETCH'".to_owned()),
+            "/a=/srv,method=FETCH".parse::<MountConfig>()
+        );
+    }
+
+    #[test]
+    fn parses_host_tables_and_flags() {
+        let config: Config =
+            toml::from_str("[hosts]\n\"blog.example.com\" = \"/srv/blog\"").unwrap();
+        let flag: HostRoot = "example.com=/srv/example".parse().unwrap();
+
+        assert_eq!(
+            Some(&PathBuf::from("/srv/blog")),
+            config.hosts.get("blog.example.com")
+        );
+        assert_eq!("example.com", flag.host);
+        assert_eq!(PathBuf::from("/srv/example"), flag.root);
+        assert!("example.com".parse::<HostRoot>().is_err());
+        assert!("=/srv".parse::<HostRoot>().is_err());
+    }
+
+    #[test]
+    fn parses_log_redaction_settings() {
+        let config: Config = toml::from_str(
+            "redact_headers = [\"X-Api-Key\"]\nmask_client = \"partial\"\nmask_path = \"hide\"\n\
+             redact_body_fields = [\"password\"]\nlog_bodies = [\"/api/\"]\nlog_body_bytes = 256",
+        )
+        .unwrap();
+
+        assert_eq!(vec!["X-Api-Key".to_owned()], config.redact_headers);
+        assert_eq!(vec!["password".to_owned()], config.redact_body_fields);
+        assert_eq!(vec!["/api/".to_owned()], config.log_bodies);
+        assert_eq!(Some(256), config.log_body_bytes);
+        assert_eq!(Some(Mask::Partial), config.mask_client);
+        assert_eq!(Some(Mask::Hide), config.mask_path);
+        assert!(toml::from_str::<Config>("mask_path = \"blur\"").is_err());
+    }
+
+    #[test]
+    fn parses_tls_settings() {
+        let config: Config = toml::from_str(
+            "tls_min_version = \"1.3\"\ntls_ciphers = [\"TLS13_AES_256_GCM_SHA384\"]",
+        )
+        .unwrap();
+
+        assert_eq!(Some(TlsVersion::Tls13), config.tls_min_version);
+        assert_eq!(None, config.tls_max_version);
+        assert_eq!(vec!["TLS13_AES_256_GCM_SHA384"], config.tls_ciphers);
+        assert!(toml::from_str::<Config>("tls_min_version = \"1.0\"").is_err());
+    }
+
+    #[test]
+    fn parses_tls_host_tables_and_flags() {
+        let config: Config = toml::from_str(
+            "[tls_hosts.\"api.example.com\"]\ncert = \"api.crt\"\nkey = \"api.key\"\n\
+             client_ca = \"clients.crt\"",
+        )
+        .unwrap();
+        let flag: TlsHostConfig = "example.com=site.crt,site.key,client_cert_optional,\
+                                   client_ca=ca.crt,alpn=http/1.1"
+            .parse()
+            .unwrap();
+
+        let api = &config.tls_hosts["api.example.com"];
+        assert_eq!(PathBuf::from("api.key"), api.key);
+        assert_eq!(Some(PathBuf::from("clients.crt")), api.client_ca);
+        assert_eq!("example.com", flag.host);
+        assert_eq!(PathBuf::from("site.crt"), flag.cert);
+        assert_eq!(PathBuf::from("site.key"), flag.key);
+        assert!(flag.client_cert_optional);
+        assert_eq!(vec!["http/1.1"], flag.alpn);
+        assert!("example.com=site.crt".parse::<TlsHostConfig>().is_err());
+        assert!("example.com=site.crt,site.key,ocsp"
+            .parse::<TlsHostConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn parses_api_key_tables() {
+        let config: Config = toml::from_str(
+            "api_key_query = \"key\"\n[[api_keys]]\nkey = \"alpha\"\nper_minute = 60\n\
+             [[api_keys]]\nkey = \"beta\"",
+        )
+        .unwrap();
+
+        assert_eq!(Some("key".to_owned()), config.api_key_query);
+        assert_eq!(
+            vec![
+                ApiKeyConfig {
+                    key: "alpha".to_owned(),
+                    per_minute: Some(60),
+                },
+                ApiKeyConfig {
+                    key: "beta".to_owned(),
+                    per_minute: None,
+                },
+            ],
+            config.api_keys
+        );
+    }
+
+    #[test]
+    fn parses_statsd_table() {
+        let config: Config = toml::from_str(
+            "[statsd]\naddress = \"127.0.0.1:8125\"\ninterval = 5\ndogstatsd = true",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(StatsdConfig {
+                address: "127.0.0.1:8125".to_owned(),
+                prefix: None,
+                interval: Some(5),
+                dogstatsd: true,
+            }),
+            config.statsd
+        );
+        assert!(toml::from_str::<Config>("[statsd]\nprefix = \"web\"").is_err());
+    }
+
+    #[test]
+    fn parses_mirror_table() {
+        let config: Config = toml::from_str(
+            "[mirror]\naddress = \"10.0.0.2:8080\"\npercent = 5\npath_prefixes = [\"/api\"]",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(MirrorConfig {
+                address: "10.0.0.2:8080".to_owned(),
+                percent: Some(5),
+                path_prefixes: vec!["/api".to_owned()],
+            }),
+            config.mirror
+        );
+        assert!(toml::from_str::<Config>("[mirror]\npercent = 5").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_route_flags() {
+        assert!("/assets".parse::<MountConfig>().is_err());
+        assert!("assets=/srv".parse::<MountConfig>().is_err());
+        assert!("/assets=/srv,cache".parse::<MountConfig>().is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::time::Duration;
+
+use custom_error::custom_error;
+use log::debug;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac::{self, HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rust_http_parse::HttpRequest;
+
+/// Secrets shorter than this are too easy to guess to sign cookies with.
+const MIN_SECRET_LEN: usize = 32;
+
+custom_error! {#[derive(PartialEq)] pub CookieKeyError
+    TooShort{len: usize} = "Cookie secret must be at least 32 bytes, not {len}"
+}
+
+/// Whether browsers send a cookie along with cross-site requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to set on the client, added to a response with
+/// `HttpResponse::with_cookie`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the client forget the cookie `name`. Give it the
+    /// same path and domain the cookie was set with.
+    pub fn removal(name: &str) -> Self {
+        Cookie::new(name, "").max_age(Duration::from_secs(0))
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+/// Formats the cookie as a Set-Cookie header value.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={:?}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+/// The cookies a request was sent with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>,
+}
+impl CookieJar {
+    pub fn from_request(request: &HttpRequest) -> Self {
+        let cookies = request
+            .header("Cookie")
+            .map(|header| {
+                header
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .map(|(name, value)| (name.to_owned(), value.trim_matches('"').to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        CookieJar { cookies }
+    }
+
+    /// The value of the first cookie called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies
+            .iter()
+            .find(|(cookie, _)| cookie == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The cookies set with `CookieKey::seal`, checked against `key`.
+    pub fn private<'a>(&'a self, key: &'a CookieKey) -> PrivateJar<'a> {
+        PrivateJar { jar: self, key }
+    }
+}
+
+/// A view of a request's cookies that only yields the ones sealed with its
+/// key, so a client can neither forge nor alter them.
+#[derive(Debug)]
+pub struct PrivateJar<'a> {
+    jar: &'a CookieJar,
+    key: &'a CookieKey,
+}
+impl PrivateJar<'_> {
+    /// The value sealed in the cookie `name`, or `None` if there's no such
+    /// cookie or it was tampered with.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let sealed = self.jar.get(name)?;
+        let value = self.key.open(name, sealed);
+        if value.is_none() {
+            debug!("Rejected tampered cookie {}", name);
+        }
+        value
+    }
+}
+
+/// The secret private cookies are sealed with. Cookies are signed with an
+/// HMAC, so clients can read but not change them; with `encrypt` they are
+/// also encrypted with AES-256-GCM, so clients can't read them either. Both
+/// keys are derived from the one secret, which must be at least 32 random
+/// bytes and stay the same across restarts for cookies to survive them.
+#[derive(Clone)]
+pub struct CookieKey {
+    signing: hmac::Key,
+    encryption: [u8; 32],
+    encrypt: bool,
+}
+impl CookieKey {
+    pub fn new(secret: &[u8]) -> Result<Self, CookieKeyError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(CookieKeyError::TooShort { len: secret.len() });
+        }
+        let master = hmac::Key::new(HMAC_SHA256, secret);
+        let mut encryption = [0; 32];
+        encryption.copy_from_slice(hmac::sign(&master, b"cookie encryption").as_ref());
+        Ok(CookieKey {
+            signing: hmac::Key::new(HMAC_SHA256, hmac::sign(&master, b"cookie signing").as_ref()),
+            encryption,
+            encrypt: false,
+        })
+    }
+
+    /// Encrypts cookie values as well as authenticating them.
+    pub fn encrypt(mut self) -> Self {
+        self.encrypt = true;
+        self
+    }
+
+    /// Replaces the cookie's value with one only this key can open. The
+    /// cookie's name is bound in, so a sealed value can't be moved to
+    /// another cookie.
+    pub fn seal(&self, mut cookie: Cookie) -> Cookie {
+        cookie.value = if self.encrypt {
+            let mut nonce = [0; NONCE_LEN];
+            SystemRandom::new()
+                .fill(&mut nonce)
+                .expect("no system randomness for a cookie nonce");
+            let mut sealed = cookie.value.into_bytes();
+            self.aead()
+                .seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::from(cookie.name.as_bytes()),
+                    &mut sealed,
+                )
+                .expect("cookie too large to encrypt");
+            sealed.splice(0..0, nonce);
+            encode(&sealed)
+        } else {
+            let tag = hmac::sign(&self.signing, &signed_content(&cookie.name, &cookie.value));
+            format!("{}.{}", encode(tag.as_ref()), cookie.value)
+        };
+        cookie
+    }
+
+    /// The value sealed in the cookie `name`, if it was sealed with this key.
+    fn open(&self, name: &str, sealed: &str) -> Option<String> {
+        if self.encrypt {
+            let mut sealed = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).ok()?;
+            if sealed.len() < NONCE_LEN {
+                return None;
+            }
+            let mut ciphertext = sealed.split_off(NONCE_LEN);
+            let nonce = Nonce::try_assume_unique_for_key(&sealed).ok()?;
+            let value = self
+                .aead()
+                .open_in_place(nonce, Aad::from(name.as_bytes()), &mut ciphertext)
+                .ok()?;
+            String::from_utf8(value.to_vec()).ok()
+        } else {
+            let (tag, value) = sealed.split_once('.')?;
+            let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+            hmac::verify(&self.signing, &signed_content(name, value), &tag).ok()?;
+            Some(value.to_owned())
+        }
+    }
+
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, &self.encryption).expect("AES-256 key is 32 bytes"),
+        )
+    }
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieKey")
+            .field("encrypt", &self.encrypt)
+            .finish()
+    }
+}
+
+fn signed_content(name: &str, value: &str) -> Vec<u8> {
+    format!("{}={}", name, value).into_bytes()
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn jar(cookie: &str) -> CookieJar {
+        let mut request = HttpRequest::new(HttpMethod::GET, "/");
+        request.set_header("Cookie", cookie);
+        CookieJar::from_request(&request)
+    }
+
+    #[test]
+    fn formats_set_cookie_values() {
+        let cookie = Cookie::new("session", "abc")
+            .path("/")
+            .max_age(Duration::from_secs(3600))
+            .secure()
+            .http_only()
+            .same_site(SameSite::Lax);
+
+        assert_eq!(
+            "session=abc; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Lax",
+            cookie.to_string()
+        );
+        assert_eq!("theme=; Max-Age=0", Cookie::removal("theme").to_string());
+    }
+
+    #[test]
+    fn reads_request_cookies() {
+        let jar = jar("theme=dark; session=\"abc\"; flag");
+
+        assert_eq!(Some("dark"), jar.get("theme"));
+        assert_eq!(Some("abc"), jar.get("session"));
+        assert_eq!(None, jar.get("flag"));
+
+        let mut lowercase = HttpRequest::new(HttpMethod::GET, "/");
+        lowercase.set_header("cookie", "theme=light");
+        assert_eq!(
+            Some("light"),
+            CookieJar::from_request(&lowercase).get("theme")
+        );
+    }
+
+    #[test]
+    fn opens_only_untampered_cookies() {
+        for key in [
+            CookieKey::new(SECRET).unwrap(),
+            CookieKey::new(SECRET).unwrap().encrypt(),
+        ] {
+            let sealed = key.seal(Cookie::new("user", "42")).value().to_owned();
+            let mut tampered = sealed.clone().into_bytes();
+            let last = tampered.len() - 1;
+            tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+            let tampered = String::from_utf8(tampered).unwrap();
+            let other_key = CookieKey::new(&[7; 32]).unwrap();
+            let cookies = jar(&format!(
+                "user={0}; moved={0}; forged=42; tampered={1}",
+                sealed, tampered
+            ));
+
+            assert_eq!(Some("42".to_owned()), cookies.private(&key).get("user"));
+            assert_eq!(None, cookies.private(&key).get("moved"));
+            assert_eq!(None, cookies.private(&key).get("forged"));
+            assert_eq!(None, cookies.private(&key).get("tampered"));
+            assert_eq!(None, cookies.private(&other_key).get("user"));
+        }
+    }
+
+    #[test]
+    fn encrypted_cookies_hide_their_value() {
+        let key = CookieKey::new(SECRET).unwrap();
+
+        let signed = key.seal(Cookie::new("user", "alice"));
+        let encrypted = key.clone().encrypt().seal(Cookie::new("user", "alice"));
+
+        assert!(signed.value().ends_with(".alice"));
+        assert!(!encrypted.value().contains("alice"));
+    }
+
+    #[test]
+    fn rejects_short_secrets() {
+        assert_eq!(
+            Err(CookieKeyError::TooShort { len: 6 }),
+            CookieKey::new(b"secret").map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use log::debug;
+use ring::digest::{digest, SHA256};
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next};
+
+/// The digest headers of RFC 9530. The server applies no content codings,
+/// so a message's content and representation are the same bytes and the
+/// two headers always agree.
+const DIGEST_HEADERS: [&str; 2] = ["Content-Digest", "Repr-Digest"];
+
+/// Integrity checks for bodies with RFC 9530 digests. Requests whose
+/// `Content-Digest` or `Repr-Digest` gives a SHA-256 that doesn't match
+/// their body are answered 400; digests in other algorithms are ignored.
+/// Buffered 200 responses get both headers with the SHA-256 of their body,
+/// and 206 responses just `Content-Digest`, since they carry only part of
+/// the representation. Add it as middleware or `wrap` a single route's
+/// handler, such as a static file mount.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContentDigest;
+impl ContentDigest {
+    pub fn new() -> Self {
+        ContentDigest
+    }
+
+    /// Checks and adds digests for just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        DigestedHandler {
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl Middleware for ContentDigest {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        if let Some(rejection) = verify(&request) {
+            return Box::pin(async { rejection });
+        }
+        Box::pin(async move { add_digests(next.run(request).await) })
+    }
+}
+
+struct DigestedHandler<H> {
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for DigestedHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        if let Some(rejection) = verify(&request) {
+            return Box::pin(async { rejection });
+        }
+        let response = self.handler.call(request);
+        Box::pin(async move { add_digests(response.await) })
+    }
+}
+
+/// A 400 for a request whose body doesn't match a SHA-256 digest it sent.
+fn verify(request: &HttpRequest) -> Option<HttpResponse> {
+    let actual = sha256(request.body());
+    for name in DIGEST_HEADERS.iter() {
+        let expected = match request.header(name).and_then(|value| sha256_field(value)) {
+            Some(expected) => expected,
+            None => continue,
+        };
+        if expected != actual {
+            debug!(
+                "{} of {:?} {} doesn't match its body",
+                name, request.method, request.path
+            );
+            return Some(
+                HttpResponse::new(400)
+                    .with_header("Content-Type", "text/plain; charset=utf-8")
+                    .with_body(format!("{} does not match the body", name).into_bytes()),
+            );
+        }
+    }
+    None
+}
+
+fn add_digests(response: HttpResponse) -> HttpResponse {
+    let headers: &[&str] = match response.status {
+        200 => &DIGEST_HEADERS,
+        206 => &DIGEST_HEADERS[..1],
+        _ => return response,
+    };
+    if response.is_streaming() {
+        return response;
+    }
+    let value = format!("sha-256=:{}:", sha256(response.body()));
+    headers.iter().fold(response, |response, name| {
+        response.with_header(name, &value)
+    })
+}
+
+/// The base64 SHA-256 of `bytes`, as digest headers carry it.
+fn sha256(bytes: &[u8]) -> String {
+    base64::encode(digest(&SHA256, bytes))
+}
+
+/// The `sha-256` member of a digest header, whose members look like
+/// `sha-256=:base64:`.
+fn sha256_field(value: &str) -> Option<String> {
+    value.split(',').find_map(|member| {
+        let (algorithm, digest) = member.trim().split_once('=')?;
+        if !algorithm.trim().eq_ignore_ascii_case("sha-256") {
+            return None;
+        }
+        let digest = digest.trim().strip_prefix(':')?.strip_suffix(':')?;
+        Some(digest.to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::{HttpMethod, HttpRequestBuilder};
+
+    /// The SHA-256 of `hello`.
+    const HELLO: &str = "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+
+    fn router() -> Router {
+        Router::new()
+            .route(
+                HttpMethod::POST,
+                "/echo",
+                |request: HttpRequest| async move {
+                    HttpResponse::ok().with_body(request.body().to_vec())
+                },
+            )
+            .route(HttpMethod::GET, "/part", |_| async {
+                HttpResponse::new(206).with_body(b"hello".to_vec())
+            })
+            .middleware(ContentDigest::new())
+    }
+
+    fn post(headers: &[(&str, &str)]) -> HttpRequest {
+        headers
+            .iter()
+            .fold(HttpRequestBuilder::new(), |builder, (name, value)| {
+                builder.with_header(name, *value)
+            })
+            .with_method(HttpMethod::POST)
+            .with_path("/echo")
+            .with_body("hello")
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn adds_digests_to_responses() {
+        let router = router();
+
+        let full = router.dispatch(post(&[])).await;
+        let part = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/part"))
+            .await;
+
+        assert_eq!(Some(&HELLO.to_owned()), full.header("Content-Digest"));
+        assert_eq!(Some(&HELLO.to_owned()), full.header("Repr-Digest"));
+        assert_eq!(Some(&HELLO.to_owned()), part.header("Content-Digest"));
+        assert_eq!(None, part.header("Repr-Digest"));
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_that_dont_match_their_digest() {
+        let router = router();
+        let wrong = "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:";
+
+        let matching = router.dispatch(post(&[("Content-Digest", HELLO)])).await;
+        let other_algorithm = router
+            .dispatch(post(&[("Content-Digest", "sha-512=:AAAA:")]))
+            .await;
+        let corrupt = router
+            .dispatch(post(&[(
+                "Content-Digest",
+                &format!("sha-512=:AAAA:, {}", wrong),
+            )]))
+            .await;
+        let corrupt_repr = router.dispatch(post(&[("Repr-Digest", wrong)])).await;
+
+        assert_eq!(200, matching.status);
+        assert_eq!(200, other_algorithm.status);
+        assert_eq!(400, corrupt.status);
+        assert_eq!(b"Content-Digest does not match the body", corrupt.body());
+        assert_eq!(400, corrupt_repr.status);
+    }
+}
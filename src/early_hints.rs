@@ -0,0 +1,180 @@
+use std::future::Future;
+
+use rust_http_parse::HttpRequest;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use super::{BoxFuture, HttpResponse, Middleware, Next};
+
+/// Sends `103 Early Hints` interim responses for a request, so clients can
+/// start fetching critical assets while the final response is still being
+/// made. The server gives every HTTP/1.1 request one in its extensions.
+#[derive(Debug, Clone)]
+pub struct EarlyHints(mpsc::UnboundedSender<Vec<String>>);
+impl EarlyHints {
+    pub fn from_request(request: &HttpRequest) -> Option<EarlyHints> {
+        request.extensions().get::<EarlyHints>().cloned()
+    }
+
+    /// Sends `links` as the `Link` headers of a 103 response, each a value
+    /// such as `</app.css>; rel=preload; as=style`. Hints sent once the final
+    /// response is on its way are dropped.
+    pub fn send<S: AsRef<str>>(&self, links: &[S]) {
+        let links = links.iter().map(|link| link.as_ref().to_owned()).collect();
+        let _ = self.0.send(links);
+    }
+
+    pub(crate) fn channel() -> (EarlyHints, mpsc::UnboundedReceiver<Vec<String>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (EarlyHints(sender), receiver)
+    }
+}
+
+/// Sends preload hints for requests under chosen path prefixes, as a 103
+/// response before the handler runs and added to the final response's
+/// `Link` header, e.g. a site's stylesheet and fonts for its pages.
+#[derive(Debug, Clone, Default)]
+pub struct Preload {
+    links: Vec<(String, String)>,
+}
+impl Preload {
+    pub fn new() -> Self {
+        Preload::default()
+    }
+
+    /// Hints `link`, a `Link` header value, for requests whose path starts
+    /// with `prefix`.
+    pub fn link(mut self, prefix: &str, link: &str) -> Self {
+        self.links.push((prefix.to_owned(), link.to_owned()));
+        self
+    }
+}
+
+impl Middleware for Preload {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        let links: Vec<String> = self
+            .links
+            .iter()
+            .filter(|(prefix, _)| request.path.starts_with(prefix.as_str()))
+            .map(|(_, link)| link.clone())
+            .collect();
+        if links.is_empty() {
+            return next.run(request);
+        }
+        if let Some(hints) = EarlyHints::from_request(&request) {
+            hints.send(&links);
+        }
+        Box::pin(async move {
+            let response = next.run(request).await;
+            // Link takes a list, so the handler's own links are kept.
+            let link = match response.header("Link") {
+                Some(existing) => format!("{}, {}", existing, links.join(", ")),
+                None => links.join(", "),
+            };
+            response.with_header("Link", &link)
+        })
+    }
+}
+
+/// Runs `handling` to completion, writing each batch of hints it sends to
+/// `stream` as a 103 response meanwhile.
+pub(crate) async fn with_early_hints<S, F>(
+    stream: &mut S,
+    mut hints: mpsc::UnboundedReceiver<Vec<String>>,
+    handling: F,
+) -> F::Output
+where
+    S: AsyncWrite + Unpin,
+    F: Future,
+{
+    tokio::pin!(handling);
+    loop {
+        // Hints already sent go out before the final response.
+        tokio::select! {
+            biased;
+            Some(links) = hints.recv() => {
+                // A failed write shows up again when the final response is
+                // written.
+                let _ = stream.write_all(&interim_response(&links)).await;
+            }
+            output = &mut handling => return output,
+        }
+    }
+}
+
+fn interim_response(links: &[String]) -> Vec<u8> {
+    let mut head = String::from("HTTP/1.1 103 Early Hints\r\n");
+    for link in links {
+        head.push_str(&format!("Link: {}\r\n", link));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+
+    #[tokio::test]
+    async fn lists_every_link_on_the_final_response() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/app", |_| async {
+                HttpResponse::ok().with_header("Link", "</api>; rel=preconnect")
+            })
+            .route(HttpMethod::GET, "/page", |_| async { HttpResponse::ok() })
+            .middleware(
+                Preload::new()
+                    .link("/", "</a.css>; rel=preload; as=style")
+                    .link("/", "</b.js>; rel=preload; as=script"),
+            );
+        let get = |path: &str| router.dispatch(HttpRequest::new(HttpMethod::GET, path));
+
+        assert_eq!(
+            Some(&"</a.css>; rel=preload; as=style, </b.js>; rel=preload; as=script".to_owned()),
+            get("/page").await.header("Link")
+        );
+        assert_eq!(
+            Some(
+                &"</api>; rel=preconnect, </a.css>; rel=preload; as=style, \
+                  </b.js>; rel=preload; as=script"
+                    .to_owned()
+            ),
+            get("/app").await.header("Link")
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_hints_before_the_final_response() {
+        let router = Router::new()
+            .route(
+                HttpMethod::GET,
+                "/docs/intro",
+                |request: HttpRequest| async move {
+                    if let Some(hints) = EarlyHints::from_request(&request) {
+                        hints.send(&["</docs.js>; rel=preload; as=script"]);
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                    HttpResponse::ok()
+                },
+            )
+            .middleware(Preload::new().link("/docs", "</site.css>; rel=preload; as=style"));
+        let (hints, receiver) = EarlyHints::channel();
+        let mut request = HttpRequest::new(HttpMethod::GET, "/docs/intro");
+        request.extensions_mut().insert(hints);
+        let mut written = Vec::new();
+
+        let response = with_early_hints(&mut written, receiver, router.dispatch(request)).await;
+
+        assert_eq!(
+            "HTTP/1.1 103 Early Hints\r\nLink: </site.css>; rel=preload; as=style\r\n\r\n\
+             HTTP/1.1 103 Early Hints\r\nLink: </docs.js>; rel=preload; as=script\r\n\r\n",
+            String::from_utf8(written).unwrap()
+        );
+        assert_eq!(
+            Some(&"</site.css>; rel=preload; as=style".to_owned()),
+            response.header("Link")
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt;
+use std::io;
+
+use log::error;
+
+use super::HttpResponse;
+
+/// Anything a handler can answer with. Handlers may return a response, or a
+/// `Result` whose error is itself turned into a response, so `?` works in
+/// them. Error responses go through the server's error renderer, if one is
+/// set with `ServerBuilder::error_renderer`.
+pub trait IntoResponse {
+    fn into_response(self) -> HttpResponse;
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Ok(response) => response.into_response(),
+            Err(error) => error.into_response().into_error(),
+        }
+    }
+}
+
+/// An error for a handler to return, answered with its status and, for a
+/// client error, its message as a plain text body. Server errors are logged
+/// and answered with just the reason phrase, so their details don't leak.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpError {
+    status: u16,
+    message: String,
+}
+impl HttpError {
+    pub fn new(status: u16, message: &str) -> Self {
+        HttpError {
+            status,
+            message: message.to_owned(),
+        }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        HttpError::new(400, message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        HttpError::new(404, message)
+    }
+
+    pub fn internal(message: &str) -> Self {
+        HttpError::new(500, message)
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> HttpResponse {
+        let response = HttpResponse::new(self.status);
+        let body = if self.status >= 500 {
+            error!("Handler failed: {}", self.message);
+            response.reason_phrase().to_owned()
+        } else {
+            self.message
+        };
+        response
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body.into_bytes())
+    }
+}
+
+/// Missing files are a 404 and unreadable ones a 403; anything else is a
+/// server error.
+impl From<io::Error> for HttpError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => HttpError::not_found("Not Found"),
+            io::ErrorKind::PermissionDenied => HttpError::new(403, "Forbidden"),
+            _ => HttpError::internal(&error.to_string()),
+        }
+    }
+}
+
+/// A query string or form that doesn't fit the handler's type is the
+/// client's mistake, so it's a 400 saying which field was wrong.
+#[cfg(feature = "serde")]
+impl From<rust_http_parse::DeserializeError> for HttpError {
+    fn from(error: rust_http_parse::DeserializeError) -> Self {
+        HttpError::bad_request(&error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Router, Server, TestClient};
+    use rust_http_parse::{HttpMethod, HttpRequest};
+
+    async fn user(request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        let id: u32 = request
+            .query()
+            .get("id")
+            .ok_or_else(|| HttpError::bad_request("id is required"))?
+            .parse()
+            .map_err(|_| HttpError::bad_request("id must be a number"))?;
+        if id == 0 {
+            std::fs::read("/nonexistent/user-0")?;
+        }
+        if id > 100 {
+            return Err(HttpError::internal("user table is corrupt"));
+        }
+        Ok(HttpResponse::ok())
+    }
+
+    fn body(response: &HttpResponse) -> &str {
+        std::str::from_utf8(response.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_handlers_error() {
+        let router = Router::new().route(HttpMethod::GET, "/user", user);
+        let get = |target: &str| router.dispatch(HttpRequest::new(HttpMethod::GET, target));
+
+        let found = get("/user?id=1").await;
+        let missing_id = get("/user").await;
+        let missing_file = get("/user?id=0").await;
+        let broken = get("/user?id=101").await;
+
+        assert_eq!(200, found.status);
+        assert_eq!(400, missing_id.status);
+        assert_eq!("id is required", body(&missing_id));
+        assert_eq!(404, missing_file.status);
+        assert_eq!(500, broken.status);
+        assert_eq!("Internal Server Error", body(&broken));
+    }
+
+    #[tokio::test]
+    async fn renders_errors_with_the_servers_renderer() {
+        let client = TestClient::new(
+            Server::builder()
+                .route(HttpMethod::GET, "/user", user)
+                .route(HttpMethod::GET, "/teapot", |_| async {
+                    HttpResponse::new(418)
+                })
+                .error_renderer(|response: HttpResponse| {
+                    let body = format!(
+                        "{{\"error\":\"{}\"}}",
+                        String::from_utf8_lossy(response.body())
+                    );
+                    HttpResponse::new(response.status)
+                        .with_header("Content-Type", "application/json")
+                        .with_body(body.into_bytes())
+                }),
+        )
+        .unwrap();
+
+        let error = client.get("/user?id=x").await.unwrap();
+        let plain = client.get("/teapot").await.unwrap();
+
+        assert_eq!(400, error.status);
+        assert_eq!("{\"error\":\"id must be a number\"}", body(&error));
+        assert_eq!(418, plain.status);
+        assert_eq!("", body(&plain));
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn answers_bad_queries_with_400() {
+        #[derive(serde::Deserialize)]
+        struct Page {
+            page: u32,
+        }
+        let router = Router::new().route(
+            HttpMethod::GET,
+            "/items",
+            |request: HttpRequest| async move {
+                let page: Page = request.query_as()?;
+                Ok::<_, HttpError>(HttpResponse::ok().with_body(page.page.to_string().into_bytes()))
+            },
+        );
+        let get = |target: &str| router.dispatch(HttpRequest::new(HttpMethod::GET, target));
+
+        let found = get("/items?page=3").await;
+        let malformed = get("/items?page=last").await;
+
+        assert_eq!("3", body(&found));
+        assert_eq!(400, malformed.status);
+        assert_eq!(
+            "page: invalid value: string \"last\", expected u32",
+            body(&malformed)
+        );
+    }
+}
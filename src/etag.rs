@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use ring::digest::{digest, SHA256};
+use rust_http_parse::{HttpMethod, HttpRequest};
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next};
+
+/// Gives successful GET and HEAD responses a strong ETag hashed from their
+/// body, and answers 304 Not Modified without the body when the request's
+/// If-None-Match already names it, so clients polling an API only download
+/// what changed. Streamed bodies and responses that set their own ETag are
+/// left alone. Add it as middleware or `wrap` a single route's handler.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ETag;
+impl ETag {
+    pub fn new() -> Self {
+        ETag
+    }
+
+    /// Tags just `handler`'s responses.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        TaggedHandler {
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl Middleware for ETag {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        let if_none_match = cacheable_request(&request);
+        Box::pin(async move { tag(if_none_match, next.run(request).await) })
+    }
+}
+
+struct TaggedHandler<H> {
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for TaggedHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let if_none_match = cacheable_request(&request);
+        let response = self.handler.call(request);
+        Box::pin(async move { tag(if_none_match, response.await) })
+    }
+}
+
+/// The request's If-None-Match, empty if it has none, or `None` if the
+/// request's method isn't one whose responses are tagged.
+fn cacheable_request(request: &HttpRequest) -> Option<String> {
+    match request.method {
+        HttpMethod::GET | HttpMethod::HEAD => {
+            Some(request.header("If-None-Match").cloned().unwrap_or_default())
+        }
+        _ => None,
+    }
+}
+
+fn tag(if_none_match: Option<String>, response: HttpResponse) -> HttpResponse {
+    let if_none_match = match if_none_match {
+        Some(if_none_match) => if_none_match,
+        None => return response,
+    };
+    if response.status != 200 || response.is_streaming() || response.header("ETag").is_some() {
+        return response;
+    }
+    let etag = etag_for(response.body());
+    let response = response.with_header("ETag", &etag);
+    if matches(&if_none_match, &etag) {
+        response.into_not_modified()
+    } else {
+        response
+    }
+}
+
+/// A quoted, URL-safe base64 digest of the body's first 128 bits of SHA-256.
+fn etag_for(body: &[u8]) -> String {
+    let hash = digest(&SHA256, body);
+    format!(
+        "\"{}\"",
+        base64::encode_config(&hash.as_ref()[..16], base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Whether an If-None-Match list names `etag`, using the weak comparison
+/// RFC 9110 asks for, so `W/` prefixes are ignored.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+
+    fn router() -> Router {
+        let items = |_| async { HttpResponse::ok().with_body(b"[1, 2, 3]".to_vec()) };
+        Router::new()
+            .route(HttpMethod::GET, "/items", items)
+            .route(HttpMethod::POST, "/items", items)
+            .route(HttpMethod::GET, "/tagged", |_| async {
+                HttpResponse::ok().with_header("ETag", "\"v1\"")
+            })
+            .middleware(ETag::new())
+    }
+
+    fn request(method: HttpMethod, target: &str, if_none_match: Option<&str>) -> HttpRequest {
+        let mut request = HttpRequest::new(method, target);
+        if let Some(if_none_match) = if_none_match {
+            request.set_header("If-None-Match", if_none_match);
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn answers_304_when_the_clients_copy_is_current() {
+        let router = router();
+
+        let first = router
+            .dispatch(request(HttpMethod::GET, "/items", None))
+            .await;
+        let etag = first.header("ETag").unwrap().clone();
+        let cached = router
+            .dispatch(request(
+                HttpMethod::GET,
+                "/items",
+                Some(&format!("\"x\", W/{}", etag)),
+            ))
+            .await;
+        let stale = router
+            .dispatch(request(HttpMethod::GET, "/items", Some("\"x\"")))
+            .await;
+        let mut lowercase = HttpRequest::new(HttpMethod::GET, "/items");
+        lowercase.set_header("if-none-match", &etag);
+        let lowercase = router.dispatch(lowercase).await;
+
+        assert_eq!(200, first.status);
+        assert_eq!(b"[1, 2, 3]", first.body());
+        assert_eq!(304, cached.status);
+        assert_eq!(Some(&etag), cached.header("ETag"));
+        assert!(cached.body().is_empty());
+        assert_eq!(200, stale.status);
+        assert_eq!(304, lowercase.status);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_responses_alone() {
+        let router = router();
+
+        let post = router
+            .dispatch(request(HttpMethod::POST, "/items", Some("*")))
+            .await;
+        let tagged = router
+            .dispatch(request(HttpMethod::GET, "/tagged", None))
+            .await;
+
+        assert_eq!(200, post.status);
+        assert_eq!(None, post.header("ETag"));
+        assert_eq!(Some(&"\"v1\"".to_owned()), tagged.header("ETag"));
+    }
+}
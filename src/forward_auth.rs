@@ -0,0 +1,257 @@
+use std::io;
+use std::time::Duration;
+
+use log::{debug, warn};
+use rust_http_parse::HttpRequest;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{BoxFuture, HttpResponse, Middleware, Next};
+
+/// Auth service answers larger than this are treated as malformed.
+const MAX_AUTH_RESPONSE: u64 = 64 * 1024;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Headers describing the original request's body or connection, which don't
+/// apply to the bodiless subrequest, and the ones it sets itself.
+const NOT_FORWARDED: [&str; 6] = [
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "x-original-method",
+    "x-original-uri",
+];
+
+/// Asks an external service whether each request may go ahead, like nginx's
+/// `auth_request`. The service gets a bodiless GET for `path` over plain
+/// HTTP, carrying the original request's headers plus `X-Original-Method`
+/// and `X-Original-URI` (the path as sent, without the query string).
+///
+/// A 2xx answer lets the request through, with the headers named by
+/// `copy_header` copied from the answer onto it; the client's own values for
+/// those are dropped before asking. A 401 or 403 is passed back
+/// to the client along with any `WWW-Authenticate` header. Any other answer,
+/// or none within the timeout, fails the request with a 500.
+#[derive(Debug, Clone)]
+pub struct ForwardAuth {
+    address: String,
+    path: String,
+    copy_headers: Vec<String>,
+    timeout: Duration,
+}
+impl ForwardAuth {
+    /// Checks requests against `path` on the service at `address`, given as
+    /// `host:port`.
+    pub fn new(address: &str, path: &str) -> Self {
+        ForwardAuth {
+            address: address.to_owned(),
+            path: path.to_owned(),
+            copy_headers: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Copies `name` from an allowing answer onto the request, e.g. `X-User`.
+    /// Any value the client sent is dropped, even if the answer has none.
+    pub fn copy_header(mut self, name: &str) -> Self {
+        self.copy_headers.push(name.to_owned());
+        self
+    }
+
+    /// How long to wait for the auth service; 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn check(self, mut request: HttpRequest, next: Next) -> HttpResponse {
+        for name in &self.copy_headers {
+            request.remove_header(name);
+        }
+        let answer = match tokio::time::timeout(self.timeout, self.ask(&request)).await {
+            Ok(Ok(answer)) => answer,
+            Ok(Err(e)) => {
+                warn!("Auth request to {} failed: {}", self.address, e);
+                return HttpResponse::new(500);
+            }
+            Err(_) => {
+                warn!("Auth request to {} timed out", self.address);
+                return HttpResponse::new(500);
+            }
+        };
+
+        match answer.status {
+            200..=299 => {
+                for name in &self.copy_headers {
+                    if let Some(value) = header(&answer, name) {
+                        request.set_header(name, value);
+                    }
+                }
+                next.run(request).await
+            }
+            401 | 403 => {
+                debug!("Auth service denied {:?} {}", request.method, request.path);
+                let mut denied = HttpResponse::new(answer.status);
+                if let Some(challenge) = header(&answer, "WWW-Authenticate") {
+                    denied = denied.with_header("WWW-Authenticate", challenge);
+                }
+                denied
+            }
+            status => {
+                warn!("Auth service answered with unexpected status {}", status);
+                HttpResponse::new(500)
+            }
+        }
+    }
+
+    async fn ask(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+        let mut head = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+             X-Original-Method: {:?}\r\nX-Original-URI: {}\r\n",
+            self.path,
+            self.address,
+            request.method,
+            request.raw_path()
+        );
+        for (name, value) in request.headers() {
+            let forwarded = !NOT_FORWARDED
+                .iter()
+                .any(|skipped| skipped.eq_ignore_ascii_case(name));
+            if forwarded {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("\r\n");
+
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(head.as_bytes()).await?;
+        let mut raw = Vec::new();
+        stream.take(MAX_AUTH_RESPONSE).read_to_end(&mut raw).await?;
+        HttpResponse::from_bytes(&raw)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed auth response"))
+    }
+}
+
+impl Middleware for ForwardAuth {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        Box::pin(self.clone().check(request, next))
+    }
+}
+
+fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+    response
+        .headers()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+    use tokio::net::TcpListener;
+
+    /// Answers every auth request with `response`, sending back the request
+    /// it received on the returned channel.
+    async fn auth_service(response: &'static str) -> (String, tokio::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut received = vec![0; 4096];
+                let read = stream.read(&mut received).await.unwrap();
+                let _ = sender
+                    .send(String::from_utf8_lossy(&received[..read]).into_owned())
+                    .await;
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (address, receiver)
+    }
+
+    fn protected(auth: ForwardAuth) -> Router {
+        Router::new()
+            .route(
+                HttpMethod::GET,
+                "/private",
+                |request: HttpRequest| async move {
+                    let user = request.header("X-User").cloned().unwrap_or_default();
+                    HttpResponse::ok().with_body(user.into_bytes())
+                },
+            )
+            .middleware(auth)
+    }
+
+    #[tokio::test]
+    async fn allowed_requests_get_headers_from_the_auth_service() {
+        let (address, mut received) =
+            auth_service("HTTP/1.1 200 OK\r\nX-User: alice\r\nContent-Length: 0\r\n\r\n").await;
+        let router = protected(ForwardAuth::new(&address, "/auth").copy_header("X-User"));
+        let mut request = HttpRequest::new(HttpMethod::GET, "/private");
+        request.set_header("Cookie", "session=1");
+        request.set_header("X-User", "mallory");
+        request.set_header("X-Original-URI", "/public");
+
+        let response = router.dispatch(request).await;
+
+        assert_eq!(200, response.status);
+        assert_eq!(b"alice", response.body());
+        let subrequest = received.recv().await.unwrap();
+        assert!(
+            subrequest.starts_with("GET /auth HTTP/1.1\r\n"),
+            "{}",
+            subrequest
+        );
+        assert!(subrequest.contains("X-Original-URI: /private\r\n"));
+        assert!(!subrequest.contains("/public"), "{}", subrequest);
+        assert!(!subrequest.contains("mallory"), "{}", subrequest);
+        assert!(subrequest.contains("Cookie: session=1\r\n"));
+
+        let (address, _received) =
+            auth_service("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let router = protected(ForwardAuth::new(&address, "/auth").copy_header("X-User"));
+        let mut request = HttpRequest::new(HttpMethod::GET, "/private");
+        request.set_header("x-user", "mallory");
+
+        let response = router.dispatch(request).await;
+
+        assert_eq!(200, response.status);
+        assert_eq!(b"", response.body());
+    }
+
+    #[tokio::test]
+    async fn denied_requests_never_reach_the_handler() {
+        let (address, _received) = auth_service(
+            "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let router = protected(ForwardAuth::new(&address, "/auth"));
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/private"))
+            .await;
+
+        assert_eq!(401, response.status);
+        assert_eq!(
+            Some(&"Basic".to_owned()),
+            response.header("WWW-Authenticate")
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_auth_service_fails_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let router = protected(ForwardAuth::new(&address, "/auth"));
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/private"))
+            .await;
+
+        assert_eq!(500, response.status);
+    }
+}
@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::debug;
+use rust_http_parse::HttpRequest;
+
+use super::api_keys::Bucket;
+use super::{BoxFuture, HttpResponse, Middleware, Next};
+
+/// Marks the start of a database's metadata, which comes last.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Zero bytes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+/// Nesting deeper than this is taken as a corrupt database.
+const MAX_DEPTH: usize = 32;
+
+/// Where a client address is, as found by `GeoIp`, in the request's
+/// extensions. Fields the databases don't have are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Geo {
+    /// The ISO 3166-1 alpha-2 country code, such as `US`.
+    pub country: Option<String>,
+    /// The autonomous system number of the address's network.
+    pub asn: Option<u32>,
+}
+
+/// Looks client addresses up in databases in the MaxMind DB format, such as
+/// GeoLite2 Country and GeoLite2 ASN. The server adds each request's `Geo` to
+/// its extensions, for access rules, guards, handlers and the access log.
+/// Add it with `ServerBuilder::geoip`.
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp {
+    databases: Vec<Arc<Database>>,
+}
+impl GeoIp {
+    pub fn new() -> Self {
+        GeoIp::default()
+    }
+
+    /// Also looks addresses up in the database at `path`. A field found in an
+    /// earlier database wins.
+    pub fn database(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let database = Database::parse(std::fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.databases.push(Arc::new(database));
+        Ok(self)
+    }
+
+    /// Where `ip` is, or `None` if no database knows it.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Geo> {
+        let mut geo = Geo::default();
+        for database in &self.databases {
+            let record = match database.lookup(ip) {
+                Ok(Some(record)) => record,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("Could not look up {}: {}", ip, e);
+                    continue;
+                }
+            };
+            if geo.country.is_none() {
+                geo.country = ["country", "registered_country"]
+                    .iter()
+                    .find_map(|key| record.get(&[key, "iso_code"])?.as_str())
+                    .map(str::to_owned);
+            }
+            if geo.asn.is_none() {
+                geo.asn = record
+                    .get(&["autonomous_system_number"])
+                    .and_then(Value::as_u64)
+                    .and_then(|asn| std::convert::TryFrom::try_from(asn).ok());
+            }
+        }
+        if geo == Geo::default() {
+            None
+        } else {
+            Some(geo)
+        }
+    }
+}
+
+/// Turns requests away by the country or network they come from, answering
+/// 403, and limits the request rate from chosen countries, answering 429
+/// with `Retry-After`. Needs the `Geo` that `ServerBuilder::geoip` adds;
+/// requests without one only get the allow list check, which they fail.
+#[derive(Debug, Default)]
+pub struct GeoRules {
+    allowed_countries: Vec<String>,
+    denied_countries: Vec<String>,
+    denied_asns: Vec<u32>,
+    country_rates: HashMap<String, Mutex<Bucket>>,
+}
+impl GeoRules {
+    pub fn new() -> Self {
+        GeoRules::default()
+    }
+
+    /// Lets through only requests from `country`, and the others allowed.
+    pub fn allow_country(mut self, country: &str) -> Self {
+        self.allowed_countries.push(country.to_ascii_uppercase());
+        self
+    }
+
+    pub fn deny_country(mut self, country: &str) -> Self {
+        self.denied_countries.push(country.to_ascii_uppercase());
+        self
+    }
+
+    pub fn deny_asn(mut self, asn: u32) -> Self {
+        self.denied_asns.push(asn);
+        self
+    }
+
+    /// Lets through up to `per_minute` requests a minute from `country` as a
+    /// whole, allowing bursts of up to a minute's worth.
+    pub fn country_per_minute(mut self, country: &str, per_minute: u32) -> Self {
+        self.country_rates.insert(
+            country.to_ascii_uppercase(),
+            Mutex::new(Bucket::new(per_minute)),
+        );
+        self
+    }
+
+    /// The response to refuse the request with, if any.
+    fn refusal(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let geo = request.extensions().get::<Geo>();
+        let country = geo.and_then(|geo| geo.country.as_deref());
+        let asn = geo.and_then(|geo| geo.asn);
+        let allowed = self.allowed_countries.is_empty()
+            || country.is_some_and(|country| self.allowed_countries.iter().any(|c| c == country));
+        let denied = country
+            .is_some_and(|country| self.denied_countries.iter().any(|c| c == country))
+            || asn.is_some_and(|asn| self.denied_asns.contains(&asn));
+        if !allowed || denied {
+            debug!(
+                "Refused {:?} {} from country {:?}, AS {:?}",
+                request.method, request.path, country, asn
+            );
+            return Some(HttpResponse::new(403));
+        }
+
+        let wait = self
+            .country_rates
+            .get(country?)?
+            .lock()
+            .unwrap()
+            .take(Instant::now())?;
+        Some(HttpResponse::new(429).with_retry_after(wait))
+    }
+}
+
+impl Middleware for GeoRules {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        match self.refusal(&request) {
+            Some(refused) => Box::pin(async move { refused }),
+            None => next.run(request),
+        }
+    }
+}
+
+/// A database in the MaxMind DB format: a binary search tree over address
+/// bits whose leaves point into a section of typed data.
+#[derive(Debug)]
+struct Database {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Where the data section starts.
+    data: usize,
+    /// The node IPv4 lookups start from in an IPv6 tree.
+    ipv4_start: usize,
+}
+
+impl Database {
+    fn parse(bytes: Vec<u8>) -> Result<Self, String> {
+        let metadata_start = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file")?
+            + METADATA_MARKER.len();
+        let metadata = Decoder::new(&bytes[metadata_start..]).value(0, 0)?.0;
+        let field = |key| {
+            metadata
+                .get(&[key])
+                .and_then(Value::as_u64)
+                .ok_or(format!("database metadata has no {}", key))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("unsupported record size {}", record_size));
+        }
+        let data = node_count
+            .checked_mul(record_size / 4)
+            .and_then(|tree| tree.checked_add(DATA_SEPARATOR))
+            .filter(|&data| data <= metadata_start)
+            .ok_or("search tree runs past the end of the file")?;
+
+        let mut database = Database {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            for _ in 0..96 {
+                if database.ipv4_start >= node_count {
+                    break;
+                }
+                database.ipv4_start = database.record(database.ipv4_start, false)?;
+            }
+        }
+        Ok(database)
+    }
+
+    /// The record for `ip`, or `None` if the database has none.
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        let (bits, mut node) = match ip {
+            IpAddr::V4(ip) if self.ip_version == 6 => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V4(ip) => (ip.octets().to_vec(), 0),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(ip) => (ip.octets().to_vec(), 0),
+        };
+        for index in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let right = bits[index / 8] & (0x80 >> (index % 8)) != 0;
+            node = self.record(node, right)?;
+        }
+        if node <= self.node_count {
+            return Ok(None);
+        }
+        let offset = (node - self.node_count)
+            .checked_sub(DATA_SEPARATOR)
+            .ok_or("record points into the data section separator")?;
+        let section = self.bytes.get(self.data..).ok_or("no data section")?;
+        Ok(Some(Decoder::new(section).value(offset, 0)?.0))
+    }
+
+    /// The left or right record of `node`.
+    fn record(&self, node: usize, right: bool) -> Result<usize, String> {
+        let size = self.record_size / 4;
+        let bytes = self
+            .bytes
+            .get(node * size..(node + 1) * size)
+            .ok_or("search tree is cut short")?;
+        let read = |bytes: &[u8]| bytes.iter().fold(0, |n, &byte| n << 8 | usize::from(byte));
+        Ok(match (self.record_size, right) {
+            (24, false) => read(&bytes[..3]),
+            (24, true) => read(&bytes[3..]),
+            (28, false) => (usize::from(bytes[3]) & 0xf0) << 20 | read(&bytes[..3]),
+            (28, true) => (usize::from(bytes[3]) & 0x0f) << 24 | read(&bytes[4..]),
+            (_, false) => read(&bytes[..4]),
+            (_, true) => read(&bytes[4..]),
+        })
+    }
+}
+
+/// A decoded value from a database's data section or metadata.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u64),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The value found by following `keys` through nested maps.
+    fn get(&self, keys: &[&str]) -> Option<&Value> {
+        keys.iter().try_fold(self, |value, key| match value {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        })
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Uint(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Reads values from a data section, where pointers are offsets from its
+/// start.
+struct Decoder<'a> {
+    section: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(section: &'a [u8]) -> Self {
+        Decoder { section }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], String> {
+        self.section
+            .get(offset..offset + len)
+            .ok_or_else(|| "data section is cut short".to_owned())
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<u64, String> {
+        Ok(self
+            .bytes(offset, len)?
+            .iter()
+            .fold(0, |n, &byte| n << 8 | u64::from(byte)))
+    }
+
+    /// The value at `offset`, and the offset just past it.
+    fn value(&self, offset: usize, depth: usize) -> Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("data nests too deeply".to_owned());
+        }
+        let control = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            return self.pointer(control, offset, depth);
+        }
+        if kind == 0 {
+            kind = self.bytes(offset, 1)?[0]
+                .checked_add(7)
+                .ok_or("unsupported extended data type")?;
+            offset += 1;
+        }
+        let (size, offset) = match usize::from(control & 0x1f) {
+            29 => (29 + self.uint(offset, 1)? as usize, offset + 1),
+            30 => (285 + self.uint(offset, 2)? as usize, offset + 2),
+            31 => (65_821 + self.uint(offset, 3)? as usize, offset + 3),
+            size => (size, offset),
+        };
+
+        Ok(match kind {
+            2 => {
+                let bytes = self.bytes(offset, size)?;
+                let string = String::from_utf8_lossy(bytes).into_owned();
+                (Value::String(string), offset + size)
+            }
+            3 => {
+                let bits = self.uint(offset, 8)?;
+                (Value::Double(f64::from_bits(bits)), offset + 8)
+            }
+            4 => (
+                Value::Bytes(self.bytes(offset, size)?.to_vec()),
+                offset + size,
+            ),
+            5 | 6 | 9 | 10 if size <= 8 => (Value::Uint(self.uint(offset, size)?), offset + size),
+            // Wider than the lookups here need; skipped.
+            10 => (
+                Value::Bytes(self.bytes(offset, size)?.to_vec()),
+                offset + size,
+            ),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                let mut next = offset;
+                for _ in 0..size {
+                    let (key, after_key) = self.value(next, depth + 1)?;
+                    let (value, after_value) = self.value(after_key, depth + 1)?;
+                    let key = key.as_str().ok_or("map key is not a string")?.to_owned();
+                    entries.push((key, value));
+                    next = after_value;
+                }
+                (Value::Map(entries), next)
+            }
+            8 => {
+                let value = self.uint(offset, size)? as u32 as i32;
+                (Value::Int(value), offset + size)
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(64));
+                let mut next = offset;
+                for _ in 0..size {
+                    let (item, after) = self.value(next, depth + 1)?;
+                    items.push(item);
+                    next = after;
+                }
+                (Value::Array(items), next)
+            }
+            14 => (Value::Bool(size != 0), offset),
+            15 => {
+                let bits = self.uint(offset, 4)? as u32;
+                (Value::Double(f64::from(f32::from_bits(bits))), offset + 4)
+            }
+            kind => return Err(format!("unsupported data type {}", kind)),
+        })
+    }
+
+    /// Follows a pointer to the value it points at. The offset returned is
+    /// just past the pointer itself.
+    fn pointer(&self, control: u8, offset: usize, depth: usize) -> Result<(Value, usize), String> {
+        let high = usize::from(control & 0x07);
+        let (target, len) = match (control >> 3) & 0x03 {
+            0 => (high << 8 | self.uint(offset, 1)? as usize, 1),
+            1 => ((high << 16 | self.uint(offset, 2)? as usize) + 2048, 2),
+            2 => ((high << 24 | self.uint(offset, 3)? as usize) + 526_336, 3),
+            _ => (self.uint(offset, 4)? as usize, 4),
+        };
+        let (value, _) = self.value(target, depth + 1)?;
+        Ok((value, offset + len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+
+    /// Encodes a map of string keys to strings, unsigned ints or maps.
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0xe0 | entries.len() as u8];
+        for (key, value) in entries {
+            bytes.extend(string(key));
+            bytes.extend(value);
+        }
+        bytes
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![0x40 | value.len() as u8];
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+
+    fn uint32(value: u32) -> Vec<u8> {
+        let mut bytes = vec![0xc4];
+        bytes.extend(&value.to_be_bytes());
+        bytes
+    }
+
+    /// An IPv4 database with 24-bit records mapping each `/prefix` network to
+    /// its data.
+    fn database(networks: &[([u8; 4], usize, Vec<u8>)]) -> Vec<u8> {
+        let mut nodes: Vec<[usize; 2]> = vec![[usize::MAX; 2]];
+        let mut data: Vec<u8> = Vec::new();
+        let mut leaves = Vec::new();
+        for (network, prefix, value) in networks {
+            let mut node = 0;
+            for index in 0..*prefix {
+                let bit = usize::from(network[index / 8] & (0x80 >> (index % 8)) != 0);
+                if index + 1 == *prefix {
+                    leaves.push((node, bit, data.len()));
+                    data.extend(value);
+                } else {
+                    if nodes[node][bit] == usize::MAX {
+                        nodes.push([usize::MAX; 2]);
+                        nodes[node][bit] = nodes.len() - 1;
+                    }
+                    node = nodes[node][bit];
+                }
+            }
+        }
+        let node_count = nodes.len();
+        for (node, bit, offset) in leaves {
+            nodes[node][bit] = node_count + DATA_SEPARATOR + offset;
+        }
+
+        let mut bytes = Vec::new();
+        for node in &nodes {
+            for &record in node {
+                let record = if record == usize::MAX {
+                    node_count
+                } else {
+                    record
+                };
+                bytes.extend(&(record as u32).to_be_bytes()[1..]);
+            }
+        }
+        bytes.extend(&[0; DATA_SEPARATOR]);
+        bytes.extend(data);
+        bytes.extend(METADATA_MARKER);
+        bytes.extend(map(&[
+            ("node_count", uint32(node_count as u32)),
+            ("record_size", vec![0xa1, 24]),
+            ("ip_version", vec![0xa1, 4]),
+        ]));
+        bytes
+    }
+
+    #[test]
+    fn looks_up_countries_and_networks() {
+        let country = |code| map(&[("country", map(&[("iso_code", string(code))]))]);
+        let countries = database(&[
+            ([10, 0, 0, 0], 8, country("NL")),
+            ([192, 168, 0, 0], 16, country("CA")),
+        ]);
+        let asns = database(&[(
+            [10, 1, 0, 0],
+            16,
+            map(&[("autonomous_system_number", uint32(64512))]),
+        )]);
+        let geoip = GeoIp {
+            databases: vec![
+                Arc::new(Database::parse(countries).unwrap()),
+                Arc::new(Database::parse(asns).unwrap()),
+            ],
+        };
+
+        assert_eq!(
+            Some(Geo {
+                country: Some("NL".to_owned()),
+                asn: Some(64512),
+            }),
+            geoip.lookup("10.1.2.3".parse().unwrap())
+        );
+        assert_eq!(
+            Some(Geo {
+                country: Some("CA".to_owned()),
+                asn: None,
+            }),
+            geoip.lookup("192.168.1.1".parse().unwrap())
+        );
+        assert_eq!(None, geoip.lookup("172.16.0.1".parse().unwrap()));
+        assert_eq!(None, geoip.lookup("::1".parse().unwrap()));
+        assert!(Database::parse(b"not a database".to_vec()).is_err());
+    }
+
+    #[test]
+    fn rejects_records_pointing_into_the_separator() {
+        let mut bytes = database(&[([0, 0, 0, 0], 1, map(&[]))]);
+        // The only node's left record should point past the separator.
+        bytes[..3].copy_from_slice(&[0, 0, 2]);
+        let database = Database::parse(bytes).unwrap();
+
+        assert!(database.lookup("10.0.0.1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_sizes_and_types() {
+        let mut huge = METADATA_MARKER.to_vec();
+        huge.extend(map(&[
+            ("node_count", [&[0x08, 0x02][..], &[0xff; 8]].concat()),
+            ("record_size", vec![0xa1, 32]),
+            ("ip_version", vec![0xa1, 4]),
+        ]));
+        assert!(Database::parse(huge).is_err());
+
+        // An extended type byte of 255 would be type 262.
+        let bytes = database(&[([0, 0, 0, 0], 1, vec![0x00, 0xff])]);
+        let database = Database::parse(bytes).unwrap();
+        assert!(database.lookup("10.0.0.1".parse().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn rules_match_on_country_and_network() {
+        let rules = GeoRules::new()
+            .deny_country("nl")
+            .deny_asn(64512)
+            .country_per_minute("CA", 1);
+        let router = crate::Router::new()
+            .route(HttpMethod::GET, "/", |_| async { HttpResponse::ok() })
+            .middleware(rules);
+        let from = |country: &str, asn: Option<u32>| {
+            let mut request = HttpRequest::new(HttpMethod::GET, "/");
+            request.extensions_mut().insert(Geo {
+                country: Some(country.to_owned()),
+                asn,
+            });
+            router.dispatch(request)
+        };
+
+        assert_eq!(403, from("NL", None).await.status);
+        assert_eq!(403, from("US", Some(64512)).await.status);
+        assert_eq!(200, from("US", Some(15169)).await.status);
+        assert_eq!(200, from("CA", None).await.status);
+        assert_eq!(429, from("CA", None).await.status);
+
+        let allow_list = GeoRules::new().allow_country("US");
+        assert!(allow_list
+            .refusal(&HttpRequest::new(HttpMethod::GET, "/"))
+            .is_some());
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rust_http_parse::HttpRequest;
+
+use super::hosts::normalize_host;
+use super::{Geo, Scheme};
+
+/// A condition a request must meet for a route to handle it, added with
+/// `Router::guard`. Requests a guard turns away fall through to the next
+/// matching route, as if the route wasn't there.
+#[derive(Clone)]
+pub struct Guard(Arc<dyn Fn(&HttpRequest) -> bool + Send + Sync>);
+impl Guard {
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> bool + Send + Sync + 'static,
+    {
+        Guard(Arc::new(check))
+    }
+
+    /// Requires the `Host` header to name `host`, compared without case or
+    /// port.
+    pub fn host(host: &str) -> Self {
+        let host = normalize_host(host);
+        Guard::new(move |request| {
+            request
+                .header("Host")
+                .is_some_and(|actual| normalize_host(actual) == host)
+        })
+    }
+
+    /// Requires the request to carry the header `name`.
+    pub fn header(name: &str) -> Self {
+        let name = name.to_owned();
+        Guard::new(move |request| request.header(&name).is_some())
+    }
+
+    /// Requires the header `name` to be exactly `value`.
+    pub fn header_value(name: &str, value: &str) -> Self {
+        let (name, value) = (name.to_owned(), value.to_owned());
+        Guard::new(move |request| request.header(&name) == Some(&value))
+    }
+
+    /// Requires the request to have come over HTTPS.
+    pub fn https() -> Self {
+        Guard::new(|request| request.extensions().get::<Scheme>() == Some(&Scheme::Https))
+    }
+
+    /// Requires the client to be in one of `countries`, given as ISO codes
+    /// such as `US`, as found by `ServerBuilder::geoip`.
+    pub fn country(countries: &[&str]) -> Self {
+        let countries: Vec<String> = countries.iter().map(|c| c.to_ascii_uppercase()).collect();
+        Guard::new(move |request| {
+            request
+                .extensions()
+                .get::<Geo>()
+                .and_then(|geo| geo.country.as_ref())
+                .is_some_and(|country| countries.contains(country))
+        })
+    }
+
+    pub(crate) fn allows(&self, request: &HttpRequest) -> bool {
+        (self.0)(request)
+    }
+}
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Guard")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+
+    fn request(headers: &[(&str, &str)], scheme: Option<Scheme>) -> HttpRequest {
+        let mut request = HttpRequest::new(HttpMethod::GET, "/");
+        for (name, value) in headers {
+            request.set_header(name, value);
+        }
+        if let Some(scheme) = scheme {
+            request.extensions_mut().insert(scheme);
+        }
+        request
+    }
+
+    #[test]
+    fn checks_hosts_headers_and_scheme() {
+        let host = Guard::host("API.example.com");
+        let beta = Guard::header("X-Beta");
+        let version = Guard::header_value("X-Version", "2");
+
+        assert!(host.allows(&request(&[("Host", "api.example.com:8080")], None)));
+        assert!(host.allows(&request(&[("host", "api.example.com")], None)));
+        assert!(!host.allows(&request(&[("Host", "www.example.com")], None)));
+        assert!(!host.allows(&request(&[], None)));
+        assert!(beta.allows(&request(&[("X-Beta", "")], None)));
+        assert!(beta.allows(&request(&[("x-beta", "")], None)));
+        assert!(!beta.allows(&request(&[], None)));
+        assert!(version.allows(&request(&[("X-Version", "2")], None)));
+        assert!(!version.allows(&request(&[("X-Version", "1")], None)));
+        assert!(Guard::https().allows(&request(&[], Some(Scheme::Https))));
+        assert!(!Guard::https().allows(&request(&[], Some(Scheme::Http))));
+        assert!(!Guard::https().allows(&request(&[], None)));
+
+        let mut from_canada = request(&[], None);
+        from_canada.extensions_mut().insert(Geo {
+            country: Some("CA".to_owned()),
+            asn: None,
+        });
+        assert!(Guard::country(&["us", "ca"]).allows(&from_canada));
+        assert!(!Guard::country(&["US"]).allows(&from_canada));
+        assert!(!Guard::country(&["US"]).allows(&request(&[], None)));
+    }
+}
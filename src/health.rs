@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, Handler, HttpResponse};
+
+type ReadinessCheck = Box<dyn Fn() -> bool + Send + Sync>;
+
+/// Whether the server should be sent traffic, as reported by the readiness
+/// endpoint. It is ready once the listener is bound and every registered
+/// check passes, and stops being ready for good once draining starts.
+#[derive(Default)]
+pub struct Health {
+    listening: AtomicBool,
+    draining: AtomicBool,
+    checks: Mutex<Vec<ReadinessCheck>>,
+}
+impl Health {
+    pub fn new() -> Self {
+        Health::default()
+    }
+
+    /// Adds a check that must pass for the server to be ready, e.g. one
+    /// reading the last known state of an upstream. Checks run on every
+    /// readiness probe, so they should be cheap.
+    pub fn add_check<F>(&self, check: F)
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.checks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Box::new(check));
+    }
+
+    /// Reports not ready from now on, so load balancers stop sending new
+    /// traffic ahead of a shutdown.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+            && !self.draining.load(Ordering::Relaxed)
+            && self
+                .checks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .all(|check| check())
+    }
+
+    pub(crate) fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Answers 200 while `Health` is ready and 503 otherwise.
+pub(crate) struct Readiness(pub Arc<Health>);
+
+impl Handler for Readiness {
+    fn call(&self, _request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let status = if self.0.is_ready() { 200 } else { 503 };
+        Box::pin(async move { HttpResponse::new(status) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+
+    async fn probe(health: &Arc<Health>) -> u16 {
+        Readiness(health.clone())
+            .call(HttpRequest::new(HttpMethod::GET, "/readyz"))
+            .await
+            .status
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_listening() {
+        let health = Arc::new(Health::new());
+
+        assert_eq!(503, probe(&health).await);
+        health.set_listening();
+        assert_eq!(200, probe(&health).await);
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_a_check_fails() {
+        let upstream_up = Arc::new(AtomicBool::new(false));
+        let health = Arc::new(Health::new());
+        health.set_listening();
+        let check_flag = upstream_up.clone();
+        health.add_check(move || check_flag.load(Ordering::Relaxed));
+
+        assert_eq!(503, probe(&health).await);
+        upstream_up.store(true, Ordering::Relaxed);
+        assert_eq!(200, probe(&health).await);
+    }
+
+    #[tokio::test]
+    async fn not_ready_once_draining() {
+        let health = Arc::new(Health::new());
+        health.set_listening();
+
+        health.start_draining();
+
+        assert_eq!(503, probe(&health).await);
+    }
+}
@@ -0,0 +1,263 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rust_http_parse::HttpRequest;
+
+use super::api_keys::Bucket;
+use super::{BoxFuture, ConcurrencyLimit, Handler, HttpResponse, Middleware, Next, UploadLimit};
+
+/// Limits shared by every request for one virtual host, so one tenant of a
+/// shared server can't starve the others: a request rate, a cap on requests
+/// in flight, a budget of bytes transferred per window and a maximum body
+/// size. Requests over the rate or out of budget get a 429 with
+/// `Retry-After`, those over the in-flight cap a 503, and bodies that are too
+/// large a 413. `wrap` each host's handler before adding it to `Hosts`, or
+/// add it as middleware to share the limits between routes. Clones share
+/// the limits.
+#[derive(Debug, Clone, Default)]
+pub struct HostQuota {
+    rate: Option<Arc<Mutex<Bucket>>>,
+    in_flight: Option<ConcurrencyLimit>,
+    bandwidth: Option<Arc<Mutex<Budget>>>,
+    body: UploadLimit,
+}
+impl HostQuota {
+    pub fn new() -> Self {
+        HostQuota::default()
+    }
+
+    /// Lets through up to `per_minute` requests a minute, allowing bursts
+    /// of up to a minute's worth.
+    pub fn per_minute(mut self, per_minute: u32) -> Self {
+        self.rate = Some(Arc::new(Mutex::new(Bucket::new(per_minute))));
+        self
+    }
+
+    /// Lets up to `max` requests run at once. Requests rather than
+    /// connections are counted, since a connection's host is only known
+    /// once a request arrives on it.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.in_flight = Some(ConcurrencyLimit::new(max));
+        self
+    }
+
+    /// Lets request and response bodies add up to `bytes` in each `window`,
+    /// e.g. a day. The request that goes over the budget still completes.
+    pub fn bandwidth(mut self, bytes: u64, window: Duration) -> Self {
+        self.bandwidth = Some(Arc::new(Mutex::new(Budget {
+            bytes,
+            window,
+            started: Instant::now(),
+            used: 0,
+        })));
+        self
+    }
+
+    /// Caps the host's request bodies as `UploadLimit::max_body` does.
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.body = self.body.max_body(bytes);
+        self
+    }
+
+    /// Applies the limits to just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        QuotaHandler {
+            quota: self,
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// The response to refuse the request with, if it's over a limit.
+    fn check(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if let Some(refused) = self.body.check(request) {
+            return Some(refused);
+        }
+        let now = Instant::now();
+        let wait = self
+            .bandwidth
+            .as_ref()
+            .and_then(|budget| budget.lock().unwrap().exhausted(now))
+            .or_else(|| {
+                let rate = self.rate.as_ref()?;
+                rate.lock().unwrap().take(now)
+            })?;
+        debug!("Quota used up for {}", request.path);
+        Some(HttpResponse::new(429).with_retry_after(wait))
+    }
+
+    async fn run(
+        self,
+        request: HttpRequest,
+        handler: impl FnOnce(HttpRequest) -> BoxFuture<HttpResponse> + Send + 'static,
+    ) -> HttpResponse {
+        if let Some(refused) = self.check(&request) {
+            return refused;
+        }
+        let received = request.body().len() as u64;
+        let response = match self.in_flight.clone() {
+            Some(limit) => {
+                let description = format!("{:?} {}", request.method, request.path);
+                limit.run(description, request, handler).await
+            }
+            None => handler(request).await,
+        };
+        if let Some(budget) = &self.bandwidth {
+            let sent = if response.is_streaming() {
+                response
+                    .header("Content-Length")
+                    .and_then(|len| len.parse().ok())
+                    .unwrap_or(0)
+            } else {
+                response.body().len() as u64
+            };
+            budget
+                .lock()
+                .unwrap()
+                .spend(Instant::now(), received + sent);
+        }
+        response
+    }
+}
+
+/// Bytes a host may still transfer in the current window.
+#[derive(Debug)]
+struct Budget {
+    bytes: u64,
+    window: Duration,
+    started: Instant,
+    used: u64,
+}
+impl Budget {
+    /// Starts a new window once the last one is over.
+    fn roll(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.started) >= self.window {
+            self.started = now;
+            self.used = 0;
+        }
+    }
+
+    /// How long until the budget is renewed, if it's used up.
+    fn exhausted(&mut self, now: Instant) -> Option<Duration> {
+        self.roll(now);
+        if self.used < self.bytes {
+            return None;
+        }
+        Some((self.started + self.window).saturating_duration_since(now))
+    }
+
+    fn spend(&mut self, now: Instant, bytes: u64) {
+        self.roll(now);
+        self.used = self.used.saturating_add(bytes);
+    }
+}
+
+impl Middleware for HostQuota {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        Box::pin(self.clone().run(request, move |request| next.run(request)))
+    }
+
+    fn max_body(&self, request: &HttpRequest) -> Option<usize> {
+        Middleware::max_body(&self.body, request)
+    }
+}
+
+struct QuotaHandler<H> {
+    quota: HostQuota,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for QuotaHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let handler = self.handler.clone();
+        Box::pin(
+            self.quota
+                .clone()
+                .run(request, move |request| handler.call(request)),
+        )
+    }
+
+    fn max_body(&self, request: &HttpRequest) -> Option<usize> {
+        let handler = self.handler.max_body(request);
+        let quota = Middleware::max_body(&self.quota.body, request);
+        quota.into_iter().chain(handler).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Hosts, Server, TestClient};
+    use rust_http_parse::{HttpMethod, HttpRequestBuilder};
+
+    fn request(host: &str, body: &str) -> HttpRequest {
+        HttpRequestBuilder::new()
+            .with_method(HttpMethod::POST)
+            .with_path("/")
+            .with_header("Host", host)
+            .with_body(body)
+            .build()
+            .unwrap()
+    }
+
+    fn hosts(quota: HostQuota) -> Hosts {
+        Hosts::new()
+            .host(
+                "tenant.example",
+                quota.wrap(|_| async { HttpResponse::ok().with_body(b"12345".to_vec()) }),
+            )
+            .host("other.example", |_| async { HttpResponse::ok() })
+    }
+
+    #[tokio::test]
+    async fn limits_each_host_on_its_own() {
+        let hosts = hosts(HostQuota::new().per_minute(2).max_body(4));
+
+        let statuses = [
+            hosts.call(request("tenant.example", "")).await.status,
+            hosts.call(request("tenant.example", "01234")).await.status,
+            hosts.call(request("tenant.example", "")).await.status,
+            hosts.call(request("tenant.example", "")).await.status,
+            hosts.call(request("other.example", "01234")).await.status,
+        ];
+
+        assert_eq!([200, 413, 200, 429, 200], statuses);
+    }
+
+    #[tokio::test]
+    async fn stops_once_the_bandwidth_budget_is_spent() {
+        let hosts = hosts(HostQuota::new().bandwidth(8, Duration::from_secs(60)));
+
+        let first = hosts.call(request("tenant.example", "")).await;
+        let second = hosts.call(request("tenant.example", "abc")).await;
+        let over = hosts.call(request("tenant.example", "")).await;
+
+        assert_eq!(200, first.status);
+        assert_eq!(200, second.status);
+        assert_eq!(429, over.status);
+        assert_eq!(Some(&"60".to_owned()), over.header("Retry-After"));
+        assert_eq!(200, hosts.call(request("other.example", "")).await.status);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_declared_body_before_reading_it() {
+        let client =
+            TestClient::new(Server::builder().mount("/", hosts(HostQuota::new().max_body(4))))
+                .unwrap();
+        let declaring = |host: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nHost: {}\r\nContent-Length: 1000\r\n\r\n",
+                host
+            )
+        };
+
+        let tenant = client
+            .send_raw(declaring("tenant.example").as_bytes())
+            .await;
+        let other = client.send_raw(declaring("other.example").as_bytes()).await;
+
+        assert_eq!(413, tenant.unwrap().status);
+        // The other host has no limit, so its body is waited for.
+        assert_eq!(400, other.unwrap().status);
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, Handler, HttpResponse};
+
+/// Hands each request to the handler registered for its `Host` header,
+/// compared without case or port, so one listener can serve several sites.
+/// Requests for any other host go to the fallback, or get a 404.
+#[derive(Default)]
+pub struct Hosts {
+    hosts: HashMap<String, Box<dyn Handler>>,
+    fallback: Option<Box<dyn Handler>>,
+}
+impl Hosts {
+    pub fn new() -> Self {
+        Hosts::default()
+    }
+
+    pub fn host<H: Handler>(mut self, name: &str, handler: H) -> Self {
+        self.hosts.insert(normalize_host(name), Box::new(handler));
+        self
+    }
+
+    pub fn fallback<H: Handler>(mut self, handler: H) -> Self {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+}
+
+impl Hosts {
+    fn handler(&self, request: &HttpRequest) -> Option<&dyn Handler> {
+        request
+            .header("Host")
+            .and_then(|host| self.hosts.get(&normalize_host(host)))
+            .or(self.fallback.as_ref())
+            .map(|handler| handler.as_ref())
+    }
+}
+
+impl Handler for Hosts {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        match self.handler(&request) {
+            Some(handler) => handler.call(request),
+            None => Box::pin(async { HttpResponse::not_found() }),
+        }
+    }
+
+    fn max_body(&self, request: &HttpRequest) -> Option<usize> {
+        self.handler(request)?.max_body(request)
+    }
+}
+
+/// Lowercases a host and drops any port and trailing dot, keeping IPv6
+/// literals in their brackets.
+pub(crate) fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = if host.starts_with('[') {
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TestClient};
+    use rust_http_parse::HttpMethod;
+
+    async fn get(hosts: &Hosts, host: Option<&str>) -> HttpResponse {
+        let mut request = HttpRequest::new(HttpMethod::GET, "/");
+        if let Some(host) = host {
+            request.set_header("Host", host);
+        }
+        hosts.call(request).await
+    }
+
+    #[tokio::test]
+    async fn dispatches_on_host_ignoring_case_and_port() {
+        let hosts = Hosts::new()
+            .host("example.com", |_| async { HttpResponse::new(200) })
+            .host("blog.example.com", |_| async { HttpResponse::new(201) });
+
+        assert_eq!(200, get(&hosts, Some("Example.COM:8080")).await.status);
+        assert_eq!(201, get(&hosts, Some("blog.example.com.")).await.status);
+        assert_eq!(404, get(&hosts, Some("other.com")).await.status);
+        assert_eq!(404, get(&hosts, None).await.status);
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_go_to_the_fallback() {
+        let hosts = Hosts::new()
+            .host("[::1]", |_| async { HttpResponse::new(200) })
+            .fallback(|_| async { HttpResponse::new(202) });
+
+        assert_eq!(200, get(&hosts, Some("[::1]:80")).await.status);
+        assert_eq!(202, get(&hosts, Some("example.com")).await.status);
+    }
+
+    #[tokio::test]
+    async fn reads_a_lowercase_host_header() {
+        let client = TestClient::new(
+            Server::builder().mount(
+                "/",
+                Hosts::new()
+                    .host("a.example", |_| async { HttpResponse::new(201) })
+                    .fallback(|_| async { HttpResponse::new(202) }),
+            ),
+        )
+        .unwrap();
+
+        let response = client
+            .send(HttpMethod::GET, "/", &[("host", "a.example")], b"")
+            .await
+            .unwrap();
+
+        assert_eq!(201, response.status);
+    }
+}
@@ -0,0 +1,93 @@
+extern crate custom_error;
+
+mod access_log;
+mod admin;
+mod api_keys;
+mod auth;
+mod body_log;
+mod broadcast;
+mod buffer_pool;
+mod capture;
+mod concurrency;
+mod cookies;
+mod digest;
+mod early_hints;
+mod error;
+mod etag;
+mod forward_auth;
+mod geoip;
+mod guard;
+mod health;
+mod host_quota;
+mod hosts;
+mod limits;
+mod methods;
+mod metrics;
+mod middleware;
+mod mirror;
+mod net;
+mod panic;
+mod plugin;
+mod range;
+mod response;
+mod router;
+mod runtime_mounts;
+mod server;
+mod sni;
+mod state;
+mod static_files;
+mod statsd;
+#[cfg(feature = "templates")]
+mod templates;
+mod test_client;
+mod timeout;
+mod tls;
+mod traffic;
+mod upload;
+
+pub use self::access_log::W3cLog;
+pub use self::api_keys::ApiKeys;
+pub use self::body_log::BodyLog;
+pub use self::broadcast::{Broadcaster, Event, Lagged};
+pub use self::buffer_pool::{BufferPool, BufferPoolStats, PooledBuffer};
+pub use self::capture::RequestCapture;
+pub use self::concurrency::ConcurrencyLimit;
+pub use self::cookies::{Cookie, CookieJar, CookieKey, CookieKeyError, PrivateJar, SameSite};
+pub use self::digest::ContentDigest;
+pub use self::early_hints::{EarlyHints, Preload};
+pub use self::error::{HttpError, IntoResponse};
+pub use self::etag::ETag;
+pub use self::forward_auth::ForwardAuth;
+pub use self::geoip::{Geo, GeoIp, GeoRules};
+pub use self::guard::Guard;
+pub use self::health::Health;
+pub use self::host_quota::HostQuota;
+pub use self::hosts::Hosts;
+pub use self::methods::{AllowedMethods, MethodPolicy};
+pub use self::metrics::{
+    ConnectionStats, Histogram, Metrics, Phase, RequestTiming, RouteStats, LATENCY_BUCKETS_MICROS,
+};
+pub use self::middleware::{Middleware, Next};
+pub use self::mirror::Mirror;
+pub use self::net::{BindRetry, NetError};
+pub use self::plugin::{Plugin, PluginRegistry};
+pub use self::response::{HttpResponse, ResponseWriter};
+pub use self::router::{BoxFuture, Handler, MatchedRoute, RouteError, Router};
+pub use self::runtime_mounts::RuntimeMounts;
+pub use self::server::{ClientAddr, OriginalDst, Scheme, Server, ServerBuilder, ServerError};
+pub use self::state::State;
+pub use self::static_files::StaticFiles;
+pub use self::statsd::StatsdExporter;
+#[cfg(feature = "templates")]
+pub use self::templates::{TemplateError, Templates};
+pub use self::test_client::TestClient;
+pub use self::timeout::Timeout;
+pub use self::tls::{TlsConfig, TlsError, TlsVersion};
+pub use self::traffic::{read_transactions, TrafficLog, Transaction};
+pub use self::upload::UploadLimit;
+#[cfg(feature = "serde")]
+pub use rust_http_parse::DeserializeError;
+pub use rust_http_parse::{
+    Extensions, HttpMethod, HttpRequest, Mask, ParseError, ParseOptions, ParseTiming, Query,
+    Redacted, Redaction,
+};
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+type OpenPerIp = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Caps on how many connections are handled at once, overall and from a
+/// single client address.
+#[derive(Default)]
+pub(crate) struct ConnectionLimits {
+    total: Option<Arc<Semaphore>>,
+    per_ip: Option<PerIpLimit>,
+    shed_above: Option<(usize, Duration)>,
+    fd_budget: Option<usize>,
+}
+impl ConnectionLimits {
+    pub fn new(max_connections: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        ConnectionLimits {
+            total: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            per_ip: max_per_ip.map(|max| PerIpLimit {
+                max,
+                open: Arc::new(Mutex::new(HashMap::new())),
+            }),
+            shed_above: None,
+            fd_budget: None,
+        }
+    }
+
+    /// Turns requests away once more than `max_pending` connections are
+    /// being handled, telling clients to retry after `retry_after`.
+    pub fn with_load_shedding(mut self, max_pending: usize, retry_after: Duration) -> Self {
+        self.shed_above = Some((max_pending, retry_after));
+        self
+    }
+
+    /// The `Retry-After` delay to answer with if `pending` connections is
+    /// more than the server should take on, or `None` to handle the request.
+    pub fn shed(&self, pending: usize) -> Option<Duration> {
+        match self.shed_above {
+            Some((max_pending, retry_after)) if pending > max_pending => Some(retry_after),
+            _ => None,
+        }
+    }
+
+    /// Turns connections away once `budget` are open, keeping the rest of the
+    /// process's file descriptors free for files, logs and the like.
+    pub fn with_fd_budget(mut self, budget: usize) -> Self {
+        self.fd_budget = Some(budget);
+        self
+    }
+
+    /// Whether `open` connections already use up the file descriptor budget.
+    pub fn near_fd_limit(&self, open: usize) -> bool {
+        self.fd_budget.is_some_and(|budget| open >= budget)
+    }
+
+    /// Waits until another connection may be accepted.
+    pub async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.total {
+            Some(total) => total.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Counts a connection from `ip`, or returns `None` if that address
+    /// already has as many open as it's allowed.
+    pub fn admit(&self, ip: IpAddr) -> Option<IpGuard> {
+        let per_ip = match &self.per_ip {
+            Some(per_ip) => per_ip,
+            None => return Some(IpGuard { slot: None }),
+        };
+
+        let mut open = per_ip.open.lock().unwrap();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= per_ip.max {
+            return None;
+        }
+        *count += 1;
+
+        Some(IpGuard {
+            slot: Some((ip, per_ip.open.clone())),
+        })
+    }
+}
+
+/// Raises the soft limit on open file descriptors as far as the hard limit
+/// allows, returning the new limit, or `None` if it is unknown or unlimited.
+#[cfg(target_os = "linux")]
+pub(crate) fn raise_fd_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it is given.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: setrlimit only reads the struct it is given.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit = raised;
+        }
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    std::convert::TryFrom::try_from(limit.rlim_cur).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn raise_fd_limit() -> Option<usize> {
+    None
+}
+
+struct PerIpLimit {
+    max: usize,
+    open: OpenPerIp,
+}
+
+/// Holds one of an address's connection slots until dropped.
+pub(crate) struct IpGuard {
+    slot: Option<(IpAddr, OpenPerIp)>,
+}
+
+impl Drop for IpGuard {
+    fn drop(&mut self) {
+        if let Some((ip, open)) = self.slot.take() {
+            let mut open = open.lock().unwrap();
+            if let Some(count) = open.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_connections_per_address() {
+        let limits = ConnectionLimits::new(None, Some(2));
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limits.admit(client).unwrap();
+        let _second = limits.admit(client).unwrap();
+
+        assert!(limits.admit(client).is_none());
+        assert!(limits.admit(other).is_some());
+        drop(first);
+        assert!(limits.admit(client).is_some());
+    }
+
+    #[test]
+    fn stops_at_the_fd_budget() {
+        let limits = ConnectionLimits::new(None, None).with_fd_budget(100);
+
+        assert!(!limits.near_fd_limit(99));
+        assert!(limits.near_fd_limit(100));
+        assert!(!ConnectionLimits::default().near_fd_limit(usize::MAX));
+    }
+
+    #[test]
+    fn sheds_load_above_threshold() {
+        let limits =
+            ConnectionLimits::new(None, None).with_load_shedding(10, Duration::from_secs(2));
+
+        assert_eq!(None, limits.shed(10));
+        assert_eq!(Some(Duration::from_secs(2)), limits.shed(11));
+        assert_eq!(None, ConnectionLimits::default().shed(1000));
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_free_connection_slot() {
+        let limits = ConnectionLimits::new(Some(1), None);
+
+        let permit = limits.reserve().await;
+        let blocked =
+            tokio::time::timeout(std::time::Duration::from_millis(20), limits.reserve()).await;
+        drop(permit);
+
+        assert!(blocked.is_err());
+        assert!(limits.reserve().await.is_some());
+    }
+}
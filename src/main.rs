@@ -1,17 +1,30 @@
-#![allow(dead_code)]
+mod config;
 
-extern crate custom_error;
-
-mod net;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Clap;
-use flexi_logger::Logger;
-use log::{debug, info};
-use net::TcpRequestListener;
-use std::{fs::read_to_string, path::Path};
-use tokio::io::AsyncWriteExt;
+use flexi_logger::{
+    Age, Cleanup, Criterion, Duplicate, FlexiLoggerError, Logger, Naming, ReconfigurationHandle,
+};
+use log::info;
+use rust_http_server::{
+    read_transactions, AllowedMethods, ApiKeys, BindRetry, BodyLog, GeoIp, GeoRules, Hosts,
+    HttpMethod, Mask, MethodPolicy, ParseOptions, Preload, Redaction, RequestCapture,
+    RuntimeMounts, Server, ServerBuilder, StaticFiles, TlsConfig, TlsVersion, TrafficLog, W3cLog,
+};
+
+use config::{Config, HostRoot, MirrorConfig, MountConfig, StatsdConfig, TlsHostConfig};
 
-use rust_http_parse::{parse_from_reader, HttpMethod, HttpRequest, ParseError};
+/// Connections that may wait to finish a Fast Open handshake at once.
+const FAST_OPEN_QUEUE: u32 = 256;
+/// The pause before the first retry of a failed bind, doubling after that.
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// File descriptors kept free for everything but connections.
+const DEFAULT_FD_HEADROOM: usize = 64;
+/// Bytes of each body shown by --log-bodies.
+const DEFAULT_LOG_BODY_BYTES: usize = 1024;
 
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
@@ -24,53 +37,656 @@ struct Opts {
     /// A level of verbosity, and can be used multiple times
     #[clap(short, long, default_value = "80")]
     port: u32,
+    /// TOML config file; command line flags take precedence over it
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+    /// Directory to serve static files from [default: ./files]
+    #[clap(long)]
+    root: Option<PathBuf>,
+    /// File to serve when a directory is requested [default: index.html]
+    #[clap(long)]
+    index: Option<String>,
+    /// URL path the static files are served under [default: /static]
+    #[clap(long)]
+    static_prefix: Option<String>,
+    /// Serve a directory under a URL prefix, as
+    /// PREFIX=DIR[,index=FILE][,confined][,write_token=TOKEN][,download=PATTERN][,method=METHOD].
+    /// Can be repeated; replaces the default mount unless --root or --static-prefix is given
+    #[clap(long = "route", number_of_values = 1)]
+    routes: Vec<MountConfig>,
+    /// Serve requests for a host name from its own directory, as HOST=DIR. Can be repeated
+    #[clap(long = "host", number_of_values = 1)]
+    hosts: Vec<HostRoot>,
+    /// PEM certificate chain to serve HTTPS with, leaf certificate first
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[clap(long)]
+    tls_key: Option<PathBuf>,
+    /// Port to serve HTTPS on [default: 443]
+    #[clap(long)]
+    tls_port: Option<u32>,
+    /// Serve a host name with its own certificate, picked by SNI, as
+    /// HOST=CERT,KEY[,client_ca=FILE][,client_cert_optional][,alpn=PROTOCOL].
+    /// Can be repeated
+    #[clap(long = "tls-host", number_of_values = 1)]
+    tls_hosts: Vec<TlsHostConfig>,
+    /// Oldest TLS version to accept: 1.2 or 1.3 [default: 1.2]
+    #[clap(long)]
+    tls_min_version: Option<TlsVersion>,
+    /// Newest TLS version to accept: 1.2 or 1.3 [default: 1.3]
+    #[clap(long)]
+    tls_max_version: Option<TlsVersion>,
+    /// Only offer this cipher suite, by IANA name, e.g. TLS13_AES_256_GCM_SHA384.
+    /// Can be repeated; suites are preferred in the order given
+    #[clap(long = "tls-cipher", number_of_values = 1)]
+    tls_ciphers: Vec<String>,
+    /// DER-encoded OCSP response to staple to HTTPS handshakes, kept fresh by an
+    /// external tool and reloaded with the certificate
+    #[clap(long)]
+    tls_ocsp: Option<PathBuf>,
+    /// Check the certificate and key files for renewals this often, in seconds.
+    /// They are always reloaded on SIGHUP
+    #[clap(long)]
+    tls_reload_interval: Option<u64>,
+    /// Maximum number of connections handled at once
+    #[clap(long)]
+    max_connections: Option<usize>,
+    /// Maximum number of open connections from a single client address
+    #[clap(long)]
+    max_per_ip: Option<usize>,
+    /// Number of worker threads [default: one per CPU core]
+    #[clap(long)]
+    workers: Option<usize>,
+    /// Seconds a handler may take to respond before the request fails with 503
+    #[clap(long)]
+    request_timeout: Option<u64>,
+    /// Answer 503 without running handlers while more than this many connections are pending
+    #[clap(long)]
+    shed_above: Option<usize>,
+    /// Enable TCP Fast Open on the listeners, so repeat clients can send their
+    /// request in the SYN (Linux only)
+    #[clap(long)]
+    tcp_fast_open: bool,
+    /// Accept bare-LF line endings and empty lines before the request line,
+    /// as some embedded clients send
+    #[clap(long)]
+    lenient_line_endings: bool,
+    /// Accept HTTP/0.9 simple requests (`GET /path` with no version or
+    /// headers) and answer them with the bare body
+    #[clap(long)]
+    http09: bool,
+    /// Reject requests declaring a body larger than this many bytes with 413,
+    /// before reading the body [default: no limit]
+    #[clap(long)]
+    max_body: Option<usize>,
+    /// File descriptors to keep free for everything but connections; new
+    /// connections get a 503 once only this many are left [default: 64]
+    #[clap(long)]
+    fd_headroom: Option<usize>,
+    /// Times to retry binding a port that's in use or an address that isn't
+    /// available yet, backing off from half a second [default: 0]
+    #[clap(long)]
+    bind_retries: Option<u32>,
+    /// Intercept traffic for other addresses redirected here by TPROXY
+    /// firewall rules (Linux only, needs CAP_NET_ADMIN)
+    #[clap(long)]
+    transparent: bool,
+    /// Only serve this method, answering others with 501 Not Implemented.
+    /// Can be repeated, e.g. --allow-method GET --allow-method HEAD
+    #[clap(long = "allow-method", number_of_values = 1, parse(try_from_str = config::parse_method))]
+    allowed_methods: Vec<HttpMethod>,
+    /// Look client countries and networks up in this MaxMind DB file, such as
+    /// GeoLite2-Country.mmdb. Can be repeated, e.g. to add an ASN database
+    #[clap(long = "geoip-db", number_of_values = 1)]
+    geoip_databases: Vec<PathBuf>,
+    /// Only serve clients in this country, given as an ISO code such as US. Can be repeated
+    #[clap(long = "allow-country", number_of_values = 1)]
+    allowed_countries: Vec<String>,
+    /// Answer clients in this country with 403 Forbidden. Can be repeated
+    #[clap(long = "deny-country", number_of_values = 1)]
+    denied_countries: Vec<String>,
+    /// Answer clients in this autonomous system with 403 Forbidden. Can be repeated
+    #[clap(long = "deny-asn", number_of_values = 1)]
+    denied_asns: Vec<u32>,
+    /// Only accept requests carrying one of the API keys listed in this file, one
+    /// per line with an optional requests-per-minute limit after it
+    #[clap(long)]
+    api_key_file: Option<PathBuf>,
+    /// Header to read the API key from [default: X-Api-Key]
+    #[clap(long)]
+    api_key_header: Option<String>,
+    /// Also accept the API key as this query parameter
+    #[clap(long)]
+    api_key_query: Option<String>,
+    /// Push metrics in StatsD format to this address, e.g. 127.0.0.1:8125.
+    /// The config file's [statsd] table sets the prefix, interval and DogStatsD tags
+    #[clap(long)]
+    statsd: Option<String>,
+    /// Copy requests to this shadow upstream, e.g. 127.0.0.1:9090, discarding its
+    /// responses. The config file's [mirror] table sets the percentage and path prefixes
+    #[clap(long)]
+    mirror: Option<String>,
+    /// Serve live status as JSON at /status on this port of 127.0.0.1
+    #[clap(long)]
+    admin_port: Option<u32>,
+    /// Let admin requests carrying this bearer token list, add and remove static
+    /// mounts at /mounts. Changes are saved to the config file's [[runtime_mounts]]
+    #[clap(long)]
+    admin_token: Option<String>,
+    /// Debugging: save the raw bytes of requests to numbered files in this directory
+    #[clap(long)]
+    capture_dir: Option<PathBuf>,
+    /// Only capture requests whose path starts with this prefix
+    #[clap(long)]
+    capture_path: Option<String>,
+    /// Only capture requests from this client address
+    #[clap(long)]
+    capture_ip: Option<IpAddr>,
+    /// Append every request and its response to this file, for the replay command
+    #[clap(long)]
+    record: Option<PathBuf>,
+    /// Mask this header's value in logged requests and responses, in addition to
+    /// Authorization, Proxy-Authorization, Cookie and Set-Cookie. Can be repeated
+    #[clap(long = "redact-header", number_of_values = 1)]
+    redact_headers: Vec<String>,
+    /// Mask this field's value in logged JSON and form bodies, e.g. password.
+    /// Can be repeated
+    #[clap(long = "redact-body-field", number_of_values = 1)]
+    redact_body_fields: Vec<String>,
+    /// Debugging: log requests and responses with their bodies, under the
+    /// "bodies" target, for paths starting with this prefix. Can be repeated;
+    /// use / for every path
+    #[clap(long = "log-bodies", number_of_values = 1)]
+    log_bodies: Vec<String>,
+    /// Bytes of each body to show with --log-bodies [default: 1024]
+    #[clap(long)]
+    log_body_bytes: Option<usize>,
+    /// How client addresses appear in the access log: keep, partial or hide [default: keep]
+    #[clap(long)]
+    mask_client: Option<Mask>,
+    /// How paths appear in logs: keep, partial or hide [default: keep]
+    #[clap(long)]
+    mask_path: Option<Mask>,
+    /// Write the access log in the W3C Extended Log File Format
+    #[clap(long)]
+    w3c_log: bool,
+    /// A field of the W3C access log, such as cs-uri-stem or cs(User-Agent). Can be
+    /// repeated [default: date time c-ip cs-method cs-uri-stem cs-uri-query sc-status time-taken]
+    #[clap(long = "w3c-field", number_of_values = 1)]
+    w3c_fields: Vec<String>,
+    /// Write the W3C access log to a file a day in this directory [default: the --log-dir, or ./logs]
+    #[clap(long)]
+    w3c_log_dir: Option<PathBuf>,
+    /// Write application and access logs to files in this directory instead of stderr
+    #[clap(long)]
+    log_dir: Option<PathBuf>,
+    /// Start a new log file once the current one reaches this many bytes
+    #[clap(long)]
+    log_rotate_size: Option<u64>,
+    /// Start a new log file every day
+    #[clap(long)]
+    log_rotate_daily: bool,
+    /// Number of rotated log files to keep
+    #[clap(long, default_value = "7")]
+    log_keep: usize,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Re-send requests recorded with --record through the configured routes
+    /// and report any whose response differs
+    Replay(Replay),
 }
 
-#[allow(unreachable_code)]
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Logger::with_env_or_str("debug").start()?;
+#[derive(Clap)]
+struct Replay {
+    /// File written by --record
+    file: PathBuf,
+}
+
+fn start_logger(opts: &Opts) -> Result<ReconfigurationHandle, FlexiLoggerError> {
+    let logger = Logger::with_env_or_str("debug");
+    let log_dir = match &opts.log_dir {
+        Some(log_dir) => log_dir,
+        None => return logger.start(),
+    };
+
+    let logger = logger
+        .log_to_file()
+        .directory(log_dir)
+        .duplicate_to_stderr(Duplicate::Warn);
+    let criterion = match (opts.log_rotate_size, opts.log_rotate_daily) {
+        (Some(size), true) => Criterion::AgeOrSize(Age::Day, size),
+        (Some(size), false) => Criterion::Size(size),
+        (None, true) => Criterion::Age(Age::Day),
+        (None, false) => return logger.start(),
+    };
+
+    logger
+        .rotate(
+            criterion,
+            Naming::Timestamps,
+            Cleanup::KeepLogFiles(opts.log_keep),
+        )
+        .start()
+}
 
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts: Opts = Opts::parse();
+    let _logger = start_logger(&opts)?;
 
-    info!("Binding to {}:{}", &opts.bind_address, opts.port);
-    let mut listener = TcpRequestListener::new(&opts.bind_address, opts.port);
-    listener.open().await?;
-
-    loop {
-        if let Ok(mut stream) = listener.accept_request().await {
-            let (mut read_half, mut write_half) = stream.split();
-            let response = match parse_from_reader(&mut read_half).await {
-                Ok(request) => {
-                    debug!("Got request {:?}", &request);
-                    handle_request(&request)
-                }
-                Err(ParseError::MaxHeaderSizeExceeded) => {
-                    "HTTP/1.1 413 Entity Too Large\r\n\r\n".to_owned()
-                }
-                _ => "HTTP/1.1 500 Internal Server Error\r\n\r\n".to_owned(),
-            };
+    let config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = opts.workers.or(config.workers) {
+        runtime.worker_threads(workers);
+    }
+    runtime.enable_all().build()?.block_on(run(opts, config))
+}
+
+async fn run(mut opts: Opts, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let command = opts.command.take();
+    let builder = configure(opts, config)?;
+    match command {
+        Some(Command::Replay(replay_opts)) => replay(builder.build()?, &replay_opts.file).await,
+        None => Ok(builder.serve().await?),
+    }
+}
 
-            debug!("Sending response {}", &response);
-            write_half.write(&response.as_bytes()).await?;
+/// Replays recorded transactions, comparing the status line and body of
+/// each response with the recorded one. Header order isn't stable, so
+/// headers aren't compared.
+async fn replay(server: Server, file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let transactions = read_transactions(file)?;
+    let mut mismatches = 0;
+    for (number, transaction) in transactions.iter().enumerate() {
+        let response = server.handle_raw(&transaction.request).await?;
+        let (expected_status, expected_body) = split_response(&transaction.response);
+        let (status, body) = split_response(&response);
+        if expected_status != status || expected_body != body {
+            mismatches += 1;
+            println!(
+                "#{} {}: expected \"{}\" ({} byte body), got \"{}\" ({} byte body)",
+                number + 1,
+                first_line(&transaction.request),
+                expected_status,
+                expected_body.len(),
+                status,
+                body.len()
+            );
         }
     }
 
+    println!(
+        "{} of {} transactions matched",
+        transactions.len() - mismatches,
+        transactions.len()
+    );
+    if mismatches > 0 {
+        return Err(format!("{} responses differ from the recording", mismatches).into());
+    }
     Ok(())
 }
 
-fn handle_request(request: &HttpRequest) -> String {
-    if request.method == HttpMethod::GET && request.path.starts_with("/static") {
-        debug!("Handling static request");
-        let stripped_path = Path::new(&request.path).strip_prefix("/static").unwrap();
-        let final_path = Path::new("./files").join(&stripped_path);
-        let content = read_to_string(final_path).unwrap();
-        return format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            content.len(),
-            content
-        );
+/// Splits a raw response into its status line and body.
+fn split_response(response: &[u8]) -> (String, &[u8]) {
+    let body_start = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(response.len(), |head_end| head_end + 4);
+    (first_line(response), &response[body_start..])
+}
+
+fn first_line(raw: &[u8]) -> String {
+    let end = raw
+        .iter()
+        .position(|&c| c == b'\r' || c == b'\n')
+        .unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).into_owned()
+}
+
+fn configure(opts: Opts, config: Config) -> Result<ServerBuilder, Box<dyn std::error::Error>> {
+    let index = opts
+        .index
+        .or(config.index)
+        .unwrap_or_else(|| "index.html".to_owned());
+    let mut mounts = if opts.routes.is_empty() {
+        config.routes
+    } else {
+        opts.routes
+    };
+    let mut hosts = config.hosts;
+    for host in opts.hosts {
+        hosts.insert(host.host, host.root);
+    }
+    let root = opts.root.or(config.root);
+    let static_prefix = opts.static_prefix.or(config.static_prefix);
+    if (mounts.is_empty() && hosts.is_empty()) || root.is_some() || static_prefix.is_some() {
+        mounts.push(MountConfig {
+            prefix: static_prefix.unwrap_or_else(|| "/static".to_owned()),
+            root: root.unwrap_or_else(|| PathBuf::from("./files")),
+            index: None,
+            confined: false,
+            write_token: None,
+            download: Vec::new(),
+            methods: Vec::new(),
+        });
+    }
+
+    info!("Binding to {}:{}", &opts.bind_address, opts.port);
+    let mut builder = Server::builder().bind(&opts.bind_address, opts.port);
+    match (
+        opts.tls_cert.or(config.tls_cert),
+        opts.tls_key.or(config.tls_key),
+    ) {
+        (Some(cert), Some(key)) => {
+            let tls_port = opts.tls_port.or(config.tls_port).unwrap_or(443);
+            info!("Binding HTTPS to {}:{}", &opts.bind_address, tls_port);
+            let mut tls = TlsConfig::from_pem_files(cert, key)?;
+            let min_version = opts.tls_min_version.or(config.tls_min_version);
+            let max_version = opts.tls_max_version.or(config.tls_max_version);
+            if min_version.is_some() || max_version.is_some() {
+                tls = tls.versions(
+                    min_version.unwrap_or(TlsVersion::Tls12),
+                    max_version.unwrap_or(TlsVersion::Tls13),
+                )?;
+            }
+            let ciphers = if opts.tls_ciphers.is_empty() {
+                config.tls_ciphers
+            } else {
+                opts.tls_ciphers
+            };
+            if !ciphers.is_empty() {
+                tls = tls.cipher_suites(&ciphers)?;
+            }
+            if let Some(ocsp) = opts.tls_ocsp.or(config.tls_ocsp) {
+                tls = tls.staple_ocsp(ocsp)?;
+            }
+            let reload_interval = opts
+                .tls_reload_interval
+                .or(config.tls_reload_interval)
+                .map(Duration::from_secs);
+            if let Some(interval) = reload_interval {
+                tls = tls.watch_files(interval);
+            }
+            let mut tls_hosts = config.tls_hosts;
+            for tls_host in opts.tls_hosts {
+                tls_hosts.insert(tls_host.host.clone(), tls_host);
+            }
+            for (host, tls_host) in &tls_hosts {
+                info!(
+                    "Serving HTTPS for {} with {}",
+                    host,
+                    tls_host.cert.display()
+                );
+                let mut host_tls = tls_host.tls_config()?;
+                if let Some(interval) = reload_interval {
+                    host_tls = host_tls.watch_files(interval);
+                }
+                tls = tls.host(host, host_tls);
+            }
+            builder = builder.bind_tls(&opts.bind_address, tls_port, tls);
+        }
+        (None, None) => {}
+        _ => return Err("--tls-cert and --tls-key must be given together".into()),
+    }
+    if let Some(max) = opts.max_connections.or(config.max_connections) {
+        builder = builder.max_connections(max);
+    }
+    if let Some(max) = opts.max_per_ip.or(config.max_per_ip) {
+        builder = builder.max_connections_per_ip(max);
+    }
+    if let Some(seconds) = opts.request_timeout.or(config.request_timeout) {
+        builder = builder.request_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(max_pending) = opts.shed_above.or(config.shed_above) {
+        builder = builder.shed_load_above(max_pending, Duration::from_secs(1));
+    }
+    if opts.tcp_fast_open || config.tcp_fast_open {
+        builder = builder.tcp_fast_open(FAST_OPEN_QUEUE);
+    }
+    let mut parse_options = ParseOptions::new();
+    if opts.lenient_line_endings || config.lenient_line_endings {
+        parse_options = parse_options.lenient_line_endings();
+    }
+    if opts.http09 || config.http09 {
+        parse_options = parse_options.http09();
+    }
+    if let Some(bytes) = opts.max_body.or(config.max_body) {
+        parse_options = parse_options.max_body(bytes);
+    }
+    builder = builder.parse_options(parse_options);
+    let fd_headroom = opts.fd_headroom.or(config.fd_headroom);
+    builder = builder.fd_headroom(fd_headroom.unwrap_or(DEFAULT_FD_HEADROOM));
+    if let Some(retries) = opts.bind_retries.or(config.bind_retries) {
+        builder = builder.bind_retry(BindRetry::new(retries, BIND_RETRY_DELAY));
+    }
+    if opts.transparent || config.transparent {
+        builder = builder.transparent();
+    }
+    let allowed_methods = if opts.allowed_methods.is_empty() {
+        config.allowed_methods
+    } else {
+        opts.allowed_methods
+    };
+    if !allowed_methods.is_empty() {
+        info!("Only serving {:?} requests", allowed_methods);
+        builder = builder.middleware(AllowedMethods::new(&allowed_methods).not_implemented());
+    }
+    let geoip_databases = if opts.geoip_databases.is_empty() {
+        config.geoip_databases
+    } else {
+        opts.geoip_databases
+    };
+    if !geoip_databases.is_empty() {
+        let mut geoip = GeoIp::new();
+        for path in &geoip_databases {
+            info!("Looking up client locations in {}", path.display());
+            geoip = geoip.database(path)?;
+        }
+        builder = builder.geoip(geoip);
+
+        let mut rules = GeoRules::new();
+        for country in config.allow_countries.iter().chain(&opts.allowed_countries) {
+            rules = rules.allow_country(country);
+        }
+        for country in config.deny_countries.iter().chain(&opts.denied_countries) {
+            rules = rules.deny_country(country);
+        }
+        for asn in config.deny_asns.iter().chain(&opts.denied_asns) {
+            rules = rules.deny_asn(*asn);
+        }
+        for (country, per_minute) in &config.country_per_minute {
+            rules = rules.country_per_minute(country, *per_minute);
+        }
+        builder = builder.middleware(rules);
+    }
+    if !config.path_methods.is_empty() {
+        let policy = config
+            .path_methods
+            .iter()
+            .fold(MethodPolicy::new(), |policy, (prefix, methods)| {
+                policy.prefix(prefix, methods)
+            });
+        builder = builder.middleware(policy);
+    }
+    let mut redaction = Redaction::new()
+        .mask_client(
+            opts.mask_client
+                .or(config.mask_client)
+                .unwrap_or(Mask::Keep),
+        )
+        .mask_path(opts.mask_path.or(config.mask_path).unwrap_or(Mask::Keep));
+    for header in config.redact_headers.iter().chain(&opts.redact_headers) {
+        redaction = redaction.header(header);
+    }
+    for field in config
+        .redact_body_fields
+        .iter()
+        .chain(&opts.redact_body_fields)
+    {
+        redaction = redaction.body_field(field);
+    }
+    let log_bodies = if opts.log_bodies.is_empty() {
+        config.log_bodies
+    } else {
+        opts.log_bodies
+    };
+    if !log_bodies.is_empty() {
+        let max_body = opts
+            .log_body_bytes
+            .or(config.log_body_bytes)
+            .unwrap_or(DEFAULT_LOG_BODY_BYTES);
+        let mut body_log = BodyLog::new(redaction.clone().max_body(max_body));
+        for prefix in &log_bodies {
+            info!("Logging bodies of requests under {}", prefix);
+            body_log = body_log.path_prefix(prefix);
+        }
+        builder = builder.middleware(body_log);
+    }
+    builder = builder.redact(redaction);
+    if opts.w3c_log || config.w3c_log {
+        let fields = if opts.w3c_fields.is_empty() {
+            config.w3c_fields
+        } else {
+            opts.w3c_fields
+        };
+        let log = if fields.is_empty() {
+            W3cLog::default()
+        } else {
+            W3cLog::new(&fields)?
+        };
+        let directory = opts
+            .w3c_log_dir
+            .or(config.w3c_log_dir)
+            .or(opts.log_dir)
+            .unwrap_or_else(|| PathBuf::from("./logs"));
+        info!("Writing the W3C access log to {}", directory.display());
+        builder = builder.w3c_access_log(log.directory(directory));
+    }
+    let api_key_file = opts.api_key_file.or(config.api_key_file);
+    if api_key_file.is_some() || !config.api_keys.is_empty() {
+        let mut keys = ApiKeys::new();
+        if let Some(header) = opts.api_key_header.or(config.api_key_header) {
+            keys = keys.header(&header);
+        }
+        if let Some(param) = opts.api_key_query.or(config.api_key_query) {
+            keys = keys.query(&param);
+        }
+        for key in &config.api_keys {
+            keys = match key.per_minute {
+                Some(per_minute) => keys.limited_key(&key.key, per_minute),
+                None => keys.key(&key.key),
+            };
+        }
+        if let Some(path) = &api_key_file {
+            info!("Reading API keys from {}", path.display());
+            keys = keys.keys_from_file(path)?;
+        }
+        builder = builder.middleware(keys);
+    }
+    let statsd = match (opts.statsd, config.statsd) {
+        (Some(address), Some(statsd)) => Some(StatsdConfig { address, ..statsd }),
+        (Some(address), None) => Some(StatsdConfig {
+            address,
+            prefix: None,
+            interval: None,
+            dogstatsd: false,
+        }),
+        (None, statsd) => statsd,
+    };
+    if let Some(statsd) = &statsd {
+        info!("Sending StatsD metrics to {}", statsd.address);
+        builder = builder.statsd(statsd.exporter());
+    }
+    let mirror = match (opts.mirror, config.mirror) {
+        (Some(address), Some(mirror)) => Some(MirrorConfig { address, ..mirror }),
+        (Some(address), None) => Some(MirrorConfig {
+            address,
+            percent: None,
+            path_prefixes: Vec::new(),
+        }),
+        (None, mirror) => mirror,
+    };
+    if let Some(mirror) = &mirror {
+        info!("Mirroring requests to {}", mirror.address);
+        builder = builder.middleware(mirror.mirror());
+    }
+    if let Some(path) = &opts.record {
+        info!("Recording traffic to {}", path.display());
+        builder = builder.record_traffic(TrafficLog::create(path)?);
+    }
+    if let Some(dir) = &opts.capture_dir {
+        info!("Capturing raw requests to {}", dir.display());
+        let mut capture = RequestCapture::new(dir);
+        if let Some(prefix) = &opts.capture_path {
+            capture = capture.path_prefix(prefix);
+        }
+        if let Some(ip) = opts.capture_ip {
+            capture = capture.client_ip(ip);
+        }
+        builder = builder.capture_requests(capture);
+    }
+    if !config.preload.is_empty() {
+        let mut preload = Preload::new();
+        for (prefix, links) in &config.preload {
+            for link in links {
+                preload = preload.link(prefix, link);
+            }
+        }
+        builder = builder.middleware(preload);
+    }
+    let admin_port = opts.admin_port.or(config.admin_port);
+    if let Some(port) = admin_port {
+        info!("Serving admin endpoints on 127.0.0.1:{}", port);
+        builder = builder.admin("127.0.0.1", port);
+    }
+    let admin_token = opts.admin_token.or(config.admin_token);
+    if !config.runtime_mounts.is_empty() || admin_token.is_some() {
+        let mut runtime_mounts = RuntimeMounts::new().index(&index);
+        for mount in &config.runtime_mounts {
+            info!("Serving {} under {}", mount.root.display(), &mount.prefix);
+            runtime_mounts.add(&mount.prefix, &mount.root)?;
+        }
+        if let Some(path) = opts.config.clone() {
+            runtime_mounts =
+                runtime_mounts.on_change(move |mounts| config::save_runtime_mounts(&path, mounts));
+        }
+        builder = match (&admin_token, admin_port) {
+            (Some(token), Some(_)) => builder.runtime_mounts(runtime_mounts, token),
+            _ => builder.middleware(runtime_mounts),
+        };
+    }
+    for mount in &mounts {
+        info!("Serving {} under {}", mount.root.display(), &mount.prefix);
+        let files = mount.static_files(&index);
+        builder = if mount.methods.is_empty() {
+            builder.mount(&mount.prefix, files)
+        } else {
+            builder.mount(
+                &mount.prefix,
+                AllowedMethods::new(&mount.methods).wrap(files),
+            )
+        };
+    }
+    if !hosts.is_empty() {
+        let mut host_roots = Hosts::new();
+        for (host, root) in &hosts {
+            info!("Serving {} for host {}", root.display(), host);
+            let files = StaticFiles::new(root).index(&index);
+            host_roots = match config.host_quotas.get(host) {
+                Some(quota) => host_roots.host(host, quota.quota().wrap(files)),
+                None => host_roots.host(host, files),
+            };
+        }
+        builder = builder.mount("/", host_roots);
     }
 
-    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n".to_string()
+    Ok(builder)
 }
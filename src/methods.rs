@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use rust_http_parse::{HttpMethod, HttpRequest};
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next};
+
+/// Only lets requests with the given methods through, answering anything
+/// else with 405 Method Not Allowed and an Allow header listing them. Use
+/// `not_implemented` to answer 501 instead, for a server-wide restriction
+/// such as GET and HEAD only on a static deployment. Add it as middleware,
+/// or `wrap` a mount's handler to restrict just that mount.
+#[derive(Debug, Clone)]
+pub struct AllowedMethods {
+    methods: Vec<HttpMethod>,
+    status: u16,
+}
+impl AllowedMethods {
+    pub fn new(methods: &[HttpMethod]) -> Self {
+        AllowedMethods {
+            methods: methods.to_vec(),
+            status: 405,
+        }
+    }
+
+    /// Answers other methods with 501 Not Implemented.
+    pub fn not_implemented(mut self) -> Self {
+        self.status = 501;
+        self
+    }
+
+    /// Restricts just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        RestrictedHandler {
+            allowed: self,
+            handler: Arc::new(handler),
+        }
+    }
+
+    fn reject(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if self.methods.contains(&request.method) {
+            return None;
+        }
+        let allow = self
+            .methods
+            .iter()
+            .map(|method| format!("{:?}", method))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some(HttpResponse::new(self.status).with_header("Allow", &allow))
+    }
+}
+
+impl Middleware for AllowedMethods {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        match self.reject(&request) {
+            Some(rejection) => Box::pin(async { rejection }),
+            None => next.run(request),
+        }
+    }
+}
+
+/// Which methods are allowed under each path prefix, such as GET and POST
+/// under `/api` but only GET and HEAD under `/static`, checked for every
+/// request before its handler runs. The longest prefix that matches whole
+/// path segments decides; paths under no prefix are left alone. Requests
+/// with other methods get a 405 listing the allowed ones.
+#[derive(Debug, Clone, Default)]
+pub struct MethodPolicy {
+    prefixes: Vec<(String, AllowedMethods)>,
+}
+impl MethodPolicy {
+    pub fn new() -> Self {
+        MethodPolicy::default()
+    }
+
+    /// Allows only `methods` for paths under `prefix`.
+    pub fn prefix(mut self, prefix: &str, methods: &[HttpMethod]) -> Self {
+        let prefix = prefix.trim_end_matches('/').to_owned();
+        self.prefixes.retain(|(existing, _)| *existing != prefix);
+        self.prefixes.push((prefix, AllowedMethods::new(methods)));
+        self.prefixes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self
+    }
+
+    fn allowed(&self, path: &str) -> Option<&AllowedMethods> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            })
+            .map(|(_, allowed)| allowed)
+    }
+}
+
+impl Middleware for MethodPolicy {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        match self
+            .allowed(&request.path)
+            .and_then(|allowed| allowed.reject(&request))
+        {
+            Some(rejection) => Box::pin(async { rejection }),
+            None => next.run(request),
+        }
+    }
+}
+
+struct RestrictedHandler<H> {
+    allowed: AllowedMethods,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for RestrictedHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        match self.allowed.reject(&request) {
+            Some(rejection) => Box::pin(async { rejection }),
+            None => self.handler.call(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+
+    fn ok() -> impl Handler {
+        |_| async { HttpResponse::ok() }
+    }
+
+    #[tokio::test]
+    async fn answers_other_methods_with_405_or_501() {
+        let read_only = AllowedMethods::new(&[HttpMethod::GET, HttpMethod::HEAD]);
+        let router = Router::new()
+            .mount("/files", read_only.clone().wrap(ok()))
+            .mount("/", ok())
+            .middleware(
+                AllowedMethods::new(&[HttpMethod::GET, HttpMethod::HEAD, HttpMethod::PUT])
+                    .not_implemented(),
+            );
+        let send = |method, target: &str| router.dispatch(HttpRequest::new(method, target));
+
+        let get = send(HttpMethod::GET, "/files/a.txt").await;
+        let put_file = send(HttpMethod::PUT, "/files/a.txt").await;
+        let put_other = send(HttpMethod::PUT, "/other").await;
+        let delete = send(HttpMethod::DELETE, "/other").await;
+
+        assert_eq!(200, get.status);
+        assert_eq!(405, put_file.status);
+        assert_eq!(Some(&"GET, HEAD".to_owned()), put_file.header("Allow"));
+        assert_eq!(200, put_other.status);
+        assert_eq!(501, delete.status);
+    }
+
+    #[tokio::test]
+    async fn longest_matching_prefix_decides() {
+        let router = Router::new().mount("/", ok()).middleware(
+            MethodPolicy::new()
+                .prefix("/api", &[HttpMethod::GET, HttpMethod::POST])
+                .prefix("/api/admin/", &[HttpMethod::GET])
+                .prefix("/static", &[HttpMethod::GET, HttpMethod::HEAD]),
+        );
+        let send = |method, target: &str| router.dispatch(HttpRequest::new(method, target));
+
+        let post_api = send(HttpMethod::POST, "/api/users").await;
+        let post_admin = send(HttpMethod::POST, "/api/admin").await;
+        let post_static = send(HttpMethod::POST, "/static/a.css").await;
+        let post_lookalike = send(HttpMethod::POST, "/apis").await;
+        let delete_other = send(HttpMethod::DELETE, "/other").await;
+
+        assert_eq!(200, post_api.status);
+        assert_eq!(405, post_admin.status);
+        assert_eq!(Some(&"GET".to_owned()), post_admin.header("Allow"));
+        assert_eq!(405, post_static.status);
+        assert_eq!(Some(&"GET, HEAD".to_owned()), post_static.header("Allow"));
+        assert_eq!(200, post_lookalike.status);
+        assert_eq!(200, delete_other.status);
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::BTreeMap;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rust_http_parse::HttpRequest;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::router::MatchedRoute;
+use super::{BoxFuture, HttpResponse, Middleware, Next};
+
+/// Request counts and latencies for a single route.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteStats {
+    pub requests: u64,
+    /// Responses with a 5xx status.
+    pub server_errors: u64,
+    /// Responses by status code.
+    pub statuses: BTreeMap<u16, u64>,
+    /// Time from the route being matched to its response being ready.
+    pub latency: Histogram,
+}
+
+/// Running totals over every connection the server has handled. Rates such
+/// as connections opened per second come from sampling these twice.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub opened: u64,
+    pub closed: u64,
+    /// Bytes received and sent on the wire, including any TLS overhead.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub requests: u64,
+    /// Failed accepts, which don't count as opened connections.
+    pub accept_errors: u64,
+    /// Responses cut short because the client closed or reset the connection.
+    pub client_aborts: u64,
+}
+
+/// Upper bounds, in microseconds, of the latency histogram buckets. A final
+/// bucket catches everything slower.
+pub const LATENCY_BUCKETS_MICROS: [u64; 10] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+
+/// Time spent in each phase of handling one request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestTiming {
+    pub headers: Duration,
+    pub body: Duration,
+    pub handler: Duration,
+    pub write: Duration,
+}
+impl RequestTiming {
+    fn phases(&self) -> [Duration; 4] {
+        [self.headers, self.body, self.handler, self.write]
+    }
+}
+
+/// The phases of handling a request, in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Headers,
+    Body,
+    Handler,
+    Write,
+}
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Headers, Phase::Body, Phase::Handler, Phase::Write];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Headers => "headers",
+            Phase::Body => "body",
+            Phase::Handler => "handler",
+            Phase::Write => "write",
+        }
+    }
+}
+
+/// A snapshot of a latency histogram. `buckets[i]` counts samples no
+/// slower than `LATENCY_BUCKETS_MICROS[i]`, but slower than the bucket
+/// before; the last entry counts the rest.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_micros: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        self.buckets.resize(LATENCY_BUCKETS_MICROS.len() + 1, 0);
+        self.buckets[bucket_for(micros)] += 1;
+        self.count += 1;
+        self.sum_micros += micros;
+    }
+}
+
+fn bucket_for(micros: u64) -> usize {
+    LATENCY_BUCKETS_MICROS
+        .iter()
+        .position(|bound| micros <= *bound)
+        .unwrap_or(LATENCY_BUCKETS_MICROS.len())
+}
+
+#[derive(Default)]
+struct AtomicHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+impl AtomicHistogram {
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        self.buckets[bucket_for(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Live counters for a running server, reported by the admin endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    active_connections: AtomicUsize,
+    requests_in_flight: AtomicUsize,
+    routes: Mutex<BTreeMap<String, RouteStats>>,
+    phases: [AtomicHistogram; 4],
+    opened: AtomicU64,
+    closed: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    requests: AtomicU64,
+    accept_errors: AtomicU64,
+    client_aborts: AtomicU64,
+    connection_lifetime: AtomicHistogram,
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn requests_in_flight(&self) -> usize {
+        self.requests_in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Counters keyed by route pattern, e.g. `GET /users/:id`, rather than
+    /// by path, so there's one entry per route however many IDs are used.
+    pub fn routes(&self) -> BTreeMap<String, RouteStats> {
+        self.routes.lock().unwrap().clone()
+    }
+
+    /// Latencies of one phase across all requests so far.
+    pub fn phase_latency(&self, phase: Phase) -> Histogram {
+        self.phases[phase as usize].snapshot()
+    }
+
+    pub fn connections(&self) -> ConnectionStats {
+        ConnectionStats {
+            opened: self.opened.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            client_aborts: self.client_aborts.load(Ordering::Relaxed),
+        }
+    }
+
+    /// How long connections stayed open, from accept to close.
+    pub fn connection_lifetime(&self) -> Histogram {
+        self.connection_lifetime.snapshot()
+    }
+
+    pub(crate) fn record_timing(&self, timing: &RequestTiming) {
+        for (histogram, duration) in self.phases.iter().zip(timing.phases().iter()) {
+            histogram.record(*duration);
+        }
+    }
+
+    /// Counts a connection as active until the returned guard is dropped,
+    /// which also adds its traffic and lifetime to the totals.
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.opened.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: self.clone(),
+            opened: Instant::now(),
+            counts: Arc::default(),
+        }
+    }
+
+    pub(crate) fn accept_failed(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_aborted(&self) {
+        self.client_aborts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record(&self, route: &str, status: u16, latency: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry(route.to_owned()).or_default();
+        stats.requests += 1;
+        if status >= 500 {
+            stats.server_errors += 1;
+        }
+        *stats.statuses.entry(status).or_insert(0) += 1;
+        stats.latency.record(latency);
+    }
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    requests: AtomicU64,
+}
+
+pub(crate) struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    opened: Instant,
+    counts: Arc<ConnectionCounts>,
+}
+impl ConnectionGuard {
+    /// Wraps the connection's stream so the bytes through it are counted.
+    pub(crate) fn count<S>(&self, stream: S) -> CountingStream<S> {
+        CountingStream {
+            inner: stream,
+            counts: self.counts.clone(),
+        }
+    }
+
+    pub(crate) fn served(&self, requests: u64) {
+        self.counts.requests.fetch_add(requests, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let lifetime = self.opened.elapsed();
+        let bytes_read = self.counts.bytes_read.load(Ordering::Relaxed);
+        let bytes_written = self.counts.bytes_written.load(Ordering::Relaxed);
+        let requests = self.counts.requests.load(Ordering::Relaxed);
+        debug!(
+            "Connection closed after {:?}: {} requests, {} bytes read, {} bytes written",
+            lifetime, requests, bytes_read, bytes_written
+        );
+
+        let metrics = &self.metrics;
+        metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+        metrics.closed.fetch_add(1, Ordering::Relaxed);
+        metrics.bytes_read.fetch_add(bytes_read, Ordering::Relaxed);
+        metrics
+            .bytes_written
+            .fetch_add(bytes_written, Ordering::Relaxed);
+        metrics.requests.fetch_add(requests, Ordering::Relaxed);
+        metrics.connection_lifetime.record(lifetime);
+    }
+}
+
+/// A stream that counts the bytes read from and written to it into the
+/// connection it belongs to.
+pub(crate) struct CountingStream<S> {
+    inner: S,
+    counts: Arc<ConnectionCounts>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.counts.bytes_read.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.count_written(&result);
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.count_written(&result);
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> CountingStream<S> {
+    fn count_written(&self, result: &Poll<std::io::Result<usize>>) {
+        if let Poll::Ready(Ok(written)) = result {
+            self.counts
+                .bytes_written
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Middleware counting requests in flight, and requests and their latency
+/// per matched route.
+pub(crate) struct RecordMetrics(pub Arc<Metrics>);
+
+impl Middleware for RecordMetrics {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        let metrics = self.0.clone();
+        let route = request
+            .extensions()
+            .get::<MatchedRoute>()
+            .map(|matched| matched.0.clone());
+
+        Box::pin(async move {
+            metrics.requests_in_flight.fetch_add(1, Ordering::Relaxed);
+            let started = Instant::now();
+            let response = next.run(request).await;
+            let latency = started.elapsed();
+            metrics.requests_in_flight.fetch_sub(1, Ordering::Relaxed);
+
+            if let Some(route) = route {
+                metrics.record(&route, response.status, latency);
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+
+    #[tokio::test]
+    async fn counts_requests_per_matched_route() {
+        let metrics = Arc::new(Metrics::new());
+        let router = Router::new()
+            .route(HttpMethod::GET, "/users/:id", |_| async {
+                HttpResponse::ok()
+            })
+            .route(HttpMethod::POST, "/users", |_| async {
+                HttpResponse::new(500)
+            })
+            .middleware(RecordMetrics(metrics.clone()));
+
+        for path in &["/users/1", "/users/2", "/missing"] {
+            router
+                .dispatch(HttpRequest::new(HttpMethod::GET, path))
+                .await;
+        }
+        router
+            .dispatch(HttpRequest::new(HttpMethod::POST, "/users"))
+            .await;
+
+        let routes = metrics.routes();
+        assert_eq!(2, routes.len());
+        let users = &routes["GET /users/:id"];
+        assert_eq!(2, users.requests);
+        assert_eq!(0, users.server_errors);
+        assert_eq!(Some(&2), users.statuses.get(&200));
+        assert_eq!(2, users.latency.count);
+        assert_eq!(
+            LATENCY_BUCKETS_MICROS.len() + 1,
+            users.latency.buckets.len()
+        );
+        assert_eq!(1, routes["POST /users"].server_errors);
+        assert_eq!(Some(&1), routes["POST /users"].statuses.get(&500));
+        assert_eq!(0, metrics.requests_in_flight());
+    }
+
+    #[test]
+    fn buckets_phase_latencies() {
+        let metrics = Metrics::new();
+        let timing = RequestTiming {
+            headers: Duration::from_micros(50),
+            body: Duration::from_micros(0),
+            handler: Duration::from_millis(7),
+            write: Duration::from_secs(10),
+        };
+
+        metrics.record_timing(&timing);
+        metrics.record_timing(&timing);
+
+        let handler = metrics.phase_latency(Phase::Handler);
+        assert_eq!(2, handler.count);
+        assert_eq!(14_000, handler.sum_micros);
+        assert_eq!(2, handler.buckets[4]);
+        assert_eq!(2, metrics.phase_latency(Phase::Headers).buckets[0]);
+        assert_eq!(
+            2,
+            metrics.phase_latency(Phase::Write).buckets[LATENCY_BUCKETS_MICROS.len()]
+        );
+    }
+
+    #[test]
+    fn connection_guard_tracks_active_connections() {
+        let metrics = Arc::new(Metrics::new());
+
+        let first = metrics.connection_opened();
+        let second = metrics.connection_opened();
+        drop(first);
+
+        assert_eq!(1, metrics.active_connections());
+        drop(second);
+        assert_eq!(0, metrics.active_connections());
+    }
+
+    #[tokio::test]
+    async fn totals_traffic_and_lifetime_of_closed_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let metrics = Arc::new(Metrics::new());
+        let connection = metrics.connection_opened();
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = client;
+        let mut server = connection.count(server);
+
+        client.write_all(b"request").await.unwrap();
+        let mut request = [0; 7];
+        server.read_exact(&mut request).await.unwrap();
+        server.write_all(b"response!").await.unwrap();
+        connection.served(1);
+        assert_eq!(0, metrics.connections().closed);
+        drop(connection);
+
+        assert_eq!(
+            ConnectionStats {
+                opened: 1,
+                closed: 1,
+                bytes_read: 7,
+                bytes_written: 9,
+                requests: 1,
+                accept_errors: 0,
+                client_aborts: 0,
+            },
+            metrics.connections()
+        );
+        assert_eq!(1, metrics.connection_lifetime().count);
+    }
+}
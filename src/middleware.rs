@@ -0,0 +1,90 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, Handler, HttpResponse};
+
+/// Wraps request handling, e.g. to check auth, rewrite the request, or
+/// decorate the response. Call `next.run(request)` to pass the request on to
+/// the rest of the chain, or return a response directly to short-circuit it.
+/// Implemented for async closures taking the request and `Next`.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse>;
+
+    /// The largest body the middleware lets through for `request`, as
+    /// `Handler::max_body`.
+    fn max_body(&self, _request: &HttpRequest) -> Option<usize> {
+        None
+    }
+}
+
+impl<F, R> Middleware for F
+where
+    F: Fn(HttpRequest, Next) -> R + Send + Sync + 'static,
+    R: Future<Output = HttpResponse> + Send + 'static,
+{
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        Box::pin(self(request, next))
+    }
+}
+
+pub(crate) type MiddlewareChain = Arc<Vec<Arc<dyn Middleware>>>;
+
+/// The remainder of a middleware chain, ending in the matched handler.
+pub struct Next {
+    chain: MiddlewareChain,
+    index: usize,
+    endpoint: Arc<dyn Handler>,
+}
+impl Next {
+    pub(crate) fn new(chain: MiddlewareChain, endpoint: Arc<dyn Handler>) -> Self {
+        Next {
+            chain,
+            index: 0,
+            endpoint,
+        }
+    }
+
+    pub fn run(mut self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                self.index += 1;
+                middleware.handle(request, self)
+            }
+            None => self.endpoint.call(request),
+        }
+    }
+}
+
+/// A handler with a group's middleware baked in, used when nesting routers.
+pub(crate) struct Layered {
+    chain: MiddlewareChain,
+    handler: Arc<dyn Handler>,
+}
+impl Layered {
+    pub fn wrap(chain: &MiddlewareChain, handler: Arc<dyn Handler>) -> Arc<dyn Handler> {
+        if chain.is_empty() {
+            return handler;
+        }
+
+        Arc::new(Layered {
+            chain: chain.clone(),
+            handler,
+        })
+    }
+}
+
+impl Handler for Layered {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        Next::new(self.chain.clone(), self.handler.clone()).run(request)
+    }
+
+    fn max_body(&self, request: &HttpRequest) -> Option<usize> {
+        self.chain
+            .iter()
+            .filter_map(|middleware| middleware.max_body(request))
+            .chain(self.handler.max_body(request))
+            .min()
+    }
+}
@@ -0,0 +1,286 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use rust_http_parse::HttpRequest;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// Headers describing the original connection or body framing, which the
+/// copy sets for itself.
+const NOT_MIRRORED: [&str; 3] = ["content-length", "transfer-encoding", "connection"];
+
+/// Copies a share of requests to a shadow upstream, for trying a new backend
+/// with real traffic. Copies are sent over plain HTTP from a separate task
+/// once the request arrives, and whatever the shadow answers is thrown away,
+/// so clients never wait on it or see its responses. `percent` of the
+/// requests are copied, spread evenly; only requests whose path starts with
+/// one of the `path_prefix`es are counted, or every request if none are
+/// given. Add it as middleware or `wrap` a single route's handler.
+///
+/// Each copy holds the whole request until the shadow answers or times out,
+/// so only 64 are kept in flight at once by default. Requests sampled while
+/// that many are outstanding aren't copied, and a slow shadow costs dropped
+/// copies rather than memory.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    address: String,
+    percent: u64,
+    prefixes: Vec<String>,
+    timeout: Duration,
+    in_flight: Arc<Semaphore>,
+    seen: Arc<AtomicU64>,
+}
+impl Mirror {
+    /// Copies every request to the upstream at `address`, given as
+    /// `host:port`.
+    pub fn new(address: &str) -> Self {
+        Mirror {
+            address: address.to_owned(),
+            percent: 100,
+            prefixes: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            in_flight: Arc::new(Semaphore::new(DEFAULT_MAX_IN_FLIGHT)),
+            seen: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Copies only `percent` of the requests, up to 100.
+    pub fn percent(mut self, percent: u8) -> Self {
+        self.percent = u64::from(percent.min(100));
+        self
+    }
+
+    /// Counts only requests whose path starts with `prefix`; call it again to
+    /// count more.
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.prefixes.push(prefix.to_owned());
+        self
+    }
+
+    /// How long a copy may take to send and answer before it's dropped; 10
+    /// seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many copies may be on their way to the shadow at once; 64 by
+    /// default.
+    pub fn max_in_flight(mut self, copies: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(copies));
+        self
+    }
+
+    /// Copies requests for just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        MirroredHandler {
+            mirror: self,
+            handler: Arc::new(handler),
+        }
+    }
+
+    fn mirror(&self, request: &HttpRequest) {
+        let matches = self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| request.path.starts_with(prefix));
+        if !matches || !self.sampled() {
+            return;
+        }
+        let request_line = format!("{:?} {}", request.method, request.path);
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("Too many copies in flight, not mirroring {}", request_line);
+                return;
+            }
+        };
+        let copy = raw_copy(request);
+        let address = self.address.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let _permit = permit;
+            match tokio::time::timeout(timeout, send(&address, &copy)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Mirroring {} to {} failed: {}", request_line, address, e),
+                Err(_) => debug!("Mirroring {} to {} timed out", request_line, address),
+            }
+        });
+    }
+
+    /// Whether the next request is one of the `percent` copied. Counting
+    /// requests rather than drawing random numbers spreads the copies evenly.
+    fn sampled(&self) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+        (seen + 1) * self.percent / 100 != seen * self.percent / 100
+    }
+}
+
+impl Middleware for Mirror {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        self.mirror(&request);
+        next.run(request)
+    }
+}
+
+struct MirroredHandler<H> {
+    mirror: Mirror,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for MirroredHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        self.mirror.mirror(&request);
+        self.handler.call(request)
+    }
+}
+
+/// The request as sent to the shadow, keeping the client's Host header so
+/// the shadow routes it like the original.
+fn raw_copy(request: &HttpRequest) -> Vec<u8> {
+    let mut head = format!("{:?} {}", request.method, request.raw_path());
+    if !request.raw_query().is_empty() {
+        head.push_str(&format!("?{}", request.raw_query()));
+    }
+    head.push_str(" HTTP/1.1\r\n");
+    for (name, value) in request.headers() {
+        let mirrored = !NOT_MIRRORED
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(name));
+        if mirrored {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        request.body().len()
+    ));
+    let mut copy = head.into_bytes();
+    copy.extend_from_slice(request.body());
+    copy
+}
+
+async fn send(address: &str, copy: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(copy).await?;
+    tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::{HttpMethod, HttpRequestBuilder};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Answers every copy with a 500, sending back what it received.
+    async fn shadow() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut received = vec![0; 4096];
+                let read = stream.read(&mut received).await.unwrap();
+                let _ = sender
+                    .send(String::from_utf8_lossy(&received[..read]).into_owned())
+                    .await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")
+                    .await;
+            }
+        });
+        (address, receiver)
+    }
+
+    fn router(mirror: Mirror) -> Router {
+        Router::new()
+            .route(HttpMethod::POST, "/api/orders", |_| async {
+                HttpResponse::new(201)
+            })
+            .route(HttpMethod::GET, "/health", |_| async { HttpResponse::ok() })
+            .middleware(mirror)
+    }
+
+    #[tokio::test]
+    async fn copies_matching_requests_and_ignores_the_answer() {
+        let (address, mut received) = shadow().await;
+        let router = router(Mirror::new(&address).path_prefix("/api"));
+        let request = HttpRequestBuilder::new()
+            .with_method(HttpMethod::POST)
+            .with_path("/api/orders?dry_run=1")
+            .with_header("Host", "shop.example")
+            .with_body("{}")
+            .build()
+            .unwrap();
+
+        let response = router.dispatch(request).await;
+        let health = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/health"))
+            .await;
+
+        assert_eq!(201, response.status);
+        assert_eq!(200, health.status);
+        let copy = received.recv().await.unwrap();
+        assert!(
+            copy.starts_with("POST /api/orders?dry_run=1 HTTP/1.1\r\n"),
+            "{}",
+            copy
+        );
+        assert!(copy.contains("Host: shop.example\r\n"));
+        assert!(copy.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\n{}"));
+    }
+
+    #[tokio::test]
+    async fn drops_copies_while_too_many_are_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, mut accepted) = mpsc::channel(16);
+        tokio::spawn(async move {
+            // Never answers, holding each copy until it times out.
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+                let _ = sender.send(()).await;
+            }
+        });
+        let router = router(Mirror::new(&address).max_in_flight(1));
+
+        for _ in 0..3 {
+            let health = router
+                .dispatch(HttpRequest::new(HttpMethod::GET, "/health"))
+                .await;
+            assert_eq!(200, health.status);
+        }
+
+        accepted.recv().await.unwrap();
+        let more = tokio::time::timeout(Duration::from_millis(100), accepted.recv()).await;
+        assert!(more.is_err());
+    }
+
+    #[test]
+    fn samples_the_given_share_evenly() {
+        let mirror = Mirror::new("127.0.0.1:1").percent(25);
+
+        let sampled: Vec<bool> = (0..8).map(|_| mirror.sampled()).collect();
+
+        assert_eq!(
+            vec![false, false, false, true, false, false, false, true],
+            sampled
+        );
+        assert!(!Mirror::new("127.0.0.1:1").percent(0).sampled());
+    }
+}
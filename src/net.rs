@@ -1,6 +1,15 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+
 use custom_error::custom_error;
-use log::debug;
-use tokio::net::{TcpListener, TcpStream};
+use log::{debug, warn};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream};
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(30);
+const LISTEN_BACKLOG: u32 = 1024;
 
 custom_error! {pub NetError
     NotOpened = "TCP stream used before opened",
@@ -10,6 +19,9 @@ custom_error! {pub NetError
 pub struct TcpRequestListener {
     address: String,
     port: u32,
+    fast_open_queue: Option<u32>,
+    bind_retry: BindRetry,
+    transparent: bool,
     listener: Option<TcpListener>,
 }
 
@@ -18,18 +30,72 @@ impl TcpRequestListener {
         TcpRequestListener {
             address: address.to_owned(),
             port,
+            fast_open_queue: None,
+            bind_retry: BindRetry::default(),
+            transparent: false,
             listener: None,
         }
     }
 
+    /// Retries binding as `retry` allows when the address is in use or not
+    /// available yet.
+    pub fn with_bind_retry(mut self, retry: BindRetry) -> Self {
+        self.bind_retry = retry;
+        self
+    }
+
+    /// Enables TCP Fast Open once the listener is opened, letting repeat
+    /// clients send their request in the SYN. `queue` caps the connections
+    /// still waiting to complete the handshake.
+    pub fn with_fast_open(mut self, queue: u32) -> Self {
+        self.fast_open_queue = Some(queue);
+        self
+    }
+
+    /// Sets IP_TRANSPARENT on the socket (Linux only, needing
+    /// CAP_NET_ADMIN), so connections that the firewall's TPROXY rules
+    /// redirect to it are accepted, whatever address they were sent to.
+    /// Their local address is then the one the client connected to.
+    pub fn with_transparent(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transparent
+    }
+
+    /// Binds the address with SO_REUSEADDR, so a port whose old connections
+    /// are still in TIME_WAIT can be bound straight away.
     pub async fn open(&mut self) -> Result<(), NetError> {
-        match TcpListener::bind(format!("{}:{}", self.address, self.port)).await {
-            Ok(opened) => {
-                self.listener = Some(opened);
-                return Ok(());
+        let mut retry = self.bind_retry;
+        let opened = loop {
+            match bind(&self.address, self.port, self.transparent).await {
+                Ok(opened) => break opened,
+                Err(e) => match retry.next_delay(&e) {
+                    Some(delay) => {
+                        warn!(
+                            "Could not bind {}:{}, retrying in {:?}: {}",
+                            self.address, self.port, delay, e
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => return Err(NetError::IoError { source: e }),
+                },
             }
-            Err(e) => Err(NetError::IoError { source: e }),
+        };
+        if let Some(queue) = self.fast_open_queue {
+            enable_fast_open(&opened, queue);
         }
+        self.listener = Some(opened);
+        Ok(())
+    }
+
+    /// Closes the listening socket and binds the address again, for a
+    /// listener that keeps failing to accept.
+    pub async fn reopen(&mut self) -> Result<(), NetError> {
+        self.listener = None;
+        self.open().await
     }
 
     pub async fn accept_request(&self) -> Result<TcpStream, NetError> {
@@ -46,3 +112,324 @@ impl TcpRequestListener {
         }
     }
 }
+
+/// Binds the first address `address` resolves to that can be bound.
+async fn bind(address: &str, port: u32, transparent: bool) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for address in lookup_host(format!("{}:{}", address, port)).await? {
+        match bind_reusable(address, transparent) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| ErrorKind::AddrNotAvailable.into()))
+}
+
+fn bind_reusable(address: SocketAddr, transparent: bool) -> std::io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // Windows lets another socket steal a port bound with SO_REUSEADDR.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if transparent {
+        enable_transparent(&socket, address)?;
+    }
+    socket.bind(address)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Turns on IP_TRANSPARENT, or IPV6_TRANSPARENT for an IPv6 socket. Unlike
+/// Fast Open, failing to is an error, since the listener would never see
+/// the traffic it's meant to intercept.
+#[cfg(target_os = "linux")]
+fn enable_transparent(socket: &TcpSocket, address: SocketAddr) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, option) = match address {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    let enabled: libc::c_int = 1;
+    // SAFETY: the descriptor is a live socket owned by `socket`, and the
+    // option value points to a c_int of the size given.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_transparent(_socket: &TcpSocket, _address: SocketAddr) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Other,
+        "transparent proxying is only supported on Linux",
+    ))
+}
+
+/// How often to retry binding a listener whose address is in use or not
+/// available yet, e.g. while its interface is still coming up. The pause
+/// starts at `delay` and doubles after each failure, up to 30 seconds.
+/// By default binding isn't retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BindRetry {
+    attempts: u32,
+    delay: Duration,
+}
+impl BindRetry {
+    pub fn new(attempts: u32, delay: Duration) -> Self {
+        BindRetry { attempts, delay }
+    }
+
+    /// The pause before trying again after `error`, or `None` if the error
+    /// won't go away by waiting or the attempts are used up.
+    fn next_delay(&mut self, error: &std::io::Error) -> Option<Duration> {
+        let transient = matches!(
+            error.kind(),
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable
+        );
+        if !transient || self.attempts == 0 {
+            return None;
+        }
+        self.attempts -= 1;
+        let delay = self.delay;
+        self.delay = (delay * 2).min(MAX_BIND_BACKOFF);
+        Some(delay)
+    }
+}
+
+impl Default for BindRetry {
+    fn default() -> Self {
+        BindRetry::new(0, Duration::from_millis(500))
+    }
+}
+
+/// Turns on TCP Fast Open for a listening socket. Failing to is only logged,
+/// since the listener works the same without it.
+#[cfg(target_os = "linux")]
+fn enable_fast_open(listener: &TcpListener, queue: u32) {
+    use std::os::unix::io::AsRawFd;
+
+    let queue = queue as libc::c_int;
+    // SAFETY: the descriptor is a live socket owned by `listener`, and the
+    // option value points to a c_int of the size given.
+    let result = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        warn!(
+            "Could not enable TCP Fast Open: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_fast_open(_listener: &TcpListener, _queue: u32) {
+    warn!("TCP Fast Open is only supported on Linux");
+}
+
+/// What a failed accept means for the accept loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AcceptFailure {
+    /// Only the connection being accepted was affected, e.g. the client
+    /// reset it while it sat in the backlog. Accepting again is fine.
+    Connection,
+    /// The process or system is out of file descriptors or memory. Retrying
+    /// straight away would spin until some are freed.
+    Exhausted,
+    /// Anything else, which is retried after a pause as well.
+    Other,
+}
+impl AcceptFailure {
+    pub(crate) fn classify(error: &std::io::Error) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) =
+                error.raw_os_error()
+            {
+                return AcceptFailure::Exhausted;
+            }
+        }
+        match error.kind() {
+            ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut => AcceptFailure::Connection,
+            ErrorKind::OutOfMemory => AcceptFailure::Exhausted,
+            _ => AcceptFailure::Other,
+        }
+    }
+}
+
+/// The pause before accepting again after an error, doubling from 10ms up
+/// to a second while errors continue and starting over after a success.
+#[derive(Debug, Default)]
+pub(crate) struct AcceptBackoff {
+    delay: Option<Duration>,
+    failures: u32,
+}
+impl AcceptBackoff {
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        self.failures += 1;
+        let delay = self.delay.map_or(MIN_ACCEPT_BACKOFF, |delay| {
+            (delay * 2).min(MAX_ACCEPT_BACKOFF)
+        });
+        self.delay = Some(delay);
+        delay
+    }
+
+    /// How many pauses there have been since the last success.
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.delay = None;
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_accept_errors() {
+        let reset = std::io::Error::from(ErrorKind::ConnectionAborted);
+        let other = std::io::Error::from(ErrorKind::PermissionDenied);
+
+        assert_eq!(AcceptFailure::Connection, AcceptFailure::classify(&reset));
+        assert_eq!(AcceptFailure::Other, AcceptFailure::classify(&other));
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            AcceptFailure::Exhausted,
+            AcceptFailure::classify(&std::io::Error::from_raw_os_error(libc::EMFILE))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn enables_fast_open_on_the_listener() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut listener = TcpRequestListener::new("127.0.0.1", 0).with_fast_open(16);
+        listener.open().await.unwrap();
+
+        let mut queue: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: both pointers are valid for the sizes given.
+        let result = unsafe {
+            libc::getsockopt(
+                listener.listener.as_ref().unwrap().as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN,
+                &mut queue as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(0, result);
+        assert_eq!(16, queue);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn sets_ip_transparent_on_the_listener() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut listener = TcpRequestListener::new("127.0.0.1", 0).with_transparent();
+        match listener.open().await {
+            Ok(()) => {}
+            // Without CAP_NET_ADMIN there's nothing more to check.
+            Err(NetError::IoError { source }) if source.kind() == ErrorKind::PermissionDenied => {
+                return
+            }
+            Err(e) => panic!("{}", e),
+        }
+
+        let mut enabled: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: both pointers are valid for the sizes given.
+        let result = unsafe {
+            libc::getsockopt(
+                listener.listener.as_ref().unwrap().as_raw_fd(),
+                libc::SOL_IP,
+                libc::IP_TRANSPARENT,
+                &mut enabled as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(0, result);
+        assert_eq!(1, enabled);
+    }
+
+    #[test]
+    fn retries_binding_only_while_the_address_is_unavailable() {
+        let in_use = std::io::Error::from(ErrorKind::AddrInUse);
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        let mut retry = BindRetry::new(3, Duration::from_secs(10));
+
+        assert_eq!(
+            None,
+            BindRetry::new(3, Duration::from_secs(10)).next_delay(&denied)
+        );
+        assert_eq!(Some(Duration::from_secs(10)), retry.next_delay(&in_use));
+        assert_eq!(Some(Duration::from_secs(20)), retry.next_delay(&in_use));
+        assert_eq!(Some(MAX_BIND_BACKOFF), retry.next_delay(&in_use));
+        assert_eq!(None, retry.next_delay(&in_use));
+    }
+
+    #[tokio::test]
+    async fn rebinds_a_port_with_connections_in_time_wait() {
+        let mut listener = TcpRequestListener::new("127.0.0.1", 0);
+        listener.open().await.unwrap();
+        let port = listener
+            .listener
+            .as_ref()
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        listener.port = port as u32;
+        let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let accepted = listener.accept_request().await.unwrap();
+        drop(accepted);
+        drop(client);
+
+        listener.reopen().await.unwrap();
+
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_ok());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_limit_and_resets() {
+        let mut backoff = AcceptBackoff::default();
+        let delays: Vec<_> = (0..9).map(|_| backoff.next_delay()).collect();
+
+        assert_eq!(Duration::from_millis(10), delays[0]);
+        assert_eq!(Duration::from_millis(20), delays[1]);
+        assert_eq!(Duration::from_millis(640), delays[6]);
+        assert_eq!(MAX_ACCEPT_BACKOFF, delays[8]);
+        assert_eq!(9, backoff.failures());
+        backoff.reset();
+        assert_eq!(0, backoff.failures());
+        assert_eq!(MIN_ACCEPT_BACKOFF, backoff.next_delay());
+    }
+}
@@ -0,0 +1,60 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Runs a future, turning a panic while it's polled into an `Err` holding
+/// the panic's message, so one broken handler can't take its connection's
+/// task down with it.
+pub(crate) struct CatchPanic<F>(pub F);
+
+impl<F: Future + Unpin> Future for CatchPanic<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The future isn't polled again after a panic, so state it left
+        // half-updated is never seen.
+        let inner = &mut self.0;
+        match catch_unwind(AssertUnwindSafe(|| Pin::new(inner).poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(panic_message(payload))),
+        }
+    }
+}
+
+/// The message a panic was raised with, for logging.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoxFuture, HttpResponse};
+
+    #[tokio::test]
+    async fn turns_panics_into_errors() {
+        let fine: BoxFuture<HttpResponse> = Box::pin(async { HttpResponse::ok() });
+        let broken: BoxFuture<HttpResponse> = Box::pin(async {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            let status: u16 = "".parse().expect("no status");
+            HttpResponse::new(status)
+        });
+        let formatted: BoxFuture<()> = Box::pin(async { panic!("status {}", 42) });
+
+        assert_eq!(200, CatchPanic(fine).await.unwrap().status);
+        assert_eq!(
+            Err("no status: ParseIntError { kind: Empty }".to_owned()),
+            CatchPanic(broken).await.map(|_| ())
+        );
+        assert_eq!(Err("status 42".to_owned()), CatchPanic(formatted).await);
+    }
+}
@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use log::info;
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, HttpResponse, Middleware, Next, ServerBuilder};
+
+/// An extension a downstream crate adds to the server as one unit: its
+/// routes, middleware and state, plus hooks run over the server's life.
+/// Every method but `name` has a default that does nothing, so a plugin
+/// implements only the hooks it needs. Add plugins with
+/// `ServerBuilder::plugins`.
+pub trait Plugin: Send + Sync + 'static {
+    /// Names the plugin in logs, and its section in the registry's config.
+    fn name(&self) -> &str;
+
+    /// Adds the plugin's routes, middleware and state while the server is
+    /// built. `config` is the plugin's config section, if one was given.
+    fn register(&self, builder: ServerBuilder, _config: Option<&toml::Value>) -> ServerBuilder {
+        builder
+    }
+
+    /// Runs once the listeners are bound, before any connection is accepted.
+    fn on_startup(&self) {}
+
+    /// Sees every request before it reaches the middleware added after the
+    /// plugins and the handler, e.g. to tag or rewrite it.
+    fn on_request(&self, request: HttpRequest) -> HttpRequest {
+        request
+    }
+
+    /// Sees every response on its way back, in the reverse order of
+    /// `on_request`.
+    fn on_response(&self, response: HttpResponse) -> HttpResponse {
+        response
+    }
+
+    /// Runs once the server stops serving.
+    fn on_shutdown(&self) {}
+}
+
+/// The plugins to add to a server, in order, with their config sections,
+/// such as the `[plugins]` table of an embedding application's config file.
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Arc<dyn Plugin>>,
+    config: toml::value::Table,
+}
+impl PluginRegistry {
+    pub fn new() -> Self {
+        PluginRegistry::default()
+    }
+
+    pub fn register<P: Plugin>(mut self, plugin: P) -> Self {
+        self.plugins.push(Arc::new(plugin));
+        self
+    }
+
+    /// Gives each plugin the entry of `sections` under its name.
+    pub fn config(mut self, sections: toml::value::Table) -> Self {
+        self.config = sections;
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.plugins.iter().map(|plugin| plugin.name())
+    }
+
+    /// Lets each plugin register itself with `builder`, returning the
+    /// builder and the plugins for the server's lifecycle hooks.
+    pub(crate) fn apply(self, mut builder: ServerBuilder) -> (ServerBuilder, Plugins) {
+        for plugin in &self.plugins {
+            info!("Registering plugin {}", plugin.name());
+            builder = plugin.register(builder, self.config.get(plugin.name()));
+        }
+        let plugins = Plugins(Arc::new(self.plugins));
+        (builder.middleware(plugins.clone()), plugins)
+    }
+}
+
+/// The registered plugins, run as middleware for their request and response
+/// hooks.
+#[derive(Clone)]
+pub(crate) struct Plugins(Arc<Vec<Arc<dyn Plugin>>>);
+impl Plugins {
+    pub(crate) fn start(&self) {
+        for plugin in self.0.iter() {
+            plugin.on_startup();
+        }
+    }
+
+    pub(crate) fn shut_down(&self) {
+        for plugin in self.0.iter().rev() {
+            plugin.on_shutdown();
+        }
+    }
+}
+
+impl Middleware for Plugins {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        let plugins = self.0.clone();
+        let request = plugins
+            .iter()
+            .fold(request, |request, plugin| plugin.on_request(request));
+        Box::pin(async move {
+            let response = next.run(request).await;
+            plugins
+                .iter()
+                .rev()
+                .fold(response, |response, plugin| plugin.on_response(response))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+    use std::sync::Mutex;
+
+    /// Serves its greeting at `/hello` and records the hooks it sees.
+    struct Greeter {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+    impl Plugin for Greeter {
+        fn name(&self) -> &str {
+            "greeter"
+        }
+
+        fn register(&self, builder: ServerBuilder, config: Option<&toml::Value>) -> ServerBuilder {
+            let greeting = config
+                .and_then(|config| config.get("greeting"))
+                .and_then(|greeting| greeting.as_str())
+                .unwrap_or("hello")
+                .to_owned();
+            builder.route(HttpMethod::GET, "/hello", move |_| {
+                let greeting = greeting.clone();
+                async move { HttpResponse::ok().with_body(greeting.into_bytes()) }
+            })
+        }
+
+        fn on_startup(&self) {
+            self.events.lock().unwrap().push("startup".to_owned());
+        }
+
+        fn on_request(&self, mut request: HttpRequest) -> HttpRequest {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("request {}", request.path));
+            request.set_header("X-Greeter", "1");
+            request
+        }
+
+        fn on_response(&self, response: HttpResponse) -> HttpResponse {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("response {}", response.status));
+            response.with_header("X-Greeted", "yes")
+        }
+
+        fn on_shutdown(&self) {
+            self.events.lock().unwrap().push("shutdown".to_owned());
+        }
+    }
+
+    #[tokio::test]
+    async fn plugins_add_routes_and_see_every_request() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let config: toml::value::Table = toml::from_str("[greeter]\ngreeting = \"hi\"").unwrap();
+        let registry = PluginRegistry::new()
+            .register(Greeter {
+                events: events.clone(),
+            })
+            .config(config);
+        assert_eq!(vec!["greeter"], registry.names().collect::<Vec<_>>());
+        let (builder, plugins) = registry.apply(ServerBuilder::new());
+
+        let server = builder.bind("127.0.0.1", 0).build().unwrap();
+        plugins.start();
+        let raw = server
+            .handle_raw(b"GET /hello HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        plugins.shut_down();
+        let response = HttpResponse::from_bytes(&raw).unwrap();
+
+        assert_eq!(b"hi", response.body());
+        assert_eq!(Some(&"yes".to_owned()), response.header("X-Greeted"));
+        assert_eq!(
+            vec!["startup", "request /hello", "response 200", "shutdown"],
+            *events.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::HttpResponse;
+
+/// More ranges than this in one request are ignored and the whole body is
+/// sent, as RFC 9110 §14.2 allows, rather than doing the work of many tiny
+/// parts.
+const MAX_RANGES: usize = 16;
+
+/// Answers a request for parts of `content` given its `Range` header value:
+/// a 206 with one part, a 206 `multipart/byteranges` with several, or a 416
+/// if none of the ranges overlap the content. Returns `None` when the whole
+/// body should be sent instead, e.g. for a malformed or unsupported header.
+pub(crate) fn respond(range: &str, content: &[u8]) -> Option<HttpResponse> {
+    let len = content.len() as u64;
+    let ranges = match parse(range, len)? {
+        Some(ranges) => ranges,
+        None => {
+            return Some(
+                HttpResponse::new(416).with_header("Content-Range", &format!("bytes */{}", len)),
+            )
+        }
+    };
+
+    if let [range] = ranges.as_slice() {
+        return Some(
+            HttpResponse::new(206)
+                .with_header("Content-Range", &content_range(range, len))
+                .with_body(content[range.start as usize..range.end as usize].to_vec()),
+        );
+    }
+
+    let boundary = boundary();
+    let mut body = Vec::new();
+    for range in &ranges {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_range(range, len)
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&content[range.start as usize..range.end as usize]);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Some(
+        HttpResponse::new(206)
+            .with_header(
+                "Content-Type",
+                &format!("multipart/byteranges; boundary={}", boundary),
+            )
+            .with_body(body),
+    )
+}
+
+/// Parses a `bytes=` range header against a body of `len` bytes. The outer
+/// `None` means the header should be ignored; `Some(None)` means it was valid
+/// but nothing it asks for exists. Overlapping or adjacent ranges are merged.
+fn parse(header: &str, len: u64) -> Option<Option<Vec<Range<u64>>>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    let mut specs = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .peekable();
+    specs.peek()?;
+    for spec in specs {
+        let (first, last) = spec.split_at(spec.find('-')?);
+        let last = &last[1..];
+        let range = match (first, last) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                len.saturating_sub(suffix)..len
+            }
+            (first, "") => first.parse().ok()?..len,
+            (first, last) => {
+                let first: u64 = first.parse().ok()?;
+                let last: u64 = last.parse().ok()?;
+                if last < first {
+                    return None;
+                }
+                first..last.saturating_add(1).min(len)
+            }
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    if ranges.is_empty() {
+        return Some(None);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Some(Some(merged))
+}
+
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// A boundary unlikely to appear in the content, unique within the process.
+fn boundary() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    format!(
+        "byteranges-{:016x}{:04x}",
+        nanos,
+        NEXT.fetch_add(1, Ordering::Relaxed) & 0xffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses against a 100 byte body, with ranges as `(start, end)` pairs.
+    fn parsed(header: &str) -> Option<Option<Vec<(u64, u64)>>> {
+        parse(header, 100).map(|ranges| {
+            ranges.map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|range| (range.start, range.end))
+                    .collect()
+            })
+        })
+    }
+
+    #[test]
+    fn parses_and_merges_ranges() {
+        assert_eq!(Some(Some(vec![(0, 10)])), parsed("bytes=0-9"));
+        assert_eq!(Some(Some(vec![(90, 100)])), parsed("bytes=-10"));
+        assert_eq!(Some(Some(vec![(95, 100)])), parsed("bytes=95-"));
+        assert_eq!(Some(Some(vec![(90, 100)])), parsed("bytes=90-200"));
+        assert_eq!(
+            Some(Some(vec![(0, 20), (50, 60)])),
+            parsed("bytes=50-59, 0-9, 5-19")
+        );
+        assert_eq!(Some(None), parsed("bytes=100-"));
+        assert_eq!(None, parsed("bytes=9-0"));
+        assert_eq!(None, parsed("items=0-9"));
+        assert_eq!(None, parsed("bytes=a-b"));
+        assert_eq!(None, parsed("bytes="));
+    }
+
+    #[test]
+    fn answers_one_range_with_a_single_part() {
+        let response = respond("bytes=2-4", b"0123456789").unwrap();
+
+        assert_eq!(206, response.status);
+        assert_eq!(
+            Some(&"bytes 2-4/10".to_owned()),
+            response.header("Content-Range")
+        );
+        assert_eq!(b"234", response.body());
+    }
+
+    #[test]
+    fn answers_several_ranges_with_multipart_byteranges() {
+        let response = respond("bytes=0-1,-2", b"0123456789").unwrap();
+        let content_type = response.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        assert_eq!(206, response.status);
+        assert_eq!(
+            format!(
+                "--{b}\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{b}\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n",
+                b = boundary
+            )
+            .as_bytes(),
+            response.body()
+        );
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        let response = respond("bytes=20-30", b"0123456789").unwrap();
+
+        assert_eq!(416, response.status);
+        assert_eq!(
+            Some(&"bytes */10".to_owned()),
+            response.header("Content-Range")
+        );
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::HashMap;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::error;
+use rust_http_parse::{Redacted, Redaction};
+use tokio::io::{duplex, AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+use super::Cookie;
+
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+enum Body {
+    Bytes(Vec<u8>),
+    Stream(DuplexStream),
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    headers: HashMap<String, String>,
+    /// Set-Cookie values, kept apart from `headers` since there may be
+    /// several.
+    cookies: Vec<String>,
+    body: Body,
+    /// Whether a handler answered with an error, for the error renderer.
+    error: bool,
+}
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        HttpResponse {
+            status,
+            headers: HashMap::new(),
+            cookies: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+            error: false,
+        }
+    }
+
+    /// Creates a response whose body is produced through the returned
+    /// `ResponseWriter` rather than buffered up front. The handler returns the
+    /// response straight away and writes from another task; writes wait while
+    /// the client is slower than the producer, and dropping the writer ends the
+    /// body. Without a Content-Length the body is delimited by closing the
+    /// connection.
+    pub fn streaming(status: u16) -> (Self, ResponseWriter) {
+        let (reader, writer) = duplex(STREAM_BUFFER_SIZE);
+        let response = HttpResponse {
+            status,
+            headers: HashMap::new(),
+            cookies: Vec::new(),
+            body: Body::Stream(reader),
+            error: false,
+        }
+        .with_header("Connection", "close");
+
+        (response, ResponseWriter { inner: writer })
+    }
+
+    pub fn ok() -> Self {
+        HttpResponse::new(200)
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::new(404)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Sets Retry-After to `wait`, rounded up so clients never come back early.
+    pub fn with_retry_after(self, wait: Duration) -> Self {
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        self.with_header("Retry-After", &seconds.to_string())
+    }
+
+    /// Adds a Set-Cookie header for `cookie`, alongside any others.
+    pub fn with_cookie(mut self, cookie: &Cookie) -> Self {
+        self.cookies.push(cookie.to_string());
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    /// Marks the response as made from a handler's error.
+    pub(crate) fn into_error(mut self) -> Self {
+        self.error = true;
+        self
+    }
+
+    pub(crate) fn is_error(&self) -> bool {
+        self.error
+    }
+
+    /// Whether the body is streamed rather than buffered.
+    pub(crate) fn is_streaming(&self) -> bool {
+        matches!(self.body, Body::Stream(_))
+    }
+
+    /// Turns the response into a 304 for a client whose cached copy is
+    /// current, keeping its headers but not its body.
+    pub(crate) fn into_not_modified(mut self) -> Self {
+        self.status = 304;
+        self.body = Body::Bytes(Vec::new());
+        self.headers
+            .retain(|name, _| !name.eq_ignore_ascii_case("content-length"));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers.get(name)
+    }
+
+    /// Every header, with each cookie as its own Set-Cookie header.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(
+                self.cookies
+                    .iter()
+                    .map(|cookie| ("Set-Cookie", cookie.as_str())),
+            )
+    }
+
+    /// Formats the response for logging, masking headers such as Set-Cookie
+    /// as `redaction` says.
+    pub fn redacted<'a>(&'a self, redaction: &'a Redaction) -> Redacted<'a> {
+        let status_line = format!("{} {}", self.status, self.reason_phrase());
+        redaction.message(status_line, self.headers(), self.body())
+    }
+
+    /// The declared Content-Length, looked up case-insensitively.
+    fn content_length(&self) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value)
+    }
+
+    /// Whether the status forbids a body, and so a Content-Length too.
+    fn is_bodiless(&self) -> bool {
+        self.status < 200 || self.status == 204 || self.status == 304
+    }
+
+    /// The buffered body; empty for streaming responses.
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            Body::Bytes(body) => body,
+            Body::Stream(_) => &[],
+        }
+    }
+
+    /// Parses a buffered response in HTTP/1.1 wire format, as written by
+    /// `to_bytes`. Everything after the head is taken as the body.
+    pub(crate) fn from_bytes(raw: &[u8]) -> Option<Self> {
+        let head_end = raw.windows(4).position(|window| window == b"\r\n\r\n")?;
+        let head = std::str::from_utf8(&raw[..head_end]).ok()?;
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()?
+            .strip_prefix("HTTP/1.1 ")?
+            .get(..3)?
+            .parse()
+            .ok()?;
+
+        let mut response = HttpResponse::new(status).with_body(raw[head_end + 4..].to_vec());
+        for line in lines {
+            let colon = line.find(':')?;
+            let (name, value) = (&line[..colon], line[colon + 1..].trim());
+            if name.eq_ignore_ascii_case("Set-Cookie") {
+                response.cookies.push(value.to_owned());
+            } else {
+                response = response.with_header(name, value);
+            }
+        }
+        Some(response)
+    }
+
+    pub fn reason_phrase(&self) -> &'static str {
+        reason_phrase(self.status)
+    }
+
+    /// Serializes the status line, headers and body into HTTP/1.1 wire format,
+    /// adding a Content-Length for buffered bodies that don't declare one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_bytes();
+        bytes.extend_from_slice(self.body());
+        bytes
+    }
+
+    /// Writes the whole response and flushes the writer. A buffered head and
+    /// body go out in a single vectored write where the writer allows it; a
+    /// streaming body is copied through as the handler produces it.
+    ///
+    /// A buffered response whose declared Content-Length disagrees with its
+    /// body is never sent: a 500 goes out in its place and an `InvalidData`
+    /// error is returned.
+    pub async fn write_to<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.write_checked(writer, true).await
+    }
+
+    /// Writes the response without its body and flushes the writer, as the
+    /// answer to a HEAD request. The Content-Length is the one the body
+    /// would have been sent with.
+    pub(crate) async fn write_head_to<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.write_checked(writer, false).await
+    }
+
+    async fn write_checked<W>(&mut self, writer: &mut W, with_body: bool) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if let Err(msg) = self.check_content_length() {
+            error!("Refusing to send {} response: {}", self.status, msg);
+            writer.write_all(&HttpResponse::new(500).to_bytes()).await?;
+            writer.flush().await?;
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+        }
+
+        let head = self.head_bytes();
+        if !with_body {
+            writer.write_all(&head).await?;
+            return writer.flush().await;
+        }
+        self.write_with_head(writer, &head).await
+    }
+
+    /// Writes just the body and flushes the writer, as the answer to an
+    /// HTTP/0.9 simple request, which has no status line or headers.
+    pub(crate) async fn write_body_to<W>(&mut self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.write_with_head(writer, &[]).await
+    }
+
+    async fn write_with_head<W>(&mut self, writer: &mut W, head: &[u8]) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let written = match &mut self.body {
+            Body::Bytes(body) => write_all_vectored(writer, head, body).await,
+            Body::Stream(stream) => write_streaming(writer, head, stream).await,
+        };
+        let written = match written {
+            Ok(()) => writer.flush().await,
+            Err(e) => Err(e),
+        };
+
+        if written.is_err() && matches!(self.body, Body::Stream(_)) {
+            // Dropping our end makes the handler's next write fail, so it
+            // stops producing a body that can no longer be delivered.
+            self.body = Body::Bytes(Vec::new());
+        }
+        written
+    }
+
+    /// Checks that a declared Content-Length matches the buffered body.
+    /// Streaming bodies aren't known up front, so they always pass.
+    fn check_content_length(&self) -> Result<(), String> {
+        let body = match &self.body {
+            Body::Bytes(body) => body,
+            Body::Stream(_) => return Ok(()),
+        };
+        match self.content_length() {
+            Some(declared) if self.is_bodiless() => Err(format!(
+                "Content-Length {} declared on a status without a body",
+                declared
+            )),
+            Some(declared) if declared.trim().parse::<usize>() != Ok(body.len()) => Err(format!(
+                "Content-Length {} declared for a {} byte body",
+                declared,
+                body.len()
+            )),
+            None if self.is_bodiless() && !body.is_empty() => Err(format!(
+                "{} byte body on a status without a body",
+                body.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason_phrase());
+        for (name, value) in self.headers() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Body::Bytes(body) = &self.body {
+            if self.content_length().is_none() && !self.is_bodiless() {
+                head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Writes `head` followed by `body`, continuing after short writes until
+/// everything is sent.
+async fn write_all_vectored<W>(writer: &mut W, head: &[u8], body: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let total = head.len() + body.len();
+    let mut written = 0;
+
+    while written < total {
+        let count = if written < head.len() {
+            let slices = [IoSlice::new(&head[written..]), IoSlice::new(body)];
+            writer.write_vectored(&slices).await?
+        } else {
+            writer.write(&body[written - head.len()..]).await?
+        };
+
+        if count == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        written += count;
+    }
+
+    Ok(())
+}
+
+async fn write_streaming<W>(
+    writer: &mut W,
+    head: &[u8],
+    stream: &mut DuplexStream,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(head).await?;
+    tokio::io::copy(stream, writer).await?;
+    Ok(())
+}
+
+/// Whether a write failed because the client reset or closed the connection,
+/// as opposed to anything wrong with the response.
+pub(crate) fn is_client_abort(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::WriteZero
+    )
+}
+
+/// The producing end of a streaming response body. Writes fail with
+/// `BrokenPipe` once the client has gone away.
+#[derive(Debug)]
+pub struct ResponseWriter {
+    inner: DuplexStream,
+}
+
+impl AsyncWrite for ResponseWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // Nothing is ever sent back to the writer, so the reverse direction
+        // only becomes readable once the response side has been dropped.
+        // Polling it also wakes a write waiting on a full buffer then.
+        let mut probe = [0; 1];
+        if Pin::new(&mut self.inner)
+            .poll_read(cx, &mut ReadBuf::new(&mut probe))
+            .is_ready()
+        {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        421 => "Misdirected Request",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts at most a few bytes per write, like a congested socket.
+    struct ShortWriter {
+        written: Vec<u8>,
+        max_per_write: usize,
+    }
+    impl AsyncWrite for ShortWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let count = buf.len().min(self.max_per_write);
+            self.written.extend_from_slice(&buf[..count]);
+            Poll::Ready(Ok(count))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn serializes_status_line_headers_and_body() {
+        let response = HttpResponse::ok()
+            .with_header("Content-Length", "5")
+            .with_body(b"hello".to_vec());
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            String::from_utf8(response.to_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn serializes_response_without_headers_or_body() {
+        let response = HttpResponse::new(413);
+
+        assert_eq!(
+            "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n",
+            String::from_utf8(response.to_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_its_own_wire_format() {
+        let response = HttpResponse::new(404)
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"missing".to_vec());
+
+        let parsed = HttpResponse::from_bytes(&response.to_bytes()).unwrap();
+
+        assert_eq!(404, parsed.status);
+        assert_eq!(
+            Some(&"text/plain".to_owned()),
+            parsed.header("Content-Type")
+        );
+        assert_eq!(Some(&"7".to_owned()), parsed.header("Content-Length"));
+        assert_eq!(b"missing", parsed.body());
+        assert!(HttpResponse::from_bytes(b"garbage").is_none());
+    }
+
+    #[tokio::test]
+    async fn write_to_continues_after_short_writes() {
+        let mut response = HttpResponse::ok()
+            .with_header("Content-Length", "11")
+            .with_body(b"hello world".to_vec());
+        let mut writer = ShortWriter {
+            written: Vec::new(),
+            max_per_write: 3,
+        };
+
+        response.write_to(&mut writer).await.unwrap();
+
+        assert_eq!(response.to_bytes(), writer.written);
+    }
+
+    #[tokio::test]
+    async fn write_to_writes_head_followed_by_body() {
+        let mut response = HttpResponse::ok().with_body(b"body".to_vec());
+        let mut writer: Vec<u8> = Vec::new();
+
+        response.write_to(&mut writer).await.unwrap();
+
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody".to_vec(),
+            writer
+        );
+    }
+
+    #[tokio::test]
+    async fn write_body_to_writes_only_the_body() {
+        let mut response = HttpResponse::not_found().with_body(b"gone".to_vec());
+        let mut writer: Vec<u8> = Vec::new();
+
+        response.write_body_to(&mut writer).await.unwrap();
+
+        assert_eq!(b"gone".to_vec(), writer);
+    }
+
+    #[test]
+    fn omits_content_length_for_bodiless_statuses() {
+        let response = HttpResponse::new(204);
+
+        assert_eq!(
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            String::from_utf8(response.to_bytes()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_send_mismatched_content_length() {
+        let mut response = HttpResponse::ok()
+            .with_header("content-length", "10")
+            .with_body(b"short".to_vec());
+        let mut writer: Vec<u8> = Vec::new();
+
+        let result = response.write_to(&mut writer).await;
+
+        assert_eq!(std::io::ErrorKind::InvalidData, result.unwrap_err().kind());
+        assert_eq!(
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+            String::from_utf8(writer).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_body_written_by_another_task() {
+        let (mut response, mut body) = HttpResponse::streaming(200);
+        let producer = tokio::spawn(async move {
+            for chunk in &["first ", "second ", "third"] {
+                body.write_all(chunk.as_bytes()).await.unwrap();
+            }
+        });
+        let mut writer: Vec<u8> = Vec::new();
+
+        response.write_to(&mut writer).await.unwrap();
+        producer.await.unwrap();
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nfirst second third",
+            String::from_utf8(writer).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn streaming_writes_wait_for_the_client_to_catch_up() {
+        let (mut response, mut body) = HttpResponse::streaming(200);
+        let chunk = vec![b'x'; STREAM_BUFFER_SIZE];
+
+        body.write_all(&chunk).await.unwrap();
+        let blocked =
+            tokio::time::timeout(std::time::Duration::from_millis(50), body.write_all(b"y")).await;
+        assert!(blocked.is_err());
+
+        let producer = tokio::spawn(async move { body.write_all(b"y").await.unwrap() });
+        let mut writer: Vec<u8> = Vec::new();
+        response.write_to(&mut writer).await.unwrap();
+        producer.await.unwrap();
+
+        assert!(writer.ends_with(b"xy"));
+    }
+
+    #[test]
+    fn sets_each_cookie_in_its_own_header() {
+        let response = HttpResponse::ok()
+            .with_cookie(&Cookie::new("theme", "dark"))
+            .with_cookie(&Cookie::new("lang", "en").path("/"));
+
+        let bytes = response.to_bytes();
+        let head = String::from_utf8_lossy(&bytes);
+        let parsed = HttpResponse::from_bytes(&bytes).unwrap();
+
+        assert!(head.contains("Set-Cookie: theme=dark\r\nSet-Cookie: lang=en; Path=/\r\n"));
+        assert_eq!(response.cookies, parsed.cookies);
+    }
+
+    #[test]
+    fn redacted_responses_hide_cookies() {
+        let response = HttpResponse::ok()
+            .with_header("Set-Cookie", "session=secret")
+            .with_body(b"hello".to_vec());
+
+        assert_eq!(
+            "200 OK [Set-Cookie: <redacted>] 5 byte body \"hello\"",
+            response.redacted(&Redaction::default()).to_string()
+        );
+    }
+
+    /// Fails every write, like a socket the client has reset.
+    struct ResetWriter;
+    impl AsyncWrite for ResetWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_the_body_stream_when_the_client_goes_away() {
+        let (mut response, mut writer) = HttpResponse::streaming(200);
+        writer.write_all(b"partial").await.unwrap();
+
+        let error = response.write_to(&mut ResetWriter).await.unwrap_err();
+
+        assert!(is_client_abort(&error));
+        let next_write = writer.write_all(b"more").await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, next_write.kind());
+    }
+}
@@ -0,0 +1,825 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use log::debug;
+use rust_http_parse::{HttpMethod, HttpRequest};
+
+use super::middleware::{Layered, MiddlewareChain, Next};
+use super::{Guard, HttpResponse, IntoResponse, Middleware};
+
+custom_error! {pub RouteError
+    Conflict{first: String, second: String} = "Route {second} is ambiguous with {first}"
+}
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Anything that can turn a request into a response. Implemented for async
+/// closures taking the request by value and returning a response, or a
+/// `Result` whose error converts into one.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse>;
+
+    /// The largest body the handler takes for `request`, which has yet to
+    /// be read, so the server can turn a larger one away without buffering
+    /// it. Handlers that wrap another should pass this on.
+    fn max_body(&self, _request: &HttpRequest) -> Option<usize> {
+        None
+    }
+}
+
+impl<F, R> Handler for F
+where
+    F: Fn(HttpRequest) -> R + Send + Sync + 'static,
+    R: Future + Send + 'static,
+    R::Output: IntoResponse,
+{
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let response = self(request);
+        Box::pin(async move { response.await.into_response() })
+    }
+}
+
+/// A route and the params it captured from the path.
+type Matched<'a> = (&'a Route, Vec<(&'a str, String)>);
+
+/// Request extension naming the route that matched, e.g. `GET /users/:id`.
+#[derive(Debug, Clone)]
+pub struct MatchedRoute(pub String);
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+    CatchAll(String),
+}
+
+struct Route {
+    method: Option<HttpMethod>,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+    /// Media types the route accepts request bodies in; empty accepts any.
+    consumes: Vec<String>,
+    guards: Vec<Guard>,
+}
+impl Route {
+    /// Matches the route against a request, returning the params it captures.
+    fn match_request(
+        &self,
+        method: HttpMethod,
+        path_segments: &[&str],
+    ) -> Option<Vec<(&str, String)>> {
+        if matches!(self.method, Some(route_method) if route_method != method) {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            if let Segment::CatchAll(name) = segment {
+                params.push((name.as_str(), path_segments.get(index..)?.join("/")));
+                return Some(params);
+            }
+
+            let path_segment = path_segments.get(index)?;
+            match segment {
+                Segment::Literal(literal) if literal != path_segment => return None,
+                Segment::Param(_) | Segment::Wildcard if path_segment.is_empty() => return None,
+                Segment::Param(name) => params.push((name.as_str(), path_segment.to_string())),
+                _ => {}
+            }
+        }
+
+        if path_segments.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the route accepts a body of `content_type`, given without
+    /// its parameters. `type/*` accepts any subtype.
+    fn consumes_type(&self, content_type: Option<&str>) -> bool {
+        if self.consumes.is_empty() {
+            return true;
+        }
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        self.consumes
+            .iter()
+            .any(|accepted| match accepted.strip_suffix("/*") {
+                Some(kind) => content_type
+                    .split('/')
+                    .next()
+                    .is_some_and(|actual| actual.eq_ignore_ascii_case(kind)),
+                None => accepted.eq_ignore_ascii_case(content_type),
+            })
+    }
+
+    /// Whether this route matches every request `other`, registered after
+    /// it, would, so `other` could never be reached. A route with guards or
+    /// that only takes some content types lets the rest fall through to
+    /// later routes.
+    fn conflicts_with(&self, other: &Route) -> bool {
+        if !self.consumes.is_empty() || !self.guards.is_empty() {
+            return false;
+        }
+
+        let methods_overlap = match (self.method, other.method) {
+            (Some(method), Some(other_method)) => method == other_method,
+            _ => true,
+        };
+        methods_overlap && covers(&self.segments, &other.segments)
+    }
+
+    fn describe(&self) -> String {
+        match self.method {
+            Some(method) => format!("{:?} {}", method, self.pattern),
+            None => format!("* {}", self.pattern),
+        }
+    }
+}
+
+/// The request's Content-Type without its parameters.
+fn media_type(request: &HttpRequest) -> Option<String> {
+    request.header("Content-Type").map(|value| {
+        value
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_owned()
+    })
+}
+
+/// Whether a pattern of `segments` matches every path one of `other` does.
+/// A param or `*` covers any literal, as the earlier route wins. Catch-alls
+/// only cover each other, since the router tries them after every other
+/// route.
+fn covers(segments: &[Segment], other: &[Segment]) -> bool {
+    match (segments.split_first(), other.split_first()) {
+        (Some((Segment::CatchAll(_), _)), Some((Segment::CatchAll(_), _))) | (None, None) => true,
+        (Some((segment, rest)), Some((other_segment, other_rest))) => {
+            let covered = match (segment, other_segment) {
+                (Segment::Literal(a), Segment::Literal(b)) => a == b,
+                (Segment::Param(_) | Segment::Wildcard, Segment::Literal(b)) => !b.is_empty(),
+                (Segment::Param(_) | Segment::Wildcard, Segment::Param(_) | Segment::Wildcard) => {
+                    true
+                }
+                _ => false,
+            };
+            covered && covers(rest, other_rest)
+        }
+        _ => false,
+    }
+}
+
+/// Routes indexed by the segments of their patterns, so matching a path
+/// only walks the branches its own segments lead to, however many routes
+/// there are. It finds the routes whose pattern could fit; their method,
+/// guards and params are checked afterwards.
+#[derive(Default)]
+struct RouteTree {
+    literals: HashMap<String, RouteTree>,
+    /// Params and `*`, which both match any non-empty segment.
+    any: Option<Box<RouteTree>>,
+    /// Indexes of the routes whose pattern ends here.
+    routes: Vec<usize>,
+    /// Indexes of the catch-all routes whose rest of the path starts here.
+    catch_alls: Vec<usize>,
+}
+impl RouteTree {
+    fn insert(&mut self, segments: &[Segment], index: usize) {
+        match segments.split_first() {
+            None => self.routes.push(index),
+            Some((Segment::CatchAll(_), _)) => self.catch_alls.push(index),
+            Some((Segment::Literal(literal), rest)) => self
+                .literals
+                .entry(literal.clone())
+                .or_default()
+                .insert(rest, index),
+            Some((_, rest)) => self
+                .any
+                .get_or_insert_with(Box::default)
+                .insert(rest, index),
+        }
+    }
+
+    /// Adds the indexes of the routes and catch-all routes that could match
+    /// `path`, in no particular order.
+    fn candidates(&self, path: &[&str], routes: &mut Vec<usize>, catch_alls: &mut Vec<usize>) {
+        catch_alls.extend(&self.catch_alls);
+        let (segment, rest) = match path.split_first() {
+            Some(split) => split,
+            None => {
+                routes.extend(&self.routes);
+                return;
+            }
+        };
+        if let Some(literal) = self.literals.get(*segment) {
+            literal.candidates(rest, routes, catch_alls);
+        }
+        if let (Some(any), false) = (&self.any, segment.is_empty()) {
+            any.candidates(rest, routes, catch_alls);
+        }
+    }
+}
+
+/// Dispatches requests to handlers by method and path pattern. Pattern
+/// segments starting with `:` capture the matching path segment as a
+/// request param, and `*` matches any single segment without capturing it.
+/// A final `*name` segment captures the rest of the path, however many
+/// segments that is, so `/assets/*path` serves everything under `/assets`.
+/// Routes without a catch-all are tried first in the order they were added,
+/// then catch-all routes from the most specific prefix down. Mounts are
+/// catch-all routes that accept any method.
+///
+/// Middleware wraps every request the router sees, in the order it was
+/// added. Another router can be nested under a prefix; its middleware then
+/// only applies to its own routes.
+pub struct Router {
+    routes: Vec<Route>,
+    tree: RouteTree,
+    middleware: MiddlewareChain,
+    not_found: Arc<dyn Handler>,
+}
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            tree: RouteTree::default(),
+            middleware: Arc::new(Vec::new()),
+            not_found: Arc::new(|_| async { HttpResponse::not_found() }),
+        }
+    }
+
+    pub fn route<H: Handler>(self, method: HttpMethod, pattern: &str, handler: H) -> Self {
+        self.add_route(Some(method), pattern, Arc::new(handler))
+    }
+
+    /// Sends requests with any method under `prefix` to `handler`, capturing
+    /// the remainder of the path as the `path` param.
+    pub fn mount<H: Handler>(self, prefix: &str, handler: H) -> Self {
+        self.add_route(None, &join_paths(prefix, "/*path"), Arc::new(handler))
+    }
+
+    /// Only lets the route added last handle requests whose Content-Type is
+    /// `media_type`, e.g. `application/json` or `text/*`; call it again to
+    /// accept more. Requests it turns away fall through to later matching
+    /// routes, and are answered with 415 if there are none.
+    pub fn consumes(mut self, media_type: &str) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.consumes.push(media_type.to_owned());
+        }
+        self
+    }
+
+    /// Only lets the route added last handle requests `guard` allows, e.g.
+    /// `Guard::host("api.example.com")`. A route can have several guards,
+    /// all of which must pass. Requests they turn away fall through to later
+    /// matching routes.
+    pub fn guard(mut self, guard: Guard) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.guards.push(guard);
+        }
+        self
+    }
+
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+
+    /// Serves every route of `router` under `prefix`, wrapped in `router`'s
+    /// middleware.
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        for route in router.routes {
+            let handler = Layered::wrap(&router.middleware, route.handler);
+            self = self.add_route(route.method, &join_paths(prefix, &route.pattern), handler);
+            if let Some(nested) = self.routes.last_mut() {
+                nested.consumes = route.consumes;
+                nested.guards = route.guards;
+            }
+        }
+        self
+    }
+
+    /// Checks that no two routes match the same requests, e.g. `/users/:id`
+    /// and `/users/:name`, so a mistake shows up at startup rather than as a
+    /// handler that never runs.
+    pub fn validate(&self) -> Result<(), RouteError> {
+        for (index, route) in self.routes.iter().enumerate() {
+            if let Some(earlier) = self.routes[..index]
+                .iter()
+                .find(|earlier| earlier.conflicts_with(route))
+            {
+                return Err(RouteError::Conflict {
+                    first: earlier.describe(),
+                    second: route.describe(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn dispatch(&self, mut request: HttpRequest) -> HttpResponse {
+        let handler = self.resolve(&mut request);
+        Next::new(self.middleware.clone(), handler)
+            .run(request)
+            .await
+    }
+
+    /// The most any body sent to `request`'s route may be, as set by the
+    /// route's handler and the router's middleware, checked before the body
+    /// is read.
+    pub(crate) fn max_body(&self, request: &HttpRequest) -> Option<usize> {
+        let (matched, _) = self.find(request);
+        let handler = matched.and_then(|(route, _)| route.handler.max_body(request));
+        self.middleware
+            .iter()
+            .filter_map(|middleware| middleware.max_body(request))
+            .chain(handler)
+            .min()
+    }
+
+    fn resolve(&self, request: &mut HttpRequest) -> Arc<dyn Handler> {
+        let (matched, unsupported_type) = self.find(request);
+        match matched {
+            Some((route, params)) => {
+                for (name, value) in params {
+                    request.set_param(name, &value);
+                }
+                request
+                    .extensions_mut()
+                    .insert(MatchedRoute(route.describe()));
+                route.handler.clone()
+            }
+            None if unsupported_type => {
+                debug!(
+                    "No route for {:?} {} takes {}",
+                    request.method,
+                    &request.path,
+                    media_type(request)
+                        .as_deref()
+                        .unwrap_or("a body without a Content-Type")
+                );
+                Arc::new(|_| async { HttpResponse::new(415) })
+            }
+            None => {
+                debug!("No route for {:?} {}", request.method, &request.path);
+                self.not_found.clone()
+            }
+        }
+    }
+
+    /// The route that handles `request` and the params it captures, and
+    /// whether any route was passed over for the request's Content-Type.
+    fn find(&self, request: &HttpRequest) -> (Option<Matched<'_>>, bool) {
+        let path_segments = split_path(&request.path);
+        let content_type = media_type(request);
+        let unsupported_type = Cell::new(false);
+        let accepts = |route: &Route| {
+            if !route.guards.iter().all(|guard| guard.allows(request)) {
+                return false;
+            }
+            let accepted = route.consumes_type(content_type.as_deref());
+            if !accepted {
+                unsupported_type.set(true);
+            }
+            accepted
+        };
+
+        let (mut exact_indexes, mut catch_all_indexes) = (Vec::new(), Vec::new());
+        self.tree
+            .candidates(&path_segments, &mut exact_indexes, &mut catch_all_indexes);
+        exact_indexes.sort_unstable();
+        catch_all_indexes.sort_unstable();
+
+        let candidate = |&index: &usize| {
+            let route = &self.routes[index];
+            let params = route.match_request(request.method, &path_segments)?;
+            Some((route, params)).filter(|_| accepts(route))
+        };
+        let exact = exact_indexes.iter().find_map(candidate);
+        let matched = exact.or_else(|| {
+            catch_all_indexes
+                .iter()
+                .filter_map(candidate)
+                .max_by_key(|(route, _)| route.segments.len())
+        });
+        (matched, unsupported_type.get())
+    }
+
+    fn add_route(
+        mut self,
+        method: Option<HttpMethod>,
+        pattern: &str,
+        handler: Arc<dyn Handler>,
+    ) -> Self {
+        let segments = parse_pattern(pattern);
+        self.tree.insert(&segments, self.routes.len());
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_owned(),
+            segments,
+            handler,
+            consumes: Vec::new(),
+            guards: Vec::new(),
+        });
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+fn join_paths(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    match path {
+        "" | "/" if prefix.is_empty() => "/".to_owned(),
+        "" | "/" => prefix.to_owned(),
+        _ => format!("{}{}", prefix, path),
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.trim_start_matches('/').split('/').collect()
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    split_path(pattern)
+        .into_iter()
+        .map(|segment| {
+            if segment == "*" {
+                Segment::Wildcard
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::CatchAll(name.to_owned())
+            } else if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else {
+                Segment::Literal(segment.to_owned())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond_with(status: u16) -> impl Handler {
+        move |_| async move { HttpResponse::new(status) }
+    }
+
+    #[tokio::test]
+    async fn dispatches_to_route_matching_method_and_path() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/users", respond_with(200))
+            .route(HttpMethod::POST, "/users", respond_with(201));
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::POST, "/users"))
+            .await;
+
+        assert_eq!(201, response.status);
+    }
+
+    #[tokio::test]
+    async fn captures_path_params() {
+        let router = Router::new().route(
+            HttpMethod::GET,
+            "/users/:id",
+            |request: HttpRequest| async move {
+                HttpResponse::ok().with_body(request.param("id").unwrap().as_bytes().to_vec())
+            },
+        );
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/users/42"))
+            .await;
+
+        assert_eq!(b"42", response.body());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_longest_matching_mount() {
+        let router = Router::new()
+            .mount("/static", respond_with(200))
+            .mount("/static/private", respond_with(403));
+
+        let public = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/static/index.html"))
+            .await;
+        let private = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/static/private/key"))
+            .await;
+        let sibling = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/staticfoo"))
+            .await;
+
+        assert_eq!(200, public.status);
+        assert_eq!(403, private.status);
+        assert_eq!(404, sibling.status);
+    }
+
+    #[tokio::test]
+    async fn unmatched_request_is_not_found() {
+        let router = Router::new().route(HttpMethod::GET, "/", respond_with(200));
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/missing"))
+            .await;
+
+        assert_eq!(404, response.status);
+    }
+
+    #[tokio::test]
+    async fn nested_router_routes_are_served_under_prefix() {
+        let api = Router::new()
+            .route(HttpMethod::GET, "/", respond_with(200))
+            .route(
+                HttpMethod::GET,
+                "/users/:id",
+                |request: HttpRequest| async move {
+                    HttpResponse::ok().with_body(request.param("id").unwrap().as_bytes().to_vec())
+                },
+            );
+        let router = Router::new().nest("/api/v1", api);
+
+        let index = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/api/v1"))
+            .await;
+        let user = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/api/v1/users/7"))
+            .await;
+        let unprefixed = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/users/7"))
+            .await;
+
+        assert_eq!(200, index.status);
+        assert_eq!(b"7", user.body());
+        assert_eq!(404, unprefixed.status);
+    }
+
+    #[tokio::test]
+    async fn group_middleware_only_wraps_group_routes() {
+        let admin = Router::new()
+            .route(HttpMethod::GET, "/stats", respond_with(200))
+            .middleware(|_request: HttpRequest, _next: Next| async { HttpResponse::new(403) });
+        let router = Router::new()
+            .route(HttpMethod::GET, "/", respond_with(200))
+            .nest("/admin", admin);
+
+        let stats = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/admin/stats"))
+            .await;
+        let index = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/"))
+            .await;
+
+        assert_eq!(403, stats.status);
+        assert_eq!(200, index.status);
+    }
+
+    #[tokio::test]
+    async fn middleware_runs_outermost_first_and_wraps_nested_groups() {
+        fn tag(name: &'static str) -> impl Middleware {
+            move |request: HttpRequest, next: Next| async move {
+                let response = next.run(request).await;
+                let trail = response.header("Trail").cloned().unwrap_or_default();
+                response.with_header("Trail", &format!("{}{}", name, trail))
+            }
+        }
+        let inner = Router::new()
+            .route(HttpMethod::GET, "/leaf", respond_with(200))
+            .middleware(tag("c"));
+        let router = Router::new()
+            .middleware(tag("a"))
+            .middleware(tag("b"))
+            .nest("/group", inner);
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/group/leaf"))
+            .await;
+
+        assert_eq!(Some(&"abc".to_string()), response.header("Trail"));
+    }
+
+    #[tokio::test]
+    async fn wildcard_matches_any_single_segment() {
+        let router = Router::new().route(HttpMethod::GET, "/files/*/latest", respond_with(200));
+
+        let matched = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/files/reports/latest"))
+            .await;
+        let too_deep = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/files/a/b/latest"))
+            .await;
+
+        assert_eq!(200, matched.status);
+        assert_eq!(404, too_deep.status);
+    }
+
+    #[tokio::test]
+    async fn catch_all_captures_rest_of_path() {
+        let router = Router::new().route(
+            HttpMethod::GET,
+            "/assets/*path",
+            |request: HttpRequest| async move {
+                HttpResponse::ok().with_body(request.param("path").unwrap().as_bytes().to_vec())
+            },
+        );
+
+        let nested = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/assets/css/site.css"))
+            .await;
+        let root = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/assets"))
+            .await;
+
+        assert_eq!(b"css/site.css", nested.body());
+        assert_eq!(200, root.status);
+        assert_eq!(b"", root.body());
+    }
+
+    #[tokio::test]
+    async fn root_catch_all_is_a_fallback_for_everything_else() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/*rest", respond_with(418))
+            .route(HttpMethod::GET, "/health", respond_with(200));
+
+        let health = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/health"))
+            .await;
+        let other = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/some/other/page"))
+            .await;
+
+        assert_eq!(200, health.status);
+        assert_eq!(418, other.status);
+        assert!(router.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn routes_by_content_type_and_rejects_the_rest_with_415() {
+        let router = Router::new()
+            .route(HttpMethod::POST, "/users", respond_with(201))
+            .consumes("application/json")
+            .route(HttpMethod::POST, "/users", respond_with(202))
+            .consumes("application/x-www-form-urlencoded")
+            .consumes("text/*");
+        let post = |content_type: Option<&str>| {
+            let mut request = HttpRequest::new(HttpMethod::POST, "/users");
+            if let Some(content_type) = content_type {
+                request.set_header("Content-Type", content_type);
+            }
+            router.dispatch(request)
+        };
+
+        assert_eq!(
+            201,
+            post(Some("Application/JSON; charset=utf-8")).await.status
+        );
+        assert_eq!(
+            202,
+            post(Some("application/x-www-form-urlencoded")).await.status
+        );
+        assert_eq!(202, post(Some("text/csv")).await.status);
+        assert_eq!(415, post(Some("application/xml")).await.status);
+        assert_eq!(415, post(None).await.status);
+        let rejected = post(Some("application/xml")).await.to_bytes();
+        assert!(rejected.starts_with(b"HTTP/1.1 415 Unsupported Media Type\r\n"));
+        let mut lowercase = HttpRequest::new(HttpMethod::POST, "/users");
+        lowercase.set_header("content-type", "application/json");
+        assert_eq!(201, router.dispatch(lowercase).await.status);
+        assert!(router.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn guarded_routes_fall_through_to_the_next_match() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/", respond_with(201))
+            .guard(Guard::host("api.example.com"))
+            .guard(Guard::header("X-Beta"))
+            .route(HttpMethod::GET, "/", respond_with(200))
+            .nest(
+                "/admin",
+                Router::new()
+                    .route(HttpMethod::GET, "/", respond_with(200))
+                    .guard(Guard::https()),
+            );
+        let get = |path: &str, headers: &[(&str, &str)]| {
+            let mut request = HttpRequest::new(HttpMethod::GET, path);
+            for (name, value) in headers {
+                request.set_header(name, value);
+            }
+            router.dispatch(request)
+        };
+
+        let beta = get("/", &[("Host", "api.example.com"), ("X-Beta", "1")]).await;
+        let no_header = get("/", &[("Host", "api.example.com")]).await;
+        let plain_admin = get("/admin", &[]).await;
+
+        assert_eq!(201, beta.status);
+        assert_eq!(200, no_header.status);
+        assert_eq!(404, plain_admin.status);
+        assert!(router.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_routes_differing_only_in_param_names() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/users/:id", respond_with(200))
+            .route(HttpMethod::GET, "/users/:name", respond_with(200));
+
+        let error = router.validate().unwrap_err();
+
+        assert_eq!(
+            "Route GET /users/:name is ambiguous with GET /users/:id",
+            error.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn prefers_earlier_routes_among_overlapping_patterns() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/users/:id", respond_with(200))
+            .route(HttpMethod::GET, "/posts/latest", respond_with(202))
+            .route(HttpMethod::GET, "/posts/:id", respond_with(203))
+            .route(HttpMethod::GET, "/*path", respond_with(204));
+        let get = |target: &str| router.dispatch(HttpRequest::new(HttpMethod::GET, target));
+
+        assert_eq!(200, get("/users/me").await.status);
+        assert_eq!(202, get("/posts/latest").await.status);
+        assert_eq!(203, get("/posts/7").await.status);
+        assert_eq!(204, get("/posts/7/comments").await.status);
+        assert_eq!(204, get("/users/").await.status);
+        assert!(router.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn matches_among_thousands_of_routes() {
+        let router = (0..5000).fold(Router::new(), |router, index| {
+            router.route(
+                HttpMethod::GET,
+                &format!("/items{}/:id", index),
+                respond_with(200 + (index % 100) as u16),
+            )
+        });
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/items4321/9"))
+            .await;
+        let missing = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/items5000/9"))
+            .await;
+
+        assert_eq!(221, response.status);
+        assert_eq!(404, missing.status);
+    }
+
+    #[test]
+    fn validate_rejects_mount_overlapping_catch_all_route() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/static/*file", respond_with(200))
+            .mount("/static", respond_with(200));
+
+        assert!(router.validate().is_err());
+    }
+
+    #[test]
+    fn validate_accepts_distinguishable_routes() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/users/:id", respond_with(200))
+            .route(HttpMethod::POST, "/users/:id", respond_with(201))
+            .route(HttpMethod::GET, "/users/:id/posts", respond_with(200))
+            .route(HttpMethod::GET, "/users/*rest", respond_with(200));
+
+        assert!(router.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_literal_routes_shadowed_by_earlier_params() {
+        let literal_first = Router::new()
+            .route(HttpMethod::GET, "/users/me", respond_with(200))
+            .route(HttpMethod::GET, "/users/:id", respond_with(200));
+        let param_first = Router::new()
+            .route(HttpMethod::GET, "/users/:id", respond_with(200))
+            .route(HttpMethod::GET, "/users/me", respond_with(200));
+
+        assert!(literal_first.validate().is_ok());
+        assert_eq!(
+            "Route GET /users/me is ambiguous with GET /users/:id",
+            param_first.validate().unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next, StaticFiles};
+
+type OnChange = Arc<dyn Fn(&[(String, PathBuf)]) -> io::Result<()> + Send + Sync>;
+
+/// Static file mounts added and removed while the server runs, through the
+/// admin listener's `/mounts` endpoint. Requests under one of the mounts are
+/// served from it before reaching the router, so a runtime mount shadows
+/// routes under the same prefix. Add it with `ServerBuilder::runtime_mounts`.
+#[derive(Clone, Default)]
+pub struct RuntimeMounts {
+    mounts: Arc<RwLock<Vec<Mount>>>,
+    index: Option<String>,
+    on_change: Option<OnChange>,
+}
+
+struct Mount {
+    prefix: String,
+    root: PathBuf,
+    files: Arc<StaticFiles>,
+}
+
+impl RuntimeMounts {
+    pub fn new() -> Self {
+        RuntimeMounts::default()
+    }
+
+    /// Serves the file named `index` from a directory when the directory
+    /// itself is requested, under every mount.
+    pub fn index(mut self, index: &str) -> Self {
+        self.index = Some(index.to_owned());
+        self
+    }
+
+    /// Calls `save` with every mount, in the order added, after each change,
+    /// e.g. to write them back to a config file. A failed save is reported
+    /// to the admin client, but the change stays in effect.
+    pub fn on_change<F>(mut self, save: F) -> Self
+    where
+        F: Fn(&[(String, PathBuf)]) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.on_change = Some(Arc::new(save));
+        self
+    }
+
+    /// Serves `root` under `prefix`, replacing any mount already there.
+    pub fn add(&self, prefix: &str, root: impl AsRef<Path>) -> io::Result<()> {
+        let prefix = format!("/{}", prefix.trim_matches('/'));
+        let mut files = StaticFiles::new(&root);
+        if let Some(index) = &self.index {
+            files = files.index(index);
+        }
+        let mount = Mount {
+            prefix,
+            root: root.as_ref().to_path_buf(),
+            files: Arc::new(files),
+        };
+        {
+            let mut mounts = self.mounts.write().unwrap();
+            match mounts.iter_mut().find(|m| m.prefix == mount.prefix) {
+                Some(existing) => *existing = mount,
+                None => mounts.push(mount),
+            }
+        }
+        self.changed()
+    }
+
+    /// Stops serving the mount at `prefix`, returning whether there was one.
+    pub fn remove(&self, prefix: &str) -> io::Result<bool> {
+        let prefix = format!("/{}", prefix.trim_matches('/'));
+        let removed = {
+            let mut mounts = self.mounts.write().unwrap();
+            let before = mounts.len();
+            mounts.retain(|mount| mount.prefix != prefix);
+            mounts.len() != before
+        };
+        if removed {
+            self.changed()?;
+        }
+        Ok(removed)
+    }
+
+    /// The prefix and root of every mount, in the order added.
+    pub fn mounts(&self) -> Vec<(String, PathBuf)> {
+        self.mounts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|mount| (mount.prefix.clone(), mount.root.clone()))
+            .collect()
+    }
+
+    fn changed(&self) -> io::Result<()> {
+        match &self.on_change {
+            Some(save) => save(&self.mounts()),
+            None => Ok(()),
+        }
+    }
+
+    /// The files of the longest mount prefix `path` is under, and the rest
+    /// of the path below it.
+    fn resolve(&self, path: &str) -> Option<(Arc<StaticFiles>, String)> {
+        self.mounts
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|mount| {
+                let rest = match mount.prefix.as_str() {
+                    "/" => path,
+                    prefix => match path.strip_prefix(prefix)? {
+                        rest if rest.is_empty() || rest.starts_with('/') => rest,
+                        _ => return None,
+                    },
+                };
+                Some((mount.prefix.len(), &mount.files, rest))
+            })
+            .max_by_key(|(length, _, _)| *length)
+            .map(|(_, files, rest)| (files.clone(), rest.trim_start_matches('/').to_owned()))
+    }
+}
+
+impl Middleware for RuntimeMounts {
+    fn handle(&self, mut request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        match self.resolve(&request.path) {
+            Some((files, rest)) => {
+                request.set_param("path", &rest);
+                files.call(request)
+            }
+            None => next.run(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn serves_mounts_added_at_runtime_until_removed() {
+        let root = std::env::temp_dir().join(format!("runtime-mounts-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("guide.txt"), "read me").unwrap();
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mounts = RuntimeMounts::new().on_change({
+            let saved = saved.clone();
+            move |mounts| {
+                *saved.lock().unwrap() = mounts.to_vec();
+                Ok(())
+            }
+        });
+        let router = Router::new()
+            .route(HttpMethod::GET, "/docs/*path", |_| async {
+                HttpResponse::new(418)
+            })
+            .middleware(mounts.clone());
+        let get = |path: &str| router.dispatch(HttpRequest::new(HttpMethod::GET, path));
+
+        assert_eq!(418, get("/docs/guide.txt").await.status);
+        mounts.add("/docs/", &root).unwrap();
+        let response = get("/docs/guide.txt").await;
+        assert_eq!(200, response.status);
+        assert_eq!(b"read me", response.body());
+        assert_eq!(404, get("/docsguide.txt").await.status);
+        assert_eq!(
+            vec![("/docs".to_owned(), root.clone())],
+            *saved.lock().unwrap()
+        );
+
+        assert!(mounts.remove("/docs").unwrap());
+        assert!(!mounts.remove("/docs").unwrap());
+        assert_eq!(418, get("/docs/guide.txt").await.status);
+        assert!(saved.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,962 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use custom_error::custom_error;
+use log::{debug, error, info, trace, warn};
+use rust_http_parse::{
+    parse_from_reader_with_limit, HttpMethod, ParseError, ParseOptions, ParseTiming, Redaction,
+    SimpleRequest,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::access_log::W3cLog;
+use super::admin;
+use super::capture::Recording;
+use super::early_hints::{with_early_hints, EarlyHints};
+use super::health::{Health, Readiness};
+use super::limits::{raise_fd_limit, ConnectionLimits};
+use super::metrics::{Metrics, RecordMetrics, RequestTiming};
+use super::net::{AcceptBackoff, AcceptFailure, BindRetry, NetError, TcpRequestListener};
+use super::panic::CatchPanic;
+use super::plugin::Plugins;
+use super::response::is_client_abort;
+use super::router::RouteError;
+use super::state::StateMap;
+use super::statsd::StatsdExporter;
+use super::tls::{HostAcceptor, TlsHost};
+use super::{
+    BufferPool, Geo, GeoIp, Guard, Handler, HttpResponse, Middleware, PluginRegistry,
+    RequestCapture, Router, RuntimeMounts, StaticFiles, Timeout, TlsConfig, TrafficLog,
+};
+
+/// How long to keep reading from a client after shutting down the write half.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Logged in place of the status when the client went away mid-response,
+/// following nginx.
+const CLIENT_CLOSED_REQUEST: u16 = 499;
+/// Accept failures in a row after which the listening socket is closed and
+/// bound again, about four seconds of errors with the accept backoff.
+const REBIND_AFTER_FAILURES: u32 = 10;
+
+custom_error! {pub ServerError
+    NotBound = "No bind address configured",
+    Net{source: NetError} = "Network error: {source}",
+    Route{source: RouteError} = "Invalid routes: {source}"
+}
+
+/// The address of the client a request came from, in the request's
+/// extensions. Missing for requests that didn't come over a socket, such as
+/// replayed ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientAddr(pub IpAddr);
+
+/// The address a connection intercepted in transparent proxy mode was
+/// originally sent to, in the request's extensions, for routing on. Only
+/// set when the server runs with `ServerBuilder::transparent`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OriginalDst(pub SocketAddr);
+
+/// Whether a request came over HTTPS, in the request's extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+pub struct ServerBuilder {
+    address: Option<(String, u32)>,
+    tls_address: Option<(String, u32, TlsConfig)>,
+    admin_address: Option<(String, u32)>,
+    runtime_mounts: Option<(RuntimeMounts, String)>,
+    router: Router,
+    buffer_pool: Arc<BufferPool>,
+    states: StateMap,
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    load_shedding: Option<(usize, Duration)>,
+    capture: Option<RequestCapture>,
+    traffic_log: Option<TrafficLog>,
+    redaction: Redaction,
+    parse_options: ParseOptions,
+    w3c_log: Option<W3cLog>,
+    geoip: Option<GeoIp>,
+    fd_headroom: Option<usize>,
+    fast_open_queue: Option<u32>,
+    bind_retry: BindRetry,
+    transparent: bool,
+    plugins: Vec<Plugins>,
+    statsd: Option<StatsdExporter>,
+    error_renderer: Option<Arc<ErrorRenderer>>,
+}
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder {
+            address: None,
+            tls_address: None,
+            admin_address: None,
+            runtime_mounts: None,
+            router: Router::new(),
+            buffer_pool: Arc::new(BufferPool::default()),
+            states: StateMap::default(),
+            health: Arc::new(Health::new()),
+            metrics: Arc::new(Metrics::new()),
+            max_connections: None,
+            max_connections_per_ip: None,
+            load_shedding: None,
+            capture: None,
+            traffic_log: None,
+            redaction: Redaction::default(),
+            parse_options: ParseOptions::default(),
+            w3c_log: None,
+            geoip: None,
+            fd_headroom: None,
+            fast_open_queue: None,
+            bind_retry: BindRetry::default(),
+            transparent: false,
+            plugins: Vec::new(),
+            statsd: None,
+            error_renderer: None,
+        }
+    }
+
+    pub fn bind(mut self, address: &str, port: u32) -> Self {
+        self.address = Some((address.to_owned(), port));
+        self
+    }
+
+    /// Serves HTTPS on `address`:`port`, alongside plain HTTP if `bind` is
+    /// also called.
+    pub fn bind_tls(mut self, address: &str, port: u32, tls: TlsConfig) -> Self {
+        self.tls_address = Some((address.to_owned(), port, tls));
+        self
+    }
+
+    /// Answers 503 for any request whose handler takes longer than
+    /// `duration` to respond.
+    pub fn request_timeout(self, duration: Duration) -> Self {
+        self.middleware(Timeout::new(duration))
+    }
+
+    /// Stops accepting new connections while `max` are open, leaving further
+    /// clients waiting in the listen backlog.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Closes new connections straight away from any address that already
+    /// has `max` open.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Answers 503 with a `Retry-After` header, without running the handler,
+    /// while more than `max_pending` connections are being handled. Shedding
+    /// the excess keeps latency steady for the requests that do get served.
+    pub fn shed_load_above(mut self, max_pending: usize, retry_after: Duration) -> Self {
+        self.load_shedding = Some((max_pending, retry_after));
+        self
+    }
+
+    /// Keeps `reserved` file descriptors free for everything but connections.
+    /// At startup the open file limit is raised as far as the system allows;
+    /// once only `reserved` descriptors are left, new connections get a 503
+    /// and are closed straight away, rather than failing wherever the next
+    /// descriptor happens to be needed. Off unless called, since raising the
+    /// limit affects the whole process.
+    pub fn fd_headroom(mut self, reserved: usize) -> Self {
+        self.fd_headroom = Some(reserved);
+        self
+    }
+
+    /// Enables TCP Fast Open on the HTTP and HTTPS listeners where the OS
+    /// supports it, saving repeat clients a round trip. `queue` caps the
+    /// connections still completing their handshake.
+    pub fn tcp_fast_open(mut self, queue: u32) -> Self {
+        self.fast_open_queue = Some(queue);
+        self
+    }
+
+    /// Retries binding the listeners as `retry` allows when their address is
+    /// in use or not available yet, rather than failing to start.
+    pub fn bind_retry(mut self, retry: BindRetry) -> Self {
+        self.bind_retry = retry;
+        self
+    }
+
+    /// Runs the HTTP and HTTPS listeners as a Linux transparent proxy: with
+    /// TPROXY firewall rules sending traffic for other addresses to the
+    /// server, their connections are accepted, and each request carries the
+    /// address it was sent to as `OriginalDst`. Needs CAP_NET_ADMIN.
+    pub fn transparent(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+
+    /// Saves the raw bytes of requests to disk for debugging, as configured
+    /// by `capture`.
+    pub fn capture_requests(mut self, capture: RequestCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Appends every request and its response to `log`, for replaying later.
+    pub fn record_traffic(mut self, log: TrafficLog) -> Self {
+        self.traffic_log = Some(log);
+        self
+    }
+
+    /// Sets which headers are masked, and how much of each body is shown, when
+    /// requests and responses are logged, and how client addresses and paths
+    /// are masked in the access log. Credentials headers are masked by
+    /// default.
+    pub fn redact(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// Sets how forgiving the request parser is, e.g. of bare-LF line
+    /// endings. Requests are parsed strictly by default.
+    pub fn parse_options(mut self, options: ParseOptions) -> Self {
+        self.parse_options = options;
+        self
+    }
+
+    /// Writes the access log in the W3C Extended Log File Format, to the
+    /// `W3cLog::TARGET` log target or the log's directory, instead of the
+    /// server's own format.
+    pub fn w3c_access_log(mut self, log: W3cLog) -> Self {
+        self.w3c_log = Some(log);
+        self
+    }
+
+    /// Looks each client's address up in `geoip`, adding the `Geo` found to
+    /// the request's extensions and the access log.
+    pub fn geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Serves live status as JSON at `/status` on a separate admin listener.
+    /// Keep it off public interfaces.
+    pub fn admin(mut self, address: &str, port: u32) -> Self {
+        self.admin_address = Some((address.to_owned(), port));
+        self
+    }
+
+    /// Serves `mounts` at this point in the middleware chain, and lets them
+    /// be listed, added and removed at `/mounts` on the admin listener by
+    /// requests carrying `Authorization: Bearer <token>`.
+    pub fn runtime_mounts(mut self, mounts: RuntimeMounts, token: &str) -> Self {
+        self.runtime_mounts = Some((mounts.clone(), token.to_owned()));
+        self.middleware(mounts)
+    }
+
+    /// Restyles every response made from a handler's error, e.g. as JSON.
+    /// `render` gets the error's own response, whose body holds the message.
+    pub fn error_renderer<F>(mut self, render: F) -> Self
+    where
+        F: Fn(HttpResponse) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.error_renderer = Some(Arc::new(render));
+        self
+    }
+
+    /// Pushes the server's metrics to a StatsD agent while it runs.
+    pub fn statsd(mut self, exporter: StatsdExporter) -> Self {
+        self.statsd = Some(exporter);
+        self
+    }
+
+    pub fn route<H: Handler>(mut self, method: HttpMethod, pattern: &str, handler: H) -> Self {
+        self.router = self.router.route(method, pattern, handler);
+        self
+    }
+
+    /// Restricts the route added last to requests with a Content-Type of
+    /// `media_type`, as `Router::consumes` does.
+    pub fn consumes(mut self, media_type: &str) -> Self {
+        self.router = self.router.consumes(media_type);
+        self
+    }
+
+    /// Adds a guard to the route added last, as `Router::guard` does.
+    pub fn guard(mut self, guard: Guard) -> Self {
+        self.router = self.router.guard(guard);
+        self
+    }
+
+    pub fn mount<H: Handler>(mut self, prefix: &str, handler: H) -> Self {
+        self.router = self.router.mount(prefix, handler);
+        self
+    }
+
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        self.router = self.router.middleware(middleware);
+        self
+    }
+
+    /// Adds the plugins in `registry`, letting each register its routes,
+    /// middleware and state. Their request and response hooks run as
+    /// middleware at this point in the chain, and their startup and shutdown
+    /// hooks when `serve` starts and stops.
+    pub fn plugins(self, registry: PluginRegistry) -> Self {
+        let (mut builder, plugins) = registry.apply(self);
+        builder.plugins.push(plugins);
+        builder
+    }
+
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        self.router = self.router.nest(prefix, router);
+        self
+    }
+
+    pub fn static_files(self, prefix: &str, root: impl AsRef<Path>) -> Self {
+        self.mount(prefix, StaticFiles::new(root))
+    }
+
+    /// Serves a liveness endpoint at `liveness`, which answers 200 while the
+    /// process is up, and a readiness endpoint at `readiness`, which answers
+    /// 503 until the server is listening, while any readiness check fails,
+    /// and once draining has started.
+    pub fn health_endpoints(self, liveness: &str, readiness: &str) -> Self {
+        let health = self.health.clone();
+        self.route(HttpMethod::GET, liveness, |_| async { HttpResponse::ok() })
+            .route(HttpMethod::GET, readiness, Readiness(health))
+    }
+
+    /// Adds a check that must pass for the readiness endpoint to report ready.
+    pub fn readiness_check<F>(self, check: F) -> Self
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.health.add_check(check);
+        self
+    }
+
+    /// The health shared with the built server, e.g. to start draining
+    /// before a shutdown.
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    /// The counters shared with the built server.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Shares read buffers with the given pool instead of a private default
+    /// one, e.g. to keep a handle for reading its stats.
+    pub fn buffer_pool(mut self, buffer_pool: Arc<BufferPool>) -> Self {
+        self.buffer_pool = buffer_pool;
+        self
+    }
+
+    /// Makes `state` available to every handler through `State::<T>::from_request`.
+    /// Registering a second state of the same type replaces the first.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        self.states.insert(state);
+        self
+    }
+
+    /// Fails if no address was bound or if any two routes are ambiguous.
+    pub fn build(self) -> Result<Server, ServerError> {
+        let admin = self.admin_listener();
+        let tcp_listener = |address: &str, port: u32| {
+            let mut listener =
+                TcpRequestListener::new(address, port).with_bind_retry(self.bind_retry);
+            if self.transparent {
+                listener = listener.with_transparent();
+            }
+            match self.fast_open_queue {
+                Some(queue) => listener.with_fast_open(queue),
+                None => listener,
+            }
+        };
+        let mut listeners = Vec::new();
+        if let Some((address, port)) = &self.address {
+            listeners.push(Listener {
+                tcp: tcp_listener(address, *port),
+                tls: None,
+            });
+        }
+        if let Some((address, port, tls)) = &self.tls_address {
+            listeners.push(Listener {
+                tcp: tcp_listener(address, *port),
+                tls: Some(tls.host_acceptor()),
+            });
+        }
+        if listeners.is_empty() {
+            return Err(ServerError::NotBound);
+        }
+        let health = self.health.clone();
+        let tls = self.tls_address.as_ref().map(|(_, _, tls)| tls.clone());
+        let statsd = self.statsd.clone();
+        let plugins = self.plugins.clone();
+
+        Ok(Server {
+            listeners,
+            admin,
+            health,
+            tls,
+            statsd,
+            plugins,
+            context: self.into_context()?,
+        })
+    }
+
+    /// Everything connections are handled with, checking the routes first.
+    pub(crate) fn into_context(self) -> Result<Arc<ConnectionContext>, ServerError> {
+        self.router.validate()?;
+
+        let mut limits = ConnectionLimits::new(self.max_connections, self.max_connections_per_ip);
+        if let Some((max_pending, retry_after)) = self.load_shedding {
+            limits = limits.with_load_shedding(max_pending, retry_after);
+        }
+        if let (Some(reserved), Some(fd_limit)) = (self.fd_headroom, raise_fd_limit()) {
+            let budget = fd_limit.saturating_sub(reserved);
+            debug!(
+                "Open file limit is {}, allowing {} connections",
+                fd_limit, budget
+            );
+            limits = limits.with_fd_budget(budget);
+        }
+
+        Ok(Arc::new(ConnectionContext {
+            router: self.router.middleware(RecordMetrics(self.metrics.clone())),
+            buffer_pool: self.buffer_pool,
+            states: self.states,
+            metrics: self.metrics,
+            limits,
+            capture: self.capture,
+            traffic_log: self.traffic_log,
+            redaction: self.redaction,
+            parse_options: self.parse_options,
+            w3c_log: self.w3c_log,
+            geoip: self.geoip,
+            error_renderer: self.error_renderer,
+        }))
+    }
+
+    pub async fn serve(self) -> Result<(), ServerError> {
+        self.build()?.serve().await
+    }
+
+    fn admin_listener(&self) -> Option<(Listener, Arc<ConnectionContext>)> {
+        let (address, port) = self.admin_address.as_ref()?;
+        let mut router = admin::router(
+            self.metrics.clone(),
+            self.health.clone(),
+            self.buffer_pool.clone(),
+        );
+        if let Some((mounts, token)) = &self.runtime_mounts {
+            router = admin::mount_routes(router, mounts.clone(), token);
+        }
+
+        Some((
+            Listener {
+                tcp: TcpRequestListener::new(address, *port).with_bind_retry(self.bind_retry),
+                tls: None,
+            },
+            Arc::new(ConnectionContext {
+                router,
+                buffer_pool: self.buffer_pool.clone(),
+                states: StateMap::default(),
+                metrics: Arc::new(Metrics::new()),
+                limits: ConnectionLimits::default(),
+                capture: None,
+                traffic_log: None,
+                redaction: self.redaction.clone(),
+                parse_options: ParseOptions::default(),
+                w3c_log: None,
+                geoip: None,
+                error_renderer: None,
+            }),
+        ))
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+pub struct Server {
+    listeners: Vec<Listener>,
+    admin: Option<(Listener, Arc<ConnectionContext>)>,
+    health: Arc<Health>,
+    tls: Option<TlsConfig>,
+    statsd: Option<StatsdExporter>,
+    plugins: Vec<Plugins>,
+    context: Arc<ConnectionContext>,
+}
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Binds the listeners and handles connections until an unrecoverable
+    /// error occurs, running the plugins' shutdown hooks once it stops.
+    pub async fn serve(mut self) -> Result<(), ServerError> {
+        let plugins = std::mem::take(&mut self.plugins);
+        let served = self.accept_connections(&plugins).await;
+        for plugins in plugins.iter().rev() {
+            plugins.shut_down();
+        }
+        served
+    }
+
+    async fn accept_connections(mut self, plugins: &[Plugins]) -> Result<(), ServerError> {
+        if let Some((mut listener, context)) = self.admin.take() {
+            listener.tcp.open().await?;
+            info!("Admin listener started");
+            tokio::spawn(accept_loop(listener, context));
+        }
+
+        for listener in &mut self.listeners {
+            listener.tcp.open().await?;
+        }
+        for plugins in plugins {
+            plugins.start();
+        }
+        self.health.set_listening();
+        info!("Listening for connections");
+        if let Some(log) = &self.context.w3c_log {
+            log.start(SystemTime::now());
+        }
+        if let Some(tls) = self.tls.take() {
+            for tls in tls.with_hosts() {
+                tokio::spawn(tls.reload_on_changes());
+            }
+        }
+        if let Some(statsd) = self.statsd.take() {
+            tokio::spawn(statsd.run(self.context.metrics.clone()));
+        }
+
+        let last = self.listeners.pop().ok_or(ServerError::NotBound)?;
+        for listener in self.listeners {
+            tokio::spawn(accept_loop(listener, self.context.clone()));
+        }
+        accept_loop(last, self.context).await;
+        Ok(())
+    }
+
+    /// Runs raw request bytes through the same parse, route and respond
+    /// pipeline as a connection would, over an in-memory stream, and returns
+    /// the raw response. Nothing needs to be listening.
+    pub async fn handle_raw(&self, request: &[u8]) -> std::io::Result<Vec<u8>> {
+        handle_raw(self.context.clone(), request).await
+    }
+}
+
+pub(crate) async fn handle_raw(
+    context: Arc<ConnectionContext>,
+    request: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let connection = tokio::spawn(async move {
+        let connection = context.metrics.connection_opened();
+        let stream = connection.count(server);
+        handle_connection(stream, "-".to_owned(), Scheme::Http, None, None, &context).await
+    });
+
+    client.write_all(request).await?;
+    client.shutdown().await?;
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await?;
+    connection.await.map_err(std::io::Error::other)??;
+    Ok(response)
+}
+
+/// A bound address and, for HTTPS, the acceptor that wraps its connections.
+struct Listener {
+    tcp: TcpRequestListener,
+    tls: Option<HostAcceptor>,
+}
+
+async fn accept_loop(mut listener: Listener, context: Arc<ConnectionContext>) {
+    let mut backoff = AcceptBackoff::default();
+    loop {
+        let permit = context.limits.reserve().await;
+        let stream = match listener.tcp.accept_request().await {
+            Ok(stream) => {
+                backoff.reset();
+                stream
+            }
+            Err(NetError::IoError { source }) => {
+                context.metrics.accept_failed();
+                match AcceptFailure::classify(&source) {
+                    AcceptFailure::Connection => debug!("Accept failed, retrying: {}", source),
+                    AcceptFailure::Other if backoff.failures() >= REBIND_AFTER_FAILURES => {
+                        warn!("Accept keeps failing, rebinding the listener: {}", source);
+                        backoff.reset();
+                        if let Err(e) = listener.tcp.reopen().await {
+                            error!("Stopping accept loop, could not rebind: {}", e);
+                            return;
+                        }
+                    }
+                    failure => {
+                        let delay = backoff.next_delay();
+                        warn!(
+                            "Accept failed ({:?}), pausing {:?}: {}",
+                            failure, delay, source
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+                continue;
+            }
+            Err(e) => {
+                error!("Stopping accept loop: {}", e);
+                return;
+            }
+        };
+        if context
+            .limits
+            .near_fd_limit(context.metrics.active_connections())
+        {
+            debug!("Too close to the open file limit, turning a connection away");
+            if listener.tls.is_none() {
+                tokio::spawn(turn_away(stream));
+            }
+            continue;
+        }
+        let peer_ip = stream.peer_addr().map(|address| address.ip());
+        let original_dst = if listener.tcp.is_transparent() {
+            stream.local_addr().ok()
+        } else {
+            None
+        };
+        let ip_guard = match peer_ip {
+            Ok(ip) => match context.limits.admit(ip) {
+                Some(ip_guard) => Some(ip_guard),
+                None => {
+                    debug!("Too many connections from {}, closing", ip);
+                    continue;
+                }
+            },
+            Err(_) => None,
+        };
+
+        let context = context.clone();
+        let tls = listener.tls.clone();
+        tokio::spawn(async move {
+            let _limits = (permit, ip_guard);
+            let tls = match &tls {
+                Some(tls) => Some(tls.select(&stream).await),
+                None => None,
+            };
+            let connection = context.metrics.connection_opened();
+            let stream = connection.count(stream);
+            let peer = peer_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|_| "-".to_owned());
+            let result = match tls {
+                Some((acceptor, tls_host)) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let scheme = Scheme::Https;
+                        let tls_host = Some(&tls_host);
+                        handle_connection(stream, peer, scheme, original_dst, tls_host, &context)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                },
+                None => {
+                    handle_connection(stream, peer, Scheme::Http, original_dst, None, &context)
+                        .await
+                }
+            };
+
+            match result {
+                Ok(()) => connection.served(1),
+                Err(e) => debug!("Connection closed with error: {}", e),
+            }
+        });
+    }
+}
+
+/// Answers 503 and closes, for a connection accepted too close to the open
+/// file limit to be served.
+async fn turn_away(mut stream: TcpStream) {
+    let mut response = HttpResponse::new(503)
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close");
+    if response.write_to(&mut stream).await.is_ok() {
+        let _ = stream.shutdown().await;
+    }
+}
+
+/// Everything a connection task needs, shared between all of them.
+pub(crate) struct ConnectionContext {
+    router: Router,
+    buffer_pool: Arc<BufferPool>,
+    states: StateMap,
+    metrics: Arc<Metrics>,
+    limits: ConnectionLimits,
+    capture: Option<RequestCapture>,
+    traffic_log: Option<TrafficLog>,
+    redaction: Redaction,
+    parse_options: ParseOptions,
+    w3c_log: Option<W3cLog>,
+    geoip: Option<GeoIp>,
+    error_renderer: Option<Arc<ErrorRenderer>>,
+}
+
+type ErrorRenderer = dyn Fn(HttpResponse) -> HttpResponse + Send + Sync;
+
+/// Serves a request on `stream`. For HTTPS, `tls_host` is the host config
+/// the handshake picked, which the request's Host must agree with.
+pub(crate) async fn handle_connection<S>(
+    stream: S,
+    peer: String,
+    scheme: Scheme,
+    original_dst: Option<SocketAddr>,
+    tls_host: Option<&TlsHost>,
+    context: &ConnectionContext,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let recording = context.capture.is_some() || context.traffic_log.is_some();
+    let mut stream = Recording::new(stream, recording);
+    let mut buffer = context.buffer_pool.acquire();
+    let parsed =
+        parse_from_reader_with_limit(&mut stream, &mut buffer, context.parse_options, |head| {
+            context.router.max_body(head)
+        })
+        .await;
+    drop(buffer);
+    trace!("Buffer pool: {:?}", context.buffer_pool.stats());
+    let raw_request = stream.take_recorded();
+    if let (Some(capture), Some(raw)) = (&context.capture, &raw_request) {
+        capture
+            .save(peer.parse().ok(), parsed.as_ref().ok(), raw)
+            .await;
+    }
+
+    let mut timing = RequestTiming::default();
+    let simple = parsed
+        .as_ref()
+        .is_ok_and(|request| request.extensions().get::<SimpleRequest>().is_some());
+    let head = parsed
+        .as_ref()
+        .is_ok_and(|request| request.method == HttpMethod::HEAD);
+    let mut w3c_fields = Vec::new();
+    let mut geo = None;
+    let (request_line, mut response) = match parsed {
+        Ok(mut request) => {
+            debug!("Got request {}", context.redaction.request(&request));
+            if let (Some(geoip), Ok(ip)) = (&context.geoip, peer.parse()) {
+                geo = geoip.lookup(ip);
+                if let Some(geo) = &geo {
+                    request.extensions_mut().insert(geo.clone());
+                }
+            }
+            if let Some(log) = &context.w3c_log {
+                w3c_fields = log.request_fields(&request, &context.redaction);
+            }
+            let request_line = format!(
+                "{:?} {}",
+                request.method,
+                context.redaction.path(&request.path)
+            );
+            let parse_timing = request
+                .extensions()
+                .get::<ParseTiming>()
+                .copied()
+                .unwrap_or_default();
+            timing.headers = parse_timing.headers;
+            timing.body = parse_timing.body;
+
+            let misdirected = match (tls_host, request.header("Host")) {
+                (Some(tls_host), Some(host)) => !tls_host.serves(host),
+                _ => false,
+            };
+            let response = match context.limits.shed(context.metrics.active_connections()) {
+                Some(retry_after) => {
+                    debug!("Overloaded, shedding {}", request_line);
+                    HttpResponse::new(503)
+                        .with_header("Retry-After", &retry_after.as_secs().max(1).to_string())
+                }
+                None if misdirected => {
+                    debug!("Host of {} doesn't match the TLS handshake", request_line);
+                    HttpResponse::new(421)
+                }
+                None => {
+                    context.states.inject(&mut request);
+                    if let Ok(ip) = peer.parse() {
+                        request.extensions_mut().insert(ClientAddr(ip));
+                    }
+                    request.extensions_mut().insert(scheme);
+                    if let Some(address) = original_dst {
+                        request.extensions_mut().insert(OriginalDst(address));
+                    }
+                    let (hints, hint_receiver) = EarlyHints::channel();
+                    if !simple {
+                        request.extensions_mut().insert(hints);
+                    }
+                    let handler_started = Instant::now();
+                    let handling = CatchPanic(Box::pin(context.router.dispatch(request)));
+                    let response =
+                        match with_early_hints(&mut stream, hint_receiver, handling).await {
+                            Ok(response) => response,
+                            Err(message) => {
+                                error!(
+                                    "Handler panicked on {} from {}: {}",
+                                    request_line,
+                                    context.redaction.client(&peer),
+                                    message
+                                );
+                                HttpResponse::new(500).into_error()
+                            }
+                        };
+                    timing.handler = handler_started.elapsed();
+                    match &context.error_renderer {
+                        Some(render) if response.is_error() => render(response),
+                        _ => response,
+                    }
+                }
+            };
+            (request_line, response)
+        }
+        Err(ParseError::MaxHeaderSizeExceeded) => ("-".to_owned(), HttpResponse::new(413)),
+        Err(ParseError::BodyTooLarge { length, max }) => {
+            debug!("Rejecting {} byte body over the {} byte limit", length, max);
+            ("-".to_owned(), HttpResponse::new(413))
+        }
+        Err(ParseError::UnsupportedTransferCoding { coding }) => {
+            debug!("Rejecting request with transfer coding {}", coding);
+            ("-".to_owned(), HttpResponse::new(501))
+        }
+        Err(ParseError::UnsupportedMethod { method }) => {
+            debug!("Rejecting request with method {}", method);
+            ("-".to_owned(), HttpResponse::new(501))
+        }
+        Err(e) => {
+            debug!("Rejecting malformed request: {}", e);
+            ("-".to_owned(), HttpResponse::new(400))
+        }
+    };
+
+    debug!("Sending response {}", response.redacted(&context.redaction));
+    let write_started = Instant::now();
+    let written = if simple {
+        response.write_body_to(&mut stream).await
+    } else if head {
+        response.write_head_to(&mut stream).await
+    } else {
+        response.write_to(&mut stream).await
+    };
+    timing.write = write_started.elapsed();
+    let status = match &written {
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => 500,
+        _ => response.status,
+    };
+    if let (Some(traffic_log), Some(raw)) = (&context.traffic_log, &raw_request) {
+        let sent = if simple {
+            response.body().to_vec()
+        } else if status != response.status {
+            HttpResponse::new(status).to_bytes()
+        } else if head {
+            response.head_bytes()
+        } else {
+            response.to_bytes()
+        };
+        traffic_log.record(raw, &sent);
+    }
+
+    context.metrics.record_timing(&timing);
+    let aborted = written.as_ref().err().is_some_and(is_client_abort);
+    if aborted {
+        debug!("Client went away during the response to {}", request_line);
+        context.metrics.client_aborted();
+    }
+    let logged_status = if aborted {
+        CLIENT_CLOSED_REQUEST
+    } else {
+        status
+    };
+    match &context.w3c_log {
+        Some(log) => {
+            let now = SystemTime::now();
+            let line = log.line(
+                &w3c_fields,
+                &context.redaction.client(&peer),
+                logged_status,
+                &response,
+                timing.headers + timing.body + timing.handler + timing.write,
+                now,
+            );
+            log.write(&line, now);
+        }
+        None => info!(
+            target: "access",
+            "{} \"{}\" {} headers={} body={} handler={} write={}{}",
+            context.redaction.client(&peer),
+            request_line,
+            logged_status,
+            format_millis(timing.headers),
+            format_millis(timing.body),
+            format_millis(timing.handler),
+            format_millis(timing.write),
+            geo.as_ref().map_or_else(String::new, describe_geo)
+        ),
+    }
+    match written {
+        Err(_) if aborted => Ok(()),
+        Err(e) if e.kind() != std::io::ErrorKind::InvalidData => Err(e),
+        written => {
+            close_gracefully(&mut stream).await;
+            written
+        }
+    }
+}
+
+/// The client's location as appended to the access log.
+fn describe_geo(geo: &Geo) -> String {
+    let mut described = String::new();
+    if let Some(country) = &geo.country {
+        described.push_str(&format!(" country={}", country));
+    }
+    if let Some(asn) = geo.asn {
+        described.push_str(&format!(" asn={}", asn));
+    }
+    described
+}
+
+/// Ends the connection in order once the last response is out: the write
+/// half is shut down, so the client reads a clean end of stream (and TLS
+/// sends close_notify), then anything the client is still sending is read
+/// and thrown away for a short while. Closing a socket with unread input
+/// makes the kernel answer with a RST, which can destroy a response the
+/// client hasn't read yet.
+async fn close_gracefully<S>(stream: &mut S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+    let mut discard = [0; 4096];
+    let drain = async {
+        while let Ok(read) = stream.read(&mut discard).await {
+            if read == 0 {
+                break;
+            }
+        }
+    };
+    if tokio::time::timeout(LINGER_TIMEOUT, drain).await.is_err() {
+        trace!("Client kept the connection open, closing it anyway");
+    }
+}
+
+fn format_millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,113 @@
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: usize = 1;
+const SERVER_NAME_EXTENSION: usize = 0;
+const HOST_NAME: usize = 0;
+
+/// The most a client's first TLS record can hold.
+pub(crate) const MAX_RECORD: usize = 5 + 16 * 1024;
+
+/// Reads the host name a client asked for from the start of its first TLS
+/// record, so a certificate can be picked before the handshake starts.
+/// Returns `None` if `data` doesn't hold the whole record yet, and
+/// `Some(None)` if it isn't a ClientHello or names no host. Names are
+/// lowercased.
+pub(crate) fn server_name(data: &[u8]) -> Option<Option<String>> {
+    let mut record = Reader(data);
+    let header = record.take(5)?;
+    if header[0] != HANDSHAKE_RECORD {
+        return Some(None);
+    }
+    let body = record.take(usize::from(header[3]) << 8 | usize::from(header[4]))?;
+    Some(client_hello_name(body))
+}
+
+/// Finds the host name in a ClientHello. A hello split over several
+/// records is cut short, and its name may be missed if it comes late.
+fn client_hello_name(body: &[u8]) -> Option<String> {
+    let mut hello = Reader(body);
+    if hello.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // Length, version and random.
+    hello.take(3 + 2 + 32)?;
+    // Session ID, cipher suites and compression methods.
+    hello.vec8()?;
+    hello.vec16()?;
+    hello.vec8()?;
+
+    let mut extensions = hello.vec16()?;
+    while let Some(kind) = extensions.u16() {
+        let mut extension = extensions.vec16()?;
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let mut names = extension.vec16()?;
+        while let Some(name_type) = names.u8() {
+            let name = names.vec16()?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name.0)
+                    .ok()
+                    .map(str::to_ascii_lowercase);
+            }
+        }
+    }
+    None
+}
+
+/// Reads big-endian integers and length-prefixed vectors off a byte slice.
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|bytes| usize::from(bytes[0]))
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|bytes| usize::from(bytes[0]) << 8 | usize::from(bytes[1]))
+    }
+
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()?;
+        self.take(len).map(Reader)
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()?;
+        self.take(len).map(Reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{ClientConfig, ClientSession, Session};
+    use tokio_rustls::webpki::DNSNameRef;
+
+    fn client_hello(host: &str) -> Vec<u8> {
+        let name = DNSNameRef::try_from_ascii_str(host).unwrap();
+        let mut session = ClientSession::new(&Arc::new(ClientConfig::new()), name);
+        let mut hello = Vec::new();
+        session.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn reads_the_name_from_a_client_hello() {
+        let hello = client_hello("Example.com");
+
+        assert_eq!(Some(Some("example.com".to_owned())), server_name(&hello));
+        assert_eq!(None, server_name(&hello[..hello.len() - 1]));
+        assert_eq!(None, server_name(&hello[..3]));
+        assert_eq!(Some(None), server_name(b"GET / HTTP/1.1\r\n\r\n"));
+    }
+}
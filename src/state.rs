@@ -0,0 +1,85 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rust_http_parse::{Extensions, HttpRequest};
+
+/// Application state registered with `ServerBuilder::with_state`. Every
+/// request carries a cheap clone of it in its extensions, so handlers can get
+/// at shared resources without global statics.
+pub struct State<T>(Arc<T>);
+impl<T: Send + Sync + 'static> State<T> {
+    pub fn from_request(request: &HttpRequest) -> Option<State<T>> {
+        request.extensions().get::<State<T>>().cloned()
+    }
+}
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+type Injector = Box<dyn Fn(&mut Extensions) + Send + Sync>;
+
+/// The set of states registered on a server, one per type.
+#[derive(Default)]
+pub(crate) struct StateMap {
+    injectors: Vec<Injector>,
+}
+impl StateMap {
+    pub fn insert<T: Send + Sync + 'static>(&mut self, state: T) {
+        let state = State(Arc::new(state));
+        self.injectors.push(Box::new(move |extensions| {
+            extensions.insert(state.clone());
+        }));
+    }
+
+    pub fn inject(&self, request: &mut HttpRequest) {
+        for injector in &self.injectors {
+            injector(request.extensions_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_parse::HttpMethod;
+
+    struct AppState {
+        name: &'static str,
+    }
+
+    #[test]
+    fn injected_state_is_shared_between_requests() {
+        let mut states = StateMap::default();
+        states.insert(AppState { name: "app" });
+        states.insert(42u32);
+        let mut first = HttpRequest::new(HttpMethod::GET, "/");
+        let mut second = HttpRequest::new(HttpMethod::GET, "/");
+
+        states.inject(&mut first);
+        states.inject(&mut second);
+
+        let first_state = State::<AppState>::from_request(&first).unwrap();
+        let second_state = State::<AppState>::from_request(&second).unwrap();
+        assert_eq!("app", first_state.name);
+        assert!(Arc::ptr_eq(&first_state.0, &second_state.0));
+        assert_eq!(42, *State::<u32>::from_request(&second).unwrap());
+    }
+
+    #[test]
+    fn missing_state_is_none() {
+        let request = HttpRequest::new(HttpMethod::GET, "/");
+
+        assert!(State::<AppState>::from_request(&request).is_none());
+    }
+}
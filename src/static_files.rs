@@ -0,0 +1,650 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use log::debug;
+use rust_http_parse::{HttpMethod, HttpRequest};
+use tokio::fs;
+
+use super::auth::bearer_token_matches;
+use super::range;
+use super::{BoxFuture, Handler, HttpResponse};
+
+/// Serves files from a directory on disk for GET and HEAD requests. The file is
+/// looked up from the `path` param, so register it with a catch-all pattern
+/// such as `/assets/*path`, or mount it under a prefix. Paths containing
+/// `..` or naming an absolute path are never served.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+    confined: bool,
+    write_token: Option<String>,
+    downloads: Vec<String>,
+}
+impl StaticFiles {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            index: None,
+            confined: false,
+            write_token: None,
+            downloads: Vec::new(),
+        }
+    }
+
+    /// Serves the file named `index` from a directory when the directory
+    /// itself is requested.
+    pub fn index(mut self, index: &str) -> Self {
+        self.index = Some(index.to_owned());
+        self
+    }
+
+    /// Has the OS resolve every path beneath the root, so symlinks pointing
+    /// out of it and any gap in the path checks here still can't expose
+    /// other files. Uses `openat2` with `RESOLVE_BENEATH` on Linux, and
+    /// checks the canonicalized path elsewhere.
+    pub fn confined(mut self) -> Self {
+        self.confined = true;
+        self
+    }
+
+    /// Also accepts PUT to create or replace a file and DELETE to remove
+    /// one, from requests carrying `Authorization: Bearer <token>`. Writes
+    /// never follow symlinks out of the root, whether or not it's confined.
+    pub fn writable(mut self, token: &str) -> Self {
+        self.write_token = Some(token.to_owned());
+        self
+    }
+
+    /// Serves matching files with `Content-Disposition: attachment`, so
+    /// browsers save them rather than display them. A pattern of the form
+    /// `*.zip` matches an extension, case-insensitively; anything else is a
+    /// path prefix below the root, such as `exports/`.
+    pub fn download(mut self, pattern: &str) -> Self {
+        self.downloads.push(pattern.to_owned());
+        self
+    }
+
+    fn is_download(&self, relative_path: &Path) -> bool {
+        let path = relative_path.to_string_lossy();
+        self.downloads
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(extension) => relative_path
+                    .extension()
+                    .is_some_and(|actual| actual.to_string_lossy().eq_ignore_ascii_case(extension)),
+                None => path.starts_with(pattern.as_str()),
+            })
+    }
+
+    fn is_authorized(&self, request: &HttpRequest) -> bool {
+        self.write_token
+            .as_ref()
+            .is_some_and(|token| bearer_token_matches(request, token))
+    }
+
+    fn resolve(&self, request: &HttpRequest) -> Option<PathBuf> {
+        let path = Path::new(request.param("path")?);
+        let is_relative_descendant = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if is_relative_descendant {
+            Some(path.to_path_buf())
+        } else {
+            None
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let relative_path = self.resolve(&request);
+        let root = self.root.clone();
+        let index = self.index.clone();
+        let confined = self.confined;
+        let writable = self.write_token.is_some();
+        let authorized = self.is_authorized(&request);
+        let download = relative_path
+            .as_ref()
+            .is_some_and(|path| self.is_download(path));
+
+        Box::pin(async move {
+            let allow = if writable {
+                "GET, HEAD, PUT, DELETE"
+            } else {
+                "GET, HEAD"
+            };
+            let is_write = match request.method {
+                HttpMethod::GET | HttpMethod::HEAD => false,
+                HttpMethod::PUT | HttpMethod::DELETE if writable => true,
+                _ => return HttpResponse::new(405).with_header("Allow", allow),
+            };
+            if is_write && !authorized {
+                return HttpResponse::new(401).with_header("WWW-Authenticate", "Bearer");
+            }
+
+            let relative_path = match relative_path {
+                Some(relative_path) => relative_path,
+                None => return HttpResponse::not_found(),
+            };
+            match request.method {
+                HttpMethod::PUT => store(root, relative_path, request.into_body()).await,
+                HttpMethod::DELETE => remove(root, relative_path).await,
+                _ => {
+                    let range = request.header("Range").cloned();
+                    serve(root, relative_path, index, confined, range, download).await
+                }
+            }
+        })
+    }
+}
+
+async fn serve(
+    root: PathBuf,
+    relative_path: PathBuf,
+    index: Option<String>,
+    confined: bool,
+    range: Option<String>,
+    download: bool,
+) -> HttpResponse {
+    debug!("Handling static request");
+    let relative_path = match index {
+        Some(index) if is_dir(&root.join(&relative_path)).await => relative_path.join(index),
+        _ => relative_path,
+    };
+
+    let content = if confined {
+        let path = relative_path.clone();
+        tokio::task::spawn_blocking(move || read_beneath(&root, &path))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+    } else {
+        fs::read(root.join(&relative_path)).await
+    };
+
+    let response = match content {
+        Ok(content) => match range.and_then(|range| range::respond(&range, &content)) {
+            Some(partial) => partial.with_header("Accept-Ranges", "bytes"),
+            None => HttpResponse::ok()
+                .with_header("Accept-Ranges", "bytes")
+                .with_body(content),
+        },
+        Err(e) => {
+            debug!("Could not read {}: {}", relative_path.display(), e);
+            return HttpResponse::not_found();
+        }
+    };
+    match relative_path.file_name() {
+        Some(name) if download => response.with_header(
+            "Content-Disposition",
+            &content_disposition(&name.to_string_lossy()),
+        ),
+        _ => response,
+    }
+}
+
+async fn store(root: PathBuf, relative_path: PathBuf, content: Vec<u8>) -> HttpResponse {
+    debug!("Storing {}", relative_path.display());
+    let path = relative_path.clone();
+    let stored = tokio::task::spawn_blocking(move || write_beneath(&root, &path, &content))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+    match stored {
+        Ok(true) => HttpResponse::new(204),
+        Ok(false) => HttpResponse::new(201),
+        Err(e) => write_error(&relative_path, e),
+    }
+}
+
+async fn remove(root: PathBuf, relative_path: PathBuf) -> HttpResponse {
+    debug!("Removing {}", relative_path.display());
+    let path = relative_path.clone();
+    let removed = tokio::task::spawn_blocking(move || remove_beneath(&root, &path))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+    match removed {
+        Ok(()) => HttpResponse::new(204),
+        Err(e) => write_error(&relative_path, e),
+    }
+}
+
+fn write_error(relative_path: &Path, e: io::Error) -> HttpResponse {
+    debug!("Could not modify {}: {}", relative_path.display(), e);
+    match e.kind() {
+        io::ErrorKind::NotFound => HttpResponse::not_found(),
+        io::ErrorKind::PermissionDenied => HttpResponse::new(403),
+        io::ErrorKind::InvalidInput => HttpResponse::new(400),
+        _ => HttpResponse::new(500),
+    }
+}
+
+async fn is_dir(path: &Path) -> bool {
+    fs::metadata(path)
+        .await
+        .map(|metadata| metadata.is_dir())
+        .unwrap_or(false)
+}
+
+/// The kernel's `struct open_how`, the argument to openat2.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+#[cfg(target_os = "linux")]
+fn read_beneath(root: &Path, relative_path: &Path) -> io::Result<Vec<u8>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let root_dir = File::open(root)?;
+    let relative_path = CString::new(relative_path.as_os_str().as_bytes())?;
+    let how = OpenHow {
+        flags: (libc::O_RDONLY | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve: libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS,
+    };
+
+    // SAFETY: every pointer passed outlives the call, and `how` matches the
+    // size given.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root_dir.as_raw_fd(),
+            relative_path.as_ptr(),
+            &how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd < 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::ENOSYS) {
+            let relative_path = Path::new(std::ffi::OsStr::from_bytes(relative_path.as_bytes()));
+            return read_canonical_beneath(root, relative_path);
+        }
+        return Err(error);
+    }
+
+    // SAFETY: openat2 just returned this descriptor and nothing else owns it.
+    let mut file = unsafe { File::from_raw_fd(fd as i32) };
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_beneath(root: &Path, relative_path: &Path) -> io::Result<Vec<u8>> {
+    read_canonical_beneath(root, relative_path)
+}
+
+fn read_canonical_beneath(root: &Path, relative_path: &Path) -> io::Result<Vec<u8>> {
+    let root = root.canonicalize()?;
+    let path = root.join(relative_path).canonicalize()?;
+    if !path.starts_with(&root) {
+        return Err(outside_root());
+    }
+
+    let mut content = Vec::new();
+    File::open(path)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Writes `content` to a file beneath `root`, creating missing directories
+/// on the way. Returns whether the file already existed. Refuses to write
+/// through a symlink or into a directory that resolves outside the root.
+fn write_beneath(root: &Path, relative_path: &Path, content: &[u8]) -> io::Result<bool> {
+    let file_name = relative_path.file_name().ok_or_else(not_a_file)?;
+    let root = root.canonicalize()?;
+    let mut dir = root.clone();
+    for component in relative_path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+    {
+        dir.push(component);
+        match std::fs::create_dir(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        dir = dir.canonicalize()?;
+        if !dir.starts_with(&root) {
+            return Err(outside_root());
+        }
+    }
+
+    let path = dir.join(file_name);
+    let existed = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_file() => true,
+        Ok(_) => return Err(not_a_file()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e),
+    };
+    std::fs::write(path, content)?;
+    Ok(existed)
+}
+
+/// Removes a file beneath `root`. A symlink is removed itself rather than
+/// followed; directories are never removed.
+fn remove_beneath(root: &Path, relative_path: &Path) -> io::Result<()> {
+    let file_name = relative_path.file_name().ok_or_else(not_a_file)?;
+    let root = root.canonicalize()?;
+    let dir = root
+        .join(relative_path.parent().unwrap_or_else(|| Path::new("")))
+        .canonicalize()?;
+    if !dir.starts_with(&root) {
+        return Err(outside_root());
+    }
+
+    let path = dir.join(file_name);
+    if std::fs::symlink_metadata(&path)?.is_dir() {
+        return Err(not_a_file());
+    }
+    std::fs::remove_file(path)
+}
+
+fn outside_root() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "path resolves outside the document root",
+    )
+}
+
+fn not_a_file() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "path does not name a file")
+}
+
+/// An attachment disposition for `name`. Names that aren't plain ASCII get
+/// an RFC 5987 `filename*` with the exact UTF-8 name, alongside a `filename`
+/// fallback with those characters replaced for older clients.
+fn content_disposition(name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == name {
+        return format!("attachment; filename=\"{}\"", name);
+    }
+
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllowedMethods, Server, TestClient};
+    use rust_http_parse::HttpRequestBuilder;
+
+    fn document_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-http-server-static-{}", name));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        root
+    }
+
+    async fn get(files: &StaticFiles, path: &str) -> HttpResponse {
+        let mut request = HttpRequest::new(HttpMethod::GET, "/");
+        request.set_param("path", path);
+        files.call(request).await
+    }
+
+    async fn write(
+        files: &StaticFiles,
+        method: HttpMethod,
+        path: &str,
+        token: &str,
+        body: &str,
+    ) -> HttpResponse {
+        let mut request = HttpRequestBuilder::new()
+            .with_method(method)
+            .with_path("/")
+            .with_header("Authorization", format!("Bearer {}", token))
+            .with_body(body)
+            .build()
+            .unwrap();
+        request.set_param("path", path);
+        files.call(request).await
+    }
+
+    #[tokio::test]
+    async fn serves_files_under_the_root() {
+        let files = StaticFiles::new(document_root("serve"));
+
+        let response = get(&files, "index.html").await;
+
+        assert_eq!(200, response.status);
+        assert_eq!(b"index", response.body());
+    }
+
+    #[tokio::test]
+    async fn answers_head_like_get_without_the_body() {
+        let files = StaticFiles::new(document_root("head"));
+        let read_only = AllowedMethods::new(&[HttpMethod::GET, HttpMethod::HEAD]).wrap(files);
+        let client = TestClient::new(Server::builder().mount("/", read_only)).unwrap();
+
+        let response = client
+            .send(HttpMethod::HEAD, "/index.html", &[], b"")
+            .await
+            .unwrap();
+
+        assert_eq!(200, response.status);
+        assert_eq!(Some(&"5".to_owned()), response.header("Content-Length"));
+        assert_eq!(b"", response.body());
+    }
+
+    #[tokio::test]
+    async fn serves_a_range_asked_for_in_lowercase() {
+        let files = StaticFiles::new(document_root("range"));
+        let mut request = HttpRequest::new(HttpMethod::GET, "/");
+        request.set_header("range", "bytes=1-2");
+        request.set_param("path", "index.html");
+
+        let response = files.call(request).await;
+
+        assert_eq!(206, response.status);
+        assert_eq!(b"nd", response.body());
+    }
+
+    #[tokio::test]
+    async fn marks_matching_files_as_downloads() {
+        let root = document_root("download");
+        std::fs::create_dir_all(root.join("exports")).unwrap();
+        std::fs::write(root.join("exports/report.csv"), "a,b").unwrap();
+        std::fs::write(root.join("Résumé \"final\".PDF"), "pdf").unwrap();
+        let files = StaticFiles::new(&root)
+            .download("exports/")
+            .download("*.pdf");
+
+        let export = get(&files, "exports/report.csv").await;
+        let pdf = get(&files, "Résumé \"final\".PDF").await;
+        let page = get(&files, "index.html").await;
+
+        assert_eq!(
+            Some(&"attachment; filename=\"report.csv\"".to_owned()),
+            export.header("Content-Disposition")
+        );
+        assert_eq!(
+            Some(
+                &"attachment; filename=\"R_sum_ _final_.PDF\"; \
+                  filename*=UTF-8''R%C3%A9sum%C3%A9%20%22final%22.PDF"
+                    .to_owned()
+            ),
+            pdf.header("Content-Disposition")
+        );
+        assert_eq!(None, page.header("Content-Disposition"));
+    }
+
+    #[tokio::test]
+    async fn serves_index_file_for_directories() {
+        let root = document_root("index");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/index.html"), "docs").unwrap();
+        let files = StaticFiles::new(&root).index("index.html");
+
+        let top = get(&files, "").await;
+        let nested = get(&files, "docs").await;
+        let without_index = get(&StaticFiles::new(&root), "docs").await;
+
+        assert_eq!(b"index", top.body());
+        assert_eq!(b"docs", nested.body());
+        assert_eq!(404, without_index.status);
+    }
+
+    #[tokio::test]
+    async fn rejects_parent_directory_segments() {
+        let files = StaticFiles::new(document_root("parent"));
+
+        let response = get(&files, "../secret.txt").await;
+
+        assert_eq!(404, response.status);
+    }
+
+    #[tokio::test]
+    async fn writable_files_accept_put_and_delete() {
+        let root = document_root("writable");
+        let _ = std::fs::remove_dir_all(root.join("uploads"));
+        let files = StaticFiles::new(&root).writable("secret-token");
+
+        let created = write(
+            &files,
+            HttpMethod::PUT,
+            "uploads/a.txt",
+            "secret-token",
+            "one",
+        )
+        .await;
+        let replaced = write(
+            &files,
+            HttpMethod::PUT,
+            "uploads/a.txt",
+            "secret-token",
+            "two",
+        )
+        .await;
+        let content = get(&files, "uploads/a.txt").await;
+        let deleted = write(
+            &files,
+            HttpMethod::DELETE,
+            "uploads/a.txt",
+            "secret-token",
+            "",
+        )
+        .await;
+        let missing = write(
+            &files,
+            HttpMethod::DELETE,
+            "uploads/a.txt",
+            "secret-token",
+            "",
+        )
+        .await;
+
+        assert_eq!(201, created.status);
+        assert!(created.to_bytes().starts_with(b"HTTP/1.1 201 Created\r\n"));
+        assert_eq!(204, replaced.status);
+        assert_eq!(b"two", content.body());
+        assert_eq!(204, deleted.status);
+        assert_eq!(404, missing.status);
+    }
+
+    #[tokio::test]
+    async fn writes_need_a_valid_token_and_writable_mode() {
+        let root = document_root("write-auth");
+
+        let wrong_token = write(
+            &StaticFiles::new(&root).writable("secret-token"),
+            HttpMethod::PUT,
+            "a.txt",
+            "guess",
+            "x",
+        )
+        .await;
+        let read_only = write(
+            &StaticFiles::new(&root),
+            HttpMethod::PUT,
+            "a.txt",
+            "secret-token",
+            "x",
+        )
+        .await;
+
+        assert_eq!(401, wrong_token.status);
+        assert_eq!(405, read_only.status);
+        assert_eq!(Some(&"GET, HEAD".to_owned()), read_only.header("Allow"));
+        assert!(!root.join("a.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn writes_do_not_follow_symlinks_out_of_the_root() {
+        let root = document_root("write-symlink");
+        let link = root.join("outside");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(root.join(".."), &link).unwrap();
+        let files = StaticFiles::new(&root).writable("secret-token");
+
+        let put = write(
+            &files,
+            HttpMethod::PUT,
+            "outside/secret.txt",
+            "secret-token",
+            "x",
+        )
+        .await;
+        let delete = write(
+            &files,
+            HttpMethod::DELETE,
+            "outside/secret.txt",
+            "secret-token",
+            "",
+        )
+        .await;
+
+        assert_eq!(403, put.status);
+        assert_eq!(403, delete.status);
+        assert_eq!(
+            "secret",
+            std::fs::read_to_string(root.join("../secret.txt")).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn confined_files_do_not_follow_symlinks_out_of_the_root() {
+        let root = document_root("symlink");
+        let link = root.join("escape.txt");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(root.join("../secret.txt"), &link).unwrap();
+
+        let unconfined = get(&StaticFiles::new(&root), "escape.txt").await;
+        let confined = get(&StaticFiles::new(&root).confined(), "escape.txt").await;
+        let inside = get(&StaticFiles::new(&root).confined(), "index.html").await;
+
+        assert_eq!(200, unconfined.status);
+        assert_eq!(404, confined.status);
+        assert_eq!(200, inside.status);
+    }
+}
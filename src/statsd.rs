@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::net::UdpSocket;
+
+use super::metrics::{ConnectionStats, Metrics, RouteStats, LATENCY_BUCKETS_MICROS};
+
+/// Largest datagram to send, small enough not to be fragmented on a
+/// typical network.
+const MAX_PACKET: usize = 1432;
+
+/// Pushes the server's metrics over UDP in StatsD format every `interval`,
+/// for Telegraf, Datadog agents and the like. Connection and request totals
+/// are sent as counters of what changed since the last push, open
+/// connections as gauges, and per-route latencies as timers. Plain StatsD
+/// has the route in the metric name; DogStatsD sends it and the status as
+/// tags instead.
+#[derive(Debug, Clone)]
+pub struct StatsdExporter {
+    address: String,
+    prefix: String,
+    interval: Duration,
+    tags: bool,
+}
+impl StatsdExporter {
+    /// Sends to `address`, e.g. `127.0.0.1:8125`, every ten seconds.
+    pub fn new(address: &str) -> Self {
+        StatsdExporter {
+            address: address.to_owned(),
+            prefix: "http_server".to_owned(),
+            interval: Duration::from_secs(10),
+            tags: false,
+        }
+    }
+
+    /// Starts every metric name with `prefix` instead of `http_server`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sends routes and statuses as DogStatsD tags.
+    pub fn dogstatsd(mut self) -> Self {
+        self.tags = true;
+        self
+    }
+
+    /// Pushes `metrics` until the server stops. Failed sends are logged and
+    /// their changes sent with the next push.
+    pub(crate) async fn run(self, metrics: Arc<Metrics>) {
+        let socket = match self.connect().await {
+            Ok(socket) => socket,
+            Err(error) => {
+                warn!("Not sending StatsD metrics to {}: {}", self.address, error);
+                return;
+            }
+        };
+        let mut sent = Snapshot::default();
+        let mut ticks = tokio::time::interval(self.interval);
+        loop {
+            ticks.tick().await;
+            let current = Snapshot::of(&metrics);
+            let packets = self.packets(&sent, &current);
+            let mut failed = false;
+            for packet in &packets {
+                if let Err(error) = socket.send(packet.as_bytes()).await {
+                    warn!("Failed to send StatsD metrics: {}", error);
+                    failed = true;
+                    break;
+                }
+            }
+            if !failed {
+                debug!("Sent {} StatsD packets", packets.len());
+                sent = current;
+            }
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<UdpSocket> {
+        let target = tokio::net::lookup_host(&self.address)
+            .await?
+            .next()
+            .ok_or_else(|| std::io::Error::other("no address found"))?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+        Ok(socket)
+    }
+
+    /// What changed between two snapshots, as StatsD lines packed into
+    /// datagrams.
+    fn packets(&self, previous: &Snapshot, current: &Snapshot) -> Vec<String> {
+        let mut packets = Vec::new();
+        let mut packet = String::new();
+        for line in self.lines(previous, current) {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+                packets.push(std::mem::take(&mut packet));
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    fn lines(&self, previous: &Snapshot, current: &Snapshot) -> Vec<String> {
+        let prefix = &self.prefix;
+        let mut lines = vec![
+            format!(
+                "{}.connections.active:{}|g",
+                prefix, current.active_connections
+            ),
+            format!(
+                "{}.requests.in_flight:{}|g",
+                prefix, current.requests_in_flight
+            ),
+        ];
+        let (before, now) = (&previous.connections, &current.connections);
+        let counters = [
+            ("connections.opened", before.opened, now.opened),
+            ("connections.closed", before.closed, now.closed),
+            (
+                "connections.accept_errors",
+                before.accept_errors,
+                now.accept_errors,
+            ),
+            (
+                "connections.client_aborts",
+                before.client_aborts,
+                now.client_aborts,
+            ),
+            ("bytes.read", before.bytes_read, now.bytes_read),
+            ("bytes.written", before.bytes_written, now.bytes_written),
+        ];
+        for (name, before, now) in counters.iter() {
+            if now > before {
+                lines.push(format!("{}.{}:{}|c", prefix, name, now - before));
+            }
+        }
+
+        let unused = RouteStats::default();
+        for (route, stats) in &current.routes {
+            let before = previous.routes.get(route).unwrap_or(&unused);
+            for (status, count) in &stats.statuses {
+                let count = count - before.statuses.get(status).unwrap_or(&0);
+                if count == 0 {
+                    continue;
+                }
+                lines.push(if self.tags {
+                    format!(
+                        "{}.requests:{}|c|#route:{},status:{}",
+                        prefix,
+                        count,
+                        tag_value(route),
+                        status
+                    )
+                } else {
+                    format!(
+                        "{}.routes.{}.status.{}:{}|c",
+                        prefix,
+                        name_part(route),
+                        status,
+                        count
+                    )
+                });
+            }
+
+            // Each latency bucket is sent as one timing at the bucket's upper
+            // bound, sampled so it stands for every request in the bucket.
+            // Requests slower than the last bound count as that bound.
+            for (bucket, count) in stats.latency.buckets.iter().enumerate() {
+                let count = count - before.latency.buckets.get(bucket).unwrap_or(&0);
+                if count == 0 {
+                    continue;
+                }
+                let bound = LATENCY_BUCKETS_MICROS[bucket.min(LATENCY_BUCKETS_MICROS.len() - 1)];
+                let millis = bound as f64 / 1000.0;
+                let rate = 1.0 / count as f64;
+                lines.push(if self.tags {
+                    format!(
+                        "{}.request.latency:{}|ms|@{}|#route:{}",
+                        prefix,
+                        millis,
+                        rate,
+                        tag_value(route)
+                    )
+                } else {
+                    format!(
+                        "{}.routes.{}.latency:{}|ms|@{}",
+                        prefix,
+                        name_part(route),
+                        millis,
+                        rate
+                    )
+                });
+            }
+        }
+        lines
+    }
+}
+
+/// The metrics as of one push.
+#[derive(Default)]
+struct Snapshot {
+    active_connections: usize,
+    requests_in_flight: usize,
+    connections: ConnectionStats,
+    routes: BTreeMap<String, RouteStats>,
+}
+impl Snapshot {
+    fn of(metrics: &Metrics) -> Self {
+        Snapshot {
+            active_connections: metrics.active_connections(),
+            requests_in_flight: metrics.requests_in_flight(),
+            connections: metrics.connections(),
+            routes: metrics.routes(),
+        }
+    }
+}
+
+/// A route pattern as one dot-free part of a metric name, e.g.
+/// `GET /users/:id` as `GET_users_id`.
+fn name_part(route: &str) -> String {
+    route
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// A route pattern as a DogStatsD tag value, which can't hold separators.
+fn tag_value(route: &str) -> String {
+    route
+        .chars()
+        .map(|c| match c {
+            ',' | '|' | '#' | '@' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Histogram, RecordMetrics};
+    use crate::{HttpResponse, Router};
+    use rust_http_parse::{HttpMethod, HttpRequest};
+
+    /// Two requests to `GET /users/:id`, taking 3ms each.
+    fn snapshot() -> Snapshot {
+        let mut latency = vec![0; LATENCY_BUCKETS_MICROS.len() + 1];
+        latency[3] = 2;
+        let mut routes = BTreeMap::new();
+        routes.insert(
+            "GET /users/:id".to_owned(),
+            RouteStats {
+                requests: 2,
+                server_errors: 0,
+                statuses: vec![(200, 2)].into_iter().collect(),
+                latency: Histogram {
+                    buckets: latency,
+                    count: 2,
+                    sum_micros: 6_000,
+                },
+            },
+        );
+        Snapshot {
+            active_connections: 1,
+            requests_in_flight: 0,
+            connections: ConnectionStats {
+                opened: 1,
+                ..ConnectionStats::default()
+            },
+            routes,
+        }
+    }
+
+    #[test]
+    fn sends_changes_since_the_last_push() {
+        let exporter = StatsdExporter::new("127.0.0.1:8125").prefix("web");
+
+        let lines = exporter.lines(&Snapshot::default(), &snapshot());
+        let unchanged = exporter.lines(&snapshot(), &snapshot());
+
+        assert_eq!(
+            vec![
+                "web.connections.active:1|g",
+                "web.requests.in_flight:0|g",
+                "web.connections.opened:1|c",
+                "web.routes.GET_users_id.status.200:2|c",
+                "web.routes.GET_users_id.latency:5|ms|@0.5",
+            ],
+            lines
+        );
+        assert_eq!(2, unchanged.len());
+    }
+
+    #[test]
+    fn tags_routes_for_dogstatsd() {
+        let exporter = StatsdExporter::new("127.0.0.1:8125").dogstatsd();
+
+        let lines = exporter.lines(&Snapshot::default(), &snapshot());
+
+        assert!(
+            lines.contains(&"http_server.requests:2|c|#route:GET_/users/:id,status:200".to_owned())
+        );
+        assert!(lines
+            .contains(&"http_server.request.latency:5|ms|@0.5|#route:GET_/users/:id".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn pushes_over_udp() {
+        let metrics = Arc::new(Metrics::new());
+        let router = Router::new()
+            .route(HttpMethod::GET, "/users/:id", |_| async {
+                HttpResponse::ok()
+            })
+            .middleware(RecordMetrics(metrics.clone()));
+        router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/users/1"))
+            .await;
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exporter = StatsdExporter::new(&agent.local_addr().unwrap().to_string())
+            .interval(Duration::from_millis(10));
+        tokio::spawn(exporter.run(metrics));
+
+        let mut packet = [0; MAX_PACKET];
+        let received = agent.recv(&mut packet).await.unwrap();
+
+        let packet = String::from_utf8_lossy(&packet[..received]);
+        assert!(packet.starts_with("http_server.connections.active:0|g\n"));
+        assert!(packet.contains("http_server.routes.GET_users_id.status.200:1|c"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::RwLock;
+
+use custom_error::custom_error;
+use log::debug;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use super::HttpResponse;
+
+custom_error! {pub TemplateError
+    Render{msg: String} = "Template error: {msg}"
+}
+
+impl From<tera::Error> for TemplateError {
+    fn from(error: tera::Error) -> Self {
+        TemplateError::Render {
+            msg: error.to_string(),
+        }
+    }
+}
+
+/// Templates loaded from disk with Tera. In debug builds every render
+/// reloads them first, so edits show up without restarting the server.
+/// Share one instance between handlers with `ServerBuilder::with_state`.
+pub struct Templates {
+    engine: RwLock<Tera>,
+    reload: bool,
+}
+impl Templates {
+    /// Loads every template matching `glob`, e.g. `"templates/**/*.html"`.
+    pub fn new(glob: &str) -> Result<Self, TemplateError> {
+        Ok(Templates {
+            engine: RwLock::new(Tera::new(glob)?),
+            reload: cfg!(debug_assertions),
+        })
+    }
+
+    /// Overrides whether templates are reloaded before each render.
+    pub fn with_reload(mut self, reload: bool) -> Self {
+        self.reload = reload;
+        self
+    }
+
+    pub fn render<C: Serialize>(&self, name: &str, context: &C) -> Result<String, TemplateError> {
+        if self.reload {
+            self.engine.write().unwrap().full_reload()?;
+        }
+
+        let context = Context::from_serialize(context)?;
+        Ok(self.engine.read().unwrap().render(name, &context)?)
+    }
+}
+
+impl HttpResponse {
+    /// Renders `name` with `context` into an HTML response, or a 500 if
+    /// rendering fails.
+    pub fn render<C: Serialize>(templates: &Templates, name: &str, context: &C) -> Self {
+        match templates.render(name, context) {
+            Ok(html) => HttpResponse::ok()
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(html.into_bytes()),
+            Err(e) => {
+                debug!("Could not render {}: {}", name, e);
+                HttpResponse::new(500)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn template_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-http-server-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn renders_template_into_html_response() {
+        let dir = template_dir("render");
+        fs::write(dir.join("page.html"), "Hello {{ name }}").unwrap();
+        let templates = Templates::new(&format!("{}/*.html", dir.display())).unwrap();
+        let context: HashMap<_, _> = vec![("name", "world")].into_iter().collect();
+
+        let response = HttpResponse::render(&templates, "page.html", &context);
+
+        assert_eq!(200, response.status);
+        assert_eq!(b"Hello world", response.body());
+        assert_eq!(
+            "text/html; charset=utf-8",
+            response.header("Content-Type").unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_template_is_a_server_error() {
+        let dir = template_dir("missing");
+        let templates = Templates::new(&format!("{}/*.html", dir.display())).unwrap();
+
+        let response =
+            HttpResponse::render(&templates, "nope.html", &HashMap::<String, String>::new());
+
+        assert_eq!(500, response.status);
+    }
+
+    #[test]
+    fn reloads_changed_templates_when_enabled() {
+        let dir = template_dir("reload");
+        fs::write(dir.join("page.html"), "before").unwrap();
+        let glob = format!("{}/*.html", dir.display());
+        let templates = Templates::new(&glob).unwrap().with_reload(true);
+        let context = HashMap::<String, String>::new();
+
+        fs::write(dir.join("page.html"), "after").unwrap();
+
+        assert_eq!("after", templates.render("page.html", &context).unwrap());
+    }
+}
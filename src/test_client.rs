@@ -0,0 +1,131 @@
+use std::io;
+use std::sync::Arc;
+
+use rust_http_parse::HttpMethod;
+
+use super::server::{handle_raw, ConnectionContext};
+use super::{HttpResponse, ServerBuilder, ServerError};
+
+/// Sends requests through a server's full parse, route and respond pipeline
+/// over an in-memory stream, so applications can test their routes,
+/// middleware and handlers end to end without opening sockets.
+///
+/// Bind addresses on the builder are ignored.
+pub struct TestClient {
+    context: Arc<ConnectionContext>,
+}
+impl TestClient {
+    /// Fails if any two routes are ambiguous, as `ServerBuilder::build` would.
+    pub fn new(builder: ServerBuilder) -> Result<Self, ServerError> {
+        Ok(TestClient {
+            context: builder.into_context()?,
+        })
+    }
+
+    pub async fn get(&self, path: &str) -> io::Result<HttpResponse> {
+        self.send(HttpMethod::GET, path, &[], b"").await
+    }
+
+    /// Sends a request with the given headers and body. A Content-Length is
+    /// added for a non-empty body.
+    pub async fn send(
+        &self,
+        method: HttpMethod,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<HttpResponse> {
+        let mut request = format!("{:?} {} HTTP/1.1\r\n", method, path);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+        self.send_raw(&request).await
+    }
+
+    /// Sends `request` exactly as given, for testing how malformed input is
+    /// handled.
+    pub async fn send_raw(&self, request: &[u8]) -> io::Result<HttpResponse> {
+        let response = handle_raw(self.context.clone(), request).await?;
+        HttpResponse::from_bytes(&response)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Router, Server};
+
+    fn client() -> TestClient {
+        let api = Router::new().route(
+            HttpMethod::POST,
+            "/echo",
+            |request: rust_http_parse::HttpRequest| {
+                let body = request.body().to_vec();
+                async move { HttpResponse::ok().with_body(body) }
+            },
+        );
+        TestClient::new(
+            Server::builder()
+                .route(HttpMethod::GET, "/hello", |_| async {
+                    HttpResponse::ok().with_body(b"hello".to_vec())
+                })
+                .nest("/api", api),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn drives_requests_through_the_pipeline() {
+        let client = client();
+
+        let hello = client.get("/hello").await.unwrap();
+        let echo = client
+            .send(HttpMethod::POST, "/api/echo", &[("X-Test", "1")], b"ping")
+            .await
+            .unwrap();
+        let missing = client.get("/missing").await.unwrap();
+
+        assert_eq!(200, hello.status);
+        assert_eq!(b"hello", hello.body());
+        assert_eq!(b"ping", echo.body());
+        assert_eq!(404, missing.status);
+    }
+
+    #[tokio::test]
+    async fn reports_how_malformed_requests_are_answered() {
+        let response = client()
+            .send_raw(b"GET /hello HTTP/1.1\r\nBad Header: x\r\n\r\n")
+            .await
+            .unwrap();
+
+        assert_eq!(400, response.status);
+    }
+
+    #[tokio::test]
+    async fn answers_500_when_a_handler_panics() {
+        let client = TestClient::new(
+            Server::builder()
+                .route(
+                    HttpMethod::GET,
+                    "/broken",
+                    |request: rust_http_parse::HttpRequest| async move {
+                        let user = request.header("X-User").unwrap();
+                        HttpResponse::ok().with_body(user.clone().into_bytes())
+                    },
+                )
+                .route(HttpMethod::GET, "/hello", |_| async { HttpResponse::ok() }),
+        )
+        .unwrap();
+
+        assert_eq!(500, client.get("/broken").await.unwrap().status);
+        assert_eq!(200, client.get("/hello").await.unwrap().status);
+    }
+}
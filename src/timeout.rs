@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, Handler, HttpResponse, Middleware, Next};
+
+/// Gives up on requests whose handler hasn't produced a response within a
+/// deadline, dropping the handler's future and answering 503 instead. Add it
+/// as middleware for a server-wide limit, or `wrap` a single route's handler
+/// for a tighter one. A streaming body can take longer; the deadline only
+/// covers producing the response.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    duration: Duration,
+}
+impl Timeout {
+    pub fn new(duration: Duration) -> Self {
+        Timeout { duration }
+    }
+
+    /// Applies the deadline to just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        TimedHandler {
+            timeout: self,
+            handler: Arc::new(handler),
+        }
+    }
+
+    async fn run(self, method_and_path: String, response: BoxFuture<HttpResponse>) -> HttpResponse {
+        match tokio::time::timeout(self.duration, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                warn!(
+                    "Request {} timed out after {:?}",
+                    method_and_path, self.duration
+                );
+                HttpResponse::new(503)
+            }
+        }
+    }
+}
+
+fn describe(request: &HttpRequest) -> String {
+    format!("{:?} {}", request.method, request.path)
+}
+
+impl Middleware for Timeout {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        let description = describe(&request);
+        Box::pin(self.run(description, next.run(request)))
+    }
+}
+
+struct TimedHandler<H> {
+    timeout: Timeout,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for TimedHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        let description = describe(&request);
+        Box::pin(self.timeout.run(description, self.handler.call(request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use rust_http_parse::HttpMethod;
+
+    async fn slow(_: HttpRequest) -> HttpResponse {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        HttpResponse::ok()
+    }
+
+    #[tokio::test]
+    async fn answers_503_when_handler_misses_the_deadline() {
+        let router = Router::new()
+            .route(HttpMethod::GET, "/slow", slow)
+            .route(HttpMethod::GET, "/fast", |_| async { HttpResponse::ok() })
+            .middleware(Timeout::new(Duration::from_millis(20)));
+
+        let slow = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/slow"))
+            .await;
+        let fast = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/fast"))
+            .await;
+
+        assert_eq!(503, slow.status);
+        assert_eq!(200, fast.status);
+    }
+
+    #[tokio::test]
+    async fn route_deadline_applies_only_to_its_route() {
+        let router = Router::new()
+            .route(
+                HttpMethod::GET,
+                "/slow",
+                Timeout::new(Duration::from_millis(20)).wrap(slow),
+            )
+            .route(HttpMethod::GET, "/fast", |_| async { HttpResponse::ok() });
+
+        let response = router
+            .dispatch(HttpRequest::new(HttpMethod::GET, "/slow"))
+            .await;
+
+        assert_eq!(503, response.status);
+    }
+}
@@ -0,0 +1,852 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use custom_error::custom_error;
+use log::{debug, info, warn};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    sign, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+    ClientHello, NoClientAuth, PrivateKey, ProtocolVersion, ResolvesServerCert, RootCertStore,
+    ServerConfig, SignatureScheme, SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use tokio_rustls::webpki;
+use tokio_rustls::TlsAcceptor;
+
+use super::hosts::normalize_host;
+use super::sni;
+
+/// Times to look again for the rest of a ClientHello that arrives in pieces
+/// before picking a host's certificate without it.
+const CLIENT_HELLO_RETRIES: usize = 10;
+/// How long to wait before each look.
+const CLIENT_HELLO_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+custom_error! {pub TlsError
+    Io{path: String, msg: String} = "Could not read {path}: {msg}",
+    InvalidPem{path: String} = "{path} is not a valid PEM file",
+    NoCertificates{path: String} = "No certificates found in {path}",
+    NoPrivateKey{path: String} = "No PKCS#8 or RSA private key found in {path}",
+    Rejected{msg: String} = "Certificate and key rejected: {msg}",
+    InvalidOcsp{path: String} = "{path} is not a DER-encoded OCSP response",
+    Unsupported{msg: String} = "Unsupported TLS settings: {msg}"
+}
+
+/// A TLS protocol version, written `1.2` or `1.3`. Older versions aren't
+/// supported at all.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+impl TlsVersion {
+    fn protocol(self) -> ProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => ProtocolVersion::TLSv1_2,
+            TlsVersion::Tls13 => ProtocolVersion::TLSv1_3,
+        }
+    }
+}
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("expected 1.2 or 1.3, got '{}'", version)),
+        }
+    }
+}
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsVersion::Tls12 => write!(f, "1.2"),
+            TlsVersion::Tls13 => write!(f, "1.3"),
+        }
+    }
+}
+
+/// Certificates and settings for serving HTTPS.
+#[derive(Clone)]
+pub struct TlsConfig {
+    cert: Arc<ReloadableCert>,
+    watch_interval: Option<Duration>,
+    versions: (TlsVersion, TlsVersion),
+    cipher_suites: Option<Vec<&'static SupportedCipherSuite>>,
+    client_auth: Option<(RootCertStore, bool)>,
+    alpn_protocols: Vec<Vec<u8>>,
+    hosts: Vec<(String, TlsConfig)>,
+}
+impl TlsConfig {
+    /// Loads a PEM certificate chain, leaf first, and the PEM private key
+    /// for it. Fails if either file is unreadable or empty, or if the key
+    /// doesn't suit the certificate.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Self, TlsError> {
+        let cert = ReloadableCert::load(
+            cert_path.as_ref().to_owned(),
+            key_path.as_ref().to_owned(),
+            None,
+        )?;
+
+        Ok(TlsConfig {
+            cert: Arc::new(cert),
+            watch_interval: None,
+            versions: (TlsVersion::Tls12, TlsVersion::Tls13),
+            cipher_suites: None,
+            client_auth: None,
+            alpn_protocols: Vec::new(),
+            hosts: Vec::new(),
+        })
+    }
+
+    /// Staples the DER-encoded OCSP response in `path` to handshakes, so
+    /// clients needn't ask the CA whether the certificate was revoked. The
+    /// response has to be fetched by something else, e.g. `openssl ocsp
+    /// -respout`, and is reloaded along with the certificate.
+    pub fn staple_ocsp(self, path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        let cert = ReloadableCert::load(
+            self.cert.cert_path.clone(),
+            self.cert.key_path.clone(),
+            Some(path.as_ref().to_owned()),
+        )?;
+
+        Ok(TlsConfig {
+            cert: Arc::new(cert),
+            ..self
+        })
+    }
+
+    /// Only negotiates protocol versions from `min` to `max`; both 1.2 and
+    /// 1.3 by default.
+    pub fn versions(mut self, min: TlsVersion, max: TlsVersion) -> Result<Self, TlsError> {
+        if min > max {
+            return Err(TlsError::Unsupported {
+                msg: format!("minimum version {} is above the maximum {}", min, max),
+            });
+        }
+        self.versions = (min, max);
+        self.check_cipher_suites()?;
+        Ok(self)
+    }
+
+    /// Only offers the named cipher suites, preferring them in the order
+    /// given over the client's order. Names are the IANA ones rustls
+    /// supports, e.g. `TLS13_AES_256_GCM_SHA384` or
+    /// `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    pub fn cipher_suites<S: AsRef<str>>(mut self, names: &[S]) -> Result<Self, TlsError> {
+        let suites = names
+            .iter()
+            .map(|name| {
+                ALL_CIPHERSUITES
+                    .iter()
+                    .copied()
+                    .find(|suite| format!("{:?}", suite.suite) == name.as_ref())
+                    .ok_or_else(|| TlsError::Unsupported {
+                        msg: format!("unknown cipher suite '{}'", name.as_ref()),
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        self.cipher_suites = Some(suites);
+        self.check_cipher_suites()?;
+        Ok(self)
+    }
+
+    /// Only accepts clients presenting a certificate issued by one of the
+    /// PEM CA certificates in `ca_path`.
+    pub fn require_client_cert(self, ca_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        self.client_auth(ca_path.as_ref(), true)
+    }
+
+    /// Asks clients for a certificate issued by one of the PEM CA
+    /// certificates in `ca_path`, but also accepts clients without one.
+    pub fn request_client_cert(self, ca_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        self.client_auth(ca_path.as_ref(), false)
+    }
+
+    fn client_auth(mut self, ca_path: &Path, required: bool) -> Result<Self, TlsError> {
+        let mut roots = RootCertStore::empty();
+        let (added, _) =
+            roots
+                .add_pem_file(&mut open(ca_path)?)
+                .map_err(|_| TlsError::InvalidPem {
+                    path: ca_path.display().to_string(),
+                })?;
+        if added == 0 {
+            return Err(TlsError::NoCertificates {
+                path: ca_path.display().to_string(),
+            });
+        }
+        self.client_auth = Some((roots, required));
+        Ok(self)
+    }
+
+    /// The ALPN protocols to agree to, in order of preference, e.g.
+    /// `http/1.1`. By default none are advertised.
+    pub fn alpn_protocols(mut self, protocols: &[&str]) -> Self {
+        self.alpn_protocols = protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        self
+    }
+
+    /// Uses `tls` instead for clients asking for `host` through SNI, so each
+    /// virtual host can have its own certificate, client certificate
+    /// requirement and ALPN protocols. `host` may start with `*.` to match
+    /// any one subdomain. Clients asking for other names or none get this
+    /// config. Hosts given to `tls` itself are ignored. A request whose Host
+    /// would pick another config than its connection's handshake did is
+    /// answered with 421 Misdirected Request.
+    pub fn host(mut self, host: &str, tls: TlsConfig) -> Self {
+        self.hosts.push((host.to_ascii_lowercase(), tls));
+        self
+    }
+
+    /// Every enabled version needs a suite, or handshakes using it fail.
+    fn check_cipher_suites(&self) -> Result<(), TlsError> {
+        let suites = match &self.cipher_suites {
+            Some(suites) => suites,
+            None => return Ok(()),
+        };
+        for version in self.enabled_versions() {
+            if !suites.iter().any(|suite| suite.usable_for_version(version)) {
+                return Err(TlsError::Unsupported {
+                    msg: format!("no cipher suite given for {:?}", version),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn enabled_versions(&self) -> Vec<ProtocolVersion> {
+        let (min, max) = self.versions;
+        [TlsVersion::Tls13, TlsVersion::Tls12]
+            .iter()
+            .filter(|version| (min..=max).contains(version))
+            .map(|version| version.protocol())
+            .collect()
+    }
+
+    /// Also checks the certificate, key and any OCSP response files for
+    /// changes this often while serving, reloading them when one is
+    /// modified. They are always reloaded on SIGHUP.
+    pub fn watch_files(mut self, interval: Duration) -> Self {
+        self.watch_interval = Some(interval);
+        self
+    }
+
+    /// Reads the certificate, key and OCSP response files again and uses them for new
+    /// handshakes. Connections already established carry on with the
+    /// certificate they started with. If the files can't be loaded, the
+    /// current certificate stays in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let reloaded = self.cert.read_files()?;
+        *self.cert.current.write().unwrap() = reloaded;
+        Ok(())
+    }
+
+    /// Accepts handshakes with this config alone, ignoring its hosts.
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        let client_verifier = match &self.client_auth {
+            Some((roots, true)) => AllowAnyAuthenticatedClient::new(roots.clone()),
+            Some((roots, false)) => AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()),
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(client_verifier);
+        config.cert_resolver = self.cert.clone();
+        config.set_protocols(&self.alpn_protocols);
+        config.versions = self.enabled_versions();
+        if let Some(suites) = &self.cipher_suites {
+            config.ciphersuites = suites.clone();
+            config.ignore_client_order = true;
+        }
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    /// Accepts handshakes with the config for the host each client asks for.
+    pub(crate) fn host_acceptor(&self) -> HostAcceptor {
+        HostAcceptor {
+            default: self.acceptor(),
+            hosts: Arc::new(
+                self.hosts
+                    .iter()
+                    .map(|(host, tls)| (host.clone(), tls.acceptor()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// This config and each host's, which reload separately.
+    pub(crate) fn with_hosts(&self) -> Vec<TlsConfig> {
+        let mut configs = vec![self.clone()];
+        configs.extend(self.hosts.iter().map(|(_, tls)| tls.clone()));
+        configs
+    }
+
+    /// Reloads the certificate on SIGHUP and, if watching, whenever its
+    /// files change. Runs for as long as the server does.
+    pub(crate) async fn reload_on_changes(self) {
+        let mut hangups = hangups();
+        let mut modified = self.cert.modified();
+        loop {
+            tokio::select! {
+                _ = next_hangup(&mut hangups) => {}
+                _ = tick(self.watch_interval) => {
+                    let now = self.cert.modified();
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                }
+            }
+            match self.reload() {
+                Ok(()) => info!("Reloaded {}", self.cert.cert_path.display()),
+                Err(e) => warn!("Keeping the current certificate: {}", e),
+            }
+        }
+    }
+}
+
+/// Picks the acceptor for a connection from the host name in its
+/// ClientHello, which is peeked at and left for the handshake to read.
+#[derive(Clone)]
+pub(crate) struct HostAcceptor {
+    default: TlsAcceptor,
+    hosts: Arc<Vec<(String, TlsAcceptor)>>,
+}
+impl HostAcceptor {
+    /// The acceptor for the host the client asks for, and which host that
+    /// was, for checking the requests that follow the handshake.
+    pub(crate) async fn select(&self, stream: &TcpStream) -> (TlsAcceptor, TlsHost) {
+        let host = if self.hosts.is_empty() {
+            None
+        } else {
+            peek_server_name(stream)
+                .await
+                .and_then(|name| self.position(&name))
+        };
+        let acceptor = host.map_or(&self.default, |index| &self.hosts[index].1);
+        let tls_host = TlsHost {
+            acceptor: self.clone(),
+            host,
+        };
+        (acceptor.clone(), tls_host)
+    }
+
+    /// Which of the hosts `name` is for, or `None` for the default config.
+    fn position(&self, name: &str) -> Option<usize> {
+        let parent = name.find('.').map(|dot| &name[dot + 1..]);
+        self.hosts
+            .iter()
+            .position(|(host, _)| match host.strip_prefix("*.") {
+                Some(suffix) => parent == Some(suffix),
+                None => host == name,
+            })
+    }
+}
+
+/// The host config a connection's handshake was made with.
+#[derive(Clone)]
+pub(crate) struct TlsHost {
+    acceptor: HostAcceptor,
+    host: Option<usize>,
+}
+impl TlsHost {
+    /// Whether a request whose Host header is `host` belongs on this
+    /// connection, because that host picks the same config the handshake
+    /// used. Otherwise a client could shake hands as a host that doesn't ask
+    /// for a client certificate and then send requests for one that does.
+    pub(crate) fn serves(&self, host: &str) -> bool {
+        self.acceptor.position(&normalize_host(host)) == self.host
+    }
+}
+
+async fn peek_server_name(stream: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; sni::MAX_RECORD];
+    for _ in 0..CLIENT_HELLO_RETRIES {
+        let read = stream.peek(&mut buf).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        if let Some(name) = sni::server_name(&buf[..read]) {
+            return name;
+        }
+        tokio::time::sleep(CLIENT_HELLO_RETRY_DELAY).await;
+    }
+    debug!("ClientHello incomplete, using the default certificate");
+    None
+}
+
+/// The certificate handed to new handshakes, swapped out on reload.
+struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    ocsp_path: Option<PathBuf>,
+    current: RwLock<sign::CertifiedKey>,
+}
+impl ReloadableCert {
+    fn load(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        ocsp_path: Option<PathBuf>,
+    ) -> Result<Self, TlsError> {
+        let current = load_certified_key(&cert_path, &key_path, ocsp_path.as_deref())?;
+        Ok(ReloadableCert {
+            cert_path,
+            key_path,
+            ocsp_path,
+            current: RwLock::new(current),
+        })
+    }
+
+    fn read_files(&self) -> Result<sign::CertifiedKey, TlsError> {
+        load_certified_key(&self.cert_path, &self.key_path, self.ocsp_path.as_deref())
+    }
+
+    /// When the files were last written, to spot renewals. Each is compared
+    /// since they are rarely replaced at once.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        [&self.cert_path, &self.key_path]
+            .iter()
+            .copied()
+            .chain(&self.ocsp_path)
+            .map(|path| modified(path))
+            .collect()
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    ocsp_path: Option<&Path>,
+) -> Result<sign::CertifiedKey, TlsError> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    check_key_matches(&certs[0], &key)?;
+    let signing_key = sign::any_supported_type(&key).map_err(|_| TlsError::Rejected {
+        msg: "unsupported private key type".to_owned(),
+    })?;
+
+    let mut certified = sign::CertifiedKey::new(certs, Arc::new(signing_key));
+    if let Some(path) = ocsp_path {
+        certified.ocsp = Some(load_ocsp_response(path)?);
+    }
+    Ok(certified)
+}
+
+async fn tick(interval: Option<Duration>) {
+    match interval {
+        Some(interval) => tokio::time::sleep(interval).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(unix)]
+fn hangups() -> Option<tokio::signal::unix::Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())
+        .map_err(|e| warn!("Can't reload certificates on SIGHUP: {}", e))
+        .ok()
+}
+
+#[cfg(unix)]
+async fn next_hangup(hangups: &mut Option<tokio::signal::unix::Signal>) {
+    if let Some(hangups) = hangups {
+        if hangups.recv().await.is_some() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+#[cfg(not(unix))]
+fn hangups() -> Option<()> {
+    None
+}
+
+#[cfg(not(unix))]
+async fn next_hangup(_hangups: &mut Option<()>) {
+    std::future::pending().await
+}
+
+/// Reads an OCSP response, checking only that it looks like DER, since
+/// clients verify the rest.
+fn load_ocsp_response(path: &Path) -> Result<Vec<u8>, TlsError> {
+    let response = std::fs::read(path).map_err(|e| TlsError::Io {
+        path: path.display().to_string(),
+        msg: e.to_string(),
+    })?;
+    if response.first() != Some(&0x30) {
+        return Err(TlsError::InvalidOcsp {
+            path: path.display().to_string(),
+        });
+    }
+    Ok(response)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs = pemfile::certs(&mut open(path)?).map_err(|_| TlsError::InvalidPem {
+        path: path.display().to_string(),
+    })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates {
+            path: path.display().to_string(),
+        });
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let invalid = |_| TlsError::InvalidPem {
+        path: path.display().to_string(),
+    };
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(invalid)?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| TlsError::NoPrivateKey {
+            path: path.display().to_string(),
+        })
+}
+
+/// Signs with the key and verifies the signature against the leaf
+/// certificate, since rustls itself only finds out about a mismatched pair
+/// when clients fail their handshakes.
+fn check_key_matches(leaf: &Certificate, key: &PrivateKey) -> Result<(), TlsError> {
+    let schemes = [
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+    ];
+    let rejected = |msg: &str| TlsError::Rejected {
+        msg: msg.to_owned(),
+    };
+
+    let signing_key =
+        sign::any_supported_type(key).map_err(|_| rejected("unsupported private key type"))?;
+    let offered: Vec<_> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = signing_key
+        .choose_scheme(&offered)
+        .ok_or_else(|| rejected("no supported signature scheme for private key"))?;
+    let algorithm = schemes
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .ok_or_else(|| rejected("no supported signature scheme for private key"))?;
+
+    let message = b"rust-http-server key check";
+    let signature = signer.sign(message).map_err(|e| rejected(&e.to_string()))?;
+    webpki::EndEntityCert::from(&leaf.0)
+        .and_then(|cert| cert.verify_signature(algorithm, message, &signature))
+        .map_err(|_| rejected("private key does not match the certificate"))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::Io {
+            path: path.display().to_string(),
+            msg: e.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{handle_connection, Scheme};
+    use crate::HttpResponse;
+    use rust_http_parse::HttpMethod;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, Session};
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    const CERT: &str = "testdata/localhost.crt";
+    const KEY: &str = "testdata/localhost.key";
+    const CA: &str = "testdata/ca.crt";
+
+    fn client_config() -> ClientConfig {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .root_store
+            .add_pem_file(&mut open(Path::new(CA)).unwrap())
+            .unwrap();
+        client_config
+    }
+
+    #[tokio::test]
+    async fn accepts_handshakes_with_loaded_chain() {
+        let acceptor = TlsConfig::from_pem_files(CERT, KEY).unwrap().acceptor();
+        let connector = TlsConnector::from(Arc::new(client_config()));
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut client = connector.connect(domain, client_io).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(b"hello", &received[..]);
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_below_the_minimum_version() {
+        let acceptor = TlsConfig::from_pem_files(CERT, KEY)
+            .unwrap()
+            .versions(TlsVersion::Tls13, TlsVersion::Tls13)
+            .unwrap()
+            .acceptor();
+        let mut tls12_only = client_config();
+        tls12_only.versions = vec![ProtocolVersion::TLSv1_2];
+        let connector = TlsConnector::from(Arc::new(tls12_only));
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+
+        let server = tokio::spawn(async move { acceptor.accept(server_io).await.is_ok() });
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let client = connector.connect(domain, client_io).await;
+
+        assert!(client.is_err());
+        assert!(!server.await.unwrap());
+    }
+
+    #[test]
+    fn checks_cipher_suites_against_versions() {
+        let tls = || TlsConfig::from_pem_files(CERT, KEY).unwrap();
+
+        assert!(tls()
+            .cipher_suites(&[
+                "TLS13_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"
+            ])
+            .is_ok());
+        assert!(tls().cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5"]).is_err());
+        assert!(tls().cipher_suites(&["TLS13_AES_256_GCM_SHA384"]).is_err());
+        assert!(tls()
+            .versions(TlsVersion::Tls13, TlsVersion::Tls13)
+            .unwrap()
+            .cipher_suites(&["TLS13_AES_256_GCM_SHA384"])
+            .is_ok());
+        assert!(tls()
+            .versions(TlsVersion::Tls13, TlsVersion::Tls12)
+            .is_err());
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let error = TlsConfig::from_pem_files("testdata/missing.crt", KEY)
+            .err()
+            .unwrap();
+
+        assert!(matches!(error, TlsError::Io { .. }));
+    }
+
+    #[test]
+    fn reports_files_without_certificates_or_keys() {
+        let no_certs = TlsConfig::from_pem_files(KEY, KEY).err().unwrap();
+        let no_key = TlsConfig::from_pem_files(CERT, CERT).err().unwrap();
+
+        assert!(matches!(no_certs, TlsError::NoCertificates { .. }));
+        assert!(matches!(no_key, TlsError::NoPrivateKey { .. }));
+    }
+
+    #[test]
+    fn keeps_the_current_certificate_when_a_reload_fails() {
+        let dir = std::env::temp_dir().join("rust-http-server-tls-reload");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::copy(CERT, &cert).unwrap();
+        std::fs::copy(KEY, &key).unwrap();
+        let tls = TlsConfig::from_pem_files(&cert, &key).unwrap();
+        let served = || tls.cert.current.read().unwrap().cert[0].clone();
+        let original = served();
+
+        std::fs::copy(CA, &cert).unwrap();
+        let mismatched = tls.reload();
+        let during = served();
+        std::fs::copy(CERT, &cert).unwrap();
+        let restored = tls.reload();
+
+        assert!(matches!(mismatched, Err(TlsError::Rejected { .. })));
+        assert_eq!(original, during);
+        assert!(restored.is_ok());
+        assert_eq!(original, served());
+    }
+
+    #[test]
+    fn staples_ocsp_responses() {
+        let path = std::env::temp_dir().join("rust-http-server-ocsp.der");
+        let response = vec![0x30, 0x03, 0x0a, 0x01, 0x00];
+        std::fs::write(&path, &response).unwrap();
+        let tls = TlsConfig::from_pem_files(CERT, KEY)
+            .unwrap()
+            .staple_ocsp(&path)
+            .unwrap();
+
+        assert_eq!(Some(response), tls.cert.current.read().unwrap().ocsp);
+        std::fs::write(&path, "not der").unwrap();
+        assert!(matches!(tls.reload(), Err(TlsError::InvalidOcsp { .. })));
+    }
+
+    /// Accepts one connection with `tls`, returning whether its handshake
+    /// succeeded and the ALPN protocol agreed on.
+    async fn serve_once(tls: TlsConfig) -> (String, tokio::task::JoinHandle<bool>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let acceptor = tls.host_acceptor();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (selected, _) = acceptor.select(&stream).await;
+            selected.accept(stream).await.is_ok()
+        });
+        (address, server)
+    }
+
+    #[tokio::test]
+    async fn picks_the_config_for_the_requested_host() {
+        let tls = TlsConfig::from_pem_files(CERT, KEY).unwrap().host(
+            "LOCALHOST",
+            TlsConfig::from_pem_files(CERT, KEY)
+                .unwrap()
+                .alpn_protocols(&["http/1.1"]),
+        );
+        let (address, server) = serve_once(tls).await;
+        let mut config = client_config();
+        config.set_protocols(&[b"http/1.1".to_vec()]);
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let client = connector.connect(domain, stream).await.unwrap();
+
+        assert!(server.await.unwrap());
+        assert_eq!(
+            Some(&b"http/1.1"[..]),
+            client.get_ref().1.get_alpn_protocol()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_without_a_required_certificate() {
+        let tls = TlsConfig::from_pem_files(CERT, KEY).unwrap().host(
+            "localhost",
+            TlsConfig::from_pem_files(CERT, KEY)
+                .unwrap()
+                .require_client_cert(CA)
+                .unwrap(),
+        );
+        let (address, server) = serve_once(tls).await;
+        let connector = TlsConnector::from(Arc::new(client_config()));
+
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let _ = connector.connect(domain, stream).await;
+
+        assert!(!server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_for_another_host_than_the_handshake() {
+        let tls = TlsConfig::from_pem_files(CERT, KEY).unwrap().host(
+            "secure.example",
+            TlsConfig::from_pem_files(CERT, KEY)
+                .unwrap()
+                .require_client_cert(CA)
+                .unwrap(),
+        );
+        let context = crate::Server::builder()
+            .route(HttpMethod::GET, "/", |_| async { HttpResponse::ok() })
+            .into_context()
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let acceptor = tls.host_acceptor();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (selected, tls_host) = acceptor.select(&stream).await;
+                let stream = selected.accept(stream).await.unwrap();
+                let peer = "-".to_owned();
+                let _ =
+                    handle_connection(stream, peer, Scheme::Https, None, Some(&tls_host), &context)
+                        .await;
+            }
+        });
+        // Shakes hands as localhost, which gets the default config.
+        let get = |host: &'static str| async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let connector = TlsConnector::from(Arc::new(client_config()));
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let mut client = connector.connect(domain, stream).await.unwrap();
+            let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            let _ = client.read_to_end(&mut response).await;
+            String::from_utf8_lossy(&response).into_owned()
+        };
+
+        let misdirected = get("Secure.Example:443").await;
+        let allowed = get("localhost").await;
+
+        assert!(
+            misdirected.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+            "{}",
+            misdirected
+        );
+        assert!(allowed.starts_with("HTTP/1.1 200 OK\r\n"), "{}", allowed);
+    }
+
+    #[test]
+    fn matches_wildcard_hosts_one_label_deep() {
+        let tls = TlsConfig::from_pem_files(CERT, KEY).unwrap().host(
+            "*.example.com",
+            TlsConfig::from_pem_files(CERT, KEY).unwrap(),
+        );
+        let acceptor = tls.host_acceptor();
+        let is_default = |name| acceptor.position(name).is_none();
+
+        assert!(!is_default("api.example.com"));
+        assert!(is_default("example.com"));
+        assert!(is_default("a.b.example.com"));
+    }
+
+    #[test]
+    fn rejects_key_for_another_certificate() {
+        let error = TlsConfig::from_pem_files(CA, KEY).err().unwrap();
+
+        assert!(matches!(error, TlsError::Rejected { .. }));
+    }
+}
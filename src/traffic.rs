@@ -0,0 +1,117 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::warn;
+
+/// One request as the client sent it and the response the server gave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub request: Vec<u8>,
+    pub response: Vec<u8>,
+}
+
+/// Appends every request/response transaction the server handles to a file,
+/// for replaying through the pipeline later with `read_transactions`.
+/// Streaming response bodies aren't known up front, so only their head is
+/// kept.
+pub struct TrafficLog {
+    file: Mutex<File>,
+}
+impl TrafficLog {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(TrafficLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends a transaction, logging rather than failing the request if
+    /// the file can't be written.
+    pub(crate) fn record(&self, request: &[u8], response: &[u8]) {
+        let mut entry = format!("request {}\n", request.len()).into_bytes();
+        entry.extend_from_slice(request);
+        entry.extend_from_slice(format!("\nresponse {}\n", response.len()).as_bytes());
+        entry.extend_from_slice(response);
+        entry.push(b'\n');
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&entry) {
+            warn!("Could not record transaction: {}", e);
+        }
+    }
+}
+
+/// Reads back the transactions written by a `TrafficLog`.
+pub fn read_transactions(path: impl AsRef<Path>) -> io::Result<Vec<Transaction>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut transactions = Vec::new();
+    while let Some(request) = read_section(&mut reader, "request")? {
+        let response = read_section(&mut reader, "response")?
+            .ok_or_else(|| invalid("request without a response"))?;
+        transactions.push(Transaction { request, response });
+    }
+    Ok(transactions)
+}
+
+/// Reads a `<label> <length>` line followed by that many bytes and a
+/// newline, or `None` at the end of the file.
+fn read_section(reader: &mut impl BufRead, label: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut header = String::new();
+    if reader.read_line(&mut header)? == 0 {
+        return Ok(None);
+    }
+    let length = header
+        .trim_end()
+        .strip_prefix(label)
+        .and_then(|length| length.trim().parse::<usize>().ok())
+        .ok_or_else(|| invalid(&format!("expected {} header, got {:?}", label, header)))?;
+
+    let mut content = vec![0; length + 1];
+    reader.read_exact(&mut content)?;
+    if content.pop() != Some(b'\n') {
+        return Err(invalid("missing newline after section"));
+    }
+    Ok(Some(content))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_recorded_transactions() {
+        let path = std::env::temp_dir().join("rust-http-server-traffic.log");
+        let _ = std::fs::remove_file(&path);
+        let log = TrafficLog::create(&path).unwrap();
+
+        log.record(b"GET / HTTP/1.1\r\n\r\n", b"HTTP/1.1 200 OK\r\n\r\n");
+        log.record(
+            b"POST /x HTTP/1.1\r\n\r\nbody\n",
+            b"HTTP/1.1 404 Not Found\r\n\r\n",
+        );
+
+        let transactions = read_transactions(&path).unwrap();
+        assert_eq!(2, transactions.len());
+        assert_eq!(
+            b"POST /x HTTP/1.1\r\n\r\nbody\n".to_vec(),
+            transactions[1].request
+        );
+        assert_eq!(
+            b"HTTP/1.1 200 OK\r\n\r\n".to_vec(),
+            transactions[0].response
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_logs() {
+        let path = std::env::temp_dir().join("rust-http-server-traffic-truncated.log");
+        std::fs::write(&path, "request 100\nGET / HTTP/1.1").unwrap();
+
+        assert!(read_transactions(&path).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rust_http_parse::HttpRequest;
+
+use super::{BoxFuture, ClientAddr, Handler, HttpResponse, Middleware, Next};
+
+/// Clients tracked before idle ones are forgotten.
+const PRUNE_ABOVE: usize = 1024;
+
+/// When each of a client's recent uploads happened, and how big it was.
+type History = VecDeque<(Instant, u64)>;
+
+/// Limits how much clients may upload: a maximum body size per request,
+/// answered with 413, and a quota of bytes per client address over a
+/// rolling window, answered with 429 and `Retry-After`. Add it as
+/// middleware to cover a group of routes, or `wrap` a single route's
+/// handler. Requests without a client address only get the size check.
+#[derive(Debug, Clone, Default)]
+pub struct UploadLimit {
+    max_body: Option<usize>,
+    quota: Option<(u64, Duration)>,
+    uploads: Arc<Mutex<HashMap<IpAddr, History>>>,
+}
+impl UploadLimit {
+    pub fn new() -> Self {
+        UploadLimit::default()
+    }
+
+    /// Rejects request bodies larger than `bytes`, before they're read when
+    /// the request says how large its body is.
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = Some(bytes);
+        self
+    }
+
+    /// Lets each client upload at most `bytes` within any `window`, e.g. an
+    /// hour.
+    pub fn per_client(mut self, bytes: u64, window: Duration) -> Self {
+        self.quota = Some((bytes, window));
+        self
+    }
+
+    /// Applies the limits to just `handler`.
+    pub fn wrap<H: Handler>(self, handler: H) -> impl Handler {
+        LimitedHandler {
+            limit: self,
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// The response to refuse the request with, if it's over a limit.
+    /// Accepted uploads count towards the client's quota.
+    pub(crate) fn check(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let len = request.body().len();
+        if self.max_body.is_some_and(|max| len > max) {
+            debug!("Rejecting {} byte body for {}", len, request.path);
+            return Some(HttpResponse::new(413));
+        }
+
+        let (quota, window) = self.quota?;
+        let ClientAddr(client) = *request.extensions().get::<ClientAddr>()?;
+        let len = len as u64;
+        if len == 0 {
+            return None;
+        }
+        if len > quota {
+            return Some(HttpResponse::new(413));
+        }
+
+        let now = Instant::now();
+        let mut uploads = self.uploads.lock().unwrap();
+        if uploads.len() > PRUNE_ABOVE {
+            uploads.retain(|_, history| {
+                expire(history, now, window);
+                !history.is_empty()
+            });
+        }
+        let history = uploads.entry(client).or_default();
+        expire(history, now, window);
+
+        // Wait until enough of the oldest uploads leave the window to make
+        // room for this one.
+        let mut used: u64 = history.iter().map(|(_, bytes)| bytes).sum();
+        let mut expiring = history.iter();
+        let mut retry_at = None;
+        while used + len > quota {
+            let (at, bytes) = expiring.next()?;
+            used -= bytes;
+            retry_at = Some(*at + window);
+        }
+        if let Some(retry_at) = retry_at {
+            debug!("Upload quota used up for {}", client);
+            let wait = retry_at.saturating_duration_since(now);
+            return Some(HttpResponse::new(429).with_retry_after(wait));
+        }
+
+        history.push_back((now, len));
+        None
+    }
+}
+
+/// Drops uploads older than `window` from a client's history.
+fn expire(history: &mut History, now: Instant, window: Duration) {
+    while history
+        .front()
+        .is_some_and(|(at, _)| now.saturating_duration_since(*at) >= window)
+    {
+        history.pop_front();
+    }
+}
+
+impl Middleware for UploadLimit {
+    fn handle(&self, request: HttpRequest, next: Next) -> BoxFuture<HttpResponse> {
+        match self.check(&request) {
+            Some(refused) => Box::pin(async move { refused }),
+            None => next.run(request),
+        }
+    }
+
+    fn max_body(&self, _request: &HttpRequest) -> Option<usize> {
+        self.max_body
+    }
+}
+
+struct LimitedHandler<H> {
+    limit: UploadLimit,
+    handler: Arc<H>,
+}
+
+impl<H: Handler> Handler for LimitedHandler<H> {
+    fn call(&self, request: HttpRequest) -> BoxFuture<HttpResponse> {
+        match self.limit.check(&request) {
+            Some(refused) => Box::pin(async move { refused }),
+            None => self.handler.call(request),
+        }
+    }
+
+    fn max_body(&self, request: &HttpRequest) -> Option<usize> {
+        let handler = self.handler.max_body(request);
+        self.limit.max_body.into_iter().chain(handler).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Router, Server, TestClient};
+    use rust_http_parse::{HttpMethod, HttpRequestBuilder};
+
+    fn upload(client: [u8; 4], body: &str) -> HttpRequest {
+        let mut request = HttpRequestBuilder::new()
+            .with_method(HttpMethod::POST)
+            .with_path("/upload")
+            .with_body(body)
+            .build()
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ClientAddr(IpAddr::from(client)));
+        request
+    }
+
+    #[tokio::test]
+    async fn limits_body_size_per_route() {
+        let router = Router::new()
+            .route(
+                HttpMethod::POST,
+                "/upload",
+                UploadLimit::new()
+                    .max_body(4)
+                    .wrap(|_| async { HttpResponse::ok() }),
+            )
+            .route(HttpMethod::POST, "/other", |_| async { HttpResponse::ok() });
+        let mut other = upload([10, 0, 0, 1], "0123456789");
+        other.path = "/other".to_owned();
+
+        assert_eq!(
+            200,
+            router.dispatch(upload([10, 0, 0, 1], "0123")).await.status
+        );
+        assert_eq!(
+            413,
+            router.dispatch(upload([10, 0, 0, 1], "01234")).await.status
+        );
+        assert_eq!(200, router.dispatch(other).await.status);
+    }
+
+    #[tokio::test]
+    async fn enforces_a_rolling_quota_per_client() {
+        let router = Router::new()
+            .route(HttpMethod::POST, "/upload", |_| async {
+                HttpResponse::ok()
+            })
+            .middleware(UploadLimit::new().per_client(10, Duration::from_secs(3600)));
+
+        let first = router.dispatch(upload([10, 0, 0, 1], "012345")).await;
+        let over = router.dispatch(upload([10, 0, 0, 1], "012345")).await;
+        let fits = router.dispatch(upload([10, 0, 0, 1], "0123")).await;
+        let other_client = router.dispatch(upload([10, 0, 0, 2], "012345")).await;
+        let too_big = router.dispatch(upload([10, 0, 0, 3], "0123456789x")).await;
+
+        assert_eq!(200, first.status);
+        assert_eq!(429, over.status);
+        assert_eq!(Some(&"3600".to_owned()), over.header("Retry-After"));
+        assert_eq!(200, fits.status);
+        assert_eq!(200, other_client.status);
+        assert_eq!(413, too_big.status);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_declared_body_before_reading_it() {
+        let api = Router::new()
+            .route(HttpMethod::POST, "/upload", |_| async {
+                HttpResponse::ok()
+            })
+            .middleware(UploadLimit::new().max_body(1024));
+        let client = TestClient::new(
+            Server::builder()
+                .route(
+                    HttpMethod::POST,
+                    "/avatar",
+                    UploadLimit::new()
+                        .max_body(4)
+                        .wrap(|_| async { HttpResponse::ok() }),
+                )
+                .nest("/api", api),
+        )
+        .unwrap();
+        // No body follows, so anything that waited to read it would fail.
+        let declaring = |path: &str, length: usize| {
+            format!(
+                "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                path, length
+            )
+        };
+
+        let avatar = client.send_raw(declaring("/avatar", 5).as_bytes()).await;
+        let api = client
+            .send_raw(declaring("/api/upload", 1 << 30).as_bytes())
+            .await;
+        let fits = client.send(HttpMethod::POST, "/avatar", &[], b"0123").await;
+
+        assert_eq!(413, avatar.unwrap().status);
+        assert_eq!(413, api.unwrap().status);
+        assert_eq!(200, fits.unwrap().status);
+    }
+}